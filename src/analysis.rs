@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::quantum_state::{Gate, Instruction};
+
+// Static analysis of flattened circuits. Noise channels and print pragmas are counted as
+// instructions but take no time, so they do not contribute to the depth.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CircuitAnalysis {
+    pub num_qubits: usize,
+    // Number of layers when every instruction is placed as soon as its qubits, and the classical
+    // registers it reads or writes, are free
+    pub depth: usize,
+    // Number of instructions of each kind, by their name in the native circuit format
+    pub gate_counts: BTreeMap<String, usize>,
+    // Number of measurements involving each qubit, including Pauli product measurements
+    pub measurements: Vec<usize>,
+    // Number of two-qubit gates acting on each pair of qubits, with the smaller qubit first
+    pub interactions: BTreeMap<(usize, usize), usize>,
+}
+
+// Instructions and qubits which can affect the final state of a qubit
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightCone {
+    // Indices into the instruction list, in increasing order
+    pub instructions: Vec<usize>,
+    pub qubits: BTreeSet<usize>,
+}
+
+fn is_measurement(gate: Gate) -> bool {
+    return matches!(gate, Gate::MXR | Gate::MYR | Gate::MZR | Gate::MR | Gate::MPP);
+}
+
+fn takes_time(gate: Gate) -> bool {
+    return !matches!(gate, Gate::Noise(_) | Gate::PRINT);
+}
+
+// Classical registers read by the condition of inst
+fn condition_cbits(inst: &Instruction) -> Vec<usize> {
+    return match &inst.condition {
+        Some(condition) => condition.cbits().clone(),
+        None => Vec::new(),
+    };
+}
+
+pub fn analyze(instructions: &Vec<Instruction>, num_qubits: usize) -> CircuitAnalysis {
+    let mut qubit_layers: Vec<usize> = vec![0; num_qubits];
+    // Last layers in which each classical register is written and read
+    let mut cbit_layers: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut cbit_read_layers: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut analysis = CircuitAnalysis { num_qubits: num_qubits, depth: 0, gate_counts: BTreeMap::new(),
+                                         measurements: vec![0; num_qubits], interactions: BTreeMap::new() };
+
+    for inst in instructions {
+        if let Gate::PRINT = inst.id {
+            continue;
+        }
+        *analysis.gate_counts.entry(String::from(inst.id.name())).or_insert(0) += 1;
+
+        if is_measurement(inst.id) {
+            for q in &inst.qubits {
+                analysis.measurements[*q] += 1;
+            }
+        }
+
+        if let Gate::CX | Gate::CY | Gate::CZ | Gate::CP(_) = inst.id {
+            let (q1, q2) = (inst.qubits[0].min(inst.qubits[1]), inst.qubits[0].max(inst.qubits[1]));
+            *analysis.interactions.entry((q1, q2)).or_insert(0) += 1;
+        }
+
+        if takes_time(inst.id) {
+            // Reads follow the last write of a register, and writes follow its last write and read
+            let reads: Vec<usize> = condition_cbits(inst);
+            let layer: usize = 1 + inst.qubits.iter().map(|q| qubit_layers[*q])
+                .chain(inst.cbits.iter().chain(reads.iter()).map(|c| *cbit_layers.get(c).unwrap_or(&0)))
+                .chain(inst.cbits.iter().map(|c| *cbit_read_layers.get(c).unwrap_or(&0)))
+                .max().unwrap_or(0);
+
+            for q in &inst.qubits {
+                qubit_layers[*q] = layer;
+            }
+            for c in &inst.cbits {
+                cbit_layers.insert(*c, layer);
+            }
+            for c in reads {
+                let read_layer: &mut usize = cbit_read_layers.entry(c).or_insert(0);
+                *read_layer = (*read_layer).max(layer);
+            }
+            analysis.depth = analysis.depth.max(layer);
+        }
+    }
+
+    return analysis;
+}
+
+// Backward light cone of qubit at the end of the circuit. An instruction belongs to the cone if it
+// acts on a qubit already in the cone, or writes a classical register read by a condition in the
+// cone before the register is next written.
+pub fn light_cone(instructions: &Vec<Instruction>, qubit: usize) -> LightCone {
+    let mut cone = LightCone { instructions: Vec::new(), qubits: BTreeSet::from([qubit]) };
+    let mut cbits: BTreeSet<usize> = BTreeSet::new();
+
+    for (i, inst) in instructions.iter().enumerate().rev() {
+        if let Gate::PRINT = inst.id {
+            continue;
+        }
+
+        let in_cone: bool = inst.qubits.iter().any(|q| cone.qubits.contains(q)) || inst.cbits.iter().any(|c| cbits.contains(c));
+        if !in_cone {
+            continue;
+        }
+
+        cone.instructions.push(i);
+        cone.qubits.extend(inst.qubits.iter());
+        for c in &inst.cbits {
+            cbits.remove(c);
+        }
+        cbits.extend(condition_cbits(inst));
+    }
+
+    cone.instructions.reverse();
+    return cone;
+}
+
+impl std::fmt::Display for CircuitAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Qubits: {}", self.num_qubits)?;
+        writeln!(f, "Depth: {}", self.depth)?;
+        writeln!(f, "Gate counts:")?;
+        for (name, count) in &self.gate_counts {
+            writeln!(f, "    {name}: {count}")?;
+        }
+        let measurements: Vec<String> = self.measurements.iter().map(|m| m.to_string()).collect();
+        writeln!(f, "Measurements per qubit: {}", measurements.join(" "))?;
+        write!(f, "Interactions:")?;
+        for ((q1, q2), count) in &self.interactions {
+            write!(f, "\n    q{q1} q{q2}: {count}")?;
+        }
+        return Ok(());
+    }
+}
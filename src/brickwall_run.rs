@@ -4,13 +4,18 @@ use std::fs;
 use crate::quantum_chp_state::QuantumCHPState;
 use crate::quantum_graph_state::QuantumGraphState;
 use crate::quantum_vector_state::QuantumVectorState;
-use crate::quantum_state::{QuantumState, Entropy};
+use crate::quantum_dense_state::QuantumDenseState;
+use crate::quantum_mps_state::QuantumMPSState;
+use crate::quantum_stabilizer_rank_state::QuantumStabilizerRankState;
+use crate::quantum_state::{QuantumState, Entropy, NonCliffordState};
+use crate::noise::NoiseModel;
 use dataframe::dataframe::{Sample, DataFrame, DataSlide, RunConfig, ParallelCompute};
 
 use serde::{Serialize, Deserialize};
 use rand::rngs::ThreadRng;
 use rayon::prelude::*;
-use rand::Rng;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_pcg::Lcg64Xsh32;
 
 const fn _true() -> bool { true }
 const fn _false() -> bool { false }
@@ -29,6 +34,10 @@ struct EntropyJSONConfig {
 
     simulator_type: String,
 
+    // The chp simulator can track only the stabilizers, which is enough for entropies
+    #[serde(default = "_true")]
+    track_destabilizers: bool,
+
     system_sizes: Vec<usize>,
     partition_sizes: Vec<usize>,
     mzr_probs: Vec<f32>,
@@ -53,6 +62,32 @@ struct EntropyJSONConfig {
     #[serde(default = "_true")]
     save_data: bool, 
 
+    #[serde(default)]
+    noise: NoiseModel,
+
+    // Probability of a T gate on each qubit after every layer of gates; only simulators of
+    // non-Clifford states accept a non-zero value
+    #[serde(default)]
+    t_gate_prob: f32,
+
+    // Truncation of the mps simulator; bonds are kept exact if absent
+    #[serde(default)]
+    max_bond_dim: Option<usize>,
+    #[serde(default)]
+    cutoff: Option<f64>,
+
+    // Largest number of branches kept by the stabilizer_rank simulator; its default if absent
+    #[serde(default)]
+    max_branches: Option<usize>,
+
+    // Seed from which the seed of every config and run is derived; drawn at random if absent
+    #[serde(default)]
+    seed: Option<u64>,
+    // Seed used by every run, as recorded in the output, to replay a single trajectory; must be
+    // less than 2^31
+    #[serde(default)]
+    run_seed: Option<u32>,
+
     filename: String
 }
 
@@ -79,6 +114,7 @@ struct EntropyConfig {
     circuit_type: CircuitType,
     gate_width: usize,
     simulator_type: String,
+    track_destabilizers: bool,
 
     system_size: usize,
     partition_size: usize,
@@ -94,6 +130,17 @@ struct EntropyConfig {
     
     space_avg: bool,
     spacing: usize,
+
+    noise: NoiseModel,
+    t_gate_prob: f32,
+
+    max_bond_dim: Option<usize>,
+    cutoff: Option<f64>,
+    max_branches: Option<usize>,
+
+    // Seeds the quantum state and every random choice of the circuit. Kept to 31 bits so that it
+    // can be stored as an int parameter of the DataSlide.
+    seed: u32,
 }
 
 enum Gate {
@@ -101,6 +148,24 @@ enum Gate {
     CX,
 }
 
+// Function applying a T gate to a qubit, with the probability of applying it to each qubit after a
+// layer of gates
+type TGates<Q> = Option<(fn(&mut Q, usize), f32)>;
+
+// Applies T gates at random. Nothing is drawn from rng without T gates, so that Clifford runs
+// are unchanged by the option.
+fn apply_t_layer<Q: QuantumState, R: Rng>(quantum_state: &mut Q, t_gates: TGates<Q>, rng: &mut R) {
+    if let Some((t_gate, t_gate_prob)) = t_gates {
+        if t_gate_prob > 0. {
+            for i in 0..quantum_state.system_size() {
+                if rng.gen::<f32>() < t_gate_prob {
+                    t_gate(quantum_state, i);
+                }
+            }
+        }
+    }
+}
+
 fn polarize<Q: QuantumState>(quantum_state: &mut Q) {
     for i in 0..quantum_state.system_size() {
         quantum_state.h_gate(i);
@@ -108,7 +173,7 @@ fn polarize<Q: QuantumState>(quantum_state: &mut Q) {
 }
 
 // Apply a quantum automaton layer
-fn apply_qa_layer<Q: QuantumState>(quantum_state: &mut Q, rng: &mut ThreadRng, offset: bool, gate_type: &Gate) {
+fn apply_qa_layer<Q: QuantumState, R: Rng>(quantum_state: &mut Q, rng: &mut R, offset: bool, gate_type: &Gate, noise: &NoiseModel) {
     let system_size = quantum_state.system_size();
     for i in 0..system_size/2 {
         let mut qubit1 = if offset { (2*i + 1) % system_size } else { 2*i };
@@ -123,18 +188,33 @@ fn apply_qa_layer<Q: QuantumState>(quantum_state: &mut Q, rng: &mut ThreadRng, o
             Gate::CZ => quantum_state.cz_gate(qubit1, qubit2),
             Gate::CX => quantum_state.cx_gate(qubit1, qubit2),
         };
+        noise.apply_two_qubit_gate(quantum_state, qubit1, qubit2, rng);
     }
 }
 
 // Apply some timesteps to the quantum automaton circuit
 pub fn timesteps_qa<Q: QuantumState>(quantum_state: &mut Q, timesteps: usize, mzr_prob: f32) {
     let mut rng: ThreadRng = rand::thread_rng();
+    timesteps_qa_noisy(quantum_state, timesteps, mzr_prob, &NoiseModel::default(), &mut rng);
+}
+
+// As timesteps_qa, with single-qubit noise applied to every qubit before the measurements of each
+// timestep, and the gates and measurement locations drawn from rng
+pub fn timesteps_qa_noisy<Q: QuantumState, R: Rng>(quantum_state: &mut Q, timesteps: usize, mzr_prob: f32, noise: &NoiseModel, rng: &mut R) {
+    timesteps_qa_t_gates(quantum_state, timesteps, mzr_prob, noise, None, rng);
+}
+
+// As timesteps_qa_noisy, with T gates applied at random after the gates of each timestep
+fn timesteps_qa_t_gates<Q: QuantumState, R: Rng>(quantum_state: &mut Q, timesteps: usize, mzr_prob: f32, noise: &NoiseModel, t_gates: TGates<Q>, rng: &mut R) {
     for i in 0..timesteps {
-        apply_qa_layer(quantum_state, &mut rng, false, &Gate::CX);
-        apply_qa_layer(quantum_state, &mut rng, false, &Gate::CZ);
+        apply_qa_layer(quantum_state, rng, false, &Gate::CX, noise);
+        apply_qa_layer(quantum_state, rng, false, &Gate::CZ, noise);
+
+        apply_qa_layer(quantum_state, rng, true, &Gate::CX, noise);
+        apply_qa_layer(quantum_state, rng, true, &Gate::CZ, noise);
 
-        apply_qa_layer(quantum_state, &mut rng, true, &Gate::CX);
-        apply_qa_layer(quantum_state, &mut rng, true, &Gate::CZ);
+        apply_t_layer(quantum_state, t_gates, rng);
+        noise.apply_layer(quantum_state, rng);
 
         for i in 0..quantum_state.system_size() {
             if rng.gen::<f32>() < mzr_prob {
@@ -145,7 +225,8 @@ pub fn timesteps_qa<Q: QuantumState>(quantum_state: &mut Q, timesteps: usize, mz
     }
 }
 
-fn timesteps_rc<Q: QuantumState>(quantum_state: &mut Q, timesteps: usize, mzr_prob: f32, gate_width: usize, init_offset: bool) {
+fn timesteps_rc<Q: QuantumState, R: Rng>(quantum_state: &mut Q, timesteps: usize, mzr_prob: f32, gate_width: usize, init_offset: bool, noise: &NoiseModel,
+                                         t_gates: TGates<Q>, rng: &mut R) {
     let system_size = quantum_state.system_size();
 
     // System size must be divisible by gate width
@@ -157,8 +238,6 @@ fn timesteps_rc<Q: QuantumState>(quantum_state: &mut Q, timesteps: usize, mzr_pr
     let offset: usize = gate_width / 2;
     let num_gates: usize = system_size / gate_width;
 
-    let mut rng: ThreadRng = rand::thread_rng();
-
     let mut offset_layer: bool = init_offset;
 
     for t in 0..timesteps {
@@ -172,11 +251,17 @@ fn timesteps_rc<Q: QuantumState>(quantum_state: &mut Q, timesteps: usize, mzr_pr
                 qubits.iter().map(|j| (j + gate_width*i + gate_width/2) % system_size).collect()
             };
             
-            quantum_state.random_clifford(offset_qubits);
+            quantum_state.random_clifford(offset_qubits.clone());
+            for pair in offset_qubits.chunks(2) {
+                noise.apply_two_qubit_gate(quantum_state, pair[0], pair[1], rng);
+            }
         }
 
         offset_layer = !offset_layer;
 
+        apply_t_layer(quantum_state, t_gates, rng);
+        noise.apply_layer(quantum_state, rng);
+
         for i in 0..system_size {
             if rng.gen::<f32>() < mzr_prob {
                 quantum_state.mzr_qubit(i);
@@ -188,9 +273,34 @@ fn timesteps_rc<Q: QuantumState>(quantum_state: &mut Q, timesteps: usize, mzr_pr
 
 impl EntropyConfig {
     pub fn from(json_config: &EntropyJSONConfig, system_size_idx: usize, timesteps_idx: usize, 
-                                                 partition_size_idx: usize, mzr_idx: usize) -> Self {
+                                                 partition_size_idx: usize, mzr_idx: usize, seed: u32) -> Self {
         assert!(json_config.system_sizes[system_size_idx] >= json_config.partition_sizes[partition_size_idx]);
         assert!(json_config.mzr_probs[mzr_idx] >= 0. && json_config.mzr_probs[mzr_idx] <= 1.);
+        if let Err(msg) = json_config.noise.validate() {
+            println!("Invalid noise: {msg}");
+            panic!();
+        }
+        if !json_config.track_destabilizers && json_config.simulator_type != "chp" {
+            println!("Only the chp simulator can drop destabilizers.");
+            panic!();
+        }
+        if !(0. ..=1.).contains(&json_config.t_gate_prob) {
+            println!("t_gate_prob must lie between 0 and 1.");
+            panic!();
+        }
+        if json_config.t_gate_prob > 0. && ["chp", "graph"].contains(&json_config.simulator_type.as_str()) {
+            println!("The {} simulator cannot apply T gates.", json_config.simulator_type);
+            panic!();
+        }
+        if json_config.circuit_type == "random_clifford" && !["chp", "stabilizer_rank"].contains(&json_config.simulator_type.as_str()) {
+            println!("The {} simulator cannot apply random Clifford gates.", json_config.simulator_type);
+            panic!();
+        }
+        // Seeds are recorded in the output as 32-bit signed integers
+        if json_config.run_seed.is_some_and(|run_seed| run_seed > i32::MAX as u32) {
+            println!("run_seed must be less than 2^31.");
+            panic!();
+        }
         EntropyConfig{
             circuit_type: match json_config.circuit_type.as_str() {
                 "default" => CircuitType::QuantumAutomaton,
@@ -203,6 +313,7 @@ impl EntropyConfig {
             },
             gate_width: json_config.gate_width,
             simulator_type: json_config.simulator_type.clone(),
+            track_destabilizers: json_config.track_destabilizers,
 
             system_size: json_config.system_sizes[system_size_idx],
             partition_size: json_config.partition_sizes[partition_size_idx],
@@ -218,22 +329,47 @@ impl EntropyConfig {
 
             space_avg: json_config.space_avg,
             spacing: json_config.spacing,
+
+            noise: json_config.noise.clone(),
+            t_gate_prob: json_config.t_gate_prob,
+
+            max_bond_dim: json_config.max_bond_dim,
+            cutoff: json_config.cutoff,
+            max_branches: json_config.max_branches,
+
+            seed: seed,
         }
     }
 
-    fn compute_entropy<Q: QuantumState + Entropy>(&self, quantum_state: &mut Q) -> Vec<Sample> {
+    fn compute_entropy<Q: QuantumState + Entropy>(&self) -> Vec<Sample> {
+        return self.compute_entropy_with(Q::new_with_seed, None).0;
+    }
+
+    // As compute_entropy, with T gates inserted with probability t_gate_prob
+    fn compute_entropy_non_clifford<Q: NonCliffordState + Entropy>(&self) -> Vec<Sample> {
+        return self.compute_entropy_with(Q::new_with_seed, Some(Q::t_gate)).0;
+    }
+
+    // Runs the circuit on the state built by new_state from the system size and a seed, returning
+    // the entropy samples along with the final state. T gates are applied by t_gate, if given.
+    fn compute_entropy_with<Q: QuantumState + Entropy, F: FnOnce(usize, u64) -> Q>(&self, new_state: F, t_gate: Option<fn(&mut Q, usize)>) -> (Vec<Sample>, Q) {
+        // The state draws measurement outcomes from its own stream, seeded from the circuit's
+        let mut rng = Lcg64Xsh32::seed_from_u64(self.seed as u64);
+        let mut state: Q = new_state(self.system_size, rng.next_u64());
+        let quantum_state: &mut Q = &mut state;
         let system_size = quantum_state.system_size();
         let qubits: Vec<usize> = (0..self.partition_size).collect();
         let mut entropy: Vec<Sample> = Vec::new();
+        let t_gates: TGates<Q> = t_gate.map(|t_gate| (t_gate, self.t_gate_prob));
 
         // Intially polarize in x-direction
         match self.circuit_type {
             CircuitType::QuantumAutomaton => {
                 polarize(quantum_state);
-                timesteps_qa(quantum_state, self.equilibration_steps, self.mzr_prob);
+                timesteps_qa_t_gates(quantum_state, self.equilibration_steps, self.mzr_prob, &self.noise, t_gates, &mut rng);
             },
             CircuitType::RandomClifford => {
-                timesteps_rc(quantum_state, self.equilibration_steps, self.mzr_prob, self.gate_width, false);
+                timesteps_rc(quantum_state, self.equilibration_steps, self.mzr_prob, self.gate_width, false, &self.noise, t_gates, &mut rng);
             },
         }
 
@@ -247,8 +383,8 @@ impl EntropyConfig {
         
         for t in 0..num_intervals {
             match self.circuit_type {
-                CircuitType::QuantumAutomaton => timesteps_qa(quantum_state, num_timesteps, self.mzr_prob),
-                CircuitType::RandomClifford => timesteps_rc(quantum_state, num_timesteps, self.mzr_prob, self.gate_width, t*num_timesteps % 2 == 0),
+                CircuitType::QuantumAutomaton => timesteps_qa_t_gates(quantum_state, num_timesteps, self.mzr_prob, &self.noise, t_gates, &mut rng),
+                CircuitType::RandomClifford => timesteps_rc(quantum_state, num_timesteps, self.mzr_prob, self.gate_width, t*num_timesteps % 2 == 0, &self.noise, t_gates, &mut rng),
             }
 
             let sample: Sample = 
@@ -277,7 +413,27 @@ impl EntropyConfig {
             entropy.push(sample);
         }
 
-        return entropy;
+        return (entropy, state);
+    }
+
+    fn compute_entropy_mps(&self) -> (Vec<Sample>, f64) {
+        let (entropy, state) = self.compute_entropy_with(|system_size, seed| {
+            let mut state = QuantumMPSState::new_with_seed(system_size, seed);
+            state.set_truncation(self.max_bond_dim.unwrap_or(usize::MAX), self.cutoff.unwrap_or(1e-14));
+            state
+        }, Some(QuantumMPSState::t_gate));
+        return (entropy, state.discarded_weight());
+    }
+
+    fn compute_entropy_stabilizer_rank(&self) -> (Vec<Sample>, f64) {
+        let (entropy, state) = self.compute_entropy_with(|system_size, seed| {
+            let mut state = QuantumStabilizerRankState::new_with_seed(system_size, seed);
+            if let Some(max_branches) = self.max_branches {
+                state.set_max_branches(max_branches);
+            }
+            state
+        }, Some(QuantumStabilizerRankState::t_gate));
+        return (entropy, state.discarded_weight());
     }
 }
 
@@ -287,6 +443,9 @@ impl RunConfig for EntropyConfig {
             "chp" => (),
             "graph" => (),
             "vector" => (),
+            "dense" => (),
+            "mps" => (),
+            "stabilizer_rank" => (),
             _ => {
                 println!("Error: simulator type provided not supported.");
                 panic!()
@@ -303,24 +462,54 @@ impl RunConfig for EntropyConfig {
         dataslide.add_int_param("timesteps", self.timesteps as i32);
         dataslide.add_int_param("partition_size", self.partition_size as i32);
         dataslide.add_float_param("mzr_prob", self.mzr_prob);
+        dataslide.add_int_param("seed", self.seed as i32);
+        if !self.noise.is_noiseless() {
+            dataslide.add_float_param("depolarize1", self.noise.depolarize1);
+            dataslide.add_float_param("depolarize2", self.noise.depolarize2);
+            dataslide.add_float_param("x_error", self.noise.x_error);
+            dataslide.add_float_param("z_error", self.noise.z_error);
+            dataslide.add_float_param("px", self.noise.pauli_channel[0]);
+            dataslide.add_float_param("py", self.noise.pauli_channel[1]);
+            dataslide.add_float_param("pz", self.noise.pauli_channel[2]);
+        }
+
+        if self.t_gate_prob > 0. {
+            dataslide.add_float_param("t_gate_prob", self.t_gate_prob);
+        }
+
+        if let Some(max_bond_dim) = self.max_bond_dim {
+            dataslide.add_int_param("max_bond_dim", max_bond_dim as i32);
+        }
+        if let Some(max_branches) = self.max_branches {
+            dataslide.add_int_param("max_branches", max_branches as i32);
+        }
 
         dataslide.add_data("entropy");
         
         // TODO revisit so that simulator is consistently stored in config
         let mut entropy: Vec<Sample> =
         match self.simulator_type.as_str() {
-            "chp" => {
-                let mut state: QuantumCHPState = QuantumCHPState::new(self.system_size);
-                self.compute_entropy(&mut state)
-            }, 
-            "graph" => {
-                let mut state: QuantumGraphState = QuantumGraphState::new(self.system_size);
-                self.compute_entropy(&mut state)
-            }, 
-            "vector" => {
-                let mut state: QuantumVectorState = QuantumVectorState::new(self.system_size);
-                self.compute_entropy(&mut state)
-            }, 
+            "chp" if !self.track_destabilizers => self.compute_entropy_with(|system_size, seed| {
+                let mut state = QuantumCHPState::new_with_seed(system_size, seed);
+                state.drop_destabilizers();
+                state
+            }, None).0,
+            "chp" => self.compute_entropy::<QuantumCHPState>(),
+            "graph" => self.compute_entropy::<QuantumGraphState>(),
+            "vector" => self.compute_entropy_non_clifford::<QuantumVectorState>(),
+            "dense" => self.compute_entropy_non_clifford::<QuantumDenseState>(),
+            "mps" => {
+                let (entropy, discarded_weight) = self.compute_entropy_mps();
+                dataslide.add_data("discarded_weight");
+                dataslide.push_data("discarded_weight", Sample::new(discarded_weight as f32));
+                entropy
+            },
+            "stabilizer_rank" => {
+                let (entropy, discarded_weight) = self.compute_entropy_stabilizer_rank();
+                dataslide.add_data("discarded_weight");
+                dataslide.push_data("discarded_weight", Sample::new(discarded_weight as f32));
+                entropy
+            },
             _ => panic!()
         };
 
@@ -338,17 +527,26 @@ impl RunConfig for EntropyConfig {
 }
 
 
+// Seed of the config with the given index, derived from the seed of the whole run
+fn run_seed(seed: u64, index: usize) -> u32 {
+    return Lcg64Xsh32::seed_from_u64(seed ^ (index as u64).wrapping_mul(0x9E3779B97F4A7C15)).next_u32() >> 1;
+}
+
 fn load_json_config(json_config: &EntropyJSONConfig) -> Vec<EntropyConfig> {
+    let seed: u64 = json_config.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>());
+    println!("Seed: {seed}");
     let mut configs: Vec<EntropyConfig> = Vec::new();
     for system_size_idx in 0..json_config.system_sizes.len() {
         for timesteps_idx in 0..json_config.timesteps.len() {
             for partition_size_idx in 0..json_config.partition_sizes.len() {
                 for mzr_prob_idx in 0..json_config.mzr_probs.len() {
                     for i in 0..json_config.num_runs {
+                        let config_seed: u32 = json_config.run_seed.unwrap_or_else(|| run_seed(seed, configs.len()));
                         configs.push(EntropyConfig::from(&json_config, system_size_idx, 
                                                                     timesteps_idx, 
                                                                     partition_size_idx, 
-                                                                    mzr_prob_idx));
+                                                                    mzr_prob_idx,
+                                                                    config_seed));
                     }
                 }
             }
@@ -0,0 +1,130 @@
+use crate::noise::NoiseChannel;
+use crate::quantum_state::{self, Block, Gate, Instruction, QuantumProgram, QuantumState, NonCliffordState};
+
+// Typed construction of circuits, as an alternative to formatting and parsing the native circuit
+// format. Methods can be chained, and panic on qubits or classical registers which are out of range,
+// as from_qasm does. Circuits with non-Clifford gates are built with build_non_clifford, for
+// backends implementing NonCliffordState.
+//
+//     let program = CircuitBuilder::new(2, 1)
+//         .h(0)
+//         .repeat(3, |b| { b.cz(0, 1).h(1); })
+//         .mzr(1, 0)
+//         .build::<QuantumCHPState>();
+#[derive(Clone, Debug, PartialEq)]
+pub struct CircuitBuilder {
+    num_qubits: usize,
+    num_cbits: usize,
+    blocks: Vec<Block>,
+}
+
+impl CircuitBuilder {
+    pub fn new(num_qubits: usize, num_cbits: usize) -> Self {
+        return CircuitBuilder { num_qubits: num_qubits, num_cbits: num_cbits, blocks: Vec::new() };
+    }
+
+    pub fn num_qubits(&self) -> usize {
+        return self.num_qubits;
+    }
+
+    pub fn num_cbits(&self) -> usize {
+        return self.num_cbits;
+    }
+
+    // Appends an arbitrary instruction
+    pub fn push(&mut self, inst: Instruction) -> &mut Self {
+        for q in &inst.qubits {
+            assert!(*q < self.num_qubits, "qubit {q} out of range for {} qubits", self.num_qubits);
+        }
+        for c in inst.cbits.iter().chain(inst.condition.iter().flat_map(|condition| condition.cbits())) {
+            assert!(*c < self.num_cbits, "classical register {c} out of range for {} registers", self.num_cbits);
+        }
+        for (i, q) in inst.qubits.iter().enumerate() {
+            assert!(!inst.qubits[..i].contains(q), "qubit {q} appears more than once in {}", inst.id.name());
+        }
+
+        self.blocks.push(Block::Instruction(inst));
+        return self;
+    }
+
+    // Appends gate acting on qubits and writing to cbits
+    pub fn gate(&mut self, gate: Gate, qubits: &[usize], cbits: &[usize]) -> &mut Self {
+        return self.push(Instruction { id: gate, qubits: qubits.to_vec(), cbits: cbits.to_vec(), condition: None, paulis: Vec::new() });
+    }
+
+    pub fn x(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::X, &[qubit], &[]); }
+    pub fn y(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::Y, &[qubit], &[]); }
+    pub fn z(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::Z, &[qubit], &[]); }
+    pub fn h(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::H, &[qubit], &[]); }
+    pub fn s(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::S, &[qubit], &[]); }
+    pub fn sd(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::Sd, &[qubit], &[]); }
+    pub fn sqrtx(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::SQRTX, &[qubit], &[]); }
+    pub fn sqrtxd(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::SQRTXd, &[qubit], &[]); }
+    pub fn sqrty(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::SQRTY, &[qubit], &[]); }
+    pub fn sqrtyd(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::SQRTYd, &[qubit], &[]); }
+    pub fn sqrtz(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::SQRTZ, &[qubit], &[]); }
+    pub fn sqrtzd(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::SQRTZd, &[qubit], &[]); }
+
+    pub fn cx(&mut self, qubit1: usize, qubit2: usize) -> &mut Self { return self.gate(Gate::CX, &[qubit1, qubit2], &[]); }
+    pub fn cy(&mut self, qubit1: usize, qubit2: usize) -> &mut Self { return self.gate(Gate::CY, &[qubit1, qubit2], &[]); }
+    pub fn cz(&mut self, qubit1: usize, qubit2: usize) -> &mut Self { return self.gate(Gate::CZ, &[qubit1, qubit2], &[]); }
+
+    pub fn t(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::T, &[qubit], &[]); }
+    pub fn td(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::Td, &[qubit], &[]); }
+    pub fn rx(&mut self, qubit: usize, theta: f64) -> &mut Self { return self.gate(Gate::Rx(theta), &[qubit], &[]); }
+    pub fn ry(&mut self, qubit: usize, theta: f64) -> &mut Self { return self.gate(Gate::Ry(theta), &[qubit], &[]); }
+    pub fn rz(&mut self, qubit: usize, theta: f64) -> &mut Self { return self.gate(Gate::Rz(theta), &[qubit], &[]); }
+    pub fn u3(&mut self, qubit: usize, theta: f64, phi: f64, lambda: f64) -> &mut Self { return self.gate(Gate::U3(theta, phi, lambda), &[qubit], &[]); }
+    pub fn cp(&mut self, qubit1: usize, qubit2: usize, phi: f64) -> &mut Self { return self.gate(Gate::CP(phi), &[qubit1, qubit2], &[]); }
+
+    pub fn mxr(&mut self, qubit: usize, cbit: usize) -> &mut Self { return self.gate(Gate::MXR, &[qubit], &[cbit]); }
+    pub fn myr(&mut self, qubit: usize, cbit: usize) -> &mut Self { return self.gate(Gate::MYR, &[qubit], &[cbit]); }
+    pub fn mzr(&mut self, qubit: usize, cbit: usize) -> &mut Self { return self.gate(Gate::MZR, &[qubit], &[cbit]); }
+    pub fn mr(&mut self, qubit: usize, cbit: usize) -> &mut Self { return self.gate(Gate::MR, &[qubit], &[cbit]); }
+
+    pub fn reset(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::R, &[qubit], &[]); }
+    pub fn resetx(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::RX, &[qubit], &[]); }
+    pub fn resety(&mut self, qubit: usize) -> &mut Self { return self.gate(Gate::RY, &[qubit], &[]); }
+
+    // Measures the product of the Paulis (Gate::X, Gate::Y or Gate::Z) on the given qubits
+    pub fn mpp(&mut self, paulis: &[(Gate, usize)], cbit: usize) -> &mut Self {
+        assert!(paulis.iter().all(|(p, _)| matches!(p, Gate::X | Gate::Y | Gate::Z)), "mpp takes only X, Y and Z");
+        return self.push(Instruction { id: Gate::MPP, qubits: paulis.iter().map(|(_, q)| *q).collect(), cbits: vec![cbit],
+                                       condition: None, paulis: paulis.iter().map(|(p, _)| *p).collect() });
+    }
+
+    pub fn noise(&mut self, channel: NoiseChannel, qubits: &[usize]) -> &mut Self {
+        assert_eq!(qubits.len(), channel.num_qubits(), "{} acts on {} qubits", channel.name(), channel.num_qubits());
+        return self.gate(Gate::Noise(channel), qubits, &[]);
+    }
+
+    pub fn print(&mut self) -> &mut Self {
+        self.blocks.push(Block::Instruction(Instruction { id: Gate::PRINT, qubits: Vec::new(), cbits: Vec::new(),
+                                                          condition: None, paulis: Vec::new() }));
+        return self;
+    }
+
+    // Appends a block repeated n times, whose body is built by body
+    pub fn repeat<F: FnOnce(&mut CircuitBuilder)>(&mut self, n: usize, body: F) -> &mut Self {
+        let mut builder = CircuitBuilder::new(self.num_qubits, self.num_cbits);
+        body(&mut builder);
+        self.blocks.push(Block::Repeat(n, builder.blocks));
+        return self;
+    }
+
+    // Appends the contents of another builder over the same registers
+    pub fn append(&mut self, other: &CircuitBuilder) -> &mut Self {
+        assert!(other.num_qubits <= self.num_qubits && other.num_cbits <= self.num_cbits);
+        self.blocks.extend(other.blocks.iter().cloned());
+        return self;
+    }
+
+    pub fn build<Q: QuantumState>(&self) -> QuantumProgram<Q> {
+        assert!(!quantum_state::contains_non_clifford(&self.blocks), "circuit contains non-Clifford gates; use build_non_clifford");
+        return QuantumProgram::from_blocks(self.num_qubits, self.num_cbits, self.blocks.clone());
+    }
+
+    pub fn build_non_clifford<Q: NonCliffordState>(&self) -> QuantumProgram<Q> {
+        return QuantumProgram::from_blocks_non_clifford(self.num_qubits, self.num_cbits, self.blocks.clone());
+    }
+}
@@ -1,9 +1,21 @@
 #![allow(warnings)]
 
 pub mod quantum_state;
+pub mod openqasm;
+pub mod stim;
+pub mod noise;
+pub mod optimizer;
+pub mod analysis;
+pub mod render;
+pub mod circuit_builder;
 pub mod quantum_vector_state;
+pub mod quantum_dense_state;
+pub mod quantum_density_matrix_state;
+pub mod quantum_mps_state;
 pub mod quantum_graph_state;
 pub mod quantum_chp_state;
+pub mod quantum_stabilizer_rank_state;
+pub mod quantum_pauli_frame_state;
 pub mod brickwall_run;
 pub mod tests;
 pub mod util;
@@ -1,9 +1,25 @@
 use quantum_circuit::brickwall_run::take_data;
+use quantum_circuit::quantum_graph_state::QuantumGraphState;
+use quantum_circuit::quantum_state::{QasmError, QuantumProgram};
 use std::time::Instant;
 
 
+const USAGE: &str = "Usage:\n    -e <num_threads> <config_file>\n    -a <circuit_file> [qubit]\n    -d <circuit_file> [svg_file]";
+
+// Prints msg with the usage and exits
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{msg}\n{USAGE}");
+    std::process::exit(1);
+}
+
 fn compute_entropy_run(args: Vec<String>) {
-    let num_threads: usize = args[0].parse::<usize>().unwrap();
+    if args.len() < 2 {
+        usage_error("Missing arguments to -e.");
+    }
+    let num_threads: usize = match args[0].parse::<usize>() {
+        Ok(num_threads) => num_threads,
+        Err(_) => usage_error(&format!("Invalid number of threads '{}'.", args[0])),
+    };
     let config_filename: &String = &args[1];
 
     let now = Instant::now();
@@ -15,6 +31,67 @@ fn compute_entropy_run(args: Vec<String>) {
 }
 
 
+// Parses a circuit file as Stim if its extension is .stim, as OpenQASM if it starts with an
+// OPENQASM header, and in the native format otherwise. The circuit is only analyzed or drawn, so
+// it is loaded onto a graph state, which is cheap to create.
+fn load_circuit(filename: &String) -> Result<QuantumProgram<QuantumGraphState>, String> {
+    let circuit: String = std::fs::read_to_string(filename).map_err(|err| format!("{filename}: {err}"))?;
+    let program: Result<QuantumProgram<QuantumGraphState>, QasmError> = if filename.ends_with(".stim") {
+        QuantumProgram::try_from_stim(&circuit)
+    } else if circuit.trim_start().starts_with("OPENQASM") {
+        QuantumProgram::try_from_openqasm(&circuit)
+    } else {
+        QuantumProgram::try_from_qasm(&circuit)
+    };
+    program.map_err(|err| format!("{filename}: {err}"))
+}
+
+// Loads the circuit file named by the first argument, exiting with an error if it cannot be loaded
+fn load_circuit_arg(args: &[String], flag: &str) -> QuantumProgram<QuantumGraphState> {
+    if args.is_empty() {
+        usage_error(&format!("Missing circuit file for {flag}."));
+    }
+    match load_circuit(&args[0]) {
+        Ok(program) => program,
+        Err(msg) => {
+            eprintln!("{msg}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Prints the analysis of a circuit file and, if a qubit is given, its backward light cone
+fn analyze_circuit(args: Vec<String>) {
+    let program = load_circuit_arg(&args, "-a");
+
+    let analysis = program.analyze();
+    println!("{analysis}");
+    if args.len() > 1 {
+        let qubit: usize = match args[1].parse::<usize>() {
+            Ok(qubit) if qubit < analysis.num_qubits => qubit,
+            _ => usage_error(&format!("Invalid qubit '{}'.", args[1])),
+        };
+        let cone = program.light_cone(qubit);
+        let qubits: Vec<String> = cone.qubits.iter().map(|q| format!("q{q}")).collect();
+        println!("Light cone of q{qubit}: {} instructions on {}", cone.instructions.len(), qubits.join(" "));
+    }
+}
+
+
+// Prints the diagram of a circuit file and, if a filename is given, writes it there as SVG
+fn draw_circuit(args: Vec<String>) {
+    let program = load_circuit_arg(&args, "-d");
+
+    println!("{}", program.to_ascii());
+    if args.len() > 1 {
+        if let Err(err) = std::fs::write(&args[1], program.to_svg()) {
+            eprintln!("{}: {err}", args[1]);
+            std::process::exit(1);
+        }
+    }
+}
+
+
 fn default() {
     println!("Success!");
     
@@ -32,14 +109,10 @@ fn main() {
         return;
     }
 
-    let flags: Vec<String> = args[1].chars().into_iter().map(|x| String::from(x)).collect();
-    assert!(flags[0] == "-");
-
-    match flags[1].as_str() {
-        "e" => compute_entropy_run(args[2..].to_vec()),
-        _ => {
-            println!("Not a valid run option.");
-            panic!();
-        }
+    match args[1].as_str() {
+        "-e" => compute_entropy_run(args[2..].to_vec()),
+        "-a" => analyze_circuit(args[2..].to_vec()),
+        "-d" => draw_circuit(args[2..].to_vec()),
+        flag => usage_error(&format!("Not a valid run option '{flag}'.")),
     }
 }
@@ -0,0 +1,202 @@
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::quantum_state::QuantumState;
+
+// Stochastic Pauli channels. Each application samples a single Pauli error from the channel and
+// applies it to the state, so that averaging over trajectories reproduces the channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseChannel {
+    // With probability p, applies one of X, Y and Z, chosen uniformly
+    Depolarize1(f32),
+    // With probability p, applies one of the 15 non-identity two-qubit Paulis, chosen uniformly
+    Depolarize2(f32),
+    XError(f32),
+    ZError(f32),
+    // Applies X, Y and Z with probabilities px, py and pz respectively
+    PauliChannel(f32, f32, f32),
+}
+
+impl NoiseChannel {
+    // Looks up a channel by its name in the native circuit format, returning it with zeroed
+    // probabilities and the number of probabilities it takes
+    pub(crate) fn from_name(name: &str) -> Option<(NoiseChannel, usize)> {
+        match name {
+            "depolarize1" | "DEPOLARIZE1" => Some((NoiseChannel::Depolarize1(0.), 1)),
+            "depolarize2" | "DEPOLARIZE2" => Some((NoiseChannel::Depolarize2(0.), 1)),
+            "x_error" | "X_ERROR" => Some((NoiseChannel::XError(0.), 1)),
+            "z_error" | "Z_ERROR" => Some((NoiseChannel::ZError(0.), 1)),
+            "pauli_channel" | "PAULI_CHANNEL" => Some((NoiseChannel::PauliChannel(0., 0., 0.), 3)),
+            _ => None,
+        }
+    }
+
+    // Returns a channel of the same kind with the given probabilities, or an error message if they
+    // do not form a valid distribution
+    pub(crate) fn with_probs(&self, probs: &[f32]) -> Result<NoiseChannel, String> {
+        if probs.iter().any(|p| !(0. ..=1.).contains(p)) {
+            return Err(String::from("probabilities must lie between 0 and 1"));
+        }
+
+        let channel = match self {
+            NoiseChannel::Depolarize1(_) => NoiseChannel::Depolarize1(probs[0]),
+            NoiseChannel::Depolarize2(_) => NoiseChannel::Depolarize2(probs[0]),
+            NoiseChannel::XError(_) => NoiseChannel::XError(probs[0]),
+            NoiseChannel::ZError(_) => NoiseChannel::ZError(probs[0]),
+            NoiseChannel::PauliChannel(_, _, _) => {
+                if probs[0] + probs[1] + probs[2] > 1. {
+                    return Err(String::from("probabilities of pauli_channel must sum to at most 1"));
+                }
+                NoiseChannel::PauliChannel(probs[0], probs[1], probs[2])
+            },
+        };
+
+        return Ok(channel);
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            NoiseChannel::Depolarize1(_) => "depolarize1",
+            NoiseChannel::Depolarize2(_) => "depolarize2",
+            NoiseChannel::XError(_) => "x_error",
+            NoiseChannel::ZError(_) => "z_error",
+            NoiseChannel::PauliChannel(_, _, _) => "pauli_channel",
+        }
+    }
+
+    pub(crate) fn probs(&self) -> Vec<f32> {
+        match *self {
+            NoiseChannel::Depolarize1(p) | NoiseChannel::Depolarize2(p) | NoiseChannel::XError(p) | NoiseChannel::ZError(p) => vec![p],
+            NoiseChannel::PauliChannel(px, py, pz) => vec![px, py, pz],
+        }
+    }
+
+    // The non-identity Paulis applied by the channel with their probabilities, with Paulis indexed
+    // as by apply_pauli and, for depolarize2, the Pauli on the second qubit in the high bits
+    pub(crate) fn pauli_distribution(&self) -> Vec<(f32, usize)> {
+        match *self {
+            NoiseChannel::Depolarize1(p) => (1..4).map(|pauli| (p/3., pauli)).collect(),
+            NoiseChannel::Depolarize2(p) => (1..16).map(|pauli| (p/15., pauli)).collect(),
+            NoiseChannel::XError(p) => vec![(p, 1)],
+            NoiseChannel::ZError(p) => vec![(p, 3)],
+            NoiseChannel::PauliChannel(px, py, pz) => vec![(px, 1), (py, 2), (pz, 3)],
+        }
+    }
+
+    pub fn num_qubits(&self) -> usize {
+        if let NoiseChannel::Depolarize2(_) = self { 2 } else { 1 }
+    }
+
+    // Samples an error from the channel, returning the index of the Pauli as in pauli_distribution,
+    // or None if no error occurs
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<usize> {
+        let r: f32 = rng.gen::<f32>();
+        match *self {
+            NoiseChannel::Depolarize1(p) => {
+                if r < p {
+                    return Some(1 + (3.*r/p) as usize % 3);
+                }
+            },
+            NoiseChannel::Depolarize2(p) => {
+                if r < p {
+                    return Some(1 + (15.*r/p) as usize % 15);
+                }
+            },
+            NoiseChannel::XError(p) => {
+                if r < p {
+                    return Some(1);
+                }
+            },
+            NoiseChannel::ZError(p) => {
+                if r < p {
+                    return Some(3);
+                }
+            },
+            NoiseChannel::PauliChannel(px, py, pz) => {
+                if r < px {
+                    return Some(1);
+                } else if r < px + py {
+                    return Some(2);
+                } else if r < px + py + pz {
+                    return Some(3);
+                }
+            },
+        }
+        return None;
+    }
+
+    // Samples an error from the channel and applies it to qubits
+    pub fn apply<Q: QuantumState, R: Rng>(&self, quantum_state: &mut Q, qubits: &[usize], rng: &mut R) {
+        if let Some(pauli) = self.sample(rng) {
+            for (i, &qubit) in qubits.iter().enumerate() {
+                apply_pauli(quantum_state, qubit, (pauli >> 2*i) & 3);
+            }
+        }
+    }
+}
+
+// Applies I, X, Y or Z for pauli = 0, 1, 2 or 3
+fn apply_pauli<Q: QuantumState>(quantum_state: &mut Q, qubit: usize, pauli: usize) {
+    match pauli {
+        1 => quantum_state.x_gate(qubit),
+        2 => quantum_state.y_gate(qubit),
+        3 => quantum_state.z_gate(qubit),
+        _ => (),
+    }
+}
+
+// Uniform noise for circuits generated on the fly, such as the brickwall runs. Single-qubit
+// channels act on every qubit once per layer, and depolarize2 acts on the qubits of every
+// two-qubit gate. Every channel defaults to a probability of zero.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NoiseModel {
+    #[serde(default)]
+    pub depolarize1: f32,
+    #[serde(default)]
+    pub depolarize2: f32,
+    #[serde(default)]
+    pub x_error: f32,
+    #[serde(default)]
+    pub z_error: f32,
+    #[serde(default)]
+    pub pauli_channel: [f32; 3],
+}
+
+impl NoiseModel {
+    pub fn is_noiseless(&self) -> bool {
+        return *self == NoiseModel::default();
+    }
+
+    // Checks that every probability lies between 0 and 1, and that pauli_channel is a distribution
+    pub fn validate(&self) -> Result<(), String> {
+        let [px, py, pz] = self.pauli_channel;
+        let channels = [NoiseChannel::Depolarize1(self.depolarize1), NoiseChannel::Depolarize2(self.depolarize2),
+                        NoiseChannel::XError(self.x_error), NoiseChannel::ZError(self.z_error), NoiseChannel::PauliChannel(px, py, pz)];
+        for channel in channels {
+            channel.with_probs(&channel.probs()).map_err(|msg| format!("{}: {msg}", channel.name()))?;
+        }
+        return Ok(());
+    }
+
+    // Channels which act on every qubit after a layer of gates
+    fn single_qubit_channels(&self) -> Vec<NoiseChannel> {
+        let [px, py, pz] = self.pauli_channel;
+        let channels = vec![NoiseChannel::XError(self.x_error), NoiseChannel::ZError(self.z_error),
+                            NoiseChannel::Depolarize1(self.depolarize1), NoiseChannel::PauliChannel(px, py, pz)];
+        return channels.into_iter().filter(|c| c.probs().iter().any(|p| *p > 0.)).collect();
+    }
+
+    pub fn apply_layer<Q: QuantumState, R: Rng>(&self, quantum_state: &mut Q, rng: &mut R) {
+        for channel in self.single_qubit_channels() {
+            for i in 0..quantum_state.system_size() {
+                channel.apply(quantum_state, &[i], rng);
+            }
+        }
+    }
+
+    pub fn apply_two_qubit_gate<Q: QuantumState, R: Rng>(&self, quantum_state: &mut Q, qubit1: usize, qubit2: usize, rng: &mut R) {
+        if self.depolarize2 > 0. {
+            NoiseChannel::Depolarize2(self.depolarize2).apply(quantum_state, &[qubit1, qubit2], rng);
+        }
+    }
+}
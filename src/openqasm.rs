@@ -0,0 +1,691 @@
+use std::collections::HashMap;
+
+use crate::quantum_state::{Condition, ExportError, Gate, Instruction, QasmError};
+
+// Front-end for OpenQASM 2.0 circuits. Only the Clifford subset of qelib1.inc, and its t, tdg, rx,
+// ry, rz, u1, u3 and cu1 gates when parsing for a NonCliffordState, are understood; everything is
+// lowered onto the same Gate/Instruction list used by the native circuit format.
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Int(usize),
+    Real(String),
+    Str(String),
+    Symbol(&'static str),
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    line: usize,
+    col: usize,
+}
+
+const SYMBOLS: [&str; 15] = ["->", "==", ";", ",", "[", "]", "(", ")", "{", "}", "+", "-", "*", "/", "^"];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, QasmError> {
+    let mut tokens: Vec<Token> = Vec::new();
+
+    for (line_idx, raw_line) in source.lines().enumerate() {
+        // Strip comments
+        let line: &str = match raw_line.find("//") {
+            Some(i) => &raw_line[0..i],
+            None => raw_line,
+        };
+
+        let chars: Vec<char> = line.chars().collect();
+        let mut i: usize = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let (line, col) = (line_idx + 1, i + 1);
+            let start = i;
+
+            let kind: TokenKind = if c.is_whitespace() {
+                i += 1;
+                continue;
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                TokenKind::Ident(chars[start..i].iter().collect())
+            } else if c.is_ascii_digit() || c == '.' {
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                match number.parse::<usize>() {
+                    Ok(n) => TokenKind::Int(n),
+                    Err(_) => TokenKind::Real(number),
+                }
+            } else if c == '"' {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += 1;
+                }
+                if i == chars.len() {
+                    let text: String = chars[start..].iter().collect();
+                    return Err(QasmError::new(line, col, &text, "unterminated string"));
+                }
+                i += 1;
+                TokenKind::Str(chars[start + 1..i - 1].iter().collect())
+            } else {
+                let rest: String = chars[i..].iter().collect();
+                match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                    Some(s) => {
+                        i += s.len();
+                        TokenKind::Symbol(s)
+                    },
+                    None => return Err(QasmError::new(line, col, &c.to_string(), "unexpected character")),
+                }
+            };
+
+            tokens.push(Token { kind: kind, text: chars[start..i].iter().collect(), line: line, col: col });
+        }
+    }
+
+    return Ok(tokens);
+}
+
+// The supported subset of qelib1.inc, mapped to (gates to apply, number of parameters, number of
+// qubit arguments). Gates which have no native counterpart are decomposed. The parameters of a
+// non-Clifford gate are its angles, in order; u1 differs from rz only by a global phase.
+fn qelib1_gate(name: &str) -> Option<(Vec<(Gate, Vec<usize>)>, usize, usize)> {
+    let gate = match name {
+        "id" => (vec![(Gate::I, vec![0])], 0, 1),
+        "u0" => (vec![(Gate::I, vec![0])], 1, 1),
+        "x" => (vec![(Gate::X, vec![0])], 0, 1),
+        "y" => (vec![(Gate::Y, vec![0])], 0, 1),
+        "z" => (vec![(Gate::Z, vec![0])], 0, 1),
+        "h" => (vec![(Gate::H, vec![0])], 0, 1),
+        "s" => (vec![(Gate::S, vec![0])], 0, 1),
+        "sdg" => (vec![(Gate::Sd, vec![0])], 0, 1),
+        "sx" => (vec![(Gate::SQRTX, vec![0])], 0, 1),
+        "sxdg" => (vec![(Gate::SQRTXd, vec![0])], 0, 1),
+        "CX" | "cx" => (vec![(Gate::CX, vec![0, 1])], 0, 2),
+        "cy" => (vec![(Gate::CY, vec![0, 1])], 0, 2),
+        "cz" => (vec![(Gate::CZ, vec![0, 1])], 0, 2),
+        "swap" => (vec![(Gate::CX, vec![0, 1]), (Gate::CX, vec![1, 0]), (Gate::CX, vec![0, 1])], 0, 2),
+        "t" => (vec![(Gate::T, vec![0])], 0, 1),
+        "tdg" => (vec![(Gate::Td, vec![0])], 0, 1),
+        "rx" => (vec![(Gate::Rx(0.), vec![0])], 1, 1),
+        "ry" => (vec![(Gate::Ry(0.), vec![0])], 1, 1),
+        "rz" | "u1" => (vec![(Gate::Rz(0.), vec![0])], 1, 1),
+        "u3" => (vec![(Gate::U3(0., 0., 0.), vec![0])], 3, 1),
+        "cu1" => (vec![(Gate::CP(0.), vec![0, 1])], 1, 2),
+        _ => return None,
+    };
+
+    return Some(gate);
+}
+
+// A register operand; either a whole register or a single element of one. Remembers the
+// position of its first token for error reporting.
+struct Operand {
+    name: String,
+    index: Option<usize>,
+    token: usize,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+
+    // Registers are laid out contiguously in order of declaration
+    qregs: HashMap<String, (usize, usize)>,
+    cregs: HashMap<String, (usize, usize)>,
+    num_qubits: usize,
+    num_cbits: usize,
+
+    instructions: Vec<Instruction>,
+    // Whether non-Clifford gates are accepted
+    non_clifford: bool,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>, non_clifford: bool) -> Self {
+        Parser { tokens: tokens, pos: 0, qregs: HashMap::new(), cregs: HashMap::new(), num_qubits: 0, num_cbits: 0,
+                 instructions: Vec::new(), non_clifford: non_clifford }
+    }
+
+    fn error_at(&self, token: usize, msg: &str) -> QasmError {
+        match self.tokens.get(token) {
+            Some(t) => QasmError::new(t.line, t.col, &t.text, msg),
+            None => match self.tokens.last() {
+                Some(t) => QasmError::new(t.line, t.col + t.text.len(), "", msg),
+                None => QasmError::new(1, 1, "", msg),
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&TokenKind> {
+        return self.tokens.get(self.pos).map(|t| &t.kind);
+    }
+
+    fn next(&mut self) -> Result<TokenKind, QasmError> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                return Ok(t.kind.clone());
+            },
+            None => Err(self.error_at(self.pos, "unexpected end of input")),
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), QasmError> {
+        match self.next()? {
+            TokenKind::Symbol(s) if s == symbol => Ok(()),
+            _ => Err(self.error_at(self.pos - 1, &format!("expected '{symbol}'"))),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, QasmError> {
+        match self.next()? {
+            TokenKind::Ident(s) => Ok(s),
+            _ => Err(self.error_at(self.pos - 1, "expected identifier")),
+        }
+    }
+
+    fn int(&mut self) -> Result<usize, QasmError> {
+        match self.next()? {
+            TokenKind::Int(n) => Ok(n),
+            _ => Err(self.error_at(self.pos - 1, "expected non-negative integer")),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, QasmError> {
+        let token = self.pos;
+        let name = self.ident()?;
+        let mut index: Option<usize> = None;
+        if self.peek() == Some(&TokenKind::Symbol("[")) {
+            self.expect("[")?;
+            index = Some(self.int()?);
+            self.expect("]")?;
+        }
+
+        return Ok(Operand { name: name, index: index, token: token });
+    }
+
+    fn operand_list(&mut self) -> Result<Vec<Operand>, QasmError> {
+        let mut operands: Vec<Operand> = vec![self.operand()?];
+        while self.peek() == Some(&TokenKind::Symbol(",")) {
+            self.expect(",")?;
+            operands.push(self.operand()?);
+        }
+
+        return Ok(operands);
+    }
+
+    // Parses a parenthesized, comma-separated list of gate parameters, returning their values
+    fn parameters(&mut self) -> Result<Vec<f64>, QasmError> {
+        self.expect("(")?;
+        let mut params: Vec<f64> = vec![self.expression()?];
+        loop {
+            let token = self.pos;
+            match self.next()? {
+                TokenKind::Symbol(",") => params.push(self.expression()?),
+                TokenKind::Symbol(")") => return Ok(params),
+                _ => return Err(self.error_at(token, "expected ')'")),
+            }
+        }
+    }
+
+    // expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<f64, QasmError> {
+        let mut value: f64 = self.term()?;
+        loop {
+            match self.peek() {
+                Some(TokenKind::Symbol("+")) => {
+                    self.pos += 1;
+                    value += self.term()?;
+                },
+                Some(TokenKind::Symbol("-")) => {
+                    self.pos += 1;
+                    value -= self.term()?;
+                },
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<f64, QasmError> {
+        let mut value: f64 = self.factor()?;
+        loop {
+            match self.peek() {
+                Some(TokenKind::Symbol("*")) => {
+                    self.pos += 1;
+                    value *= self.factor()?;
+                },
+                Some(TokenKind::Symbol("/")) => {
+                    self.pos += 1;
+                    value /= self.factor()?;
+                },
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // factor := '-' factor | primary ('^' factor)?
+    fn factor(&mut self) -> Result<f64, QasmError> {
+        if self.peek() == Some(&TokenKind::Symbol("-")) {
+            self.pos += 1;
+            return Ok(-self.factor()?);
+        }
+
+        let base: f64 = self.primary()?;
+        if self.peek() == Some(&TokenKind::Symbol("^")) {
+            self.pos += 1;
+            return Ok(base.powf(self.factor()?));
+        }
+
+        return Ok(base);
+    }
+
+    // primary := number | 'pi' | function '(' expression ')' | '(' expression ')'
+    fn primary(&mut self) -> Result<f64, QasmError> {
+        let token = self.pos;
+        let value: f64 = match self.next()? {
+            TokenKind::Int(n) => n as f64,
+            TokenKind::Real(r) => match r.parse::<f64>() {
+                Ok(v) => v,
+                Err(_) => return Err(self.error_at(token, "invalid real number")),
+            },
+            TokenKind::Ident(name) if name == "pi" => std::f64::consts::PI,
+            TokenKind::Ident(name) => {
+                let function: fn(f64) -> f64 = match name.as_str() {
+                    "sin" => f64::sin,
+                    "cos" => f64::cos,
+                    "tan" => f64::tan,
+                    "exp" => f64::exp,
+                    "ln" => f64::ln,
+                    "sqrt" => f64::sqrt,
+                    _ => return Err(self.error_at(token, &format!("unknown parameter '{name}'"))),
+                };
+                self.expect("(")?;
+                let arg: f64 = self.expression()?;
+                self.expect(")")?;
+                function(arg)
+            },
+            TokenKind::Symbol("(") => {
+                let value: f64 = self.expression()?;
+                self.expect(")")?;
+                value
+            },
+            _ => return Err(self.error_at(token, "expected parameter")),
+        };
+
+        return Ok(value);
+    }
+
+    // Resolves an operand to the flat indices it refers to
+    fn resolve(&self, operand: &Operand, quantum: bool) -> Result<Vec<usize>, QasmError> {
+        let registers = if quantum { &self.qregs } else { &self.cregs };
+        let kind = if quantum { "qreg" } else { "creg" };
+        let (offset, size) = match registers.get(&operand.name) {
+            Some(r) => *r,
+            None => return Err(self.error_at(operand.token, &format!("undeclared {kind} '{}'", operand.name))),
+        };
+
+        match operand.index {
+            None => Ok((offset..offset + size).collect()),
+            Some(idx) => {
+                if idx >= size {
+                    return Err(self.error_at(operand.token + 2, &format!("index {idx} out of range for {kind} '{}' of size {size}", operand.name)));
+                }
+                Ok(vec![offset + idx])
+            },
+        }
+    }
+
+    // OpenQASM allows whole registers as arguments, in which case the operation is applied
+    // element-wise. Returns one list of indices per application.
+    fn broadcast(&self, args: Vec<Vec<usize>>, token: usize) -> Result<Vec<Vec<usize>>, QasmError> {
+        let width: usize = args.iter().map(|a| a.len()).max().unwrap_or(1);
+        if args.iter().any(|a| a.len() != 1 && a.len() != width) {
+            return Err(self.error_at(token, "register arguments have mismatched sizes"));
+        }
+
+        return Ok((0..width).map(|i| args.iter().map(|a| if a.len() == 1 { a[0] } else { a[i] }).collect()).collect());
+    }
+
+    fn declare(&mut self, quantum: bool) -> Result<(), QasmError> {
+        let token = self.pos;
+        let name = self.ident()?;
+        self.expect("[")?;
+        let size = self.int()?;
+        self.expect("]")?;
+        self.expect(";")?;
+
+        if self.qregs.contains_key(&name) || self.cregs.contains_key(&name) {
+            return Err(self.error_at(token, &format!("register '{name}' declared twice")));
+        }
+
+        if quantum {
+            self.qregs.insert(name, (self.num_qubits, size));
+            self.num_qubits += size;
+        } else {
+            self.cregs.insert(name, (self.num_cbits, size));
+            self.num_cbits += size;
+        }
+
+        return Ok(());
+    }
+
+    fn measure(&mut self, token: usize) -> Result<(), QasmError> {
+        let qubits = self.operand()?;
+        self.expect("->")?;
+        let cbits = self.operand()?;
+        self.expect(";")?;
+
+        let qubits = self.resolve(&qubits, true)?;
+        let cbits = self.resolve(&cbits, false)?;
+        if qubits.len() != cbits.len() {
+            return Err(self.error_at(token, "measure arguments have mismatched sizes"));
+        }
+
+        for (q, c) in qubits.into_iter().zip(cbits) {
+            self.instructions.push(Instruction { id: Gate::MZR, qubits: vec![q], cbits: vec![c], condition: None, paulis: Vec::new() });
+        }
+
+        return Ok(());
+    }
+
+    fn gate(&mut self, name: String, token: usize) -> Result<(), QasmError> {
+        let (decomp, num_params, num_qubits) = match qelib1_gate(&name) {
+            Some(g) => g,
+            None => return Err(self.error_at(token, &format!("unsupported gate '{name}'"))),
+        };
+
+        if !self.non_clifford && decomp.iter().any(|(id, _)| id.is_non_clifford()) {
+            return Err(self.error_at(token, &format!("non-Clifford gate '{name}' is only supported by from_openqasm_non_clifford")));
+        }
+
+        let params: Vec<f64> = if self.peek() == Some(&TokenKind::Symbol("(")) { self.parameters()? } else { Vec::new() };
+        if params.len() != num_params {
+            return Err(self.error_at(token, &format!("gate '{name}' takes {num_params} parameters, got {}", params.len())));
+        }
+
+        let operands = self.operand_list()?;
+        self.expect(";")?;
+        if operands.len() != num_qubits {
+            return Err(self.error_at(token, &format!("gate '{name}' takes {num_qubits} qubit arguments, got {}", operands.len())));
+        }
+
+        let mut args: Vec<Vec<usize>> = Vec::new();
+        for o in &operands {
+            args.push(self.resolve(o, true)?);
+        }
+
+        for qubits in self.broadcast(args, token)? {
+            for i in 1..qubits.len() {
+                if qubits[0..i].contains(&qubits[i]) {
+                    return Err(self.error_at(operands[i].token, &format!("repeated qubit argument to gate '{name}'")));
+                }
+            }
+
+            for (id, order) in &decomp {
+                self.instructions.push(Instruction { id: id.with_params(&params), qubits: order.iter().map(|i| qubits[*i]).collect(), cbits: Vec::new(), condition: None, paulis: Vec::new() });
+            }
+        }
+
+        return Ok(());
+    }
+
+    // if(c==n) <statement>; applies the statement only when creg c, read as an integer, is equal to n
+    fn conditional(&mut self) -> Result<(), QasmError> {
+        self.expect("(")?;
+        let token = self.pos;
+        let name = self.ident()?;
+        self.expect("==")?;
+        let val = self.int()?;
+        self.expect(")")?;
+
+        let (offset, size) = match self.cregs.get(&name) {
+            Some(r) => *r,
+            None => return Err(self.error_at(token, &format!("undeclared creg '{name}'"))),
+        };
+        if size > usize::BITS as usize {
+            return Err(self.error_at(token, &format!("creg '{name}' of size {size} is too large to condition on")));
+        }
+        if size < usize::BITS as usize && val >= 1 << size {
+            return Err(self.error_at(token + 2, &format!("value {val} does not fit in creg '{name}' of size {size}")));
+        }
+
+        let start = self.instructions.len();
+        let token = self.pos;
+        match self.peek() {
+            Some(TokenKind::Ident(keyword)) if ["OPENQASM", "include", "qreg", "creg", "barrier", "if"].contains(&keyword.as_str()) => {
+                return Err(self.error_at(token, "only gates and measurements may be conditioned"));
+            },
+            Some(TokenKind::Ident(_)) => self.statement()?,
+            _ => return Err(self.error_at(token, "expected gate or measurement")),
+        }
+
+        // Each lowered instruction carries its own copy of the condition, which is rechecked when it
+        // runs. That only matches OpenQASM, where the condition is checked once for the whole
+        // statement, if no instruction before the last one can change the creg.
+        let writes = |inst: &Instruction| inst.cbits.iter().any(|c| offset <= *c && *c < offset + size);
+        let lowered: &[Instruction] = &self.instructions[start..];
+        if lowered.len() > 1 && lowered[..lowered.len() - 1].iter().any(writes) {
+            return Err(self.error_at(token, &format!("conditioned statement writes to creg '{name}' more than once")));
+        }
+
+        let condition = Condition::Equals((offset..offset + size).collect(), val);
+        for inst in &mut self.instructions[start..] {
+            inst.condition = Some(condition.clone());
+        }
+
+        return Ok(());
+    }
+
+    fn statement(&mut self) -> Result<(), QasmError> {
+        let token = self.pos;
+        let keyword = match self.next()? {
+            TokenKind::Ident(keyword) => keyword,
+            _ => return Err(self.error_at(token, "expected statement")),
+        };
+
+        match keyword.as_str() {
+            "OPENQASM" => {
+                match self.next()? {
+                    TokenKind::Real(v) if v == "2.0" => (),
+                    _ => return Err(self.error_at(self.pos - 1, "only OpenQASM 2.0 is supported")),
+                }
+                self.expect(";")
+            },
+            "include" => {
+                match self.next()? {
+                    TokenKind::Str(f) if f == "qelib1.inc" => (),
+                    _ => return Err(self.error_at(self.pos - 1, "only qelib1.inc may be included")),
+                }
+                self.expect(";")
+            },
+            "qreg" => self.declare(true),
+            "creg" => self.declare(false),
+            "measure" => self.measure(token),
+            "if" => self.conditional(),
+            "reset" => {
+                let operands = self.operand_list()?;
+                self.expect(";")?;
+                if operands.len() != 1 {
+                    return Err(self.error_at(token, &format!("reset takes 1 qubit argument, got {}", operands.len())));
+                }
+                for q in self.resolve(&operands[0], true)? {
+                    self.instructions.push(Instruction { id: Gate::R, qubits: vec![q], cbits: Vec::new(), condition: None, paulis: Vec::new() });
+                }
+                Ok(())
+            },
+            "gate" | "opaque" => Err(self.error_at(token, &format!("'{keyword}' statements are not supported"))),
+            "barrier" => {
+                // Barriers have no effect on simulation, but their arguments must still be valid
+                let operands = self.operand_list()?;
+                self.expect(";")?;
+                for o in &operands {
+                    self.resolve(o, true)?;
+                }
+                Ok(())
+            },
+            _ => self.gate(keyword, token),
+        }
+    }
+}
+
+// Parses an OpenQASM 2.0 source string, returning the number of qubits, the number of classical bits
+// and the lowered instruction list. Non-Clifford gates are rejected unless non_clifford is set.
+pub(crate) fn parse(source: &str, non_clifford: bool) -> Result<(usize, usize, Vec<Instruction>), QasmError> {
+    let mut parser = Parser::new(tokenize(source)?, non_clifford);
+    while parser.peek().is_some() {
+        parser.statement()?;
+    }
+
+    return Ok((parser.num_qubits, parser.num_cbits, parser.instructions));
+}
+
+// Splits the classical bits into registers such that every condition covers exactly one register,
+// as OpenQASM 2.0 can only condition on the value of a whole register.
+fn classical_registers(num_cbits: usize, instructions: &[Instruction]) -> Result<Vec<(usize, usize)>, ExportError> {
+    // Ranges covered by conditions, with the first instruction conditioned on each
+    let mut ranges: Vec<(usize, usize, usize)> = Vec::new();
+    for (i, inst) in instructions.iter().enumerate() {
+        match &inst.condition {
+            Some(Condition::Equals(cbits, _)) => {
+                if cbits.iter().enumerate().any(|(j, c)| *c != cbits[0] + j) {
+                    return Err(ExportError::new(i, &format!("condition on registers {:?} cannot be expressed in OpenQASM 2.0; registers must be contiguous and increasing", cbits)));
+                }
+                ranges.push((cbits[0], cbits[0] + cbits.len(), i));
+            },
+            Some(Condition::Parity(cbits, _)) => return Err(ExportError::new(i, &format!("parity condition on registers {:?} cannot be expressed in OpenQASM 2.0", cbits))),
+            None => (),
+        }
+    }
+
+    let mut boundaries: Vec<usize> = vec![0, num_cbits];
+    for (start, end, _) in &ranges {
+        boundaries.push(*start);
+        boundaries.push(*end);
+    }
+    boundaries.sort();
+    boundaries.dedup();
+
+    for (start, end, i) in &ranges {
+        if boundaries.iter().any(|b| start < b && b < end) {
+            return Err(ExportError::new(*i, "conditions on overlapping registers cannot be expressed in OpenQASM 2.0"));
+        }
+    }
+
+    return Ok(boundaries.windows(2).map(|w| (w[0], w[1] - w[0])).collect());
+}
+
+// Formats an instruction list as OpenQASM 2.0, using a single quantum register q. Gates without a
+// qelib1 counterpart are decomposed, so only circuits produced by the OpenQASM front-end are
+// guaranteed to parse back to the same instruction list. Fails on conditions which OpenQASM 2.0
+// cannot express, such as parity conditions, and on noise channels.
+pub(crate) fn emit(num_qubits: usize, num_cbits: usize, instructions: &[Instruction]) -> Result<String, ExportError> {
+    let cregs: Vec<(usize, usize)> = classical_registers(num_cbits, instructions)?;
+    let creg_name = |i: usize| if cregs.len() == 1 { String::from("c") } else { format!("c{i}") };
+    let cbit_name = |cbit: usize| {
+        let i = cregs.iter().position(|(offset, size)| offset <= &cbit && cbit < offset + size).unwrap();
+        format!("{}[{}]", creg_name(i), cbit - cregs[i].0)
+    };
+
+    let mut circuit: Vec<String> = vec![String::from("OPENQASM 2.0;"), String::from("include \"qelib1.inc\";")];
+    if num_qubits > 0 {
+        circuit.push(format!("qreg q[{num_qubits}];"));
+    }
+    for (i, (_, size)) in cregs.iter().enumerate() {
+        if *size > 0 {
+            circuit.push(format!("creg {}[{size}];", creg_name(i)));
+        }
+    }
+
+    for (i, inst) in instructions.iter().enumerate() {
+        let prefix: String = match &inst.condition {
+            Some(Condition::Equals(cbits, val)) => {
+                let i = cregs.iter().position(|(offset, _)| *offset == cbits[0]).unwrap();
+                format!("if({}=={val}) ", creg_name(i))
+            },
+            _ => String::new(),
+        };
+
+        let q: Vec<String> = inst.qubits.iter().map(|q| format!("q[{q}]")).collect();
+        let statements: Vec<String> = match inst.id {
+            Gate::I => vec![format!("id {}", q[0])],
+            Gate::X => vec![format!("x {}", q[0])],
+            Gate::Y => vec![format!("y {}", q[0])],
+            Gate::Z => vec![format!("z {}", q[0])],
+            Gate::H => vec![format!("h {}", q[0])],
+            Gate::S | Gate::SQRTZ => vec![format!("s {}", q[0])],
+            Gate::Sd | Gate::SQRTZd => vec![format!("sdg {}", q[0])],
+            Gate::SQRTX => vec![format!("sx {}", q[0])],
+            Gate::SQRTXd => vec![format!("sxdg {}", q[0])],
+            Gate::SQRTY => vec![format!("z {}", q[0]), format!("h {}", q[0])],
+            Gate::SQRTYd => vec![format!("h {}", q[0]), format!("z {}", q[0])],
+            Gate::CX => vec![format!("cx {}, {}", q[0], q[1])],
+            Gate::CY => vec![format!("cy {}, {}", q[0], q[1])],
+            Gate::CZ => vec![format!("cz {}, {}", q[0], q[1])],
+            Gate::MZR => vec![format!("measure {} -> {}", q[0], cbit_name(inst.cbits[0]))],
+            Gate::R => vec![format!("reset {}", q[0])],
+            Gate::RX => vec![format!("reset {}", q[0]), format!("h {}", q[0])],
+            Gate::RY => vec![format!("reset {}", q[0]), format!("h {}", q[0]), format!("s {}", q[0])],
+            Gate::MR => vec![format!("measure {} -> {}", q[0], cbit_name(inst.cbits[0])), format!("reset {}", q[0])],
+            Gate::MXR => vec![format!("h {}", q[0]), format!("measure {} -> {}", q[0], cbit_name(inst.cbits[0])), format!("h {}", q[0])],
+            Gate::MYR => vec![format!("sdg {}", q[0]), format!("h {}", q[0]), format!("measure {} -> {}", q[0], cbit_name(inst.cbits[0])),
+                              format!("h {}", q[0]), format!("s {}", q[0])],
+            Gate::MPP => {
+                // Rotates each factor onto Z and gathers the parity onto the last qubit
+                let target: &String = &q[q.len() - 1];
+                let mut statements: Vec<String> = Vec::new();
+                for (p, q) in inst.paulis.iter().zip(&q) {
+                    match p {
+                        Gate::X => statements.push(format!("h {q}")),
+                        Gate::Y => statements.extend([format!("sdg {q}"), format!("h {q}")]),
+                        _ => (),
+                    }
+                }
+                for q in &q[0..(q.len() - 1)] {
+                    statements.push(format!("cx {q}, {target}"));
+                }
+                statements.push(format!("measure {target} -> {}", cbit_name(inst.cbits[0])));
+                for q in q[0..(q.len() - 1)].iter().rev() {
+                    statements.push(format!("cx {q}, {target}"));
+                }
+                for (p, q) in inst.paulis.iter().zip(&q) {
+                    match p {
+                        Gate::X => statements.push(format!("h {q}")),
+                        Gate::Y => statements.extend([format!("h {q}"), format!("s {q}")]),
+                        _ => (),
+                    }
+                }
+                statements
+            },
+            Gate::T => vec![format!("t {}", q[0])],
+            Gate::Td => vec![format!("tdg {}", q[0])],
+            Gate::Rx(theta) => vec![format!("rx({theta}) {}", q[0])],
+            Gate::Ry(theta) => vec![format!("ry({theta}) {}", q[0])],
+            Gate::Rz(theta) => vec![format!("rz({theta}) {}", q[0])],
+            Gate::U3(theta, phi, lambda) => vec![format!("u3({theta},{phi},{lambda}) {}", q[0])],
+            Gate::CP(phi) => vec![format!("cu1({phi}) {}, {}", q[0], q[1])],
+            Gate::Noise(channel) => return Err(ExportError::new(i, &format!("noise channel {} cannot be expressed in OpenQASM 2.0", channel.name()))),
+            Gate::PRINT => {
+                circuit.push(String::from("// @pragma print"));
+                continue;
+            },
+        };
+
+        // The condition is repeated on every statement of a lowering, and so is checked again after
+        // each one; that is only equivalent when none of them writes to the registers it reads
+        if let Some(condition) = &inst.condition {
+            if statements.len() > 1 && inst.cbits.iter().any(|c| condition.cbits().contains(c)) {
+                return Err(ExportError::new(i, &format!("conditioned {} writing to its own condition cannot be expressed in OpenQASM 2.0", inst.id.name())));
+            }
+        }
+
+        for statement in statements {
+            circuit.push(format!("{prefix}{statement};"));
+        }
+    }
+
+    return Ok(circuit.join("\n"));
+}
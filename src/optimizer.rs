@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use crate::quantum_graph_state::{CLIFFORD_PRODUCTS, HGATE, IDGATE, SGATE, ZGATES};
+use crate::quantum_state::{Gate, Instruction};
+
+// Peephole optimization of straight-line circuits. Consecutive single-qubit Cliffords on a qubit
+// are multiplied together in the local Clifford table of the graph state backend and re-emitted as
+// the shortest equivalent sequence of gates, adjacent CZ, CX and CY gates cancel in pairs, and
+// diagonal gates are commuted through CZ (and the control of CX and CY) so that they can fuse with
+// the gates on the other side. Global phases are not preserved.
+
+// Single-qubit Cliffords which can be emitted, in order of preference
+const NATIVE_GATES: [Gate; 10] = [Gate::H, Gate::S, Gate::Sd, Gate::X, Gate::Y, Gate::Z,
+                                  Gate::SQRTX, Gate::SQRTXd, Gate::SQRTY, Gate::SQRTYd];
+
+// Index of gate in the local Clifford table, built from its decomposition into H and S
+fn clifford_index(gate: Gate) -> Option<usize> {
+    let decomposition: &str = match gate {
+        Gate::I => "",
+        Gate::X => "hssh",
+        Gate::Y => "hsshss",
+        Gate::Z => "ss",
+        Gate::H => "h",
+        Gate::S | Gate::SQRTZ => "s",
+        Gate::Sd | Gate::SQRTZd => "sss",
+        Gate::SQRTX => "ssshsss",
+        Gate::SQRTXd => "shs",
+        Gate::SQRTY => "ssh",
+        Gate::SQRTYd => "hss",
+        _ => return None,
+    };
+
+    let mut index: usize = IDGATE;
+    for c in decomposition.chars() {
+        let step: usize = if c == 'h' { HGATE } else { SGATE };
+        index = CLIFFORD_PRODUCTS[step][index];
+    }
+    return Some(index);
+}
+
+// Shortest sequence of native gates implementing each element of the table, found by breadth-first
+// search from the identity
+fn shortest_words() -> Vec<Vec<Gate>> {
+    let mut words: Vec<Option<Vec<Gate>>> = vec![None; 24];
+    words[IDGATE] = Some(Vec::new());
+    let mut frontier: Vec<usize> = vec![IDGATE];
+    while !frontier.is_empty() {
+        let mut next: Vec<usize> = Vec::new();
+        for c in frontier {
+            for gate in NATIVE_GATES {
+                let product: usize = CLIFFORD_PRODUCTS[clifford_index(gate).unwrap()][c];
+                if words[product].is_none() {
+                    let mut word: Vec<Gate> = words[c].clone().unwrap();
+                    word.push(gate);
+                    words[product] = Some(word);
+                    next.push(product);
+                }
+            }
+        }
+        frontier = next;
+    }
+
+    return words.into_iter().map(|w| w.unwrap()).collect();
+}
+
+// Single-qubit Cliffords waiting to be emitted on a qubit, with their product
+struct Run {
+    clifford: usize,
+    gates: Vec<Instruction>,
+}
+
+struct Optimizer {
+    words: Vec<Vec<Gate>>,
+    pending: HashMap<usize, Run>,
+    output: Vec<Option<Instruction>>,
+    // Position in output of the last instruction acting on each qubit
+    last: HashMap<usize, usize>,
+}
+
+impl Optimizer {
+    fn emit(&mut self, inst: Instruction) {
+        for q in &inst.qubits {
+            self.last.insert(*q, self.output.len());
+        }
+        self.output.push(Some(inst));
+    }
+
+    fn is_diagonal(&self, qubit: usize) -> bool {
+        return self.pending.get(&qubit).map_or(true, |run| ZGATES.contains(&run.clifford));
+    }
+
+    // Emits the pending gates on qubit, replacing them by the shortest equivalent sequence when that
+    // is shorter
+    fn flush(&mut self, qubit: usize) {
+        let run: Run = match self.pending.remove(&qubit) {
+            Some(run) => run,
+            None => return,
+        };
+
+        let word: Vec<Gate> = self.words[run.clifford].clone();
+        if word.len() < run.gates.len() {
+            for gate in word {
+                self.emit(Instruction { id: gate, qubits: vec![qubit], cbits: Vec::new(), condition: None, paulis: Vec::new() });
+            }
+        } else {
+            for inst in run.gates {
+                self.emit(inst);
+            }
+        }
+    }
+
+    // Applies a two-qubit gate, cancelling it against an identical gate directly before it. For CZ,
+    // diagonal gates pending on either qubit are kept pending; for CX and CY only on the control.
+    fn two_qubit_gate(&mut self, inst: &Instruction) {
+        let (q1, q2) = (inst.qubits[0], inst.qubits[1]);
+        if !self.is_diagonal(q1) {
+            self.flush(q1);
+        }
+        if inst.id != Gate::CZ || !self.is_diagonal(q2) {
+            self.flush(q2);
+        }
+
+        if let (Some(&i), Some(&j)) = (self.last.get(&q1), self.last.get(&q2)) {
+            if let Some(prev) = &self.output[i] {
+                let same_qubits: bool = prev.qubits == inst.qubits || (inst.id == Gate::CZ && prev.qubits == vec![q2, q1]);
+                if i == j && prev.id == inst.id && prev.condition.is_none() && same_qubits {
+                    self.output[i] = None;
+                    self.last.remove(&q1);
+                    self.last.remove(&q2);
+                    return;
+                }
+            }
+        }
+
+        self.emit(inst.clone());
+    }
+}
+
+// Optimizes a straight-line sequence of instructions, returning the optimized sequence. The
+// sequence is never longer than the input.
+pub(crate) fn optimize(instructions: &Vec<Instruction>) -> Vec<Instruction> {
+    let mut optimizer = Optimizer { words: shortest_words(), pending: HashMap::new(), output: Vec::new(), last: HashMap::new() };
+
+    for inst in instructions {
+        if inst.condition.is_none() {
+            if let Some(gate) = clifford_index(inst.id) {
+                let run = optimizer.pending.entry(inst.qubits[0]).or_insert(Run { clifford: IDGATE, gates: Vec::new() });
+                run.clifford = CLIFFORD_PRODUCTS[gate][run.clifford];
+                run.gates.push(inst.clone());
+                continue;
+            }
+
+            if let Gate::CX | Gate::CY | Gate::CZ = inst.id {
+                optimizer.two_qubit_gate(inst);
+                continue;
+            }
+        }
+
+        let mut qubits: Vec<usize> = match inst.id {
+            Gate::PRINT => optimizer.pending.keys().cloned().collect(),
+            _ => inst.qubits.clone(),
+        };
+        qubits.sort();
+        for q in qubits {
+            optimizer.flush(q);
+        }
+        // Prints observe the state, so no gate may cancel against one on the other side of them
+        if let Gate::PRINT = inst.id {
+            optimizer.last.clear();
+        }
+        optimizer.emit(inst.clone());
+    }
+
+    let mut qubits: Vec<usize> = optimizer.pending.keys().cloned().collect();
+    qubits.sort();
+    for q in qubits {
+        optimizer.flush(q);
+    }
+
+    return optimizer.output.into_iter().flatten().collect();
+}
@@ -8,8 +8,9 @@ use serde::{Serialize, Deserialize};
 use crate::quantum_state::{Entropy, QuantumState, MzrForce};
 use crate::quantum_vector_state::QuantumVectorState;
 
+// Product of single-qubit Paulis with a sign; phase is true for a negative sign
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-struct PauliString {
+pub struct PauliString {
 	pub num_qubits: usize,
 	bit_string: BitVec,
 	phase: bool,
@@ -39,7 +40,20 @@ impl PauliString {
 		PauliString::rand(num_qubits, rng)
 	}
 
-	fn to_op(&self, i: usize) -> &str {
+	// Sets the operator on qubit i to one of "I", "X", "Y" or "Z"
+	pub fn set_op(&mut self, i: usize, op: &str) {
+		let (x, z) = match op {
+			"I" => (false, false),
+			"X" => (true, false),
+			"Z" => (false, true),
+			"Y" => (true, true),
+			_ => panic!("Invalid Pauli operator {op}"),
+		};
+		self.set_x(i, x);
+		self.set_z(i, z);
+	}
+
+	pub fn to_op(&self, i: usize) -> &str {
 		match (self.x(i), self.z(i)) {
 			(false, false) => "I",
 			(true,  false) => "X",
@@ -100,228 +114,529 @@ impl PauliString {
 	}
 
 	pub fn commutes(&self, other: &PauliString) -> bool {
-		let commuting_indices: usize = (0..self.num_qubits).map(|i| {
+		let anticommuting_indices: usize = (0..self.num_qubits).map(|i| {
 			self.commutes_at(other, i)
-		}).filter(|i| *i).count();
-		commuting_indices % 2 == 0
+		}).filter(|i| !*i).count();
+		anticommuting_indices % 2 == 0
 	}
 }
 
+// Version of the serialized tableau; version 1 stored a PauliString per row
+const TABLEAU_VERSION: u32 = 2;
+
+// Power of i picked up by multiplying two packed rows, counted mod 4 with popcounts over the
+// qubits where the product of the single-qubit Paulis is +i (XY, YZ, ZX) or -i (YX, ZY, XZ)
+fn product_phase(x1: &[u64], z1: &[u64], x2: &[u64], z2: &[u64]) -> u32 {
+	let mut plus: u32 = 0;
+	let mut minus: u32 = 0;
+	for w in 0..x1.len() {
+		let (a, b, c, d) = (x1[w], z1[w], x2[w], z2[w]);
+		plus += ((a & !b & c & d) | (a & b & !c & d) | (!a & b & c & !d)).count_ones();
+		minus += ((a & !b & !c & d) | (a & b & c & !d) | (!a & b & c & d)).count_ones();
+	}
+	return (plus + 3*minus) % 4;
+}
+
+// Row of the tableau packed into words, for accumulating products of rows
+struct PackedRow {
+	x: Vec<u64>,
+	z: Vec<u64>,
+	r: bool,
+}
+
+impl PackedRow {
+	fn identity(num_qubits: usize) -> Self {
+		let words: usize = (num_qubits + 63)/64;
+		return PackedRow { x: vec![0; words], z: vec![0; words], r: false };
+	}
+
+	// Multiplies the commuting row other into self
+	fn mul(&mut self, other: &PackedRow) {
+		let phase: u32 = product_phase(&other.x, &other.z, &self.x, &self.z);
+		self.r = (self.r != other.r) != (phase == 2);
+		for w in 0..self.x.len() {
+			self.x[w] ^= other.x[w];
+			self.z[w] ^= other.z[w];
+		}
+	}
+}
+
+// Stabilizer tableau stored column-major: the X bits of every row on qubit j are packed into the
+// words xs[j*words..(j + 1)*words], with row i at bit i % 64 of word i / 64, and likewise for the Z
+// bits and the signs. Gates then act on whole words of rows at a time.
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
-struct Tableau {
-	rows: Vec<PauliString>,
+#[serde(try_from = "SerializedTableau", into = "SerializedTableau")]
+pub(crate) struct Tableau {
+	num_qubits: usize,
+	num_rows: usize,
+	words: usize,
+
+	xs: Vec<u64>,
+	zs: Vec<u64>,
+	rs: Vec<u64>,
 
 	track_destabilizers: bool,
 
 	pub print_ops: bool,
 }
 
+// Serialized form of the tableau. Tableaus written before the packed layout carry no version and are
+// read from their rows, including the scratch row which was kept after the stabilizers.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SerializedTableau {
+	Packed {
+		version: u32,
+		num_qubits: usize,
+		num_rows: usize,
+		xs: Vec<u64>,
+		zs: Vec<u64>,
+		rs: Vec<u64>,
+		track_destabilizers: bool,
+		print_ops: bool,
+	},
+	Rows {
+		rows: Vec<PauliString>,
+		track_destabilizers: bool,
+		print_ops: bool,
+	},
+}
+
+impl TryFrom<SerializedTableau> for Tableau {
+	type Error = String;
+
+	fn try_from(serialized: SerializedTableau) -> Result<Self, Self::Error> {
+		match serialized {
+			SerializedTableau::Packed { version, num_qubits, num_rows, xs, zs, rs, track_destabilizers, print_ops } => {
+				if version != TABLEAU_VERSION {
+					return Err(format!("unsupported tableau version {version}"));
+				}
+				if num_rows != if track_destabilizers { 2*num_qubits } else { num_qubits } {
+					return Err(format!("tableau of {num_qubits} qubits cannot have {num_rows} rows"));
+				}
+				let words: usize = (num_rows + 63)/64;
+				if xs.len() != num_qubits*words || zs.len() != num_qubits*words || rs.len() != words {
+					return Err(String::from("tableau columns do not match its size"));
+				}
+				return Ok(Tableau { num_qubits, num_rows, words, xs, zs, rs, track_destabilizers, print_ops });
+			},
+			SerializedTableau::Rows { mut rows, track_destabilizers, print_ops } => {
+				if track_destabilizers {
+					rows.pop();
+				}
+				let mut tableau: Tableau = Tableau::from_rows(rows, track_destabilizers);
+				tableau.print_ops = print_ops;
+				return Ok(tableau);
+			},
+		}
+	}
+}
+
+impl From<Tableau> for SerializedTableau {
+	fn from(tableau: Tableau) -> Self {
+		return SerializedTableau::Packed {
+			version: TABLEAU_VERSION,
+			num_qubits: tableau.num_qubits,
+			num_rows: tableau.num_rows,
+			xs: tableau.xs,
+			zs: tableau.zs,
+			rs: tableau.rs,
+			track_destabilizers: tableau.track_destabilizers,
+			print_ops: tableau.print_ops,
+		};
+	}
+}
+
 impl Tableau {
+	fn empty(num_qubits: usize, num_rows: usize, track_destabilizers: bool) -> Self {
+		let words: usize = (num_rows + 63)/64;
+		return Tableau {
+			num_qubits: num_qubits, num_rows: num_rows, words: words,
+			xs: vec![0; num_qubits*words], zs: vec![0; num_qubits*words], rs: vec![0; words],
+			track_destabilizers: track_destabilizers, print_ops: true
+		};
+	}
+
 	pub fn new(num_qubits: usize) -> Self {
-		let mut rows: Vec<PauliString> = vec![PauliString::new(num_qubits); 2*num_qubits + 1]; 
+		let mut tableau: Tableau = Tableau::empty(num_qubits, 2*num_qubits, true);
 
 		for i in 0..num_qubits {
-			rows[i].set_x(i, true);
-			rows[i + num_qubits].set_z(i, true);
+			tableau.set_x(i, i, true);
+			tableau.set_z(i + num_qubits, i, true);
 		}
-		
-		Tableau { rows: rows, track_destabilizers: true, print_ops: true}
+
+		return tableau;
+	}
+
+	// Applies a random Clifford to qubits, drawing from rng exactly as QuantumCHPState::random_clifford
+	pub(crate) fn random_clifford(&mut self, qubits: Vec<usize>, rng: &mut Lcg64Xsh32) {
+		let tableau: Tableau = std::mem::replace(self, Tableau::empty(0, 0, true));
+		let mut state: QuantumCHPState = QuantumCHPState { num_qubits: tableau.num_qubits, tableau: tableau, rng: rng.clone() };
+		state.random_clifford(qubits);
+		*self = state.tableau;
+		*rng = state.rng;
+	}
+
+	// Discards the destabilizers, keeping the stabilizers in rows 0..n
+	pub fn drop_destabilizers(&mut self) {
+		if !self.track_destabilizers {
+			return;
+		}
+		let rows: Vec<PauliString> = (self.num_qubits..2*self.num_qubits).map(|i| self.row(i)).collect();
+		let print_ops: bool = self.print_ops;
+		*self = Tableau::from_rows(rows, false);
+		self.print_ops = print_ops;
+	}
+
+	// Row holding stabilizer i
+	pub(crate) fn stabilizer_row(&self, i: usize) -> usize {
+		return if self.track_destabilizers { i + self.num_qubits } else { i };
+	}
+
+	pub(crate) fn from_rows(rows: Vec<PauliString>, track_destabilizers: bool) -> Self {
+		let num_qubits: usize = rows.first().map_or(0, |row| row.num_qubits);
+		let mut tableau: Tableau = Tableau::empty(num_qubits, rows.len(), track_destabilizers);
+		for (i, row) in rows.iter().enumerate() {
+			tableau.set_row(i, row);
+		}
+
+		return tableau;
 	}
 
 	fn num_rows(&self) -> usize {
-		// If tracking destabilizers, need an extra 'scratch row' which should not affect unitary operations
-		if self.track_destabilizers { self.rows.len() - 1 } else { self.rows.len() }
+		return self.num_rows;
 	}
 
 	pub fn print(&self) -> String {
 		let mut s: String = String::new();
 		for i in 0..self.num_rows() {
 			s.push_str(if i == 0 { "[" } else { " " });
-			s.push_str(&self.rows[i].to_string(self.print_ops));
-			s.push_str(if i == 2*self.rows.len() - 1 { "]" } else { "\n" });
+			s.push_str(&self.row(i).to_string(self.print_ops));
+			s.push_str("\n");
 		}
 		
 		s
 	}
 
+	// Row i, with the destabilizers in rows 0..n and the stabilizers in rows n..2n
+	pub(crate) fn row(&self, i: usize) -> PauliString {
+		let mut row: PauliString = PauliString::new(self.num_qubits);
+		for j in 0..self.num_qubits {
+			row.set_x(j, self.x(i, j));
+			row.set_z(j, self.z(i, j));
+		}
+		row.set_r(self.r(i));
+		return row;
+	}
+
+	fn set_row(&mut self, i: usize, row: &PauliString) {
+		for j in 0..self.num_qubits {
+			self.set_x(i, j, row.x(j));
+			self.set_z(i, j, row.z(j));
+		}
+		self.set_r(i, row.r());
+	}
+
+	fn packed_row(&self, i: usize) -> PackedRow {
+		let mut row: PackedRow = PackedRow::identity(self.num_qubits);
+		for j in 0..self.num_qubits {
+			row.x[j / 64] |= (self.x(i, j) as u64) << (j % 64);
+			row.z[j / 64] |= (self.z(i, j) as u64) << (j % 64);
+		}
+		row.r = self.r(i);
+		return row;
+	}
+
+	fn bit(words: &[u64], i: usize) -> bool {
+		return (words[i / 64] >> (i % 64)) & 1 == 1;
+	}
+
+	fn set_bit(words: &mut [u64], i: usize, v: bool) {
+		if v {
+			words[i / 64] |= 1 << (i % 64);
+		} else {
+			words[i / 64] &= !(1 << (i % 64));
+		}
+	}
+
 	fn x(&self, i: usize, j: usize) -> bool {
-		self.rows[i].x(j)
+		return Self::bit(&self.xs[j*self.words..(j + 1)*self.words], i);
 	}
 
 	fn z(&self, i: usize, j: usize) -> bool {
-		self.rows[i].z(j)
+		return Self::bit(&self.zs[j*self.words..(j + 1)*self.words], i);
 	}
 
 	fn r(&self, i: usize) -> bool {
-		self.rows[i].r()
+		return Self::bit(&self.rs, i);
 	}
 
 	fn set_x(&mut self, i: usize, j: usize, v: bool) {
-		self.rows[i].set_x(j, v);
+		let words: usize = self.words;
+		Self::set_bit(&mut self.xs[j*words..(j + 1)*words], i, v);
 	}
 
 	fn set_z(&mut self, i: usize, j: usize, v: bool) {
-		self.rows[i].set_z(j, v);
+		let words: usize = self.words;
+		Self::set_bit(&mut self.zs[j*words..(j + 1)*words], i, v);
 	}
 
 	fn set_r(&mut self, i: usize, v: bool) {
-		self.rows[i].set_r(v);
+		Self::set_bit(&mut self.rs, i, v);
+	}
+
+	// Mask of the rows in start..end
+	fn row_mask(&self, start: usize, end: usize) -> Vec<u64> {
+		return (0..self.words).map(|w| {
+			let lo: usize = (64*w).clamp(start, end);
+			let hi: usize = (64*w + 64).clamp(start, end);
+			if hi == lo { 0 } else { (u64::MAX >> (64 - (hi - lo))) << (lo - 64*w) }
+		}).collect();
 	}
 
-	fn g(x1: bool, z1: bool, x2: bool, z2: bool) -> i32 {
-		if !x1 && !z1 { 
-			return 0 
-		} else if x1 && z1 { // z2 - x2
-			//return (z2 as i32) - (x2 as i32);
-			if z2 { return if x2 { 0 } else { 1 } }
-			else { return if x2 { -1 } else { 0 } }
-		} else if x1 && !z1 { // z2 * (2*x2 - 1)
-			//return (z2 as i32) * (2*(x2 as i32) - 1);
-			if z2 { return if x2 { 1 } else { -1 } }
-			else { return 0 }
-		} else { // x2 * (1 - 2*z2) 
-			//return (x2 as i32) * (1 - 2*(z2 as i32));
-			if x2 { return if z2 { -1 } else { 1 } }
-			else { return 0 }
+	// Mask of the rows anticommuting with pauli
+	fn anticommuting_rows(&self, pauli: &PauliString) -> Vec<u64> {
+		let mut mask: Vec<u64> = vec![0; self.words];
+		for j in 0..self.num_qubits {
+			let (px, pz) = (pauli.x(j), pauli.z(j));
+			if !px && !pz {
+				continue;
+			}
+			for w in 0..self.words {
+				let k: usize = j*self.words + w;
+				if pz { mask[w] ^= self.xs[k]; }
+				if px { mask[w] ^= self.zs[k]; }
+			}
 		}
+		return mask;
 	}
 
-	pub fn rowsum(&mut self, h: usize, i: usize) {
-		assert!(self.track_destabilizers);
-		let mut s: i32 = 0;
-		if self.r(i) { s += 2 }
-		if self.r(h) { s += 2 }
+	fn first_row(mask: &[u64]) -> Option<usize> {
+		return mask.iter().enumerate().find(|(_, m)| **m != 0).map(|(w, m)| 64*w + m.trailing_zeros() as usize);
+	}
 
-		let num_qubits: usize = self.num_rows()/2;
-		for j in 0..num_qubits {
-			s += Self::g(self.x(i,j), self.z(i,j), self.x(h,j), self.z(h,j));
-		}
-		
-		if s % 4 == 0 {
-			self.set_r(h, false);
-		} else if (s % 4).abs() == 2 {
-			self.set_r(h, true);
+	// Multiplies row i into every row in mask, each of which must commute with row i. The power of i
+	// picked up on each qubit is accumulated for all rows at once in a two-bit counter spread over
+	// the words (hi, lo).
+	fn rowsum_masked(&mut self, mask: &[u64], i: usize) {
+		let words: usize = self.words;
+		let mut lo: Vec<u64> = vec![0; words];
+		let mut hi: Vec<u64> = vec![0; words];
+		for j in 0..self.num_qubits {
+			let (xi, zi) = (self.x(i, j), self.z(i, j));
+			if !xi && !zi {
+				continue;
+			}
+			let column = j*words..(j + 1)*words;
+			for (w, (xh, zh)) in self.xs[column.clone()].iter_mut().zip(self.zs[column].iter_mut()).enumerate() {
+				// Rows on which the product of the Paulis of row i and row h picks up +i or -i
+				let (plus, minus) = match (xi, zi) {
+					(true, true) => (!*xh & *zh, *xh & !*zh),
+					(true, false) => (*xh & *zh, !*xh & *zh),
+					_ => (*xh & !*zh, *xh & *zh),
+				};
+				let (plus, minus) = (plus & mask[w], minus & mask[w]);
+				hi[w] ^= lo[w] & plus;
+				lo[w] ^= plus;
+				hi[w] ^= !lo[w] & minus;
+				lo[w] ^= minus;
+
+				if xi { *xh ^= mask[w]; }
+				if zi { *zh ^= mask[w]; }
+			}
 		}
 
-		for j in 0..num_qubits {
-			self.set_x(h, j, self.x(i, j) != self.x(h, j));
-			self.set_z(h, j, self.z(i, j) != self.z(h, j));
+		let ri: u64 = if self.r(i) { u64::MAX } else { 0 };
+		for w in 0..words {
+			self.rs[w] ^= (hi[w] ^ ri) & mask[w];
 		}
 	}
 
-	pub fn h_gate(&mut self, qubit: usize) {
-		for i in 0..self.num_rows() {
-			let x = self.x(i, qubit);
-			let z = self.z(i, qubit);
-			let r = self.r(i);
-
-			// Set r_i
-			self.set_r(i, r != (x && z));
+	pub fn rowsum(&mut self, h: usize, i: usize) {
+		let mut mask: Vec<u64> = vec![0; self.words];
+		Self::set_bit(&mut mask, h, true);
+		self.rowsum_masked(&mask, i);
+	}
 
-			// Set x_ia
-			self.set_x(i, qubit, z);
-			// Set z_ia
-			self.set_z(i, qubit, x);
+	pub fn h_gate(&mut self, qubit: usize) {
+		let column = qubit*self.words..(qubit + 1)*self.words;
+		for (w, (x, z)) in self.xs[column.clone()].iter_mut().zip(self.zs[column].iter_mut()).enumerate() {
+			self.rs[w] ^= *x & *z;
+			std::mem::swap(x, z);
 		}
 	}
 
 	pub fn s_gate(&mut self, qubit: usize) {
-		for i in 0..self.num_rows() {
-			let x = self.x(i, qubit);
-			let z = self.z(i, qubit);
-			let r = self.r(i);
-
-			// Set r_i
-			self.set_r(i, r != (x && z));
-
-			// Set z_ia
-			self.set_z(i, qubit, x != z);
+		let column = qubit*self.words..(qubit + 1)*self.words;
+		for (w, (x, z)) in self.xs[column.clone()].iter().zip(self.zs[column].iter_mut()).enumerate() {
+			self.rs[w] ^= *x & *z;
+			*z ^= *x;
 		}
 	}
 
 	pub fn x_gate(&mut self, qubit: usize) {
-        self.h_gate(qubit);
-        self.z_gate(qubit);
-        self.h_gate(qubit);
+		// X anticommutes with the Z and Y components on qubit
+		let column = qubit*self.words..(qubit + 1)*self.words;
+		for (r, z) in self.rs.iter_mut().zip(self.zs[column].iter()) {
+			*r ^= *z;
+		}
 	}
 
 	pub fn y_gate(&mut self, qubit: usize) {
-        self.x_gate(qubit);
-        self.z_gate(qubit);
+		let column = qubit*self.words..(qubit + 1)*self.words;
+		for (r, (x, z)) in self.rs.iter_mut().zip(self.xs[column.clone()].iter().zip(self.zs[column].iter())) {
+			*r ^= *x ^ *z;
+		}
 	}
 
 	pub fn z_gate(&mut self, qubit: usize) {
-		self.s_gate(qubit);
-		self.s_gate(qubit);
+		let column = qubit*self.words..(qubit + 1)*self.words;
+		for (r, x) in self.rs.iter_mut().zip(self.xs[column].iter()) {
+			*r ^= *x;
+		}
 	}
 
 	pub fn cx_gate(&mut self, qubit1: usize, qubit2: usize) {
-		for i in 0..self.num_rows() {
-			let xa = self.x(i, qubit1);
-			let za = self.z(i, qubit1);
-			let xb = self.x(i, qubit2);
-			let zb = self.z(i, qubit2);
-
-			let r = self.r(i);
-
-			// Set r_i
-			self.set_r(i, r != ((xa && zb) && ((xb != za) != true)));
-
-			// Set x2
-			self.set_x(i, qubit2, xa != xb);
-
-			// Set z1
-			self.set_z(i, qubit1, za != zb);
+		assert!(qubit1 != qubit2);
+		let words: usize = self.words;
+		for w in 0..words {
+			let (a, b) = (qubit1*words + w, qubit2*words + w);
+			let (xa, za, xb, zb) = (self.xs[a], self.zs[a], self.xs[b], self.zs[b]);
+
+			self.rs[w] ^= xa & zb & !(xb ^ za);
+			self.xs[b] = xa ^ xb;
+			self.zs[a] = za ^ zb;
 		}
 	}
 
+	// Returns whether the outcome of measuring qubit is random, along with the first stabilizer row
+	// anticommuting with Z on qubit if so
 	pub fn mzr_deterministic(&self, qubit: usize) -> (bool, usize) {
-		assert!(self.track_destabilizers);
-		let num_qubits: usize = self.rows.len()/2;
-
-		for i in num_qubits..2*num_qubits {
-			if self.x(i, qubit) {
-				return (true, i);
-			}
+		let column: &[u64] = &self.xs[qubit*self.words..(qubit + 1)*self.words];
+		let stabilizers: Vec<u64> = self.row_mask(self.stabilizer_row(0), self.stabilizer_row(self.num_qubits));
+		let mask: Vec<u64> = column.iter().zip(stabilizers.iter()).map(|(x, s)| x & s).collect();
+		match Self::first_row(&mask) {
+			Some(i) => (true, i),
+			None => (false, 0),
 		}
-
-		return (false, 0);
 	}
 
-	pub fn mzr_qubit(&mut self, qubit: usize, mzr_outcome: bool) -> i32 {
-		// Must be tracking destabilizers to perform measurements
-		assert!(self.track_destabilizers);
-
-		let num_qubits: usize = self.rows.len()/2;
-
-		let (found_p, p): (bool, usize) = self.mzr_deterministic(qubit);
+	// Product of the stabilizers in mask, indexed by stabilizer
+	fn stabilizer_product(&self, mask: &[u64]) -> PackedRow {
+		let mut product: PackedRow = PackedRow::identity(self.num_qubits);
+		for i in (0..self.num_qubits).filter(|&i| Self::bit(mask, i)) {
+			product.mul(&self.packed_row(self.stabilizer_row(i)));
+		}
+		return product;
+	}
 
-		if found_p {
-			for i in 0..2*num_qubits {
-				if i != p && self.x(i, qubit) {
-					self.rowsum(i, p);
-				}
+	// Finds the stabilizers whose product is pauli up to sign, which must exist, by Gaussian
+	// elimination over the stabilizer rows. Takes O(n^3) time, against O(n^2) when destabilizers
+	// are tracked.
+	fn stabilizer_decomposition(&self, pauli: &PauliString) -> Vec<u64> {
+		let n: usize = self.num_qubits;
+		let words: usize = (n + 63)/64;
+		let pack = |row: &PauliString| -> Vec<u64> {
+			let mut bits: Vec<u64> = vec![0; 2*words];
+			for j in 0..n {
+				bits[j / 64] |= (row.x(j) as u64) << (j % 64);
+				bits[words + j / 64] |= (row.z(j) as u64) << (j % 64);
 			}
+			bits
+		};
 
-			self.rows[p - num_qubits] = self.rows[p].clone();
-			self.rows[p] = PauliString::new(num_qubits);
+		// Each row carries the mask of the stabilizers it is the product of
+		let mut rows: Vec<(Vec<u64>, Vec<u64>)> = (0..n).map(|i| {
+			let mut combination: Vec<u64> = vec![0; words];
+			Self::set_bit(&mut combination, i, true);
+			(pack(&self.row(self.stabilizer_row(i))), combination)
+		}).collect();
+
+		// Reduce to row echelon form, recording the pivot column of each row; the padding bits of
+		// the X and Z blocks are never set
+		let mut pivots: Vec<usize> = Vec::new();
+		for c in 0..128*words {
+			let rank: usize = pivots.len();
+			let Some(pivot) = (rank..n).find(|&i| Self::bit(&rows[i].0, c)) else { continue };
+			rows.swap(rank, pivot);
+			let (reduced, remaining) = rows.split_at_mut(rank + 1);
+			let (pivot_bits, pivot_combination) = &reduced[rank];
+			for (bits, combination) in remaining.iter_mut().filter(|(bits, _)| Self::bit(bits, c)) {
+				bits.iter_mut().zip(pivot_bits.iter()).for_each(|(b, p)| *b ^= p);
+				combination.iter_mut().zip(pivot_combination.iter()).for_each(|(b, p)| *b ^= p);
+			}
+			pivots.push(c);
+		}
 
-			if mzr_outcome {
-				self.set_r(p, true);
+		let mut target: Vec<u64> = pack(pauli);
+		let mut combination: Vec<u64> = vec![0; words];
+		for (i, &c) in pivots.iter().enumerate() {
+			if Self::bit(&target, c) {
+				target.iter_mut().zip(rows[i].0.iter()).for_each(|(b, p)| *b ^= p);
+				combination.iter_mut().zip(rows[i].1.iter()).for_each(|(b, p)| *b ^= p);
 			}
-			self.set_z(p, qubit, true);
+		}
+		assert!(target.iter().all(|&b| b == 0));
 
-			return mzr_outcome as i32;
+		return combination;
+	}
 
-		} else {
-			self.rows[2*num_qubits] = PauliString::new(num_qubits);
-			for i in 0..num_qubits {
-				self.rowsum(2*num_qubits, i + num_qubits);
-			}
+	// Measures the observable pauli, generalizing mzr_qubit by replacing Z on qubit with pauli
+	pub fn measure_pauli(&mut self, pauli: &PauliString, mzr_outcome: bool) -> i32 {
+		let num_qubits: usize = self.num_qubits;
+		assert!(pauli.num_qubits == num_qubits);
+
+		let mut anticommuting: Vec<u64> = self.anticommuting_rows(pauli);
+		let stabilizers: Vec<u64> = self.row_mask(self.stabilizer_row(0), self.stabilizer_row(num_qubits));
+		let anticommuting_stabilizers: Vec<u64> = anticommuting.iter().zip(stabilizers.iter()).map(|(a, s)| a & s).collect();
+		match Self::first_row(&anticommuting_stabilizers) {
+			Some(p) => {
+				// Outcome is random; every other row which anticommutes with pauli is multiplied
+				// by row p, which is then replaced by pauli
+				Self::set_bit(&mut anticommuting, p, false);
+				self.rowsum_masked(&anticommuting, p);
+
+				if self.track_destabilizers {
+					let row: PauliString = self.row(p);
+					self.set_row(p - num_qubits, &row);
+				}
+				self.set_row(p, pauli);
+				self.set_r(p, pauli.r() != mzr_outcome);
 
-			return self.r(2*num_qubits) as i32;
+				return mzr_outcome as i32;
+			},
+			None => {
+				// pauli is in the stabilizer group. With destabilizers it is the product of the
+				// stabilizers paired with anticommuting destabilizers; without them, the product
+				// is found by elimination.
+				let combination: Vec<u64> = if self.track_destabilizers {
+					anticommuting
+				} else {
+					self.stabilizer_decomposition(pauli)
+				};
+				return (self.stabilizer_product(&combination).r != pauli.r()) as i32;
+			},
 		}
+	}
 
+	// Measures qubit and resets it to |0>. When the outcome is random, the measurement leaves a
+	// stabilizer row equal to +-Z on qubit, and resetting only needs to clear its sign.
+	pub fn mr_qubit(&mut self, qubit: usize, mzr_outcome: bool) -> i32 {
+		let (found_p, p): (bool, usize) = self.mzr_deterministic(qubit);
+		let measured: i32 = self.mzr_qubit(qubit, mzr_outcome);
+		if found_p {
+			self.set_r(p, false);
+		} else if measured == 1 {
+			self.x_gate(qubit);
+		}
+
+		return measured;
+	}
+
+	pub fn mzr_qubit(&mut self, qubit: usize, mzr_outcome: bool) -> i32 {
+		let mut z: PauliString = PauliString::new(self.num_qubits);
+		z.set_z(qubit, true);
+		return self.measure_pauli(&z, mzr_outcome);
 	}
 }
 
@@ -425,6 +740,12 @@ pub struct QuantumCHPState {
 }
 
 impl QuantumCHPState {
+	// Switches to tracking only the stabilizers, which halves the memory and the cost of gates.
+	// Measurements whose outcome is determined then take O(n^3) time rather than O(n^2).
+	pub fn drop_destabilizers(&mut self) {
+		self.tableau.drop_destabilizers();
+	}
+
 	// Performs an iteration of the random clifford algorithm outlined in https://arxiv.org/pdf/2008.06011.pdf
 	fn random_clifford_iter(&mut self, qubits: &VecDeque<usize>) {
 		let num_qubits: usize = qubits.len();
@@ -602,7 +923,7 @@ impl QuantumCHPState {
 			}, _ => ()
 		} */
 
-		let mut tableau: Tableau = Tableau { rows: vec![pauli1, pauli2], track_destabilizers: false, print_ops: true };
+		let mut tableau: Tableau = Tableau::from_rows(vec![pauli1, pauli2], false);
 
 		// Step one: clear Z-block of first row
 		for i in 0..num_qubits {
@@ -663,7 +984,7 @@ impl QuantumCHPState {
 		negative_Z1.set_z(0, true);
 		negative_Z1.set_r(true);
 
-		if tableau.rows[1] != positive_Z1 && tableau.rows[1] != negative_Z1 {
+		if tableau.row(1) != positive_Z1 && tableau.row(1) != negative_Z1 {
 			tableau.h_gate(0);
 			self.h_gate(qubits[0]);
 
@@ -724,7 +1045,7 @@ impl QuantumCHPState {
 
 	fn get_pauli(&self, i: usize, j: usize) -> Matrix {
 		let mut p: Matrix = Matrix::new(2, 2);
-		match self.tableau.rows[i].to_op(j) {
+		match self.tableau.row(i).to_op(j) {
 			"I" => { // I
 				p[0][0] = ONE;
 				p[1][1] = ONE;
@@ -748,12 +1069,13 @@ impl QuantumCHPState {
 	}
 
 	fn generator(&self, idx: usize) -> Matrix {
-		let mut g: Matrix = self.get_pauli(idx + self.num_qubits, 0);
+		let row: usize = self.tableau.stabilizer_row(idx);
+		let mut g: Matrix = self.get_pauli(row, 0);
 		for i in 1..self.num_qubits {
-			g = g.kron(&self.get_pauli(idx + self.num_qubits, i))
+			g = g.kron(&self.get_pauli(row, i))
 		}
 
-		if self.tableau.r(idx + self.num_qubits) {
+		if self.tableau.r(row) {
 			g.scale(N_ONE);
 		}
 
@@ -811,6 +1133,10 @@ impl QuantumState for QuantumCHPState {
 		return QuantumCHPState { num_qubits: num_qubits, tableau: Tableau::new(num_qubits), rng: Lcg64Xsh32::from_entropy() };
 	}
 
+	fn new_with_seed(num_qubits: usize, seed: u64) -> Self {
+		return QuantumCHPState { num_qubits: num_qubits, tableau: Tableau::new(num_qubits), rng: Lcg64Xsh32::seed_from_u64(seed) };
+	}
+
 	fn print(&self) -> String {
 		let mut s: String = String::new();
 		s.push_str(&format!("Tableau: \n"));
@@ -850,9 +1176,21 @@ impl QuantumState for QuantumCHPState {
 		self.tableau.h_gate(qubit2);
 	}
 
+	fn x_gate(&mut self, qubit: usize) {
+		self.tableau.x_gate(qubit);
+	}
+
 	fn mzr_qubit(&mut self, qubit: usize) -> i32 {
 		self.tableau.mzr_qubit(qubit, self.rng.next_u32() % 2 == 0)
 	}
+
+	fn mr_qubit(&mut self, qubit: usize) -> i32 {
+		self.tableau.mr_qubit(qubit, self.rng.next_u32() % 2 == 0)
+	}
+
+	fn measure_pauli(&mut self, pauli: &PauliString) -> i32 {
+		self.tableau.measure_pauli(pauli, self.rng.next_u32() % 2 == 0)
+	}
 }
 
 impl Entropy for QuantumCHPState {
@@ -862,8 +1200,8 @@ impl Entropy for QuantumCHPState {
 		let mut truncated_tableau: Vec<BitVec> = vec![BitVec::from_elem(2*qubits.len(), false); self.num_qubits];
 		for i in 0..self.num_qubits {
 			for j in 0..qubits.len() {
-				truncated_tableau[i].set(j, 			   self.tableau.x(i + self.num_qubits, qubits[j]));
-				truncated_tableau[i].set(j + qubits.len(), self.tableau.z(i + self.num_qubits, qubits[j]));				
+				truncated_tableau[i].set(j, 			   self.tableau.x(self.tableau.stabilizer_row(i), qubits[j]));
+				truncated_tableau[i].set(j + qubits.len(), self.tableau.z(self.tableau.stabilizer_row(i), qubits[j]));				
 			}
 		}
 
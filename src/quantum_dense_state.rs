@@ -0,0 +1,277 @@
+use num::complex::Complex;
+use rand_pcg::Lcg64Xsh32;
+use rand::{RngCore, SeedableRng};
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::quantum_state::{Entropy, QuantumState, MzrForce, NonCliffordState};
+use crate::quantum_vector_state::QuantumVectorState;
+
+// Dense state vector holding all 2^n amplitudes, indexed so that qubit i is bit i of the index.
+// Gates are applied in place by bit-twiddling kernels, which are split over the rayon thread pool
+// once the state is large enough to be worth it.
+
+const ZERO: Complex<f64> = Complex::new(0., 0.);
+const ONE: Complex<f64> = Complex::new(1., 0.);
+const I: Complex<f64> = Complex::new(0., 1.);
+const EPS: f64 = 1e-10;
+
+const SQRT_HALF: Complex<f64> = Complex::new(std::f64::consts::FRAC_1_SQRT_2, 0.);
+const N_SQRT_HALF: Complex<f64> = Complex::new(-std::f64::consts::FRAC_1_SQRT_2, 0.);
+const HGATE: [Complex<f64>; 4] = [SQRT_HALF, SQRT_HALF, SQRT_HALF, N_SQRT_HALF];
+
+// Smallest number of qubits for which the kernels run in parallel
+const PARALLEL_THRESHOLD: usize = 14;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuantumDenseState {
+    pub num_qubits: usize,
+    pub amplitudes: Vec<Complex<f64>>,
+
+    rng: Lcg64Xsh32,
+}
+
+// Applies the 2x2 matrix gate = [g00, g01, g10, g11] to each pair (a0, a1) of amplitudes differing
+// only in the target bit, within a chunk starting at index base
+fn apply_to_chunk(chunk: &mut [Complex<f64>], base: usize, stride: usize, gate: &[Complex<f64>; 4], control_mask: usize) {
+    let (lower, upper) = chunk.split_at_mut(stride);
+    for (i, (a0, a1)) in lower.iter_mut().zip(upper.iter_mut()).enumerate() {
+        if (base + i) & control_mask != control_mask {
+            continue;
+        }
+        let (b0, b1) = (*a0, *a1);
+        *a0 = gate[0]*b0 + gate[1]*b1;
+        *a1 = gate[2]*b0 + gate[3]*b1;
+    }
+}
+
+impl QuantumDenseState {
+    fn parallel(&self) -> bool {
+        return self.num_qubits >= PARALLEL_THRESHOLD;
+    }
+
+    fn random_float(&mut self) -> f64 {
+        return (self.rng.next_u32() as f64) / (u32::MAX as f64);
+    }
+
+    // Applies gate to qubit on the subspace where every qubit in controls is 1
+    pub fn apply_gate(&mut self, qubit: usize, gate: [Complex<f64>; 4], controls: &[usize]) {
+        assert!(qubit < self.num_qubits);
+        let stride: usize = 1 << qubit;
+        let control_mask: usize = controls.iter().fold(0, |mask, c| mask | (1 << c));
+        assert!(control_mask & stride == 0);
+
+        if self.parallel() {
+            self.amplitudes.par_chunks_mut(2*stride).enumerate()
+                .for_each(|(i, chunk)| apply_to_chunk(chunk, 2*stride*i, stride, &gate, control_mask));
+        } else {
+            self.amplitudes.chunks_mut(2*stride).enumerate()
+                .for_each(|(i, chunk)| apply_to_chunk(chunk, 2*stride*i, stride, &gate, control_mask));
+        }
+    }
+
+    // Multiplies the amplitude of each basis state by phase(index)
+    fn apply_diagonal<F: Fn(usize) -> Complex<f64> + Sync>(&mut self, phase: F) {
+        if self.parallel() {
+            self.amplitudes.par_iter_mut().enumerate().for_each(|(i, a)| *a *= phase(i));
+        } else {
+            self.amplitudes.iter_mut().enumerate().for_each(|(i, a)| *a *= phase(i));
+        }
+    }
+
+    // Probability of measuring qubit as 1
+    fn prob_one(&self, qubit: usize) -> f64 {
+        let mask: usize = 1 << qubit;
+        return if self.parallel() {
+            self.amplitudes.par_iter().enumerate().filter(|(i, _)| i & mask != 0).map(|(_, a)| a.norm_sqr()).sum()
+        } else {
+            self.amplitudes.iter().enumerate().filter(|(i, _)| i & mask != 0).map(|(_, a)| a.norm_sqr()).sum()
+        };
+    }
+
+    // Projects qubit onto outcome, which is found with probability prob, and renormalizes
+    fn project(&mut self, qubit: usize, outcome: bool, prob: f64) {
+        let mask: usize = 1 << qubit;
+        let norm: f64 = prob.sqrt();
+        self.apply_diagonal(|i| if (i & mask != 0) == outcome { Complex::new(1./norm, 0.) } else { ZERO });
+    }
+
+    fn fix_phase(&mut self) {
+        // We fix global phase with respect to the smallest basis vector which appears, as the vector
+        // backend does
+        if let Some(a) = self.amplitudes.iter().find(|a| a.norm() > EPS) {
+            let phase: Complex<f64> = a / a.norm();
+            self.apply_diagonal(|_| phase.conj());
+        }
+    }
+
+    // Returns the partial density matrix for the specified qubits
+    pub fn reduced_state(&self, qubits: &Vec<usize>) -> Vec<Vec<Complex<f64>>> {
+        let env: Vec<usize> = (0..self.num_qubits).filter(|q| !qubits.contains(q)).collect();
+        let index = |a: usize, e: usize| -> usize {
+            let mut i: usize = 0;
+            for (j, q) in qubits.iter().enumerate() {
+                i |= ((a >> j) & 1) << q;
+            }
+            for (j, q) in env.iter().enumerate() {
+                i |= ((e >> j) & 1) << q;
+            }
+            return i;
+        };
+
+        let dim: usize = 1 << qubits.len();
+        let env_dim: usize = 1 << env.len();
+        return (0..dim).into_par_iter().map(|a1| {
+            (0..dim).map(|a2| {
+                (0..env_dim).map(|e| self.amplitudes[index(a1, e)]*self.amplitudes[index(a2, e)].conj()).sum()
+            }).collect()
+        }).collect();
+    }
+
+    pub fn to_vector_state(&self) -> QuantumVectorState {
+        let mut state = QuantumVectorState::new(self.num_qubits);
+        state.state.clear();
+        for (i, a) in self.amplitudes.iter().enumerate() {
+            if a.norm() > EPS {
+                state.add_basis(i as u64, Complex::new(a.re as f32, a.im as f32));
+            }
+        }
+        return state;
+    }
+}
+
+impl Entropy for QuantumDenseState {
+    fn renyi_entropy(&self, qubits: &Vec<usize>) -> f32 {
+        // The purity of a pure state is the same on either side of the cut, so the smaller side is
+        // traced over
+        let complement: Vec<usize> = (0..self.num_qubits).filter(|q| !qubits.contains(q)).collect();
+        let subsystem: &Vec<usize> = if qubits.len() <= complement.len() { qubits } else { &complement };
+
+        let rho: Vec<Vec<Complex<f64>>> = self.reduced_state(subsystem);
+        let purity: f64 = rho.iter().flatten().map(|r| r.norm_sqr()).sum();
+        return -purity.log2() as f32;
+    }
+}
+
+impl std::cmp::PartialEq for QuantumDenseState {
+    fn eq(&self, other: &QuantumDenseState) -> bool {
+        if self.num_qubits != other.num_qubits {
+            return false;
+        }
+
+        let mut state1: QuantumDenseState = self.clone();
+        state1.fix_phase();
+        let mut state2: QuantumDenseState = other.clone();
+        state2.fix_phase();
+
+        return state1.amplitudes.iter().zip(state2.amplitudes.iter()).all(|(a1, a2)| (a1 - a2).norm() < 1e-6);
+    }
+}
+
+impl QuantumState for QuantumDenseState {
+    fn new(num_qubits: usize) -> QuantumDenseState {
+        assert!(num_qubits < usize::BITS as usize);
+        let mut amplitudes: Vec<Complex<f64>> = vec![ZERO; 1 << num_qubits];
+        amplitudes[0] = ONE;
+        let rng = Lcg64Xsh32::from_entropy();
+        return QuantumDenseState { num_qubits: num_qubits, amplitudes: amplitudes, rng: rng };
+    }
+
+    fn new_with_seed(num_qubits: usize, seed: u64) -> QuantumDenseState {
+        let mut state = QuantumDenseState::new(num_qubits);
+        state.rng = Lcg64Xsh32::seed_from_u64(seed);
+        return state;
+    }
+
+    fn print(&self) -> String {
+        let mut s: String = String::from("\n");
+
+        for (i, a) in self.amplitudes.iter().enumerate() {
+            if a.norm() > EPS {
+                let bits: String = format!("{:0width$b}", i, width=self.num_qubits).chars().rev().collect();
+                s += &format!("{}: {:.2}\n", bits, a);
+            }
+        }
+        s = s[0..s.len()-1].to_string();
+        return s
+    }
+
+    fn system_size(&self) -> usize {
+        return self.num_qubits;
+    }
+
+    fn x_gate(&mut self, qubit: usize) {
+        self.apply_gate(qubit, [ZERO, ONE, ONE, ZERO], &[]);
+    }
+
+    fn y_gate(&mut self, qubit: usize) {
+        self.apply_gate(qubit, [ZERO, -I, I, ZERO], &[]);
+    }
+
+    fn z_gate(&mut self, qubit: usize) {
+        assert!(qubit < self.num_qubits);
+        self.apply_diagonal(|i| if (i >> qubit) & 1 == 1 { -ONE } else { ONE });
+    }
+
+    fn h_gate(&mut self, qubit: usize) {
+        self.apply_gate(qubit, HGATE, &[]);
+    }
+
+    fn s_gate(&mut self, qubit: usize) {
+        assert!(qubit < self.num_qubits);
+        self.apply_diagonal(|i| if (i >> qubit) & 1 == 1 { I } else { ONE });
+    }
+
+    fn cx_gate(&mut self, qubit1: usize, qubit2: usize) {
+        self.apply_gate(qubit2, [ZERO, ONE, ONE, ZERO], &[qubit1]);
+    }
+
+    fn cy_gate(&mut self, qubit1: usize, qubit2: usize) {
+        self.apply_gate(qubit2, [ZERO, -I, I, ZERO], &[qubit1]);
+    }
+
+    fn cz_gate(&mut self, qubit1: usize, qubit2: usize) {
+        assert!(qubit1 < self.num_qubits && qubit2 < self.num_qubits);
+        let mask: usize = (1 << qubit1) | (1 << qubit2);
+        self.apply_diagonal(|i| if i & mask == mask { -ONE } else { ONE });
+    }
+
+    fn mzr_qubit(&mut self, qubit: usize) -> i32 {
+        assert!(qubit < self.num_qubits);
+        let prob_one: f64 = self.prob_one(qubit);
+        let measured: bool = self.random_float() >= 1. - prob_one;
+        self.project(qubit, measured, if measured { prob_one } else { 1. - prob_one });
+        return measured as i32;
+    }
+
+    fn finish_execution(&mut self) {
+        self.fix_phase();
+    }
+}
+
+impl NonCliffordState for QuantumDenseState {
+    fn unitary_gate(&mut self, qubit: usize, gate: [Complex<f64>; 4]) {
+        self.apply_gate(qubit, gate, &[]);
+    }
+
+    fn cphase_gate(&mut self, qubit1: usize, qubit2: usize, phi: f64) {
+        assert!(qubit1 < self.num_qubits && qubit2 < self.num_qubits);
+        let mask: usize = (1 << qubit1) | (1 << qubit2);
+        let phase: Complex<f64> = Complex::from_polar(1., phi);
+        self.apply_diagonal(|i| if i & mask == mask { phase } else { ONE });
+    }
+}
+
+impl MzrForce for QuantumDenseState {
+    fn mzr_qubit_forced(&mut self, qubit: usize, outcome: bool) -> bool {
+        assert!(qubit < self.num_qubits);
+        let prob_one: f64 = self.prob_one(qubit);
+        let prob: f64 = if outcome { prob_one } else { 1. - prob_one };
+        if prob < EPS {
+            return false;
+        }
+
+        self.project(qubit, outcome, prob);
+        return true;
+    }
+}
@@ -0,0 +1,400 @@
+use num::complex::Complex;
+use rand_pcg::Lcg64Xsh32;
+use rand::{RngCore, SeedableRng};
+use serde::{Serialize, Deserialize};
+
+use crate::quantum_state::{Entropy, QuantumState, MzrForce, NonCliffordState};
+use crate::noise::NoiseChannel;
+
+// Density matrix of a possibly mixed state, stored densely as a 2^n x 2^n matrix in row-major order
+// with qubit i in bit i of the row and column indices. Gates and Kraus operators act as
+// rho -> K rho K^dagger, and Pauli noise channels are applied exactly rather than sampled.
+
+const ZERO: Complex<f64> = Complex::new(0., 0.);
+const ONE: Complex<f64> = Complex::new(1., 0.);
+const I: Complex<f64> = Complex::new(0., 1.);
+const EPS: f64 = 1e-10;
+
+const SQRT_HALF: Complex<f64> = Complex::new(std::f64::consts::FRAC_1_SQRT_2, 0.);
+const N_SQRT_HALF: Complex<f64> = Complex::new(-std::f64::consts::FRAC_1_SQRT_2, 0.);
+
+const XGATE: [Complex<f64>; 4] = [ZERO, ONE, ONE, ZERO];
+const YGATE: [Complex<f64>; 4] = [ZERO, Complex::new(0., -1.), I, ZERO];
+const ZGATE: [Complex<f64>; 4] = [ONE, ZERO, ZERO, Complex::new(-1., 0.)];
+const HGATE: [Complex<f64>; 4] = [SQRT_HALF, SQRT_HALF, SQRT_HALF, N_SQRT_HALF];
+const SGATE: [Complex<f64>; 4] = [ONE, ZERO, ZERO, I];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuantumDensityMatrixState {
+    pub num_qubits: usize,
+    rho: Vec<Complex<f64>>,
+
+    rng: Lcg64Xsh32,
+}
+
+// Index of the basis state holding the bits of a on qubits, and otherwise the bits of base
+fn embed(base: usize, qubits: &[usize], a: usize) -> usize {
+    let mut i: usize = base;
+    for (j, q) in qubits.iter().enumerate() {
+        i |= ((a >> j) & 1) << q;
+    }
+    return i;
+}
+
+// Eigenvalues of a Hermitian matrix, in increasing order. The matrix A + iB is embedded as the real
+// symmetric matrix [[A, -B], [B, A]], whose eigenvalues are those of A + iB each repeated twice, and
+// diagonalized by cyclic Jacobi rotations.
+fn hermitian_eigenvalues(matrix: &Vec<Vec<Complex<f64>>>) -> Vec<f64> {
+    let n: usize = matrix.len();
+    let m: usize = 2*n;
+    let mut a: Vec<Vec<f64>> = vec![vec![0.; m]; m];
+    for i in 0..n {
+        for j in 0..n {
+            a[i][j] = matrix[i][j].re;
+            a[i + n][j + n] = matrix[i][j].re;
+            a[i][j + n] = -matrix[i][j].im;
+            a[i + n][j] = matrix[i][j].im;
+        }
+    }
+
+    for _ in 0..100 {
+        let off_diagonal: f64 = (0..m).map(|i| (0..m).filter(|&j| j != i).map(|j| a[i][j].powi(2)).sum::<f64>()).sum();
+        if off_diagonal < 1e-24 {
+            break;
+        }
+
+        for p in 0..m {
+            for q in (p + 1)..m {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta: f64 = (a[q][q] - a[p][p])/(2.*a[p][q]);
+                let t: f64 = theta.signum()/(theta.abs() + (theta*theta + 1.).sqrt());
+                let c: f64 = 1./(t*t + 1.).sqrt();
+                let s: f64 = t*c;
+                for k in 0..m {
+                    let (akp, akq) = (a[k][p], a[k][q]);
+                    a[k][p] = c*akp - s*akq;
+                    a[k][q] = s*akp + c*akq;
+                }
+                for k in 0..m {
+                    let (apk, aqk) = (a[p][k], a[q][k]);
+                    a[p][k] = c*apk - s*aqk;
+                    a[q][k] = s*apk + c*aqk;
+                }
+            }
+        }
+    }
+
+    let mut eigenvalues: Vec<f64> = (0..m).map(|i| a[i][i]).collect();
+    eigenvalues.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    return eigenvalues.into_iter().step_by(2).collect();
+}
+
+impl QuantumDensityMatrixState {
+    fn dim(&self) -> usize {
+        return 1 << self.num_qubits;
+    }
+
+    fn random_float(&mut self) -> f64 {
+        return (self.rng.next_u32() as f64) / (u32::MAX as f64);
+    }
+
+    // State with the given density matrix, which should be Hermitian, positive and of unit trace
+    pub fn from_density_matrix(num_qubits: usize, rho: &Vec<Vec<Complex<f64>>>) -> QuantumDensityMatrixState {
+        let mut state = QuantumDensityMatrixState::new(num_qubits);
+        assert_eq!(rho.len(), state.dim());
+        state.rho = rho.iter().flat_map(|row| { assert_eq!(row.len(), state.dim()); row.iter().cloned() }).collect();
+        return state;
+    }
+
+    // The pure state with the given amplitudes
+    pub fn from_amplitudes(num_qubits: usize, amplitudes: &[Complex<f64>]) -> QuantumDensityMatrixState {
+        let mut state = QuantumDensityMatrixState::new(num_qubits);
+        assert_eq!(amplitudes.len(), state.dim());
+        let dim: usize = state.dim();
+        for i in 0..dim {
+            for j in 0..dim {
+                state.rho[i*dim + j] = amplitudes[i]*amplitudes[j].conj();
+            }
+        }
+        return state;
+    }
+
+    pub fn maximally_mixed(num_qubits: usize) -> QuantumDensityMatrixState {
+        let mut state = QuantumDensityMatrixState::new(num_qubits);
+        let dim: usize = state.dim();
+        for i in 0..dim {
+            state.rho[i*dim] = ZERO;
+            state.rho[i*dim + i] = Complex::new(1./(dim as f64), 0.);
+        }
+        return state;
+    }
+
+    pub fn density_matrix(&self) -> Vec<Vec<Complex<f64>>> {
+        return self.rho.chunks(self.dim()).map(|row| row.to_vec()).collect();
+    }
+
+    pub fn trace(&self) -> f64 {
+        return (0..self.dim()).map(|i| self.rho[i*self.dim() + i].re).sum();
+    }
+
+    pub fn purity(&self) -> f64 {
+        return self.rho.iter().map(|r| r.norm_sqr()).sum();
+    }
+
+    // Returns op rho op^dagger, for op a 2^k x 2^k matrix in row-major order acting on k qubits
+    fn conjugated(&self, op: &[Complex<f64>], qubits: &[usize]) -> Vec<Complex<f64>> {
+        let dim: usize = self.dim();
+        let k: usize = 1 << qubits.len();
+        assert_eq!(op.len(), k*k);
+        let mask: usize = qubits.iter().fold(0, |mask, q| mask | (1 << q));
+        let bases: Vec<usize> = (0..dim).filter(|i| i & mask == 0).collect();
+
+        let mut rho: Vec<Complex<f64>> = self.rho.clone();
+        let mut v: Vec<Complex<f64>> = vec![ZERO; k];
+
+        // op rho, acting on the row index of each column
+        for c in 0..dim {
+            for &base in &bases {
+                for a in 0..k {
+                    v[a] = rho[embed(base, qubits, a)*dim + c];
+                }
+                for a in 0..k {
+                    rho[embed(base, qubits, a)*dim + c] = (0..k).map(|b| op[a*k + b]*v[b]).sum();
+                }
+            }
+        }
+
+        // (op rho) op^dagger, acting on the column index of each row
+        for r in 0..dim {
+            for &base in &bases {
+                for a in 0..k {
+                    v[a] = rho[r*dim + embed(base, qubits, a)];
+                }
+                for a in 0..k {
+                    rho[r*dim + embed(base, qubits, a)] = (0..k).map(|b| v[b]*op[a*k + b].conj()).sum();
+                }
+            }
+        }
+
+        return rho;
+    }
+
+    // Applies the unitary op, a 2^k x 2^k matrix in row-major order, to k qubits
+    pub fn apply_unitary(&mut self, op: &[Complex<f64>], qubits: &[usize]) {
+        assert!(qubits.iter().all(|q| *q < self.num_qubits));
+        self.rho = self.conjugated(op, qubits);
+    }
+
+    // Applies the channel rho -> sum_k K_k rho K_k^dagger, for Kraus operators K_k acting on qubits.
+    // The operators must satisfy sum_k K_k^dagger K_k = 1.
+    pub fn apply_kraus(&mut self, kraus: &[Vec<Complex<f64>>], qubits: &[usize]) {
+        assert!(qubits.iter().all(|q| *q < self.num_qubits));
+        let k: usize = 1 << qubits.len();
+        for a in 0..k {
+            for b in 0..k {
+                let sum: Complex<f64> = kraus.iter().map(|op| (0..k).map(|c| op[c*k + a].conj()*op[c*k + b]).sum::<Complex<f64>>()).sum();
+                let expected: Complex<f64> = if a == b { ONE } else { ZERO };
+                assert!((sum - expected).norm() < 1e-8, "Kraus operators are not trace preserving");
+            }
+        }
+
+        let mut rho: Vec<Complex<f64>> = vec![ZERO; self.rho.len()];
+        for op in kraus {
+            for (r, c) in rho.iter_mut().zip(self.conjugated(op, qubits)) {
+                *r += c;
+            }
+        }
+        self.rho = rho;
+    }
+
+    // Returns the density matrix of the specified qubits, with qubits[j] in bit j of the indices
+    pub fn reduced_state(&self, qubits: &Vec<usize>) -> Vec<Vec<Complex<f64>>> {
+        let dim: usize = self.dim();
+        let k: usize = 1 << qubits.len();
+        let mask: usize = qubits.iter().fold(0, |mask, q| mask | (1 << q));
+
+        let mut rho: Vec<Vec<Complex<f64>>> = vec![vec![ZERO; k]; k];
+        for base in (0..dim).filter(|i| i & mask == 0) {
+            for a in 0..k {
+                for b in 0..k {
+                    rho[a][b] += self.rho[embed(base, qubits, a)*dim + embed(base, qubits, b)];
+                }
+            }
+        }
+        return rho;
+    }
+
+    // Traces out the specified qubits, returning the state of the remaining qubits in their
+    // original order
+    pub fn partial_trace(&self, qubits: &[usize]) -> QuantumDensityMatrixState {
+        let remaining: Vec<usize> = (0..self.num_qubits).filter(|q| !qubits.contains(q)).collect();
+        let mut state = QuantumDensityMatrixState::from_density_matrix(remaining.len(), &self.reduced_state(&remaining));
+        state.rng = self.rng.clone();
+        return state;
+    }
+
+    // Renyi entropy S_n = log2(tr rho^n)/(1 - n) of the specified qubits, which is the von Neumann
+    // entropy for n = 1
+    pub fn renyi_entropy_n(&self, qubits: &Vec<usize>, n: f64) -> f32 {
+        let eigenvalues: Vec<f64> = hermitian_eigenvalues(&self.reduced_state(qubits)).into_iter().filter(|p| *p > EPS).collect();
+        let entropy: f64 = if (n - 1.).abs() < EPS {
+            -eigenvalues.iter().map(|p| p*p.log2()).sum::<f64>()
+        } else {
+            eigenvalues.iter().map(|p| p.powf(n)).sum::<f64>().log2()/(1. - n)
+        };
+        return entropy as f32;
+    }
+
+    pub fn von_neumann_entropy(&self, qubits: &Vec<usize>) -> f32 {
+        return self.renyi_entropy_n(qubits, 1.);
+    }
+
+    // Probability of measuring qubit as 1
+    fn prob_one(&self, qubit: usize) -> f64 {
+        let dim: usize = self.dim();
+        return (0..dim).filter(|i| (i >> qubit) & 1 == 1).map(|i| self.rho[i*dim + i].re).sum();
+    }
+
+    // Projects qubit onto outcome, which is found with probability prob, and renormalizes
+    fn project(&mut self, qubit: usize, outcome: bool, prob: f64) {
+        let dim: usize = self.dim();
+        for i in 0..dim {
+            for j in 0..dim {
+                let keep: bool = ((i >> qubit) & 1 == 1) == outcome && ((j >> qubit) & 1 == 1) == outcome;
+                self.rho[i*dim + j] = if keep { self.rho[i*dim + j]/prob } else { ZERO };
+            }
+        }
+    }
+}
+
+impl Entropy for QuantumDensityMatrixState {
+    fn renyi_entropy(&self, qubits: &Vec<usize>) -> f32 {
+        let rho: Vec<Vec<Complex<f64>>> = self.reduced_state(qubits);
+        let purity: f64 = rho.iter().flatten().map(|r| r.norm_sqr()).sum();
+        return -purity.log2() as f32;
+    }
+}
+
+impl std::cmp::PartialEq for QuantumDensityMatrixState {
+    fn eq(&self, other: &QuantumDensityMatrixState) -> bool {
+        return self.num_qubits == other.num_qubits && self.rho.iter().zip(other.rho.iter()).all(|(r1, r2)| (r1 - r2).norm() < 1e-6);
+    }
+}
+
+impl QuantumState for QuantumDensityMatrixState {
+    fn new(num_qubits: usize) -> QuantumDensityMatrixState {
+        let mut rho: Vec<Complex<f64>> = vec![ZERO; 1 << (2*num_qubits)];
+        rho[0] = ONE;
+        let rng = Lcg64Xsh32::from_entropy();
+        return QuantumDensityMatrixState { num_qubits: num_qubits, rho: rho, rng: rng };
+    }
+
+    fn new_with_seed(num_qubits: usize, seed: u64) -> QuantumDensityMatrixState {
+        let mut state = QuantumDensityMatrixState::new(num_qubits);
+        state.rng = Lcg64Xsh32::seed_from_u64(seed);
+        return state;
+    }
+
+    fn print(&self) -> String {
+        let rows: Vec<String> = self.rho.chunks(self.dim()).map(|row| {
+            row.iter().map(|r| format!("{:.2}", r)).collect::<Vec<String>>().join(" ")
+        }).collect();
+        return String::from("\n") + &rows.join("\n");
+    }
+
+    fn system_size(&self) -> usize {
+        return self.num_qubits;
+    }
+
+    fn x_gate(&mut self, qubit: usize) {
+        self.apply_unitary(&XGATE, &[qubit]);
+    }
+
+    fn y_gate(&mut self, qubit: usize) {
+        self.apply_unitary(&YGATE, &[qubit]);
+    }
+
+    fn z_gate(&mut self, qubit: usize) {
+        self.apply_unitary(&ZGATE, &[qubit]);
+    }
+
+    fn h_gate(&mut self, qubit: usize) {
+        self.apply_unitary(&HGATE, &[qubit]);
+    }
+
+    fn s_gate(&mut self, qubit: usize) {
+        self.apply_unitary(&SGATE, &[qubit]);
+    }
+
+    // Two-qubit gates are written with qubit1 in the low bit
+    fn cx_gate(&mut self, qubit1: usize, qubit2: usize) {
+        let mut op: [Complex<f64>; 16] = [ZERO; 16];
+        (op[0], op[4*2 + 2], op[4*3 + 1], op[4 + 3]) = (ONE, ONE, ONE, ONE);
+        self.apply_unitary(&op, &[qubit1, qubit2]);
+    }
+
+    fn cy_gate(&mut self, qubit1: usize, qubit2: usize) {
+        let mut op: [Complex<f64>; 16] = [ZERO; 16];
+        (op[0], op[4*2 + 2], op[4*3 + 1], op[4 + 3]) = (ONE, ONE, I, -I);
+        self.apply_unitary(&op, &[qubit1, qubit2]);
+    }
+
+    fn cz_gate(&mut self, qubit1: usize, qubit2: usize) {
+        self.cphase_gate(qubit1, qubit2, std::f64::consts::PI);
+    }
+
+    fn mzr_qubit(&mut self, qubit: usize) -> i32 {
+        assert!(qubit < self.num_qubits);
+        let prob_one: f64 = self.prob_one(qubit);
+        let measured: bool = self.random_float() >= 1. - prob_one;
+        self.project(qubit, measured, if measured { prob_one } else { 1. - prob_one });
+        return measured as i32;
+    }
+
+    // Applies the channel exactly, as the mixture of the Pauli conjugations it samples from
+    fn apply_noise(&mut self, channel: &NoiseChannel, qubits: &[usize], _rng: &mut Lcg64Xsh32) {
+        let paulis: [[Complex<f64>; 4]; 4] = [[ONE, ZERO, ZERO, ONE], XGATE, YGATE, ZGATE];
+        let distribution: Vec<(f32, usize)> = channel.pauli_distribution();
+
+        let identity: f64 = 1. - distribution.iter().map(|(p, _)| *p as f64).sum::<f64>();
+        let mut rho: Vec<Complex<f64>> = self.rho.iter().map(|r| r*identity).collect();
+        for (p, pauli) in distribution {
+            let mut error = self.clone();
+            for (i, q) in qubits.iter().enumerate() {
+                error.apply_unitary(&paulis[(pauli >> (2*i)) & 3], &[*q]);
+            }
+            for (r, e) in rho.iter_mut().zip(error.rho) {
+                *r += e*(p as f64);
+            }
+        }
+        self.rho = rho;
+    }
+}
+
+impl NonCliffordState for QuantumDensityMatrixState {
+    fn unitary_gate(&mut self, qubit: usize, gate: [Complex<f64>; 4]) {
+        self.apply_unitary(&gate, &[qubit]);
+    }
+
+    fn cphase_gate(&mut self, qubit1: usize, qubit2: usize, phi: f64) {
+        let mut op: [Complex<f64>; 16] = [ZERO; 16];
+        (op[0], op[5], op[10], op[15]) = (ONE, ONE, ONE, Complex::from_polar(1., phi));
+        self.apply_unitary(&op, &[qubit1, qubit2]);
+    }
+}
+
+impl MzrForce for QuantumDensityMatrixState {
+    fn mzr_qubit_forced(&mut self, qubit: usize, outcome: bool) -> bool {
+        assert!(qubit < self.num_qubits);
+        let prob_one: f64 = self.prob_one(qubit);
+        let prob: f64 = if outcome { prob_one } else { 1. - prob_one };
+        if prob < EPS {
+            return false;
+        }
+
+        self.project(qubit, outcome, prob);
+        return true;
+    }
+}
@@ -9,12 +9,12 @@ use crate::quantum_state::{Entropy, QuantumState, QuantumProgram, MzrForce};
 
 const CONJUGATION_TABLE: [usize; 24] = [3, 6, 6, 3, 1, 1, 4, 4, 5, 2, 5, 2, 1, 1, 4, 4, 5, 2, 5, 2, 3, 6, 6, 3];
 
-const IDGATE: usize     =  0;
+pub(crate) const IDGATE: usize     =  0;
 const XGATE: usize      =  1;
 const YGATE: usize      =  2;
 const ZGATE: usize      =  3;
-const HGATE: usize      = 12;
-const SGATE: usize      = 20;
+pub(crate) const HGATE: usize      = 12;
+pub(crate) const SGATE: usize      = 20;
 const SDGATE: usize     = 23;
 const SQRTXGATE: usize  = 17;
 const SQRTXDGATE: usize = 16;
@@ -23,7 +23,7 @@ const SQRTYDGATE: usize = 13;
 const SQRTZGATE: usize  = 20;
 const SQRTZDGATE: usize = 23;
 
-const ZGATES: [usize; 4] = [IDGATE, ZGATE, SGATE, SDGATE];
+pub(crate) const ZGATES: [usize; 4] = [IDGATE, ZGATE, SGATE, SDGATE];
 
 const CLIFFORD_DECOMPS: [[usize; 5]; 24] =
    [[IDGATE, IDGATE, IDGATE, IDGATE,  IDGATE],
@@ -77,7 +77,7 @@ const GATE_DECOMPS: [&str; 24] = [
 	"sshsshs",
 	"sss"];
 
-const CLIFFORD_PRODUCTS: [[usize; 24]; 24] = 
+pub(crate) const CLIFFORD_PRODUCTS: [[usize; 24]; 24] = 
    [[ 0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23],
 	[ 1,  0,  3,  2,  6,  7,  4,  5, 11, 10,  9,  8, 15, 14, 13, 12, 17, 16, 19, 18, 22, 23, 20, 21],
 	[ 2,  3,  0,  1,  7,  6,  5,  4,  9,  8, 11, 10, 14, 15, 12, 13, 19, 18, 17, 16, 21, 20, 23, 22],
@@ -589,6 +589,12 @@ impl QuantumState for QuantumGraphState {
 		return QuantumGraphState { num_qubits: num_qubits, graph: graph , rng: Lcg64Xsh32::from_entropy() };
 	}
 
+	fn new_with_seed(num_qubits: usize, seed: u64) -> QuantumGraphState {
+		let mut state = QuantumGraphState::new(num_qubits);
+		state.rng = Lcg64Xsh32::seed_from_u64(seed);
+		return state;
+	}
+
 	fn print(&self) -> String { 
 		let mut s: String = String::from("Graph:\n");
 		for i in 0..self.graph.num_vertices {
@@ -676,6 +682,24 @@ impl QuantumState for QuantumGraphState {
 
 		return measured ^ positive;
 	}
+
+	// A measured vertex is left isolated, so a reset only needs to overwrite its VOP
+	fn mr_qubit(&mut self, qubit: usize) -> i32 {
+		let measured: i32 = self.mzr_qubit(qubit);
+		debug_assert!(self.graph.degree(qubit) == 0);
+		self.graph.vals[qubit] = HGATE;
+		return measured;
+	}
+
+	fn resetx_qubit(&mut self, qubit: usize) {
+		self.mzr_qubit(qubit);
+		self.graph.vals[qubit] = IDGATE;
+	}
+
+	fn resety_qubit(&mut self, qubit: usize) {
+		self.mzr_qubit(qubit);
+		self.graph.vals[qubit] = SGATE;
+	}
 }
 
 impl Entropy for QuantumGraphState {
@@ -0,0 +1,567 @@
+use num::complex::Complex;
+use rand_pcg::Lcg64Xsh32;
+use rand::{RngCore, SeedableRng};
+use serde::{Serialize, Deserialize};
+
+use crate::quantum_state::{Entropy, QuantumState, MzrForce, NonCliffordState};
+use crate::quantum_dense_state::QuantumDenseState;
+
+// Matrix-product state on an open chain, with qubit i at site i. The state is kept in mixed
+// canonical form: every tensor left of the center is left-orthonormal and every tensor right of it
+// is right-orthonormal. Two-qubit gates act on neighbouring sites by contracting them, applying the
+// gate and splitting them again with an SVD, where small singular values are truncated. Gates on
+// distant qubits are routed through swaps.
+
+const ZERO: Complex<f64> = Complex::new(0., 0.);
+const ONE: Complex<f64> = Complex::new(1., 0.);
+const I: Complex<f64> = Complex::new(0., 1.);
+const EPS: f64 = 1e-12;
+
+const SQRT_HALF: Complex<f64> = Complex::new(std::f64::consts::FRAC_1_SQRT_2, 0.);
+const N_SQRT_HALF: Complex<f64> = Complex::new(-std::f64::consts::FRAC_1_SQRT_2, 0.);
+
+const XGATE: [Complex<f64>; 4] = [ZERO, ONE, ONE, ZERO];
+const YGATE: [Complex<f64>; 4] = [ZERO, Complex::new(0., -1.), I, ZERO];
+const ZGATE: [Complex<f64>; 4] = [ONE, ZERO, ZERO, Complex::new(-1., 0.)];
+const HGATE: [Complex<f64>; 4] = [SQRT_HALF, SQRT_HALF, SQRT_HALF, N_SQRT_HALF];
+const SGATE: [Complex<f64>; 4] = [ONE, ZERO, ZERO, I];
+
+// Tensor of a site with indices (left bond, physical, right bond)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Site {
+    left: usize,
+    right: usize,
+    data: Vec<Complex<f64>>,
+}
+
+impl Site {
+    fn get(&self, l: usize, s: usize, r: usize) -> Complex<f64> {
+        return self.data[(l*2 + s)*self.right + r];
+    }
+}
+
+// Singular value decomposition M = U diag(S) Vh of a rows x cols matrix in row-major order, by
+// one-sided Jacobi rotations of the columns. Returns U (rows x k), S and Vh (k x cols) with the
+// singular values in decreasing order, where k = min(rows, cols).
+fn svd(m: &Vec<Complex<f64>>, rows: usize, cols: usize) -> (Vec<Complex<f64>>, Vec<f64>, Vec<Complex<f64>>) {
+    if rows < cols {
+        // Decompose the adjoint, which has fewer columns
+        let adjoint: Vec<Complex<f64>> = (0..cols*rows).map(|i| m[(i % rows)*cols + i/rows].conj()).collect();
+        let (u, s, vh) = svd(&adjoint, cols, rows);
+        let k: usize = s.len();
+        let new_u: Vec<Complex<f64>> = (0..rows*k).map(|i| vh[(i % k)*rows + i/k].conj()).collect();
+        let new_vh: Vec<Complex<f64>> = (0..k*cols).map(|i| u[(i % cols)*k + i/cols].conj()).collect();
+        return (new_u, s, new_vh);
+    }
+
+    // Columns of the matrix and of V, stored contiguously
+    let mut a: Vec<Vec<Complex<f64>>> = (0..cols).map(|j| (0..rows).map(|i| m[i*cols + j]).collect()).collect();
+    let mut v: Vec<Vec<Complex<f64>>> = (0..cols).map(|j| (0..cols).map(|i| if i == j { ONE } else { ZERO }).collect()).collect();
+
+    for _ in 0..60 {
+        let mut rotated: bool = false;
+        for p in 0..cols {
+            for q in (p + 1)..cols {
+                let alpha: f64 = a[p].iter().map(|x| x.norm_sqr()).sum();
+                let beta: f64 = a[q].iter().map(|x| x.norm_sqr()).sum();
+                let gamma: Complex<f64> = a[p].iter().zip(a[q].iter()).map(|(x, y)| x.conj()*y).sum();
+                if gamma.norm() <= 1e-15*(alpha*beta).sqrt() || gamma.norm() < 1e-300 {
+                    continue;
+                }
+                rotated = true;
+
+                // Rotation [a_p, a_q] -> [c a_p - s e^{-i phi} a_q, s e^{i phi} a_p + c a_q], which makes
+                // the two columns orthogonal
+                let zeta: f64 = (beta - alpha)/(2.*gamma.norm());
+                let t: f64 = (if zeta >= 0. { 1. } else { -1. })/(zeta.abs() + (1. + zeta*zeta).sqrt());
+                let c: f64 = 1./(1. + t*t).sqrt();
+                let s: f64 = c*t;
+                let phase: Complex<f64> = gamma/gamma.norm();
+                for cols in [&mut a, &mut v] {
+                    for i in 0..cols[p].len() {
+                        let (xp, xq) = (cols[p][i], cols[q][i]);
+                        cols[p][i] = xp*c - phase.conj()*xq*s;
+                        cols[q][i] = phase*xp*s + xq*c;
+                    }
+                }
+            }
+        }
+        if !rotated {
+            break;
+        }
+    }
+
+    let mut order: Vec<(f64, usize)> = a.iter().enumerate().map(|(j, col)| (col.iter().map(|x| x.norm_sqr()).sum::<f64>().sqrt(), j)).collect();
+    order.sort_by(|x, y| y.0.partial_cmp(&x.0).unwrap());
+
+    let k: usize = cols;
+    let mut u: Vec<Complex<f64>> = vec![ZERO; rows*k];
+    let mut vh: Vec<Complex<f64>> = vec![ZERO; k*cols];
+    let mut s: Vec<f64> = Vec::new();
+    for (n, (sigma, j)) in order.into_iter().enumerate() {
+        s.push(sigma);
+        for i in 0..rows {
+            u[i*k + n] = if sigma > 0. { a[j][i]/sigma } else { ZERO };
+        }
+        for i in 0..cols {
+            vh[n*cols + i] = v[j][i].conj();
+        }
+    }
+
+    return (u, s, vh);
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuantumMPSState {
+    pub num_qubits: usize,
+    sites: Vec<Site>,
+    center: usize,
+
+    // Largest bond dimension kept by truncation
+    max_bond_dim: usize,
+    // Singular values whose squared weight, relative to the whole spectrum, is below this are
+    // discarded
+    cutoff: f64,
+    // Total squared weight of the singular values discarded by truncation
+    discarded_weight: f64,
+
+    rng: Lcg64Xsh32,
+}
+
+impl QuantumMPSState {
+    pub fn set_truncation(&mut self, max_bond_dim: usize, cutoff: f64) {
+        assert!(max_bond_dim > 0);
+        self.max_bond_dim = max_bond_dim;
+        self.cutoff = cutoff;
+    }
+
+    // Total weight of the singular values discarded by truncation, summed over every truncation
+    pub fn discarded_weight(&self) -> f64 {
+        return self.discarded_weight;
+    }
+
+    // Dimension of the bond between qubits bond and bond + 1
+    pub fn bond_dimension(&self, bond: usize) -> usize {
+        return self.sites[bond].right;
+    }
+
+    pub fn max_bond_dimension(&self) -> usize {
+        return self.sites.iter().map(|site| site.right).max().unwrap_or(1);
+    }
+
+    fn random_float(&mut self) -> f64 {
+        return (self.rng.next_u32() as f64) / (u32::MAX as f64);
+    }
+
+    // Splits the matrix m, of shape (left*2) x (2*right), into two sites, truncating the bond
+    // between them. The singular values are absorbed into the left site if center_left, and
+    // otherwise into the right site.
+    fn split(&mut self, m: &Vec<Complex<f64>>, left: usize, right: usize, center_left: bool) -> (Site, Site) {
+        let (u, s, vh) = svd(m, 2*left, 2*right);
+        let k: usize = s.len();
+
+        let total: f64 = s.iter().map(|x| x*x).sum();
+        let mut kept: usize = s.iter().take_while(|x| *x * *x > self.cutoff*total && **x > EPS).count().min(self.max_bond_dim).max(1);
+        kept = kept.min(k);
+        let discarded: f64 = s[kept..].iter().map(|x| x*x).sum();
+        // Numerically vanishing singular values are dropped without counting as truncation
+        if s[kept..].iter().any(|x| *x > EPS) {
+            self.discarded_weight += discarded/total;
+        }
+        let norm: f64 = (total - discarded).sqrt();
+
+        let mut site1 = Site { left: left, right: kept, data: vec![ZERO; left*2*kept] };
+        let mut site2 = Site { left: kept, right: right, data: vec![ZERO; kept*2*right] };
+        for i in 0..2*left {
+            for n in 0..kept {
+                site1.data[i*kept + n] = u[i*k + n]*(if center_left { s[n]/norm } else { 1. });
+            }
+        }
+        for n in 0..kept {
+            for j in 0..2*right {
+                site2.data[n*2*right + j] = vh[n*2*right + j]*(if center_left { 1. } else { s[n]/norm });
+            }
+        }
+
+        return (site1, site2);
+    }
+
+    // Contracts the sites i and i + 1 into a (left*2) x (2*right) matrix
+    fn contract(&self, i: usize) -> Vec<Complex<f64>> {
+        let (a, b) = (&self.sites[i], &self.sites[i + 1]);
+        let mut m: Vec<Complex<f64>> = vec![ZERO; a.left*2*2*b.right];
+        for l in 0..a.left {
+            for s1 in 0..2 {
+                for k in 0..a.right {
+                    let x: Complex<f64> = a.get(l, s1, k);
+                    if x == ZERO {
+                        continue;
+                    }
+                    for s2 in 0..2 {
+                        for r in 0..b.right {
+                            m[(l*2 + s1)*2*b.right + s2*b.right + r] += x*b.get(k, s2, r);
+                        }
+                    }
+                }
+            }
+        }
+        return m;
+    }
+
+    // Moves the orthogonality center to site
+    fn move_center(&mut self, site: usize) {
+        while self.center < site {
+            let i: usize = self.center;
+            let m: Vec<Complex<f64>> = self.contract(i);
+            let (left, right) = (self.sites[i].left, self.sites[i + 1].right);
+            let (a, b) = self.split_exact(&m, left, right, false);
+            self.sites[i] = a;
+            self.sites[i + 1] = b;
+            self.center += 1;
+        }
+        while self.center > site {
+            let i: usize = self.center - 1;
+            let m: Vec<Complex<f64>> = self.contract(i);
+            let (left, right) = (self.sites[i].left, self.sites[i + 1].right);
+            let (a, b) = self.split_exact(&m, left, right, true);
+            self.sites[i] = a;
+            self.sites[i + 1] = b;
+            self.center -= 1;
+        }
+    }
+
+    // As split, without counting the removal of vanishing singular values as truncation
+    fn split_exact(&mut self, m: &Vec<Complex<f64>>, left: usize, right: usize, center_left: bool) -> (Site, Site) {
+        let discarded_weight: f64 = self.discarded_weight;
+        let (max_bond_dim, cutoff) = (self.max_bond_dim, self.cutoff);
+        self.max_bond_dim = usize::MAX;
+        self.cutoff = 0.;
+        let sites = self.split(m, left, right, center_left);
+        self.max_bond_dim = max_bond_dim;
+        self.cutoff = cutoff;
+        self.discarded_weight = discarded_weight;
+        return sites;
+    }
+
+    // Applies the single-qubit gate [g00, g01, g10, g11] to qubit
+    fn single_qubit_gate(&mut self, qubit: usize, gate: &[Complex<f64>; 4]) {
+        assert!(qubit < self.num_qubits);
+        let site: &mut Site = &mut self.sites[qubit];
+        let (left, right) = (site.left, site.right);
+        for l in 0..left {
+            for r in 0..right {
+                let (a0, a1) = (site.data[(l*2)*right + r], site.data[(l*2 + 1)*right + r]);
+                site.data[(l*2)*right + r] = gate[0]*a0 + gate[1]*a1;
+                site.data[(l*2 + 1)*right + r] = gate[2]*a0 + gate[3]*a1;
+            }
+        }
+    }
+
+    // Applies the gate op, a 4 x 4 matrix in row-major order with the first site in the low bit of
+    // its indices, to the neighbouring sites i and i + 1
+    fn neighbour_gate(&mut self, i: usize, op: &[Complex<f64>; 16]) {
+        self.move_center(i);
+        let m: Vec<Complex<f64>> = self.contract(i);
+        let (left, right) = (self.sites[i].left, self.sites[i + 1].right);
+
+        let mut applied: Vec<Complex<f64>> = vec![ZERO; m.len()];
+        for l in 0..left {
+            for r in 0..right {
+                let v: Vec<Complex<f64>> = (0..4).map(|a| m[(l*2 + (a & 1))*2*right + (a >> 1)*right + r]).collect();
+                for a in 0..4 {
+                    applied[(l*2 + (a & 1))*2*right + (a >> 1)*right + r] = (0..4).map(|b| op[a*4 + b]*v[b]).sum();
+                }
+            }
+        }
+
+        let (a, b) = self.split(&applied, left, right, false);
+        self.sites[i] = a;
+        self.sites[i + 1] = b;
+        self.center = i + 1;
+    }
+
+    fn swap(&mut self, i: usize) {
+        let mut op: [Complex<f64>; 16] = [ZERO; 16];
+        (op[0], op[4 + 2], op[2*4 + 1], op[15]) = (ONE, ONE, ONE, ONE);
+        self.neighbour_gate(i, &op);
+    }
+
+    // Applies the gate op, a 4 x 4 matrix in row-major order with qubit1 in the low bit of its
+    // indices, swapping qubit2 next to qubit1 and back if they are not neighbours
+    fn two_qubit_gate(&mut self, qubit1: usize, qubit2: usize, op: &[Complex<f64>; 16]) {
+        assert!(qubit1 < self.num_qubits && qubit2 < self.num_qubits && qubit1 != qubit2);
+        let target: usize = if qubit2 > qubit1 { qubit1 + 1 } else { qubit1 - 1 };
+        let mut position: usize = qubit2;
+        while position != target {
+            if position > target {
+                self.swap(position - 1);
+                position -= 1;
+            } else {
+                self.swap(position);
+                position += 1;
+            }
+        }
+
+        if qubit1 < target {
+            self.neighbour_gate(qubit1, op);
+        } else {
+            // Exchange the roles of the two qubits in the indices of op
+            let flip = |a: usize| -> usize { ((a & 1) << 1) | (a >> 1) };
+            let mut flipped: [Complex<f64>; 16] = [ZERO; 16];
+            for a in 0..4 {
+                for b in 0..4 {
+                    flipped[flip(a)*4 + flip(b)] = op[a*4 + b];
+                }
+            }
+            self.neighbour_gate(target, &flipped);
+        }
+
+        while position != qubit2 {
+            if position < qubit2 {
+                self.swap(position);
+                position += 1;
+            } else {
+                self.swap(position - 1);
+                position -= 1;
+            }
+        }
+    }
+
+    // Schmidt values across the bond between qubits bond and bond + 1, in decreasing order
+    pub fn schmidt_values(&self, bond: usize) -> Vec<f64> {
+        assert!(bond + 1 < self.num_qubits);
+        let mut state: QuantumMPSState = self.clone();
+        state.move_center(bond);
+        let site: &Site = &state.sites[bond];
+        let (_, s, _) = svd(&site.data, 2*site.left, site.right);
+        return s.into_iter().filter(|x| *x > EPS).collect();
+    }
+
+    // Renyi entropy S_n of the qubits left of the cut after qubit bond, or the von Neumann entropy for
+    // n = 1
+    pub fn bond_entropy(&self, bond: usize, n: f64) -> f32 {
+        let probs: Vec<f64> = self.schmidt_values(bond).into_iter().map(|x| x*x).collect();
+        let entropy: f64 = if (n - 1.).abs() < EPS {
+            -probs.iter().map(|p| p*p.log2()).sum::<f64>()
+        } else {
+            probs.iter().map(|p| p.powf(n)).sum::<f64>().log2()/(1. - n)
+        };
+        return entropy as f32;
+    }
+
+    // Purity of the contiguous qubits start..end, contracted with the center at start so that the
+    // rest of the chain reduces to the identity
+    fn interval_purity(&self, start: usize, end: usize) -> f64 {
+        let mut state: QuantumMPSState = self.clone();
+        state.move_center(start);
+
+        // psi[(l, s, r)] for the contracted interval, with the physical index s in the middle
+        let mut psi: Vec<Complex<f64>> = state.sites[start].data.clone();
+        let left: usize = state.sites[start].left;
+        let mut phys: usize = 2;
+        for site in &state.sites[(start + 1)..end] {
+            let mut next: Vec<Complex<f64>> = vec![ZERO; left*phys*2*site.right];
+            for ls in 0..left*phys {
+                for k in 0..site.left {
+                    let x: Complex<f64> = psi[ls*site.left + k];
+                    if x == ZERO {
+                        continue;
+                    }
+                    for s in 0..2 {
+                        for r in 0..site.right {
+                            next[(ls*2 + s)*site.right + r] += x*site.get(k, s, r);
+                        }
+                    }
+                }
+            }
+            psi = next;
+            phys *= 2;
+        }
+        let right: usize = state.sites[end - 1].right;
+
+        // psi as a matrix between the physical index and the bonds; the purity is tr (M M^dagger)^2,
+        // computed from whichever Gram matrix is smaller
+        let bonds: usize = left*right;
+        let entry = |s: usize, b: usize| -> Complex<f64> { psi[((b / right)*phys + s)*right + b % right] };
+        let (dim, inner) = if phys <= bonds { (phys, bonds) } else { (bonds, phys) };
+        let mut purity: f64 = 0.;
+        for i in 0..dim {
+            for j in 0..dim {
+                let g: Complex<f64> = (0..inner).map(|k| if phys <= bonds {
+                    entry(i, k)*entry(j, k).conj()
+                } else {
+                    entry(k, i).conj()*entry(k, j)
+                }).sum();
+                purity += g.norm_sqr();
+            }
+        }
+        return purity;
+    }
+
+    // Contracts the whole chain into a dense state; only practical for small systems
+    pub fn to_dense_state(&self) -> QuantumDenseState {
+        let mut amplitudes: Vec<Complex<f64>> = vec![ONE];
+        for (i, site) in self.sites.iter().enumerate() {
+            let mut next: Vec<Complex<f64>> = vec![ZERO; (amplitudes.len()/site.left)*2*site.right];
+            for (index, a) in amplitudes.iter().enumerate() {
+                if *a == ZERO {
+                    continue;
+                }
+                let (basis, k) = (index / site.left, index % site.left);
+                for s in 0..2 {
+                    for r in 0..site.right {
+                        next[((basis | (s << i))*site.right) + r] += a*site.get(k, s, r);
+                    }
+                }
+            }
+            amplitudes = next;
+        }
+
+        let mut state = QuantumDenseState::new(self.num_qubits);
+        state.amplitudes = amplitudes;
+        return state;
+    }
+
+    // Probability of measuring qubit as 1, with the center at qubit
+    fn prob_one(&self, qubit: usize) -> f64 {
+        let site: &Site = &self.sites[qubit];
+        let mut p: f64 = 0.;
+        for l in 0..site.left {
+            for r in 0..site.right {
+                p += site.get(l, 1, r).norm_sqr();
+            }
+        }
+        return p;
+    }
+
+    // Projects qubit, which must be the center, onto outcome, found with probability prob
+    fn project(&mut self, qubit: usize, outcome: bool, prob: f64) {
+        let site: &mut Site = &mut self.sites[qubit];
+        let norm: f64 = prob.sqrt();
+        for l in 0..site.left {
+            for r in 0..site.right {
+                site.data[(l*2 + !outcome as usize)*site.right + r] = ZERO;
+                site.data[(l*2 + outcome as usize)*site.right + r] /= norm;
+            }
+        }
+    }
+}
+
+impl Entropy for QuantumMPSState {
+    // Renyi-2 entropy of a contiguous set of qubits. Cuts of the chain are read off the Schmidt
+    // values; intervals in the bulk are contracted.
+    fn renyi_entropy(&self, qubits: &Vec<usize>) -> f32 {
+        if qubits.is_empty() {
+            return 0.;
+        }
+        let start: usize = *qubits.iter().min().unwrap();
+        let end: usize = *qubits.iter().max().unwrap() + 1;
+        assert_eq!(end - start, qubits.len(), "entropy is only available for contiguous qubits");
+
+        if start == 0 && end == self.num_qubits {
+            return 0.;
+        } else if start == 0 {
+            return self.bond_entropy(end - 1, 2.);
+        } else if end == self.num_qubits {
+            return self.bond_entropy(start - 1, 2.);
+        }
+        return -self.interval_purity(start, end).log2() as f32;
+    }
+}
+
+impl QuantumState for QuantumMPSState {
+    fn new(num_qubits: usize) -> QuantumMPSState {
+        assert!(num_qubits > 0);
+        let sites: Vec<Site> = (0..num_qubits).map(|_| Site { left: 1, right: 1, data: vec![ONE, ZERO] }).collect();
+        let rng = Lcg64Xsh32::from_entropy();
+        return QuantumMPSState { num_qubits: num_qubits, sites: sites, center: 0,
+                                 max_bond_dim: usize::MAX, cutoff: 1e-14, discarded_weight: 0., rng: rng };
+    }
+
+    fn new_with_seed(num_qubits: usize, seed: u64) -> QuantumMPSState {
+        let mut state = QuantumMPSState::new(num_qubits);
+        state.rng = Lcg64Xsh32::seed_from_u64(seed);
+        return state;
+    }
+
+    fn print(&self) -> String {
+        let bonds: Vec<String> = self.sites[..(self.num_qubits - 1)].iter().map(|site| site.right.to_string()).collect();
+        return format!("MPS on {} qubits with bond dimensions [{}], discarded weight {:e}", self.num_qubits, bonds.join(", "), self.discarded_weight);
+    }
+
+    fn system_size(&self) -> usize {
+        return self.num_qubits;
+    }
+
+    fn x_gate(&mut self, qubit: usize) {
+        self.single_qubit_gate(qubit, &XGATE);
+    }
+
+    fn y_gate(&mut self, qubit: usize) {
+        self.single_qubit_gate(qubit, &YGATE);
+    }
+
+    fn z_gate(&mut self, qubit: usize) {
+        self.single_qubit_gate(qubit, &ZGATE);
+    }
+
+    fn h_gate(&mut self, qubit: usize) {
+        self.single_qubit_gate(qubit, &HGATE);
+    }
+
+    fn s_gate(&mut self, qubit: usize) {
+        self.single_qubit_gate(qubit, &SGATE);
+    }
+
+    fn cx_gate(&mut self, qubit1: usize, qubit2: usize) {
+        let mut op: [Complex<f64>; 16] = [ZERO; 16];
+        (op[0], op[4*2 + 2], op[4*3 + 1], op[4 + 3]) = (ONE, ONE, ONE, ONE);
+        self.two_qubit_gate(qubit1, qubit2, &op);
+    }
+
+    fn cy_gate(&mut self, qubit1: usize, qubit2: usize) {
+        let mut op: [Complex<f64>; 16] = [ZERO; 16];
+        (op[0], op[4*2 + 2], op[4*3 + 1], op[4 + 3]) = (ONE, ONE, I, -I);
+        self.two_qubit_gate(qubit1, qubit2, &op);
+    }
+
+    fn cz_gate(&mut self, qubit1: usize, qubit2: usize) {
+        self.cphase_gate(qubit1, qubit2, std::f64::consts::PI);
+    }
+
+    fn mzr_qubit(&mut self, qubit: usize) -> i32 {
+        assert!(qubit < self.num_qubits);
+        self.move_center(qubit);
+        let prob_one: f64 = self.prob_one(qubit);
+        let measured: bool = self.random_float() >= 1. - prob_one;
+        self.project(qubit, measured, if measured { prob_one } else { 1. - prob_one });
+        return measured as i32;
+    }
+}
+
+impl NonCliffordState for QuantumMPSState {
+    fn unitary_gate(&mut self, qubit: usize, gate: [Complex<f64>; 4]) {
+        self.single_qubit_gate(qubit, &gate);
+    }
+
+    fn cphase_gate(&mut self, qubit1: usize, qubit2: usize, phi: f64) {
+        let mut op: [Complex<f64>; 16] = [ZERO; 16];
+        (op[0], op[5], op[10], op[15]) = (ONE, ONE, ONE, Complex::from_polar(1., phi));
+        self.two_qubit_gate(qubit1, qubit2, &op);
+    }
+}
+
+impl MzrForce for QuantumMPSState {
+    fn mzr_qubit_forced(&mut self, qubit: usize, outcome: bool) -> bool {
+        assert!(qubit < self.num_qubits);
+        self.move_center(qubit);
+        let prob_one: f64 = self.prob_one(qubit);
+        let prob: f64 = if outcome { prob_one } else { 1. - prob_one };
+        if prob < EPS {
+            return false;
+        }
+
+        self.project(qubit, outcome, prob);
+        return true;
+    }
+}
@@ -0,0 +1,256 @@
+use rand::{Rng, RngCore, SeedableRng};
+use rand_pcg::Lcg64Xsh32;
+
+use crate::noise::NoiseChannel;
+use crate::quantum_chp_state::{PauliString, QuantumCHPState};
+use crate::quantum_state::QuantumState;
+
+// Pauli frame simulator for sampling many shots of a Clifford circuit at once. A single reference
+// trajectory is simulated on a tableau, and each shot is described by the Pauli error (frame) which
+// separates it from the reference. Frames are stored bit-packed, 64 shots to a word, so gates cost
+// O(shots/64) and measurements need no Gaussian elimination.
+//
+// Random measurement outcomes are produced by gauge randomization: frames start with random Z
+// errors, which leave |0> invariant, and every measurement or reset randomizes the frame by the
+// measured observable, which leaves the post-measurement state invariant.
+pub struct QuantumPauliFrameState {
+	num_qubits: usize,
+	num_shots: usize,
+	reference: QuantumCHPState,
+
+	// X and Z components of the frames; x[q][w] holds qubit q of shots 64w to 64w + 63
+	x: Vec<Vec<u64>>,
+	z: Vec<Vec<u64>>,
+
+	// Outcome of each measurement in the reference, with the shots in which it is flipped
+	records: Vec<(i32, Vec<u64>)>,
+
+	rng: Lcg64Xsh32,
+}
+
+impl QuantumPauliFrameState {
+	pub fn new_with_shots(num_qubits: usize, num_shots: usize, seed: u64) -> Self {
+		let mut rng = Lcg64Xsh32::seed_from_u64(seed);
+		let reference = QuantumCHPState::new_with_seed(num_qubits, rng.next_u64());
+		let num_words: usize = (num_shots + 63)/64;
+
+		let mut state = QuantumPauliFrameState { num_qubits: num_qubits, num_shots: num_shots, reference: reference,
+												 x: vec![vec![0; num_words]; num_qubits], z: vec![vec![0; num_words]; num_qubits],
+												 records: Vec::new(), rng: rng };
+		for q in 0..num_qubits {
+			state.z[q] = state.random_words();
+		}
+
+		return state;
+	}
+
+	pub fn num_shots(&self) -> usize {
+		return self.num_shots;
+	}
+
+	// Measurement outcomes of every shot, in the order in which the measurements were made
+	pub fn shot_records(&self) -> Vec<Vec<i32>> {
+		return (0..self.num_shots).map(|shot| {
+			self.records.iter().map(|(outcome, flips)| outcome ^ (((flips[shot/64] >> (shot % 64)) & 1) as i32)).collect()
+		}).collect();
+	}
+
+	fn random_words(&mut self) -> Vec<u64> {
+		return (0..self.x[0].len()).map(|_| self.rng.next_u64()).collect();
+	}
+
+	fn record(&mut self, outcome: i32, flips: Vec<u64>) {
+		self.records.push((outcome, flips));
+	}
+
+	// Multiplies the frames of the shots set in mask by the Pauli with components x and z on qubit
+	fn apply_frame(&mut self, qubit: usize, x: bool, z: bool, mask: &Vec<u64>) {
+		for w in 0..mask.len() {
+			if x { self.x[qubit][w] ^= mask[w]; }
+			if z { self.z[qubit][w] ^= mask[w]; }
+		}
+	}
+
+	fn swap_xz(&mut self, qubit: usize) {
+		std::mem::swap(&mut self.x[qubit], &mut self.z[qubit]);
+	}
+}
+
+impl QuantumState for QuantumPauliFrameState {
+	fn new(num_qubits: usize) -> Self {
+		return QuantumPauliFrameState::new_with_shots(num_qubits, 1, rand::thread_rng().gen::<u64>());
+	}
+
+	fn new_with_seed(num_qubits: usize, seed: u64) -> Self {
+		return QuantumPauliFrameState::new_with_shots(num_qubits, 1, seed);
+	}
+
+	fn print(&self) -> String {
+		return format!("Reference: {}\nShots: {}, measurements: {}", self.reference.print(), self.num_shots, self.records.len());
+	}
+
+	fn system_size(&self) -> usize {
+		return self.num_qubits;
+	}
+
+	// Paulis change only the reference; frames ignore signs
+	fn x_gate(&mut self, qubit: usize) {
+		self.reference.x_gate(qubit);
+	}
+	fn y_gate(&mut self, qubit: usize) {
+		self.reference.y_gate(qubit);
+	}
+	fn z_gate(&mut self, qubit: usize) {
+		self.reference.z_gate(qubit);
+	}
+
+	fn h_gate(&mut self, qubit: usize) {
+		self.reference.h_gate(qubit);
+		self.swap_xz(qubit);
+	}
+
+	fn s_gate(&mut self, qubit: usize) {
+		self.reference.s_gate(qubit);
+		for w in 0..self.z[qubit].len() {
+			self.z[qubit][w] ^= self.x[qubit][w];
+		}
+	}
+	fn sd_gate(&mut self, qubit: usize) {
+		self.reference.sd_gate(qubit);
+		for w in 0..self.z[qubit].len() {
+			self.z[qubit][w] ^= self.x[qubit][w];
+		}
+	}
+
+	fn sqrtx_gate(&mut self, qubit: usize) {
+		self.reference.sqrtx_gate(qubit);
+		for w in 0..self.x[qubit].len() {
+			self.x[qubit][w] ^= self.z[qubit][w];
+		}
+	}
+	fn sqrtxd_gate(&mut self, qubit: usize) {
+		self.reference.sqrtxd_gate(qubit);
+		for w in 0..self.x[qubit].len() {
+			self.x[qubit][w] ^= self.z[qubit][w];
+		}
+	}
+	fn sqrty_gate(&mut self, qubit: usize) {
+		self.reference.sqrty_gate(qubit);
+		self.swap_xz(qubit);
+	}
+	fn sqrtyd_gate(&mut self, qubit: usize) {
+		self.reference.sqrtyd_gate(qubit);
+		self.swap_xz(qubit);
+	}
+
+	fn cx_gate(&mut self, qubit1: usize, qubit2: usize) {
+		self.reference.cx_gate(qubit1, qubit2);
+		for w in 0..self.x[qubit1].len() {
+			self.x[qubit2][w] ^= self.x[qubit1][w];
+			self.z[qubit1][w] ^= self.z[qubit2][w];
+		}
+	}
+	fn cy_gate(&mut self, qubit1: usize, qubit2: usize) {
+		self.reference.cy_gate(qubit1, qubit2);
+		for w in 0..self.x[qubit1].len() {
+			self.z[qubit1][w] ^= self.x[qubit2][w] ^ self.z[qubit2][w];
+			self.x[qubit2][w] ^= self.x[qubit1][w];
+			self.z[qubit2][w] ^= self.x[qubit1][w];
+		}
+	}
+	fn cz_gate(&mut self, qubit1: usize, qubit2: usize) {
+		self.reference.cz_gate(qubit1, qubit2);
+		for w in 0..self.x[qubit1].len() {
+			self.z[qubit1][w] ^= self.x[qubit2][w];
+			self.z[qubit2][w] ^= self.x[qubit1][w];
+		}
+	}
+
+	fn mxr_qubit(&mut self, qubit: usize) -> i32 {
+		let outcome = self.reference.mxr_qubit(qubit);
+		self.record(outcome, self.z[qubit].clone());
+		let r = self.random_words();
+		self.apply_frame(qubit, true, false, &r);
+		return outcome;
+	}
+	fn myr_qubit(&mut self, qubit: usize) -> i32 {
+		let outcome = self.reference.myr_qubit(qubit);
+		let flips: Vec<u64> = self.x[qubit].iter().zip(&self.z[qubit]).map(|(x, z)| x ^ z).collect();
+		self.record(outcome, flips);
+		let r = self.random_words();
+		self.apply_frame(qubit, true, true, &r);
+		return outcome;
+	}
+	fn mzr_qubit(&mut self, qubit: usize) -> i32 {
+		let outcome = self.reference.mzr_qubit(qubit);
+		self.record(outcome, self.x[qubit].clone());
+		let r = self.random_words();
+		self.apply_frame(qubit, false, true, &r);
+		return outcome;
+	}
+
+	fn measure_pauli(&mut self, pauli: &PauliString) -> i32 {
+		let outcome = self.reference.measure_pauli(pauli);
+
+		// A frame flips the outcome when it anticommutes with pauli
+		let mut flips: Vec<u64> = vec![0; self.x[0].len()];
+		for q in (0..self.num_qubits).filter(|&q| pauli.x(q) || pauli.z(q)) {
+			for w in 0..flips.len() {
+				if pauli.x(q) { flips[w] ^= self.z[q][w]; }
+				if pauli.z(q) { flips[w] ^= self.x[q][w]; }
+			}
+		}
+		self.record(outcome, flips);
+
+		let r = self.random_words();
+		for q in 0..self.num_qubits {
+			self.apply_frame(q, pauli.x(q), pauli.z(q), &r);
+		}
+		return outcome;
+	}
+
+	fn reset_qubit(&mut self, qubit: usize) {
+		self.reference.reset_qubit(qubit);
+		self.x[qubit] = vec![0; self.x[qubit].len()];
+		self.z[qubit] = self.random_words();
+	}
+	fn resetx_qubit(&mut self, qubit: usize) {
+		self.reference.resetx_qubit(qubit);
+		self.x[qubit] = self.random_words();
+		self.z[qubit] = vec![0; self.z[qubit].len()];
+	}
+	fn resety_qubit(&mut self, qubit: usize) {
+		self.reference.resety_qubit(qubit);
+		self.x[qubit] = self.random_words();
+		self.z[qubit] = self.x[qubit].clone();
+	}
+	fn mr_qubit(&mut self, qubit: usize) -> i32 {
+		let outcome = self.reference.mr_qubit(qubit);
+		self.record(outcome, self.x[qubit].clone());
+		self.x[qubit] = vec![0; self.x[qubit].len()];
+		self.z[qubit] = self.random_words();
+		return outcome;
+	}
+
+	// Errors are sampled independently for every shot and only enter the frames
+	fn apply_noise(&mut self, channel: &NoiseChannel, qubits: &[usize], rng: &mut Lcg64Xsh32) {
+		let num_words: usize = self.x[0].len();
+		let mut masks: Vec<(Vec<u64>, Vec<u64>)> = vec![(vec![0; num_words], vec![0; num_words]); qubits.len()];
+
+		for shot in 0..self.num_shots {
+			if let Some(pauli) = channel.sample(rng) {
+				for (i, mask) in masks.iter_mut().enumerate() {
+					// Pauli i of the error is X, Y or Z for 1, 2 or 3
+					let p: usize = (pauli >> 2*i) & 3;
+					if p == 1 || p == 2 { mask.0[shot/64] ^= 1 << (shot % 64); }
+					if p == 2 || p == 3 { mask.1[shot/64] ^= 1 << (shot % 64); }
+				}
+			}
+		}
+
+		for (i, (x, z)) in masks.iter().enumerate() {
+			self.apply_frame(qubits[i], true, false, x);
+			self.apply_frame(qubits[i], false, true, z);
+		}
+	}
+}
@@ -0,0 +1,497 @@
+use bit_vec::BitVec;
+use indexmap::IndexMap;
+use num::complex::Complex;
+use rand_pcg::Lcg64Xsh32;
+use rand::{RngCore, SeedableRng};
+use serde::{Serialize, Deserialize};
+
+use crate::quantum_state::{Entropy, QuantumState, MzrForce, NonCliffordState};
+use crate::quantum_chp_state::{PauliString, Tableau};
+use crate::quantum_dense_state::QuantumDenseState;
+
+// Clifford+T simulator storing the state as a weighted sum of stabilizer states. The tableau of a
+// single stabilizer state |phi> is kept as in QuantumCHPState, and each branch a is the stabilizer
+// state D^a |phi> = d_1^a_1 ... d_n^a_n |phi> obtained by applying destabilizers of the tableau, so
+// that the state is sum_a c_a D^a |phi>. The branches are orthonormal, and Clifford gates only
+// update the tableau, since conjugating the destabilizers leaves the coefficients unchanged. A
+// non-Clifford gate is expanded into Paulis, each of which maps every branch onto a single branch;
+// a T gate therefore splits each branch into two.
+
+const ZERO: Complex<f64> = Complex::new(0., 0.);
+const ONE: Complex<f64> = Complex::new(1., 0.);
+const I: Complex<f64> = Complex::new(0., 1.);
+const EPS: f64 = 1e-12;
+
+const DEFAULT_MAX_BRANCHES: usize = 1 << 16;
+
+// Power of i picked up by multiplying the single-qubit Paulis (x1, z1) and (x2, z2), where (1, 1)
+// is Y, as in the tableau
+fn g(x1: bool, z1: bool, x2: bool, z2: bool) -> i32 {
+    return match (x1, z1) {
+        (false, false) => 0,
+        (true, true) => z2 as i32 - x2 as i32,
+        (true, false) => (z2 as i32)*(2*(x2 as i32) - 1),
+        (false, true) => (x2 as i32)*(1 - 2*(z2 as i32)),
+    };
+}
+
+fn parity(a: &BitVec, b: &BitVec) -> bool {
+    return a.blocks().zip(b.blocks()).map(|(x, y)| (x & y).count_ones()).sum::<u32>() % 2 == 1;
+}
+
+fn xor(a: &BitVec, b: &BitVec) -> BitVec {
+    let mut c: BitVec = a.clone();
+    c.xor(b);
+    return c;
+}
+
+fn i_pow(k: i32) -> Complex<f64> {
+    return match k.rem_euclid(4) {
+        0 => ONE,
+        1 => I,
+        2 => -ONE,
+        _ => -I,
+    };
+}
+
+// Pauli operator with a phase i^phase
+#[derive(Clone, Debug)]
+struct Pauli {
+    x: BitVec,
+    z: BitVec,
+    phase: i32,
+}
+
+impl Pauli {
+    fn identity(num_qubits: usize) -> Self {
+        return Pauli { x: BitVec::from_elem(num_qubits, false), z: BitVec::from_elem(num_qubits, false), phase: 0 };
+    }
+
+    fn single(num_qubits: usize, qubit: usize, op: &str) -> Self {
+        let mut p = Pauli::identity(num_qubits);
+        p.x.set(qubit, op == "X" || op == "Y");
+        p.z.set(qubit, op == "Z" || op == "Y");
+        return p;
+    }
+
+    fn from_row(row: &PauliString) -> Self {
+        let mut p = Pauli::identity(row.num_qubits);
+        for i in 0..row.num_qubits {
+            p.x.set(i, row.x(i));
+            p.z.set(i, row.z(i));
+        }
+        p.phase = 2*(row.r() as i32);
+        return p;
+    }
+
+    fn mul(&self, other: &Pauli) -> Pauli {
+        let mut phase: i32 = self.phase + other.phase;
+        for i in 0..self.x.len() {
+            phase += g(self.x[i], self.z[i], other.x[i], other.z[i]);
+        }
+        return Pauli { x: xor(&self.x, &other.x), z: xor(&self.z, &other.z), phase: phase.rem_euclid(4) };
+    }
+
+    fn anticommutes(&self, other: &Pauli) -> bool {
+        return parity(&self.x, &other.z) != parity(&self.z, &other.x);
+    }
+}
+
+// Destabilizers and stabilizers of the tableau, which define the branches
+struct Frame {
+    destabilizers: Vec<Pauli>,
+    stabilizers: Vec<Pauli>,
+}
+
+impl Frame {
+    fn new(tableau: &Tableau, num_qubits: usize) -> Self {
+        return Frame {
+            destabilizers: (0..num_qubits).map(|i| Pauli::from_row(&tableau.row(i))).collect(),
+            stabilizers: (0..num_qubits).map(|i| Pauli::from_row(&tableau.row(i + num_qubits))).collect(),
+        };
+    }
+
+    // Writes p as omega D^x G^z, in which case p D^a |phi> = omega (-1)^(z.a) D^(a + x) |phi>, and
+    // returns (omega, x, z)
+    fn decompose(&self, p: &Pauli) -> (Complex<f64>, BitVec, BitVec) {
+        let num_qubits: usize = self.destabilizers.len();
+        let x: BitVec = self.stabilizers.iter().map(|s| p.anticommutes(s)).collect();
+        let z: BitVec = self.destabilizers.iter().map(|d| p.anticommutes(d)).collect();
+
+        let mut product = Pauli::identity(num_qubits);
+        for i in 0..num_qubits {
+            if x[i] {
+                product = product.mul(&self.destabilizers[i]);
+            }
+        }
+        for i in 0..num_qubits {
+            if z[i] {
+                product = product.mul(&self.stabilizers[i]);
+            }
+        }
+        assert!(product.x == p.x && product.z == p.z);
+
+        return (i_pow(p.phase - product.phase), x, z);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuantumStabilizerRankState {
+    pub num_qubits: usize,
+    tableau: Tableau,
+    // Coefficient of each branch, labelled by the destabilizers applied to the tableau state
+    branches: Vec<(BitVec, Complex<f64>)>,
+
+    // Largest number of branches kept; the smallest are discarded beyond it
+    max_branches: usize,
+    // Total weight of the branches discarded
+    discarded_weight: f64,
+
+    rng: Lcg64Xsh32,
+}
+
+impl QuantumStabilizerRankState {
+    pub fn set_max_branches(&mut self, max_branches: usize) {
+        assert!(max_branches > 0);
+        self.max_branches = max_branches;
+    }
+
+    pub fn num_branches(&self) -> usize {
+        return self.branches.len();
+    }
+
+    // Total weight of the branches discarded by the cap on their number
+    pub fn discarded_weight(&self) -> f64 {
+        return self.discarded_weight;
+    }
+
+    fn random_float(&mut self) -> f64 {
+        return (self.rng.next_u32() as f64) / (u32::MAX as f64);
+    }
+
+    fn frame(&self) -> Frame {
+        return Frame::new(&self.tableau, self.num_qubits);
+    }
+
+    // Replaces the branches by the sum of contributions, dropping vanishing branches and the smallest
+    // ones beyond max_branches before renormalizing
+    fn set_branches<T: Iterator<Item = (BitVec, Complex<f64>)>>(&mut self, contributions: T) {
+        let mut merged: IndexMap<BitVec, Complex<f64>> = IndexMap::new();
+        for (a, c) in contributions {
+            *merged.entry(a).or_insert(ZERO) += c;
+        }
+
+        let mut branches: Vec<(BitVec, Complex<f64>)> = merged.into_iter().filter(|(_, c)| c.norm() > EPS).collect();
+        let norm: f64 = branches.iter().map(|(_, c)| c.norm_sqr()).sum();
+        if branches.len() > self.max_branches {
+            branches.sort_by(|(_, c1), (_, c2)| c2.norm_sqr().partial_cmp(&c1.norm_sqr()).unwrap());
+            self.discarded_weight += branches[self.max_branches..].iter().map(|(_, c)| c.norm_sqr()).sum::<f64>()/norm;
+            branches.truncate(self.max_branches);
+        }
+
+        let norm: f64 = branches.iter().map(|(_, c)| c.norm_sqr()).sum::<f64>().sqrt();
+        self.branches = branches.into_iter().map(|(a, c)| (a, c/norm)).collect();
+    }
+
+    // Applies the operator sum_k c_k P_k
+    fn apply_pauli_sum(&mut self, terms: Vec<(Complex<f64>, Pauli)>) {
+        let frame: Frame = self.frame();
+        let terms: Vec<(Complex<f64>, BitVec, BitVec)> = terms.into_iter()
+            .filter(|(c, _)| c.norm() > EPS)
+            .map(|(c, p)| {
+                let (omega, x, z) = frame.decompose(&p);
+                (c*omega, x, z)
+            }).collect();
+
+        let branches: Vec<(BitVec, Complex<f64>)> = std::mem::take(&mut self.branches);
+        let contributions = branches.into_iter().flat_map(|(a, c)| {
+            terms.iter().map(move |(t, x, z)| {
+                let sign: f64 = if parity(z, &a) { -1. } else { 1. };
+                (xor(&a, x), t*c*sign)
+            }).collect::<Vec<_>>()
+        });
+        self.set_branches(contributions);
+    }
+
+    // Probability of measuring qubit as 1
+    fn prob_one(&self, qubit: usize) -> f64 {
+        assert!(qubit < self.num_qubits);
+        let (omega, x, z) = self.frame().decompose(&Pauli::single(self.num_qubits, qubit, "Z"));
+        let coefficients: IndexMap<&BitVec, Complex<f64>> = self.branches.iter().map(|(a, c)| (a, *c)).collect();
+
+        // <Z> = sum_a conj(c_(a + x)) omega (-1)^(z.a) c_a
+        let expectation: f64 = self.branches.iter().map(|(a, c)| {
+            let sign: f64 = if parity(&z, a) { -1. } else { 1. };
+            match coefficients.get(&xor(a, &x)) {
+                Some(c2) => (c2.conj()*omega*c*sign).re,
+                None => 0.,
+            }
+        }).sum();
+
+        return ((1. - expectation)/2.).clamp(0., 1.);
+    }
+
+    // Projects qubit onto outcome, which is found with probability prob
+    fn project(&mut self, qubit: usize, outcome: bool, prob: f64) {
+        let frame: Frame = self.frame();
+        let (omega, x, z) = frame.decompose(&Pauli::single(self.num_qubits, qubit, "Z"));
+        let branches: Vec<(BitVec, Complex<f64>)> = std::mem::take(&mut self.branches);
+
+        let (random, p): (bool, usize) = self.tableau.mzr_deterministic(qubit);
+        if !random {
+            // Z on qubit has the eigenvalue omega (-1)^(z.a) on each branch
+            let contributions = branches.into_iter().filter(|(a, _)| ((omega.re < 0.) != parity(&z, a)) == outcome);
+            self.set_branches(contributions);
+            return;
+        }
+
+        // With P_m the projector onto outcome m and g_p the stabilizer anticommuting with Z,
+        // P_m D^a |phi> = D^a P_(m + s_a) |phi> = D^a g_p^(m + s_a) |phi'> / sqrt(2), where
+        // s_a = z.a and |phi'> = sqrt(2) P_0 |phi> is the tableau state measured as 0. In the
+        // frame of |phi'>, g_p is the destabilizer d'_p, and the old destabilizers are decomposed.
+        let p: usize = p - self.num_qubits;
+        self.tableau.mzr_qubit(qubit, false);
+        let new_frame: Frame = self.frame();
+
+        let mut decompositions: IndexMap<usize, (Complex<f64>, BitVec, BitVec)> = IndexMap::new();
+        let norm: f64 = (2.*prob).sqrt();
+        let mut contributions: Vec<(BitVec, Complex<f64>)> = Vec::new();
+        for (a, c) in branches {
+            let mut key: BitVec = BitVec::from_elem(self.num_qubits, false);
+            key.set(p, outcome != parity(&z, &a));
+            let mut coefficient: Complex<f64> = c/norm;
+            for i in (0..self.num_qubits).filter(|&i| a[i]) {
+                let (omega_i, x_i, z_i) = decompositions.entry(i).or_insert_with(|| new_frame.decompose(&frame.destabilizers[i]));
+                if parity(z_i, &key) {
+                    coefficient = -coefficient;
+                }
+                coefficient *= *omega_i;
+                key.xor(x_i);
+            }
+            contributions.push((key, coefficient));
+        }
+        self.set_branches(contributions.into_iter());
+    }
+
+    // Applies p to the dense vector v, indexed so that qubit i is bit i of the index
+    fn apply_pauli_dense(p: &Pauli, v: &Vec<Complex<f64>>) -> Vec<Complex<f64>> {
+        let x: usize = (0..p.x.len()).filter(|&i| p.x[i]).map(|i| 1 << i).sum();
+        let z: usize = (0..p.z.len()).filter(|&i| p.z[i]).map(|i| 1 << i).sum();
+        // Y = i X Z on each qubit
+        let phase: Complex<f64> = i_pow(p.phase + (x & z).count_ones() as i32);
+
+        let mut w: Vec<Complex<f64>> = vec![ZERO; v.len()];
+        for (i, a) in v.iter().enumerate() {
+            let sign: f64 = if (i & z).count_ones() % 2 == 1 { -1. } else { 1. };
+            w[i ^ x] = phase*a*sign;
+        }
+        return w;
+    }
+
+    // Expands the state into a dense state; only practical for small systems
+    pub fn to_dense_state(&self) -> QuantumDenseState {
+        let frame: Frame = self.frame();
+        let dim: usize = 1 << self.num_qubits;
+
+        // |phi> is the projection of any basis state it overlaps with onto the stabilizer group
+        let mut phi: Vec<Complex<f64>> = Vec::new();
+        for basis in 0..dim {
+            phi = vec![ZERO; dim];
+            phi[basis] = ONE;
+            for s in &frame.stabilizers {
+                let sv: Vec<Complex<f64>> = Self::apply_pauli_dense(s, &phi);
+                phi = phi.iter().zip(sv.iter()).map(|(a, b)| (a + b)/2.).collect();
+            }
+            if phi.iter().map(|a| a.norm_sqr()).sum::<f64>() > EPS {
+                break;
+            }
+        }
+        let norm: f64 = phi.iter().map(|a| a.norm_sqr()).sum::<f64>().sqrt();
+
+        let mut amplitudes: Vec<Complex<f64>> = vec![ZERO; dim];
+        for (a, c) in &self.branches {
+            let mut v: Vec<Complex<f64>> = phi.iter().map(|x| x*c/norm).collect();
+            for i in (0..self.num_qubits).filter(|&i| a[i]) {
+                v = Self::apply_pauli_dense(&frame.destabilizers[i], &v);
+            }
+            amplitudes.iter_mut().zip(v.iter()).for_each(|(x, y)| *x += y);
+        }
+
+        let mut state = QuantumDenseState::new(self.num_qubits);
+        state.amplitudes = amplitudes;
+        return state;
+    }
+}
+
+impl QuantumState for QuantumStabilizerRankState {
+    fn new(num_qubits: usize) -> QuantumStabilizerRankState {
+        let rng = Lcg64Xsh32::from_entropy();
+        return QuantumStabilizerRankState { num_qubits: num_qubits, tableau: Tableau::new(num_qubits),
+                                            branches: vec![(BitVec::from_elem(num_qubits, false), ONE)],
+                                            max_branches: DEFAULT_MAX_BRANCHES, discarded_weight: 0., rng: rng };
+    }
+
+    fn new_with_seed(num_qubits: usize, seed: u64) -> QuantumStabilizerRankState {
+        let mut state = QuantumStabilizerRankState::new(num_qubits);
+        state.rng = Lcg64Xsh32::seed_from_u64(seed);
+        return state;
+    }
+
+    fn print(&self) -> String {
+        let mut s: String = format!("Tableau: \n{}\nBranches:", self.tableau.print());
+        for (a, c) in &self.branches {
+            let bits: String = a.iter().map(|b| if b { '1' } else { '0' }).collect();
+            s += &format!("\n{}: {:.2}", bits, c);
+        }
+        return s;
+    }
+
+    fn system_size(&self) -> usize {
+        return self.num_qubits;
+    }
+
+    fn x_gate(&mut self, qubit: usize) {
+        self.tableau.x_gate(qubit);
+    }
+
+    fn y_gate(&mut self, qubit: usize) {
+        self.tableau.y_gate(qubit);
+    }
+
+    fn z_gate(&mut self, qubit: usize) {
+        self.tableau.z_gate(qubit);
+    }
+
+    fn h_gate(&mut self, qubit: usize) {
+        self.tableau.h_gate(qubit);
+    }
+
+    fn s_gate(&mut self, qubit: usize) {
+        self.tableau.s_gate(qubit);
+    }
+
+    fn cx_gate(&mut self, qubit1: usize, qubit2: usize) {
+        self.tableau.cx_gate(qubit1, qubit2);
+    }
+
+    fn cz_gate(&mut self, qubit1: usize, qubit2: usize) {
+        self.tableau.h_gate(qubit2);
+        self.tableau.cx_gate(qubit1, qubit2);
+        self.tableau.h_gate(qubit2);
+    }
+
+    fn random_clifford(&mut self, qubits: Vec<usize>) {
+        self.tableau.random_clifford(qubits, &mut self.rng);
+    }
+
+    fn mzr_qubit(&mut self, qubit: usize) -> i32 {
+        let prob_one: f64 = self.prob_one(qubit);
+        let measured: bool = self.random_float() >= 1. - prob_one;
+        self.project(qubit, measured, if measured { prob_one } else { 1. - prob_one });
+        return measured as i32;
+    }
+}
+
+impl NonCliffordState for QuantumStabilizerRankState {
+    // The gate is expanded as a I + b X + c Y + d Z
+    fn unitary_gate(&mut self, qubit: usize, gate: [Complex<f64>; 4]) {
+        assert!(qubit < self.num_qubits);
+        let n: usize = self.num_qubits;
+        self.apply_pauli_sum(vec![
+            ((gate[0] + gate[3])/2., Pauli::identity(n)),
+            ((gate[1] + gate[2])/2., Pauli::single(n, qubit, "X")),
+            (I*(gate[1] - gate[2])/2., Pauli::single(n, qubit, "Y")),
+            ((gate[0] - gate[3])/2., Pauli::single(n, qubit, "Z")),
+        ]);
+    }
+
+    // diag(1, 1, 1, e^(i phi)) = ((3 + e^(i phi)) II + (1 - e^(i phi)) (ZI + IZ - ZZ))/4
+    fn cphase_gate(&mut self, qubit1: usize, qubit2: usize, phi: f64) {
+        assert!(qubit1 < self.num_qubits && qubit2 < self.num_qubits && qubit1 != qubit2);
+        let n: usize = self.num_qubits;
+        let phase: Complex<f64> = Complex::from_polar(1., phi);
+        let z1: Pauli = Pauli::single(n, qubit1, "Z");
+        let z2: Pauli = Pauli::single(n, qubit2, "Z");
+        let zz: Pauli = z1.mul(&z2);
+        self.apply_pauli_sum(vec![
+            ((3. + phase)/4., Pauli::identity(n)),
+            ((1. - phase)/4., z1),
+            ((1. - phase)/4., z2),
+            ((phase - 1.)/4., zz),
+        ]);
+    }
+}
+
+impl Entropy for QuantumStabilizerRankState {
+    // Renyi-2 entropy from the purity 2^-|A| sum_P |<P>|^2 over the Paulis P on the qubits A. With
+    // P = omega D^x G^z, <P> = omega sum_a conj(c_(a + x)) (-1)^(z.a) c_a. The (x, z) of the Paulis
+    // on A form a subspace, in which each x comes with a coset z0 + W of z's, where W holds the z
+    // of the Paulis with x = 0. Summing over the coset leaves
+    // |W| sum_K |sum_(a in K) conj(c_(a + x)) (-1)^(z0.a) c_a|^2, over the cosets K of the
+    // orthogonal complement of W, so only pairs of branches joined by such an x contribute.
+    fn renyi_entropy(&self, qubits: &Vec<usize>) -> f32 {
+        let frame: Frame = self.frame();
+
+        // Row reduce the (x, z) of X and Z on each qubit of A by their x parts. Each row with a
+        // pivot is zero at the pivots before it, so reducing against the rows in order is exact.
+        let mut pivots: Vec<(usize, BitVec, BitVec)> = Vec::new();
+        let mut w: Vec<BitVec> = Vec::new();
+        for &q in qubits {
+            for op in ["X", "Z"] {
+                let (_, mut x, mut z) = frame.decompose(&Pauli::single(self.num_qubits, q, op));
+                for (p, px, pz) in &pivots {
+                    if x[*p] {
+                        x.xor(px);
+                        z.xor(pz);
+                    }
+                }
+                match x.iter().position(|b| b) {
+                    Some(p) => pivots.push((p, x, z)),
+                    None => w.push(z),
+                }
+            }
+        }
+
+        // Reducing a branch leaves a representative of its coset of the x's, which pairs must
+        // share, and the z0 picked up, which is linear in the branch. The parities with W label
+        // the cosets of its complement.
+        let mut classes: IndexMap<BitVec, Vec<(&BitVec, Complex<f64>, BitVec, BitVec)>> = IndexMap::new();
+        for (a, c) in &self.branches {
+            let (mut r, mut z0): (BitVec, BitVec) = (a.clone(), BitVec::from_elem(self.num_qubits, false));
+            for (p, px, pz) in &pivots {
+                if r[*p] {
+                    r.xor(px);
+                    z0.xor(pz);
+                }
+            }
+            let syndrome: BitVec = w.iter().map(|wi| parity(wi, a)).collect();
+            classes.entry(r).or_insert_with(Vec::new).push((a, *c, z0, syndrome));
+        }
+
+        let mut sums: IndexMap<(BitVec, BitVec), Complex<f64>> = IndexMap::new();
+        for branches in classes.values() {
+            for (a, c_a, z_a, syndrome) in branches {
+                for (b, c_b, z_b, _) in branches {
+                    let sign: f64 = if parity(&xor(z_a, z_b), a) { -1. } else { 1. };
+                    *sums.entry((xor(a, b), syndrome.clone())).or_insert(ZERO) += c_b.conj()*c_a*sign;
+                }
+            }
+        }
+
+        let purity: f64 = 2f64.powi(w.len() as i32 - qubits.len() as i32)*sums.values().map(|s| s.norm_sqr()).sum::<f64>();
+        return -purity.log2() as f32;
+    }
+}
+
+impl MzrForce for QuantumStabilizerRankState {
+    fn mzr_qubit_forced(&mut self, qubit: usize, outcome: bool) -> bool {
+        let prob_one: f64 = self.prob_one(qubit);
+        let prob: f64 = if outcome { prob_one } else { 1. - prob_one };
+        if prob < EPS {
+            return false;
+        }
+
+        self.project(qubit, outcome, prob);
+        return true;
+    }
+}
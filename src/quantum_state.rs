@@ -1,7 +1,20 @@
 use std::collections::HashMap;
+use num::complex::Complex;
+use rand_pcg::Lcg64Xsh32;
+use rand::{RngCore, SeedableRng};
+use rayon::prelude::*;
 
-#[derive(Clone, Copy)]
-enum Gate {
+use crate::quantum_chp_state::PauliString;
+use crate::quantum_pauli_frame_state::QuantumPauliFrameState;
+use crate::noise::NoiseChannel;
+use crate::openqasm;
+use crate::stim;
+use crate::optimizer;
+use crate::render;
+use crate::analysis::{self, CircuitAnalysis, LightCone};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gate {
     I,
     X,
     Y,
@@ -21,15 +34,100 @@ enum Gate {
     SQRTYd,
     SQRTZ,
     SQRTZd,
+    R,
+    RX,
+    RY,
+    MR,
+    MPP,
+    Noise(NoiseChannel),
+
+    // Gates outside of the Clifford group, which only programs built for a NonCliffordState can
+    // execute. Angles are in radians.
+    T,
+    Td,
+    Rx(f64),
+    Ry(f64),
+    Rz(f64),
+    U3(f64, f64, f64),
+    // Controlled phase, multiplying the state where both qubits are 1 by exp(i phi)
+    CP(f64),
     
     PRINT,
 }
 
+impl Gate {
+    // Name of the gate in the native circuit format
+    pub fn name(&self) -> &'static str {
+        match self {
+            Gate::I => "id",
+            Gate::X => "x",
+            Gate::Y => "y",
+            Gate::Z => "z",
+            Gate::H => "h",
+            Gate::CX => "cx",
+            Gate::CY => "cy",
+            Gate::CZ => "cz",
+            Gate::MXR => "mxr",
+            Gate::MYR => "myr",
+            Gate::MZR => "mzr",
+            Gate::S => "s",
+            Gate::Sd => "sd",
+            Gate::SQRTX => "sqrtx",
+            Gate::SQRTXd => "sqrtxd",
+            Gate::SQRTY => "sqrty",
+            Gate::SQRTYd => "sqrtyd",
+            Gate::SQRTZ => "sqrtz",
+            Gate::SQRTZd => "sqrtzd",
+            Gate::R => "reset",
+            Gate::RX => "resetx",
+            Gate::RY => "resety",
+            Gate::MR => "mr",
+            Gate::MPP => "mpp",
+            Gate::Noise(channel) => channel.name(),
+            Gate::T => "t",
+            Gate::Td => "td",
+            Gate::Rx(_) => "rx",
+            Gate::Ry(_) => "ry",
+            Gate::Rz(_) => "rz",
+            Gate::U3(_, _, _) => "u3",
+            Gate::CP(_) => "cp",
+            Gate::PRINT => "print",
+        }
+    }
+
+    pub fn is_non_clifford(&self) -> bool {
+        return matches!(self, Gate::T | Gate::Td | Gate::Rx(_) | Gate::Ry(_) | Gate::Rz(_) | Gate::U3(_, _, _) | Gate::CP(_));
+    }
+
+    // Angles of a parameterized gate, in the order in which they are written
+    pub fn params(&self) -> Vec<f64> {
+        return match *self {
+            Gate::Rx(theta) | Gate::Ry(theta) | Gate::Rz(theta) | Gate::CP(theta) => vec![theta],
+            Gate::U3(theta, phi, lambda) => vec![theta, phi, lambda],
+            _ => Vec::new(),
+        };
+    }
+
+    // Gate of the same kind with the given angles
+    pub(crate) fn with_params(&self, params: &[f64]) -> Gate {
+        return match self {
+            Gate::Rx(_) => Gate::Rx(params[0]),
+            Gate::Ry(_) => Gate::Ry(params[0]),
+            Gate::Rz(_) => Gate::Rz(params[0]),
+            Gate::CP(_) => Gate::CP(params[0]),
+            Gate::U3(_, _, _) => Gate::U3(params[0], params[1], params[2]),
+            gate => *gate,
+        };
+    }
+}
+
 
 pub trait QuantumState {
     // At minimum, a QuantumState must implement the S-gate, the H-gate, 
     // the CZ-gate, and measurements in the computational basis
     fn new(num_qubits: usize) -> Self;
+    // As new, but with measurement outcomes drawn from a generator seeded with seed
+    fn new_with_seed(num_qubits: usize, seed: u64) -> Self;
     
     fn print(&self) -> String;
 
@@ -94,11 +192,12 @@ pub trait QuantumState {
         self.h_gate(qubit2);
     }
     fn cy_gate(&mut self, qubit1: usize, qubit2: usize) {
-        self.s_gate(qubit2);
+        // Y = S X Sd
+        self.sd_gate(qubit2);
         self.h_gate(qubit2);
         self.cz_gate(qubit1, qubit2);
         self.h_gate(qubit2);
-        self.sd_gate(qubit2);
+        self.s_gate(qubit2);
     }
     fn cz_gate(&mut self, qubit1: usize, qubit2: usize);
 
@@ -109,15 +208,88 @@ pub trait QuantumState {
         return measured;
     }
     fn myr_qubit(&mut self, qubit: usize) -> i32 {
-        self.s_gate(qubit);
+        // Rotates |+i> to |0>, so that the outcome is 0 in the +1 eigenstate of Y
+        self.sd_gate(qubit);
         self.h_gate(qubit);
         let measured = self.mzr_qubit(qubit);
         self.h_gate(qubit);
-        self.sd_gate(qubit);
+        self.s_gate(qubit);
         return measured;
     }
     fn mzr_qubit(&mut self, qubit: usize) -> i32;
 
+    // Measures the product of Paulis, returning 0 for the +1 eigenvalue. By default the product is
+    // rotated onto Z on its last qubit, with the parity of the other qubits gathered by CX gates.
+    fn measure_pauli(&mut self, pauli: &PauliString) -> i32 {
+        let qubits: Vec<usize> = (0..pauli.num_qubits).filter(|&i| pauli.x(i) || pauli.z(i)).collect();
+        if qubits.len() == 0 {
+            return pauli.r() as i32;
+        }
+
+        for &q in &qubits {
+            match pauli.to_op(q) {
+                "X" => self.h_gate(q),
+                "Y" => {
+                    self.sd_gate(q);
+                    self.h_gate(q);
+                },
+                _ => (),
+            }
+        }
+        let target: usize = qubits[qubits.len() - 1];
+        for &q in &qubits[0..(qubits.len() - 1)] {
+            self.cx_gate(q, target);
+        }
+
+        let measured = self.mzr_qubit(target) ^ (pauli.r() as i32);
+
+        for &q in qubits[0..(qubits.len() - 1)].iter().rev() {
+            self.cx_gate(q, target);
+        }
+        for &q in &qubits {
+            match pauli.to_op(q) {
+                "X" => self.h_gate(q),
+                "Y" => {
+                    self.h_gate(q);
+                    self.s_gate(q);
+                },
+                _ => (),
+            }
+        }
+
+        return measured;
+    }
+
+    // Resets qubit to |0>, discarding the outcome of the measurement collapsing it
+    fn reset_qubit(&mut self, qubit: usize) {
+        self.mr_qubit(qubit);
+    }
+    // Resets qubit to |+>
+    fn resetx_qubit(&mut self, qubit: usize) {
+        self.reset_qubit(qubit);
+        self.h_gate(qubit);
+    }
+    // Resets qubit to |+i>
+    fn resety_qubit(&mut self, qubit: usize) {
+        self.reset_qubit(qubit);
+        self.h_gate(qubit);
+        self.s_gate(qubit);
+    }
+    // Measures qubit in the computational basis and then resets it to |0>
+    fn mr_qubit(&mut self, qubit: usize) -> i32 {
+        let measured = self.mzr_qubit(qubit);
+        if measured == 1 {
+            self.x_gate(qubit);
+        }
+        return measured;
+    }
+
+    // Samples an error from channel and applies it to qubits. Backends which track many shots at once
+    // override this to sample an independent error for each shot.
+    fn apply_noise(&mut self, channel: &NoiseChannel, qubits: &[usize], rng: &mut Lcg64Xsh32) where Self: Sized {
+        channel.apply(self, qubits, rng);
+    }
+
     fn finish_execution(&mut self) {}
 }
 
@@ -129,10 +301,268 @@ pub trait Entropy {
     fn renyi_entropy(&self, qubits: &Vec<usize>) -> f32;
 }
 
+// Gates outside of the Clifford group, which only backends holding an explicit state vector can
+// apply. Single-qubit matrices are given row by row, i.e. [u00, u01, u10, u11].
+pub trait NonCliffordState: QuantumState {
+    fn unitary_gate(&mut self, qubit: usize, gate: [Complex<f64>; 4]);
+    // Multiplies the amplitude of the states where both qubits are 1 by exp(i phi)
+    fn cphase_gate(&mut self, qubit1: usize, qubit2: usize, phi: f64);
+
+    fn phase_gate(&mut self, qubit: usize, phi: f64) {
+        self.unitary_gate(qubit, [Complex::new(1., 0.), Complex::new(0., 0.), Complex::new(0., 0.), Complex::from_polar(1., phi)]);
+    }
+    fn t_gate(&mut self, qubit: usize) {
+        self.phase_gate(qubit, std::f64::consts::FRAC_PI_4);
+    }
+    fn td_gate(&mut self, qubit: usize) {
+        self.phase_gate(qubit, -std::f64::consts::FRAC_PI_4);
+    }
+
+    // exp(-i theta P/2) for P = X, Y, Z
+    fn rx_gate(&mut self, qubit: usize, theta: f64) {
+        let (c, s) = ((theta/2.).cos(), (theta/2.).sin());
+        self.unitary_gate(qubit, [Complex::new(c, 0.), Complex::new(0., -s), Complex::new(0., -s), Complex::new(c, 0.)]);
+    }
+    fn ry_gate(&mut self, qubit: usize, theta: f64) {
+        let (c, s) = ((theta/2.).cos(), (theta/2.).sin());
+        self.unitary_gate(qubit, [Complex::new(c, 0.), Complex::new(-s, 0.), Complex::new(s, 0.), Complex::new(c, 0.)]);
+    }
+    fn rz_gate(&mut self, qubit: usize, theta: f64) {
+        self.unitary_gate(qubit, [Complex::from_polar(1., -theta/2.), Complex::new(0., 0.), Complex::new(0., 0.), Complex::from_polar(1., theta/2.)]);
+    }
+
+    // General single-qubit unitary with the OpenQASM convention U3(theta, phi, lambda) = Rz(phi) Ry(theta) Rz(lambda),
+    // up to a global phase
+    fn u3_gate(&mut self, qubit: usize, theta: f64, phi: f64, lambda: f64) {
+        let (c, s) = ((theta/2.).cos(), (theta/2.).sin());
+        self.unitary_gate(qubit, [Complex::new(c, 0.), -Complex::from_polar(s, lambda),
+                                  Complex::from_polar(s, phi), Complex::from_polar(c, phi + lambda)]);
+    }
+}
+
+// Applies a non-Clifford gate to qubits. Programs which may contain non-Clifford gates hold this
+// function for their backend, since QuantumState alone cannot apply them.
+type NonCliffordGate<Q> = fn(&mut Q, Gate, &[usize]);
+
+fn apply_non_clifford<Q: NonCliffordState>(quantum_state: &mut Q, gate: Gate, qubits: &[usize]) {
+    match gate {
+        Gate::T => quantum_state.t_gate(qubits[0]),
+        Gate::Td => quantum_state.td_gate(qubits[0]),
+        Gate::Rx(theta) => quantum_state.rx_gate(qubits[0], theta),
+        Gate::Ry(theta) => quantum_state.ry_gate(qubits[0], theta),
+        Gate::Rz(theta) => quantum_state.rz_gate(qubits[0], theta),
+        Gate::U3(theta, phi, lambda) => quantum_state.u3_gate(qubits[0], theta, phi, lambda),
+        Gate::CP(phi) => quantum_state.cphase_gate(qubits[0], qubits[1], phi),
+        _ => panic!("{} is a Clifford gate", gate.name()),
+    }
+}
+
+// Classical condition guarding an instruction. Classical registers which have not yet been
+// written to are read as 0.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    // The registers, read as a little-endian integer, are equal to the value
+    Equals(Vec<usize>, usize),
+    // The XOR of the registers is equal to the value
+    Parity(Vec<usize>, i32),
+}
+
+impl Condition {
+    pub(crate) fn evaluate(&self, classical_data: &Vec<i32>) -> bool {
+        let bit = |c: &usize| (classical_data[*c] == 1) as usize;
+        match self {
+            Condition::Equals(cbits, val) => cbits.iter().enumerate().map(|(i, c)| bit(c) << i).sum::<usize>() == *val,
+            Condition::Parity(cbits, val) => (cbits.iter().map(bit).sum::<usize>() % 2) as i32 == *val,
+        }
+    }
+
+    pub(crate) fn cbits(&self) -> &Vec<usize> {
+        match self {
+            Condition::Equals(cbits, _) | Condition::Parity(cbits, _) => cbits,
+        }
+    }
+
+    pub(crate) fn to_qasm(&self) -> String {
+        let (cbits, separator, val) = match self {
+            Condition::Equals(cbits, val) => (cbits, ",", *val),
+            Condition::Parity(cbits, val) => (cbits, "^", *val as usize),
+        };
+        let registers: Vec<String> = cbits.iter().map(|c| format!("r{c}")).collect();
+        return format!("if {} == {val}", registers.join(separator));
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Instruction {
-    id: Gate,
-    qubits: Vec<usize>,
-    cbits: Vec<usize>
+    pub id: Gate,
+    pub qubits: Vec<usize>,
+    pub cbits: Vec<usize>,
+    pub condition: Option<Condition>,
+    // Pauli (X, Y or Z) acting on each qubit of an MPP instruction; empty for other gates
+    pub paulis: Vec<Gate>,
+}
+
+impl Instruction {
+    // Formats the instruction as a line of the native circuit format
+    pub(crate) fn to_qasm(&self) -> String {
+        if let Gate::PRINT = self.id {
+            return String::from("@pragma print");
+        }
+
+        let mut line: String = match &self.condition {
+            Some(condition) => condition.to_qasm() + " ",
+            None => String::new(),
+        };
+        line += self.id.name();
+        if let Gate::Noise(channel) = self.id {
+            for p in channel.probs() {
+                line += &format!(" {p}");
+            }
+        }
+        for angle in self.id.params() {
+            line += &format!(" {angle}");
+        }
+        if self.paulis.len() > 0 {
+            line += " ";
+            line += &self.paulis.iter().map(|p| p.name().to_uppercase()).collect::<String>();
+        }
+        for q in &self.qubits {
+            line += &format!(" q{q}");
+        }
+        for c in &self.cbits {
+            line += &format!(" r{c}");
+        }
+        return line;
+    }
+}
+
+// Node of a parsed circuit. Repeat blocks and calls to composite gates are kept as written and are
+// only expanded while executing, so that long periodic circuits stay compact in memory.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Block {
+    Instruction(Instruction),
+    // Number of repetitions and the body
+    Repeat(usize, Vec<Block>),
+    // Index of the definition and the qubits passed to it
+    Call(usize, Vec<usize>),
+}
+
+// Composite gate declared with @def. Qubits in the body refer to positions in params, while
+// classical registers are global.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Definition {
+    pub(crate) name: String,
+    pub(crate) params: Vec<String>,
+    pub(crate) body: Vec<Block>,
+}
+
+// Optimizes every straight-line run of instructions in blocks, returning the number of
+// instructions removed. Repeat blocks and calls are barriers.
+fn optimize_blocks(blocks: &mut Vec<Block>) -> usize {
+    let mut optimized: Vec<Block> = Vec::new();
+    let mut run: Vec<Instruction> = Vec::new();
+    let mut removed: usize = 0;
+
+    let end_run = |run: &mut Vec<Instruction>, optimized: &mut Vec<Block>| -> usize {
+        let instructions: Vec<Instruction> = optimizer::optimize(run);
+        let removed: usize = run.len() - instructions.len();
+        optimized.extend(instructions.into_iter().map(Block::Instruction));
+        run.clear();
+        return removed;
+    };
+
+    for block in blocks.drain(..) {
+        match block {
+            Block::Instruction(inst) => run.push(inst),
+            Block::Repeat(n, mut body) => {
+                removed += end_run(&mut run, &mut optimized) + optimize_blocks(&mut body);
+                optimized.push(Block::Repeat(n, body));
+            },
+            Block::Call(_, _) => {
+                removed += end_run(&mut run, &mut optimized);
+                optimized.push(block);
+            },
+        }
+    }
+    removed += end_run(&mut run, &mut optimized);
+
+    *blocks = optimized;
+    return removed;
+}
+
+// Whether any instruction of blocks, outside of calls, is a non-Clifford gate
+pub(crate) fn contains_non_clifford(blocks: &Vec<Block>) -> bool {
+    return blocks.iter().any(|block| match block {
+        Block::Instruction(inst) => inst.id.is_non_clifford(),
+        Block::Repeat(_, body) => contains_non_clifford(body),
+        Block::Call(_, _) => false,
+    });
+}
+
+// Appends the fully expanded instructions of blocks to instructions, substituting qubits through
+// qubit_map when inside a definition
+pub(crate) fn flatten_blocks(blocks: &Vec<Block>, definitions: &Vec<Definition>, qubit_map: Option<&Vec<usize>>, instructions: &mut Vec<Instruction>) {
+    for block in blocks {
+        match block {
+            Block::Instruction(inst) => {
+                let mut inst = inst.clone();
+                if let Some(map) = qubit_map {
+                    inst.qubits = inst.qubits.iter().map(|q| map[*q]).collect();
+                }
+                instructions.push(inst);
+            },
+            Block::Repeat(n, body) => {
+                for _ in 0..*n {
+                    flatten_blocks(body, definitions, qubit_map, instructions);
+                }
+            },
+            Block::Call(def, args) => {
+                let args: Vec<usize> = match qubit_map {
+                    Some(map) => args.iter().map(|q| map[*q]).collect(),
+                    None => args.clone(),
+                };
+                flatten_blocks(&definitions[*def].body, definitions, Some(&args), instructions);
+            },
+        }
+    }
+}
+
+// Formats blocks as lines of the native circuit format, naming qubits after params when inside a definition
+fn blocks_to_qasm(blocks: &Vec<Block>, definitions: &Vec<Definition>, params: Option<&Vec<String>>, indent: usize, lines: &mut Vec<String>) {
+    let qubit = |q: &usize| -> String {
+        match params {
+            Some(params) => params[*q].clone(),
+            None => format!("q{q}"),
+        }
+    };
+
+    let padding: String = " ".repeat(4*indent);
+    for block in blocks {
+        match block {
+            Block::Instruction(inst) => {
+                let mut line: String = inst.to_qasm();
+                if let Some(params) = params {
+                    // Instruction::to_qasm names qubits by index; substitute the parameter names
+                    let mut tokens: Vec<String> = line.split(' ').map(String::from).collect();
+                    let first_qubit: usize = tokens.len() - inst.qubits.len() - inst.cbits.len();
+                    for (i, q) in inst.qubits.iter().enumerate() {
+                        tokens[first_qubit + i] = params[*q].clone();
+                    }
+                    line = tokens.join(" ");
+                }
+                lines.push(padding.clone() + &line);
+            },
+            Block::Repeat(n, body) => {
+                lines.push(format!("{padding}@repeat {n} {{"));
+                blocks_to_qasm(body, definitions, params, indent + 1, lines);
+                lines.push(padding.clone() + "}");
+            },
+            Block::Call(def, args) => {
+                let args: Vec<String> = args.iter().map(qubit).collect();
+                lines.push(format!("{padding}{} {}", definitions[*def].name, args.join(" ")));
+            },
+        }
+    }
 }
 
 pub struct QuantumProgram<Q: QuantumState> {
@@ -140,11 +570,346 @@ pub struct QuantumProgram<Q: QuantumState> {
     total_num_cbits: usize,
     classical_data: Vec<i32>,
     pub quantum_state: Q, // TODO private
-    circuit: Vec<Instruction>,
+    // Source of randomness for noise channels
+    rng: Lcg64Xsh32,
+    circuit: Vec<Block>,
+    definitions: Vec<Definition>,
+    // Named quantum and classical registers
+    qregs: Vec<Register>,
+    cregs: Vec<Register>,
+    // Lines from foreign formats which do not affect simulation (e.g. Stim detectors), paired with
+    // the index of the instruction they precede
+    annotations: Vec<(usize, String)>,
+    // Set when the program is built for a NonCliffordState, and so may contain non-Clifford gates
+    non_clifford: Option<NonCliffordGate<Q>>,
+}
+
+// Error produced when a circuit fails to parse; line and column are 1-indexed
+#[derive(Clone, Debug, PartialEq)]
+pub struct QasmError {
+    pub line: usize,
+    pub column: usize,
+    pub token: String,
+    pub message: String,
+}
+
+impl QasmError {
+    pub(crate) fn new(line: usize, column: usize, token: &str, message: &str) -> Self {
+        return QasmError { line: line, column: column, token: String::from(token), message: String::from(message) };
+    }
+}
+
+impl std::fmt::Display for QasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)?;
+        if !self.token.is_empty() {
+            write!(f, " (found '{}')", self.token)?;
+        }
+        return Ok(());
+    }
+}
+
+impl std::error::Error for QasmError {}
+
+// Error produced when a program cannot be written in another circuit format; instruction is the
+// index of the offending instruction in the expanded circuit
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportError {
+    pub instruction: usize,
+    pub message: String,
+}
+
+impl ExportError {
+    pub(crate) fn new(instruction: usize, message: &str) -> Self {
+        return ExportError { instruction: instruction, message: String::from(message) };
+    }
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "instruction {}: {}", self.instruction, self.message);
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+// Splits a line on whitespace, keeping the (1-indexed) column at which each token starts
+pub(crate) fn split_tokens(line: &str) -> Vec<(usize, &str)> {
+    let mut tokens: Vec<(usize, &str)> = Vec::new();
+    let mut start: Option<usize> = None;
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(j)) => {
+                tokens.push((j + 1, &line[j..i]));
+                start = None;
+            },
+            (false, None) => start = Some(i),
+            _ => (),
+        }
+    }
+    if let Some(j) = start {
+        tokens.push((j + 1, &line[j..]));
+    }
+
+    return tokens;
+}
+
+// Parses a register such as q3 or r0, where the leading character must be one of prefixes
+fn parse_register(s: &str, prefixes: &[char]) -> Result<usize, String> {
+    let prefix: char = s.chars().next().unwrap();
+    if !prefixes.contains(&prefix) {
+        let expected: Vec<String> = prefixes.iter().map(|p| format!("'{p}'")).collect();
+        return Err(format!("expected register beginning with {}", expected.join(" or ")));
+    }
+
+    return match s[1..].parse::<usize>() {
+        Ok(idx) => Ok(idx),
+        Err(_) => Err(String::from("register index must be a non-negative integer")),
+    };
+}
+
+// Named slice of the qubits or classical registers, declared with @qreg or @creg. Registers are laid
+// out consecutively from index 0 in the order in which they are declared.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Register {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+}
+
+// Resolves an element of a named register, e.g. sys[3], to its physical index. Returns None if
+// token does not have the form name[index].
+fn parse_named_register(line_num: usize, col: usize, token: &str, registers: &Vec<Register>) -> Option<Result<usize, QasmError>> {
+    let (name, index) = token.strip_suffix(']')?.split_once('[')?;
+    let register: &Register = match registers.iter().find(|r| r.name == name) {
+        Some(register) => register,
+        None => return Some(Err(QasmError::new(line_num, col, token, &format!("unknown register '{name}'")))),
+    };
+
+    return Some(match index.parse::<usize>() {
+        Ok(i) if i < register.size => Ok(register.offset + i),
+        Ok(_) => Err(QasmError::new(line_num, col, token, &format!("index out of range for register {name} of size {}", register.size))),
+        Err(_) => Err(QasmError::new(line_num, col, token, "register index must be a non-negative integer")),
+    });
+}
+
+// Parses a register declaration, e.g. "@qreg sys 64", returning its name and size
+fn parse_register_declaration(line_num: usize, line_data: &[(usize, &str)]) -> Result<(String, usize), QasmError> {
+    let (col, first) = line_data[0];
+    if line_data.len() != 3 {
+        return Err(QasmError::new(line_num, col, first, &format!("expected declaration of the form '{first} <name> <size>'")));
+    }
+
+    let (col, name) = line_data[1];
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(QasmError::new(line_num, col, name, "expected register name"));
+    }
+
+    let (col, val) = line_data[2];
+    return match val.parse::<usize>() {
+        Ok(n) => Ok((String::from(name), n)),
+        Err(_) => Err(QasmError::new(line_num, col, val, "expected a non-negative integer")),
+    };
+}
+
+fn parse_cbit(line_num: usize, col: usize, token: &str, total_num_cbits: usize, cregs: &Vec<Register>) -> Result<usize, QasmError> {
+    if let Some(cbit) = parse_named_register(line_num, col, token, cregs) {
+        return cbit;
+    }
+
+    let cbit = parse_register(token, &['r', 'c']).map_err(|msg| QasmError::new(line_num, col, token, &msg))?;
+    if cbit >= total_num_cbits {
+        return Err(QasmError::new(line_num, col, token, &format!("classical register index out of range for total_num_cbits = {total_num_cbits}")));
+    }
+
+    return Ok(cbit);
+}
+
+// Parses a condition of the form [registers, "==", value]. registers is either a ^-separated list
+// of classical registers whose parity is compared against value, or a ,-separated list read as a
+// little-endian integer.
+fn parse_condition(line_num: usize, tokens: &[(usize, &str)], total_num_cbits: usize, cregs: &Vec<Register>) -> Result<Condition, QasmError> {
+    let (col, registers) = tokens[0];
+    let parity: bool = registers.contains('^');
+    if parity && registers.contains(',') {
+        return Err(QasmError::new(line_num, col, registers, "cannot mix ^ and , in a condition"));
+    }
+
+    let mut cbits: Vec<usize> = Vec::new();
+    let mut offset: usize = 0;
+    for token in registers.split(if parity { '^' } else { ',' }) {
+        if token.is_empty() {
+            return Err(QasmError::new(line_num, col + offset, registers, "expected classical register"));
+        }
+        cbits.push(parse_cbit(line_num, col + offset, token, total_num_cbits, cregs)?);
+        offset += token.len() + 1;
+    }
+    // The registers are read as a usize
+    if !parity && cbits.len() > usize::BITS as usize {
+        return Err(QasmError::new(line_num, col, registers, &format!("a condition may read at most {} classical registers", usize::BITS)));
+    }
+
+    let (col, token) = tokens[2];
+    let max_val: usize = if parity { 1 } else { usize::MAX >> (usize::BITS - cbits.len() as u32) };
+    let val: usize = match token.parse::<usize>() {
+        Ok(val) if val <= max_val => val,
+        _ => return Err(QasmError::new(line_num, col, token, &format!("condition value must be between 0 and {max_val}"))),
+    };
+
+    if parity {
+        return Ok(Condition::Parity(cbits, val as i32));
+    } else {
+        return Ok(Condition::Equals(cbits, val));
+    }
+}
+
+// Parses a qubit argument; inside a definition, qubits are named by its parameters and resolve to their position
+fn parse_qubit(line_num: usize, col: usize, token: &str, total_num_qubits: usize, qregs: &Vec<Register>, params: Option<&Vec<String>>) -> Result<usize, QasmError> {
+    if let Some(params) = params {
+        return match params.iter().position(|p| p == token) {
+            Some(i) => Ok(i),
+            None => Err(QasmError::new(line_num, col, token, "expected a parameter of the enclosing @def")),
+        };
+    }
+
+    if let Some(qubit) = parse_named_register(line_num, col, token, qregs) {
+        return qubit;
+    }
+
+    let qubit = parse_register(token, &['q']).map_err(|msg| QasmError::new(line_num, col, token, &msg))?;
+    if qubit >= total_num_qubits {
+        return Err(QasmError::new(line_num, col, token, &format!("qubit index out of range for total_num_qubits = {total_num_qubits}")));
+    }
+    return Ok(qubit);
+}
+
+// Parses a single gate or measurement, e.g. "cx q0 q1" or "mzr q0 r1"
+fn parse_instruction(line_num: usize, line_data: &[(usize, &str)], gates: &HashMap<String, (Gate, usize, usize)>, total_num_qubits: usize, 
+                     total_num_cbits: usize, qregs: &Vec<Register>, cregs: &Vec<Register>, params: Option<&Vec<String>>) -> Result<Instruction, QasmError> {
+    let (col, gate) = line_data[0];
+    let (mut id, mut num_qubits, num_cbits) = match gates.get(gate) {
+        Some(g) => *g,
+        None => return Err(QasmError::new(line_num, col, gate, "unknown gate")),
+    };
+
+    let mut paulis: Vec<Gate> = Vec::new();
+    let mut line_data: &[(usize, &str)] = line_data;
+    if let Gate::MPP = id {
+        if line_data.len() < 2 {
+            return Err(QasmError::new(line_num, col, gate, &format!("{gate} expects a Pauli word such as XZZ")));
+        }
+
+        let (col, word) = line_data[1];
+        for c in word.chars() {
+            match c.to_ascii_uppercase() {
+                'X' => paulis.push(Gate::X),
+                'Y' => paulis.push(Gate::Y),
+                'Z' => paulis.push(Gate::Z),
+                _ => return Err(QasmError::new(line_num, col, word, "expected a Pauli word of X, Y and Z")),
+            }
+        }
+        num_qubits = paulis.len();
+
+        // The word takes the place of the gate name when checking the remaining arguments
+        line_data = &line_data[1..];
+    }
+
+    if let Gate::Noise(channel) = id {
+        let (_, num_probs) = NoiseChannel::from_name(gate).unwrap();
+        if line_data.len() < num_probs + 1 {
+            return Err(QasmError::new(line_num, col, gate, &format!("{gate} expects {num_probs} probabilities")));
+        }
+
+        let mut probs: Vec<f32> = Vec::new();
+        for &(col, token) in &line_data[1..(num_probs + 1)] {
+            match token.parse::<f32>() {
+                Ok(p) => probs.push(p),
+                Err(_) => return Err(QasmError::new(line_num, col, token, "expected a probability")),
+            }
+        }
+        id = Gate::Noise(channel.with_probs(&probs).map_err(|msg| QasmError::new(line_num, line_data[1].0, line_data[1].1, &msg))?);
+        line_data = &line_data[num_probs..];
+    }
+
+    // Angles likewise come before the qubits, e.g. "rx 0.5 q0" or "cp 1.57 q0 q1"
+    let num_angles: usize = id.params().len();
+    if num_angles > 0 {
+        if line_data.len() < num_angles + 1 {
+            return Err(QasmError::new(line_num, col, gate, &format!("{gate} expects {num_angles} angles")));
+        }
+
+        let mut angles: Vec<f64> = Vec::new();
+        for &(col, token) in &line_data[1..(num_angles + 1)] {
+            match token.parse::<f64>() {
+                Ok(angle) if angle.is_finite() => angles.push(angle),
+                _ => return Err(QasmError::new(line_num, col, token, "expected an angle")),
+            }
+        }
+        id = id.with_params(&angles);
+        line_data = &line_data[num_angles..];
+    }
+
+    if line_data.len() != num_qubits + num_cbits + 1 {
+        let (col, token) = if line_data.len() > num_qubits + num_cbits + 1 { line_data[num_qubits + num_cbits + 1] } else { (col, gate) };
+        return Err(QasmError::new(line_num, col, token, 
+                                  &format!("{gate} expects {num_qubits} qubit and {num_cbits} classical register arguments, found {}", line_data.len() - 1)));
+    }
+
+    let mut qubits: Vec<usize> = Vec::new();
+    let mut cbits: Vec<usize> = Vec::new();
+    for &(col, token) in &line_data[1..(num_qubits + 1)] {
+        let qubit = parse_qubit(line_num, col, token, total_num_qubits, qregs, params)?;
+        if qubits.contains(&qubit) {
+            return Err(QasmError::new(line_num, col, token, "repeated qubit argument"));
+        }
+        qubits.push(qubit);
+    }
+    for &(col, token) in &line_data[(num_qubits + 1)..] {
+        cbits.push(parse_cbit(line_num, col, token, total_num_cbits, cregs)?);
+    }
+
+    return Ok(Instruction { id: id, qubits: qubits, cbits: cbits, condition: None, paulis: paulis });
 }
 
-fn parse_register(s: &str) -> usize {
-    return s[1..s.len()].parse::<usize>().unwrap();
+// Block opened by @repeat or @def whose closing brace has not yet been reached
+enum OpenBlock {
+    Repeat(usize),
+    // Name and parameters of the definition
+    Def(String, Vec<String>),
+}
+
+// Parses the header of a composite gate, e.g. "@def bell(a, b) {", returning its name and parameters
+fn parse_definition(line_num: usize, line_data: &[(usize, &str)]) -> Result<(String, Vec<String>), QasmError> {
+    let (col, first) = line_data[0];
+    if line_data.len() < 3 || line_data[line_data.len() - 1].1 != "{" {
+        return Err(QasmError::new(line_num, col, first, "expected block of the form '@def name(q0, q1) {'"));
+    }
+
+    // The signature may contain spaces between parameters
+    let (col, _) = line_data[1];
+    let signature: String = line_data[1..(line_data.len() - 1)].iter().map(|(_, t)| *t).collect::<Vec<&str>>().join("");
+    let (name, params) = match signature.strip_suffix(')').and_then(|s| s.split_once('(')) {
+        Some(parts) => parts,
+        None => return Err(QasmError::new(line_num, col, &signature, "expected signature of the form 'name(q0, q1)'")),
+    };
+
+    let is_identifier = |s: &str| s.len() > 0 && s.chars().all(|c| c.is_alphanumeric() || c == '_') && !s.starts_with(|c: char| c.is_ascii_digit());
+    if !is_identifier(name) {
+        return Err(QasmError::new(line_num, col, name, "expected gate name"));
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    for param in params.split(',') {
+        if !is_identifier(param) {
+            return Err(QasmError::new(line_num, col, param, "expected parameter name"));
+        }
+        if names.iter().any(|p| p == param) {
+            return Err(QasmError::new(line_num, col, param, "repeated parameter name"));
+        }
+        names.push(String::from(param));
+    }
+
+    return Ok((String::from(name), names));
 }
 
 impl<Q: QuantumState> QuantumProgram<Q> {
@@ -154,6 +919,7 @@ impl<Q: QuantumState> QuantumProgram<Q> {
 
     fn init_mapped_gates() -> HashMap<String, (Gate, usize, usize)> {
         let mut gates: HashMap<String, (Gate, usize, usize)> = HashMap::new();
+        gates.insert(String::from("id"), (Gate::I, 1, 0)); gates.insert(String::from("ID"), (Gate::I, 1, 0));
         gates.insert(String::from("x"), (Gate::X, 1, 0)); gates.insert(String::from("X"), (Gate::X, 1, 0));
         gates.insert(String::from("y"), (Gate::Y, 1, 0)); gates.insert(String::from("Y"), (Gate::Y, 1, 0));
         gates.insert(String::from("z"), (Gate::Z, 1, 0)); gates.insert(String::from("Z"), (Gate::Z, 1, 0));
@@ -167,8 +933,8 @@ impl<Q: QuantumState> QuantumProgram<Q> {
         gates.insert(String::from("sqrtxd"), (Gate::SQRTXd, 1, 0)); gates.insert(String::from("SQRTXD"), (Gate::SQRTXd, 1, 0));
         gates.insert(String::from("sqrty"),  (Gate::SQRTY, 1, 0)); gates.insert(String::from("SQRTY"), (Gate::SQRTY, 1, 0));
         gates.insert(String::from("sqrtyd"), (Gate::SQRTYd, 1, 0)); gates.insert(String::from("SQRTYD"), (Gate::SQRTYd, 1, 0));
-        gates.insert(String::from("sqrtz"),  (Gate::S, 1, 0)); gates.insert(String::from("SQRTZ"), (Gate::S, 1, 0));
-        gates.insert(String::from("sqrtzd"), (Gate::Sd, 1, 0)); gates.insert(String::from("SQRTZD"), (Gate::Sd, 1, 0));
+        gates.insert(String::from("sqrtz"),  (Gate::SQRTZ, 1, 0)); gates.insert(String::from("SQRTZ"), (Gate::SQRTZ, 1, 0));
+        gates.insert(String::from("sqrtzd"), (Gate::SQRTZd, 1, 0)); gates.insert(String::from("SQRTZD"), (Gate::SQRTZd, 1, 0));
 
         gates.insert(String::from("cx"), (Gate::CX, 2, 0)); gates.insert(String::from("CX"), (Gate::CX, 2, 0));
         gates.insert(String::from("cnot"), (Gate::CX, 2, 0)); gates.insert(String::from("CNOT"), (Gate::CX, 2, 0));
@@ -180,103 +946,517 @@ impl<Q: QuantumState> QuantumProgram<Q> {
         gates.insert(String::from("myr"), (Gate::MYR, 1, 1)); gates.insert(String::from("MYR"), (Gate::MYR, 1, 1));
         gates.insert(String::from("mzr"), (Gate::MZR, 1, 1)); gates.insert(String::from("MZR"), (Gate::MZR, 1, 1));
 
+        gates.insert(String::from("reset"), (Gate::R, 1, 0)); gates.insert(String::from("RESET"), (Gate::R, 1, 0));
+        gates.insert(String::from("resetx"), (Gate::RX, 1, 0)); gates.insert(String::from("RESETX"), (Gate::RX, 1, 0));
+        gates.insert(String::from("resety"), (Gate::RY, 1, 0)); gates.insert(String::from("RESETY"), (Gate::RY, 1, 0));
+        gates.insert(String::from("mr"), (Gate::MR, 1, 1)); gates.insert(String::from("MR"), (Gate::MR, 1, 1));
+
+        // The number of qubits of mpp is given by its Pauli word, e.g. "mpp XZZ q0 q1 q2 r0"
+        gates.insert(String::from("mpp"), (Gate::MPP, 0, 1)); gates.insert(String::from("MPP"), (Gate::MPP, 0, 1));
+
+        // Noise channels take their probabilities before the qubits, e.g. "depolarize2 0.01 q0 q1"
+        for name in ["depolarize1", "depolarize2", "x_error", "z_error", "pauli_channel"] {
+            let (channel, _) = NoiseChannel::from_name(name).unwrap();
+            gates.insert(String::from(name), (Gate::Noise(channel), channel.num_qubits(), 0));
+            gates.insert(name.to_uppercase(), (Gate::Noise(channel), channel.num_qubits(), 0));
+        }
+
+        // Non-Clifford gates, accepted only by from_qasm_non_clifford; angles come before the qubits
+        for (gate, num_qubits) in [(Gate::T, 1), (Gate::Td, 1), (Gate::Rx(0.), 1), (Gate::Ry(0.), 1), (Gate::Rz(0.), 1),
+                                   (Gate::U3(0., 0., 0.), 1), (Gate::CP(0.), 2)] {
+            gates.insert(String::from(gate.name()), (gate, num_qubits, 0));
+            gates.insert(gate.name().to_uppercase(), (gate, num_qubits, 0));
+        }
+
 
         return gates;
     }
 
+    pub(crate) fn from_blocks(total_num_qubits: usize, total_num_cbits: usize, blocks: Vec<Block>) -> QuantumProgram<Q> {
+        let classical_data: Vec<i32> = vec![-1; total_num_cbits];
+        let state = Q::new(total_num_qubits);
+        return QuantumProgram { total_num_qubits: total_num_qubits, total_num_cbits: total_num_cbits, 
+                                classical_data: classical_data, quantum_state: state, rng: Lcg64Xsh32::from_entropy(),
+                                circuit: blocks, definitions: Vec::new(), qregs: Vec::new(), cregs: Vec::new(), annotations: Vec::new(),
+                                non_clifford: None };
+    }
+
+    fn from_instructions(total_num_qubits: usize, total_num_cbits: usize, instructions: Vec<Instruction>) -> QuantumProgram<Q> {
+        return QuantumProgram::from_blocks(total_num_qubits, total_num_cbits, instructions.into_iter().map(Block::Instruction).collect());
+    }
+
+    // Fully expanded list of instructions, with repeat blocks unrolled and composite gates inlined
+    pub(crate) fn instructions(&self) -> Vec<Instruction> {
+        let mut instructions: Vec<Instruction> = Vec::new();
+        flatten_blocks(&self.circuit, &self.definitions, None, &mut instructions);
+        return instructions;
+    }
+
+    // Parses an OpenQASM 2.0 circuit; see openqasm.rs for the supported subset
+    pub fn from_openqasm(circuit: &String) -> QuantumProgram<Q> {
+        return match QuantumProgram::try_from_openqasm(circuit) {
+            Ok(program) => program,
+            Err(err) => panic!("Error parsing circuit: {err}"),
+        };
+    }
+
+    pub fn try_from_openqasm(circuit: &String) -> Result<QuantumProgram<Q>, QasmError> {
+        let (total_num_qubits, total_num_cbits, instructions) = openqasm::parse(circuit, false)?;
+        return Ok(QuantumProgram::from_instructions(total_num_qubits, total_num_cbits, instructions));
+    }
+
+    // Parses a Stim circuit; see stim.rs for the supported subset
+    pub fn from_stim(circuit: &String) -> QuantumProgram<Q> {
+        return match QuantumProgram::try_from_stim(circuit) {
+            Ok(program) => program,
+            Err(err) => panic!("Error parsing circuit: {err}"),
+        };
+    }
+
+    pub fn try_from_stim(circuit: &String) -> Result<QuantumProgram<Q>, QasmError> {
+        let (total_num_qubits, total_num_cbits, blocks, annotations) = stim::parse(circuit)?;
+        let mut program = QuantumProgram::from_blocks(total_num_qubits, total_num_cbits, blocks);
+        program.annotations = annotations;
+        return Ok(program);
+    }
+
     pub fn from_qasm(circuit: &String) -> QuantumProgram<Q> {
-        let lines = circuit.split("\n").collect::<Vec<&str>>();
-        let mut line_data: Vec<&str> = Vec::new();
+        return match QuantumProgram::try_from_qasm(circuit) {
+            Ok(program) => program,
+            Err(err) => panic!("Error parsing circuit: {err}"),
+        };
+    }
+
+    pub fn try_from_qasm(circuit: &String) -> Result<QuantumProgram<Q>, QasmError> {
+        return QuantumProgram::parse_qasm(circuit, false);
+    }
+
+    // Parses the native circuit format, rejecting non-Clifford gates unless non_clifford is set
+    fn parse_qasm(circuit: &String, non_clifford: bool) -> Result<QuantumProgram<Q>, QasmError> {
+        if circuit.trim_start().starts_with("OPENQASM") {
+            let (total_num_qubits, total_num_cbits, instructions) = openqasm::parse(circuit, non_clifford)?;
+            return Ok(QuantumProgram::from_instructions(total_num_qubits, total_num_cbits, instructions));
+        }
 
         let mut total_num_qubits: usize = 0;
         let mut total_num_cbits: usize = 0;
-        let mut num_qubits: usize = 0;
-        let mut num_cbits: usize = 0;
-        let mut qubits: Vec<usize> = Vec::new();
-        let mut cbits: Vec<usize> = Vec::new();
-        let mut gate: &str = "";
-        let mut id: Gate = Gate::I;
-        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut qregs: Vec<Register> = Vec::new();
+        let mut cregs: Vec<Register> = Vec::new();
+        let mut definitions: Vec<Definition> = Vec::new();
 
-        let gates = QuantumProgram::<Q>::init_mapped_gates();
-        for line in lines {
-            qubits.clear();
-            cbits.clear();
+        // Blocks which are still open, with the position of their opening line, and the contents
+        // of each; bodies[0] holds the top level
+        let mut stack: Vec<(usize, usize, OpenBlock)> = Vec::new();
+        let mut bodies: Vec<Vec<Block>> = vec![Vec::new()];
 
-            line_data = line.split_whitespace().collect::<Vec<&str>>();
+        let gates = QuantumProgram::<Q>::init_mapped_gates();
+        for (line_idx, line) in circuit.split("\n").enumerate() {
+            let line_num: usize = line_idx + 1;
+            let line_data: Vec<(usize, &str)> = split_tokens(line);
             if line_data.len() == 0 {
                 continue
-            } else if line_data[0] == "@pragma" {
-                if line_data[1] == "total_num_qbits" || line_data[1] == "total_num_qubits" {
-                    total_num_qubits = line_data[2].parse::<usize>().unwrap();
-                    continue;
-                } else if line_data[1] == "total_num_cbits" || line_data[1] == "total_num_bits" {
-                    total_num_cbits = line_data[2].parse::<usize>().unwrap();
-                    continue;
-                } else if line_data[1] == "print" {
-                    id = Gate::PRINT;
+            }
+
+            let (col, first) = line_data[0];
+            if first == "@pragma" {
+                if line_data.len() < 2 {
+                    return Err(QasmError::new(line_num, col, first, "expected pragma name"));
                 }
-            } else {
-                gate = line_data[0];
-                if gates.contains_key(gate) {
-                    id = gates[gate].0;
-                    num_qubits = gates[gate].1;
-                    num_cbits = gates[gate].2;
 
-                    assert!(line_data.len() == (num_qubits + num_cbits + 1) as usize);
+                let (col, pragma) = line_data[1];
+                match pragma {
+                    "total_num_qbits" | "total_num_qubits" | "total_num_cbits" | "total_num_bits" => {
+                        if line_data.len() != 3 {
+                            return Err(QasmError::new(line_num, col, pragma, &format!("pragma {pragma} takes exactly one argument")));
+                        }
+                        if stack.len() > 0 {
+                            return Err(QasmError::new(line_num, col, pragma, &format!("pragma {pragma} must appear outside of blocks")));
+                        }
 
-                    for i in 1..(num_qubits+1) as usize {
-                        assert!(String::from(line_data[i]).chars().nth(0).unwrap() == 'q');
-                        qubits.push(parse_register(line_data[i]));
-                    }
-                    for i in (1 + num_qubits as usize)..((1 + num_qubits + num_cbits) as usize) {
-                        assert!(String::from(line_data[i]).chars().nth(0).unwrap() == 'r' || String::from(line_data[i]).chars().nth(0).unwrap() == 'c');
-                        cbits.push(parse_register(line_data[i]));
+                        let (col, val) = line_data[2];
+                        let n: usize = match val.parse::<usize>() {
+                            Ok(n) => n,
+                            Err(_) => return Err(QasmError::new(line_num, col, val, "expected a non-negative integer")),
+                        };
+
+                        let (total, registers) = if pragma == "total_num_qbits" || pragma == "total_num_qubits" {
+                            (&mut total_num_qubits, &qregs)
+                        } else {
+                            (&mut total_num_cbits, &cregs)
+                        };
+                        let allocated: usize = registers.iter().map(|r| r.size).sum();
+                        if n < allocated {
+                            return Err(QasmError::new(line_num, col, val, &format!("{pragma} is smaller than the {allocated} declared in registers")));
+                        }
+                        *total = n;
+                    },
+                    "print" => bodies.last_mut().unwrap().push(Block::Instruction(Instruction { id: Gate::PRINT, qubits: Vec::new(), cbits: Vec::new(), condition: None, paulis: Vec::new() })),
+                    _ => return Err(QasmError::new(line_num, col, pragma, "unknown pragma")),
+                }
+                continue;
+            }
+
+            if first == "@qreg" || first == "@creg" {
+                // Named register, e.g. "@qreg sys 64" or "@creg syn 8"
+                if stack.len() > 0 {
+                    return Err(QasmError::new(line_num, col, first, &format!("{first} must appear outside of blocks")));
+                }
+                let (name, size) = parse_register_declaration(line_num, &line_data)?;
+                if qregs.iter().chain(cregs.iter()).any(|r| r.name == name) {
+                    return Err(QasmError::new(line_num, line_data[1].0, &name, "register is already defined"));
+                }
+
+                let (total, registers) = if first == "@qreg" { (&mut total_num_qubits, &mut qregs) } else { (&mut total_num_cbits, &mut cregs) };
+                let offset: usize = registers.iter().map(|r| r.size).sum();
+                registers.push(Register { name: name, offset: offset, size: size });
+                *total = std::cmp::max(*total, offset + size);
+                continue;
+            }
+
+            if first == "@repeat" {
+                // Repeated block, e.g. "@repeat 100 {"
+                if line_data.len() != 3 || line_data[2].1 != "{" {
+                    return Err(QasmError::new(line_num, col, first, "expected block of the form '@repeat <count> {'"));
+                }
+
+                let (col, val) = line_data[1];
+                let n: usize = match val.parse::<usize>() {
+                    Ok(n) => n,
+                    Err(_) => return Err(QasmError::new(line_num, col, val, "expected a non-negative integer")),
+                };
+                stack.push((line_num, line_data[0].0, OpenBlock::Repeat(n)));
+                bodies.push(Vec::new());
+                continue;
+            }
+
+            if first == "@def" {
+                // Composite gate, e.g. "@def bell(a, b) {"
+                if stack.len() > 0 {
+                    return Err(QasmError::new(line_num, col, first, "@def must appear outside of blocks"));
+                }
+                let (name, params) = parse_definition(line_num, &line_data)?;
+                if gates.contains_key(&name) || definitions.iter().any(|def| def.name == name) {
+                    return Err(QasmError::new(line_num, line_data[1].0, &name, "gate is already defined"));
+                }
+                stack.push((line_num, col, OpenBlock::Def(name, params)));
+                bodies.push(Vec::new());
+                continue;
+            }
+
+            if first == "}" {
+                if line_data.len() > 1 {
+                    return Err(QasmError::new(line_num, line_data[1].0, line_data[1].1, "unexpected token after '}'"));
+                }
+
+                let body: Vec<Block> = bodies.pop().unwrap();
+                match stack.pop() {
+                    Some((_, _, OpenBlock::Repeat(n))) => bodies.last_mut().unwrap().push(Block::Repeat(n, body)),
+                    Some((_, _, OpenBlock::Def(name, params))) => definitions.push(Definition { name: name, params: params, body: body }),
+                    None => return Err(QasmError::new(line_num, col, first, "unmatched '}'")),
+                }
+                continue;
+            }
+
+            // Qubits inside a definition are named by its parameters
+            let params: Option<&Vec<String>> = match stack.first() {
+                Some((_, _, OpenBlock::Def(_, params))) => Some(params),
+                _ => None,
+            };
+
+            if let Some(def) = definitions.iter().position(|def| def.name == first) {
+                let num_params: usize = definitions[def].params.len();
+                if line_data.len() != num_params + 1 {
+                    return Err(QasmError::new(line_num, col, first, &format!("{first} expects {num_params} qubit arguments, found {}", line_data.len() - 1)));
+                }
+
+                let mut qubits: Vec<usize> = Vec::new();
+                for &(col, token) in &line_data[1..] {
+                    let qubit = parse_qubit(line_num, col, token, total_num_qubits, &qregs, params)?;
+                    if qubits.contains(&qubit) {
+                        return Err(QasmError::new(line_num, col, token, "repeated qubit argument"));
                     }
-                    
+                    qubits.push(qubit);
+                }
+                bodies.last_mut().unwrap().push(Block::Call(def, qubits));
+                continue;
+            }
 
+            if first == "if" {
+                // Conditional instruction, e.g. "if r0 == 1 x q2" or "if r0^r1 == 1 z q0"
+                if line_data.len() < 5 || line_data[2].1 != "==" {
+                    return Err(QasmError::new(line_num, col, first, "expected condition of the form 'if r0 == 1 <gate>'"));
+                }
+                if definitions.iter().any(|def| def.name == line_data[4].1) {
+                    return Err(QasmError::new(line_num, line_data[4].0, line_data[4].1, "conditions are only supported on single gates"));
                 }
 
+                let condition = parse_condition(line_num, &line_data[1..4], total_num_cbits, &cregs)?;
+                let mut inst = parse_instruction(line_num, &line_data[4..], &gates, total_num_qubits, total_num_cbits, &qregs, &cregs, params)?;
+                if inst.id.is_non_clifford() && !non_clifford {
+                    return Err(QasmError::new(line_num, line_data[4].0, line_data[4].1, "non-Clifford gates are only supported by from_qasm_non_clifford"));
+                }
+                inst.condition = Some(condition);
+                bodies.last_mut().unwrap().push(Block::Instruction(inst));
+            } else {
+                let inst = parse_instruction(line_num, &line_data, &gates, total_num_qubits, total_num_cbits, &qregs, &cregs, params)?;
+                if inst.id.is_non_clifford() && !non_clifford {
+                    return Err(QasmError::new(line_num, col, first, "non-Clifford gates are only supported by from_qasm_non_clifford"));
+                }
+                bodies.last_mut().unwrap().push(Block::Instruction(inst));
             }
-            instructions.push( Instruction { id: id, qubits: qubits.clone(), cbits: cbits.clone() })
         }
 
-        let classical_data:Vec<i32> = vec![-1; total_num_cbits as usize];
-        let state = Q::new(total_num_qubits);
-        return QuantumProgram { total_num_qubits: total_num_qubits, total_num_cbits: total_num_cbits, 
-                                classical_data: classical_data, quantum_state: state,
-                                circuit: instructions };
+        if let Some((line_num, col, block)) = stack.pop() {
+            let token: &str = match block {
+                OpenBlock::Repeat(_) => "@repeat",
+                OpenBlock::Def(_, _) => "@def",
+            };
+            return Err(QasmError::new(line_num, col, token, "block is never closed"));
+        }
+
+        let mut program = QuantumProgram::from_blocks(total_num_qubits, total_num_cbits, bodies.pop().unwrap());
+        program.definitions = definitions;
+        program.qregs = qregs;
+        program.cregs = cregs;
+        return Ok(program);
     }
 
-    pub fn execute(&mut self) {
-        for inst in &self.circuit {
+    // Formats the program in the native circuit format. Parsing the result gives back the same program.
+    pub fn to_qasm(&self) -> String {
+        let mut circuit: Vec<String> = Vec::new();
+        circuit.push(format!("@pragma total_num_qubits {}", self.total_num_qubits));
+        circuit.push(format!("@pragma total_num_cbits {}", self.total_num_cbits));
+        for register in &self.qregs {
+            circuit.push(format!("@qreg {} {}", register.name, register.size));
+        }
+        for register in &self.cregs {
+            circuit.push(format!("@creg {} {}", register.name, register.size));
+        }
+        for def in &self.definitions {
+            circuit.push(format!("@def {}({}) {{", def.name, def.params.join(", ")));
+            blocks_to_qasm(&def.body, &self.definitions, Some(&def.params), 1, &mut circuit);
+            circuit.push(String::from("}"));
+        }
+        blocks_to_qasm(&self.circuit, &self.definitions, None, 0, &mut circuit);
+
+        return circuit.join("\n");
+    }
+
+    // Formats the program as OpenQASM 2.0; see openqasm::emit for limitations
+    pub fn to_openqasm(&self) -> Result<String, ExportError> {
+        return openqasm::emit(self.total_num_qubits, self.total_num_cbits, &self.instructions());
+    }
+
+    // Formats the program as a Stim circuit, restoring any annotations; see stim::emit for limitations
+    pub fn to_stim(&self) -> Result<String, ExportError> {
+        return stim::emit(&self.circuit, &self.definitions, &self.annotations);
+    }
+
+    // Text diagram of the fully expanded circuit, with one line per qubit
+    pub fn to_ascii(&self) -> String {
+        return render::render_ascii(&self.instructions(), self.total_num_qubits);
+    }
+
+    // Diagram of the fully expanded circuit as a standalone SVG document
+    pub fn to_svg(&self) -> String {
+        return render::render_svg(&self.instructions(), self.total_num_qubits);
+    }
+
+    // Executes blocks, substituting qubits through qubit_map when inside a definition
+    fn execute_blocks(quantum_state: &mut Q, classical_data: &mut Vec<i32>, rng: &mut Lcg64Xsh32, definitions: &Vec<Definition>,
+                      blocks: &Vec<Block>, qubit_map: Option<&Vec<usize>>, non_clifford: Option<NonCliffordGate<Q>>) {
+        for block in blocks {
+            let inst: &Instruction = match block {
+                Block::Instruction(inst) => inst,
+                Block::Repeat(n, body) => {
+                    for _ in 0..*n {
+                        QuantumProgram::execute_blocks(quantum_state, classical_data, rng, definitions, body, qubit_map, non_clifford);
+                    }
+                    continue;
+                },
+                Block::Call(def, args) => {
+                    let args: Vec<usize> = match qubit_map {
+                        Some(map) => args.iter().map(|q| map[*q]).collect(),
+                        None => args.clone(),
+                    };
+                    QuantumProgram::execute_blocks(quantum_state, classical_data, rng, definitions, &definitions[*def].body, Some(&args), non_clifford);
+                    continue;
+                },
+            };
+
+            if let Some(condition) = &inst.condition {
+                if !condition.evaluate(classical_data) {
+                    continue;
+                }
+            }
+
+            let qubit = |i: usize| -> usize {
+                match qubit_map {
+                    Some(map) => map[inst.qubits[i]],
+                    None => inst.qubits[i],
+                }
+            };
+
             match inst.id {
                 Gate::I => (),
-                Gate::X => self.quantum_state.x_gate(inst.qubits[0]), 
-                Gate::Y => self.quantum_state.y_gate(inst.qubits[0]),
-                Gate::Z => self.quantum_state.z_gate(inst.qubits[0]),
-                Gate::H => self.quantum_state.h_gate(inst.qubits[0]),
-                Gate::SQRTX => self.quantum_state.sqrtx_gate(inst.qubits[0]),
-                Gate::SQRTXd => self.quantum_state.sqrtxd_gate(inst.qubits[0]),
-                Gate::SQRTY => self.quantum_state.sqrty_gate(inst.qubits[0]),
-                Gate::SQRTYd => self.quantum_state.sqrtyd_gate(inst.qubits[0]),
-                Gate::SQRTZ => self.quantum_state.sqrtz_gate(inst.qubits[0]),
-                Gate::SQRTZd => self.quantum_state.sqrtzd_gate(inst.qubits[0]),
-                Gate::CX => self.quantum_state.cx_gate(inst.qubits[0], inst.qubits[1]),
-                Gate::CY => self.quantum_state.cy_gate(inst.qubits[0], inst.qubits[1]),
-                Gate::CZ => self.quantum_state.cz_gate(inst.qubits[0], inst.qubits[1]),
-                Gate::MXR => self.classical_data[inst.cbits[0]] = self.quantum_state.mxr_qubit(inst.qubits[0]),
-                Gate::MYR => self.classical_data[inst.cbits[0]] = self.quantum_state.myr_qubit(inst.qubits[0]),
-                Gate::MZR => self.classical_data[inst.cbits[0]] = self.quantum_state.mzr_qubit(inst.qubits[0]),
-                Gate::S => self.quantum_state.s_gate(inst.qubits[0]),
-                Gate::Sd => self.quantum_state.sd_gate(inst.qubits[0]),
-                Gate::PRINT => println!("{}", self.quantum_state.print()),
+                Gate::X => quantum_state.x_gate(qubit(0)), 
+                Gate::Y => quantum_state.y_gate(qubit(0)),
+                Gate::Z => quantum_state.z_gate(qubit(0)),
+                Gate::H => quantum_state.h_gate(qubit(0)),
+                Gate::SQRTX => quantum_state.sqrtx_gate(qubit(0)),
+                Gate::SQRTXd => quantum_state.sqrtxd_gate(qubit(0)),
+                Gate::SQRTY => quantum_state.sqrty_gate(qubit(0)),
+                Gate::SQRTYd => quantum_state.sqrtyd_gate(qubit(0)),
+                Gate::SQRTZ => quantum_state.sqrtz_gate(qubit(0)),
+                Gate::SQRTZd => quantum_state.sqrtzd_gate(qubit(0)),
+                Gate::CX => quantum_state.cx_gate(qubit(0), qubit(1)),
+                Gate::CY => quantum_state.cy_gate(qubit(0), qubit(1)),
+                Gate::CZ => quantum_state.cz_gate(qubit(0), qubit(1)),
+                Gate::MXR => classical_data[inst.cbits[0]] = quantum_state.mxr_qubit(qubit(0)),
+                Gate::MYR => classical_data[inst.cbits[0]] = quantum_state.myr_qubit(qubit(0)),
+                Gate::MZR => classical_data[inst.cbits[0]] = quantum_state.mzr_qubit(qubit(0)),
+                Gate::S => quantum_state.s_gate(qubit(0)),
+                Gate::Sd => quantum_state.sd_gate(qubit(0)),
+                Gate::R => quantum_state.reset_qubit(qubit(0)),
+                Gate::RX => quantum_state.resetx_qubit(qubit(0)),
+                Gate::RY => quantum_state.resety_qubit(qubit(0)),
+                Gate::MR => classical_data[inst.cbits[0]] = quantum_state.mr_qubit(qubit(0)),
+                Gate::Noise(channel) => match qubit_map {
+                    Some(map) => quantum_state.apply_noise(&channel, &inst.qubits.iter().map(|q| map[*q]).collect::<Vec<usize>>(), rng),
+                    None => quantum_state.apply_noise(&channel, &inst.qubits, rng),
+                },
+                Gate::MPP => {
+                    let mut pauli = PauliString::new(quantum_state.system_size());
+                    for (i, p) in inst.paulis.iter().enumerate() {
+                        pauli.set_op(qubit(i), &p.name().to_uppercase());
+                    }
+                    classical_data[inst.cbits[0]] = quantum_state.measure_pauli(&pauli);
+                },
+                Gate::T | Gate::Td | Gate::Rx(_) | Gate::Ry(_) | Gate::Rz(_) | Gate::U3(_, _, _) | Gate::CP(_) => {
+                    // Only programs built for a NonCliffordState can hold these gates
+                    let qubits: Vec<usize> = (0..inst.qubits.len()).map(qubit).collect();
+                    non_clifford.unwrap()(quantum_state, inst.id, &qubits);
+                },
+                Gate::PRINT => println!("{}", quantum_state.print()),
             };
         }
+    }
+
+    // Reinitializes the state and the noise generator from seed, so that execute is reproducible. A
+    // program seeded with the seed of a shot of sample replays that shot exactly.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Lcg64Xsh32::seed_from_u64(seed);
+        self.quantum_state = Q::new_with_seed(self.total_num_qubits, self.rng.next_u64());
+        self.classical_data = vec![-1; self.total_num_cbits];
+    }
 
+    pub fn execute(&mut self) {
+        QuantumProgram::execute_blocks(&mut self.quantum_state, &mut self.classical_data, &mut self.rng, &self.definitions, &self.circuit, None, self.non_clifford);
         self.quantum_state.finish_execution();
     }
     
+    // Seed of shot i of sample and sample_parallel
+    pub fn shot_seed(seed: u64, i: usize) -> u64 {
+        return Lcg64Xsh32::seed_from_u64(seed ^ (i as u64).wrapping_mul(0x9E3779B97F4A7C15)).next_u64();
+    }
+
+    // Runs a single trajectory from a fresh state, returning the classical registers as a bitstring
+    // with r0 first. Registers which are never written to read as 0.
+    fn run_shot(total_num_qubits: usize, total_num_cbits: usize, definitions: &Vec<Definition>, circuit: &Vec<Block>,
+                non_clifford: Option<NonCliffordGate<Q>>, seed: u64) -> String {
+        // The state and the noise channels draw from separate generators
+        let mut rng = Lcg64Xsh32::seed_from_u64(seed);
+        let mut quantum_state = Q::new_with_seed(total_num_qubits, rng.next_u64());
+        let mut classical_data: Vec<i32> = vec![-1; total_num_cbits];
+        QuantumProgram::execute_blocks(&mut quantum_state, &mut classical_data, &mut rng, definitions, circuit, None, non_clifford);
+        quantum_state.finish_execution();
+
+        return classical_data.iter().map(|&c| if c == 1 { '1' } else { '0' }).collect();
+    }
+
+    // Executes the circuit shots times, each on a freshly initialized state, and returns the number of
+    // times each classical bitstring was observed. Shot i is seeded from seed and i, so the histogram
+    // is reproducible and is the same as the one given by sample_parallel.
+    pub fn sample(&self, shots: usize, seed: u64) -> HashMap<String, usize> {
+        return self.sample_shots(shots, seed, false);
+    }
+
+    // As sample, with the shots distributed over the rayon thread pool
+    pub fn sample_parallel(&self, shots: usize, seed: u64) -> HashMap<String, usize> {
+        return self.sample_shots(shots, seed, true);
+    }
+
+    fn sample_shots(&self, shots: usize, seed: u64, parallel: bool) -> HashMap<String, usize> {
+        let (num_qubits, num_cbits) = (self.total_num_qubits, self.total_num_cbits);
+        let (definitions, circuit, non_clifford) = (&self.definitions, &self.circuit, self.non_clifford);
+        let shot_seed = |i: usize| -> u64 { QuantumProgram::<Q>::shot_seed(seed, i) };
+
+        let outcomes: Vec<String> = if parallel {
+            (0..shots).into_par_iter().map(|i| QuantumProgram::<Q>::run_shot(num_qubits, num_cbits, definitions, circuit, non_clifford, shot_seed(i))).collect()
+        } else {
+            (0..shots).map(|i| QuantumProgram::<Q>::run_shot(num_qubits, num_cbits, definitions, circuit, non_clifford, shot_seed(i))).collect()
+        };
+
+        let mut histogram: HashMap<String, usize> = HashMap::new();
+        for outcome in outcomes {
+            *histogram.entry(outcome).or_insert(0) += 1;
+        }
+        return histogram;
+    }
+
+    // Samples shots of a Clifford circuit with a single reference simulation, propagating the
+    // difference of each shot from the reference as a Pauli frame. The histogram has the same form
+    // as the one returned by sample. Classically conditioned instructions make the gates depend on
+    // the shot, and non-Clifford gates do not map Pauli frames to Pauli frames, so circuits with
+    // either are sampled shot by shot with sample_parallel instead.
+    pub fn sample_frames(&self, shots: usize, seed: u64) -> HashMap<String, usize> {
+        let instructions: Vec<Instruction> = self.instructions();
+        if instructions.iter().any(|inst| inst.condition.is_some() || inst.id.is_non_clifford()) {
+            return self.sample_parallel(shots, seed);
+        }
+
+        let mut rng = Lcg64Xsh32::seed_from_u64(seed);
+        let mut frames = QuantumPauliFrameState::new_with_shots(self.total_num_qubits, shots, rng.next_u64());
+        let mut classical_data: Vec<i32> = vec![-1; self.total_num_cbits];
+        QuantumProgram::<QuantumPauliFrameState>::execute_blocks(&mut frames, &mut classical_data, &mut rng, &self.definitions, &self.circuit, None, None);
+
+        // Without conditions every measurement is made exactly once per shot, in circuit order
+        let cbits: Vec<usize> = instructions.iter()
+            .filter(|inst| matches!(inst.id, Gate::MXR | Gate::MYR | Gate::MZR | Gate::MR | Gate::MPP))
+            .map(|inst| inst.cbits[0]).collect();
+
+        let mut histogram: HashMap<String, usize> = HashMap::new();
+        for record in frames.shot_records() {
+            let mut outcome: Vec<char> = vec!['0'; self.total_num_cbits];
+            for (cbit, measured) in cbits.iter().zip(record) {
+                outcome[*cbit] = if measured == 1 { '1' } else { '0' };
+            }
+            *histogram.entry(outcome.into_iter().collect()).or_insert(0) += 1;
+        }
+        return histogram;
+    }
+
+    // Runs the peephole optimizer over the circuit and every definition, returning the number of
+    // gates removed. Each gate is counted once, however often it would be executed.
+    pub fn optimize(&mut self) -> usize {
+        let mut removed: usize = optimize_blocks(&mut self.circuit);
+        for definition in self.definitions.iter_mut() {
+            removed += optimize_blocks(&mut definition.body);
+        }
+        return removed;
+    }
+
+    // Depth, gate counts, measurements per qubit and interaction graph of the fully expanded circuit
+    pub fn analyze(&self) -> CircuitAnalysis {
+        return analysis::analyze(&self.instructions(), self.total_num_qubits);
+    }
+
+    // Backward light cone of qubit, with instructions indexed into the fully expanded circuit
+    pub fn light_cone(&self, qubit: usize) -> LightCone {
+        assert!(qubit < self.total_num_qubits);
+        return analysis::light_cone(&self.instructions(), qubit);
+    }
+
     pub fn get_classical_data(&self) -> Vec<i32> {
         return self.classical_data.clone();
     }
@@ -285,4 +1465,67 @@ impl<Q: QuantumState> QuantumProgram<Q> {
         assert!(register < self.total_num_cbits);
         return self.classical_data[register];
     }
+
+    // Contents of the classical register declared with "@creg name", lowest index first
+    pub fn get_named_classical_register(&self, name: &str) -> Option<Vec<i32>> {
+        let register: &Register = self.cregs.iter().find(|r| r.name == name)?;
+        return Some(self.classical_data[register.offset..(register.offset + register.size)].to_vec());
+    }
+
+    pub fn quantum_registers(&self) -> &Vec<Register> {
+        return &self.qregs;
+    }
+
+    pub fn classical_registers(&self) -> &Vec<Register> {
+        return &self.cregs;
+    }
+
+    // Physical index of element index of a named quantum register
+    pub fn qubit_index(&self, name: &str, index: usize) -> Option<usize> {
+        let register: &Register = self.qregs.iter().find(|r| r.name == name)?;
+        return if index < register.size { Some(register.offset + index) } else { None };
+    }
+
+    // Physical index of element index of a named classical register
+    pub fn cbit_index(&self, name: &str, index: usize) -> Option<usize> {
+        let register: &Register = self.cregs.iter().find(|r| r.name == name)?;
+        return if index < register.size { Some(register.offset + index) } else { None };
+    }
+}
+
+impl<Q: NonCliffordState> QuantumProgram<Q> {
+    // As from_qasm, also accepting the non-Clifford gates t, td, rx, ry, rz, u3 and cp
+    pub fn from_qasm_non_clifford(circuit: &String) -> QuantumProgram<Q> {
+        return match QuantumProgram::try_from_qasm_non_clifford(circuit) {
+            Ok(program) => program,
+            Err(err) => panic!("Error parsing circuit: {err}"),
+        };
+    }
+
+    pub fn try_from_qasm_non_clifford(circuit: &String) -> Result<QuantumProgram<Q>, QasmError> {
+        let mut program = QuantumProgram::parse_qasm(circuit, true)?;
+        program.non_clifford = Some(apply_non_clifford::<Q>);
+        return Ok(program);
+    }
+
+    // As from_openqasm, also accepting the non-Clifford gates t, tdg, rx, ry, rz, u1, u3 and cu1
+    pub fn from_openqasm_non_clifford(circuit: &String) -> QuantumProgram<Q> {
+        return match QuantumProgram::try_from_openqasm_non_clifford(circuit) {
+            Ok(program) => program,
+            Err(err) => panic!("Error parsing circuit: {err}"),
+        };
+    }
+
+    pub fn try_from_openqasm_non_clifford(circuit: &String) -> Result<QuantumProgram<Q>, QasmError> {
+        let (total_num_qubits, total_num_cbits, instructions) = openqasm::parse(circuit, true)?;
+        let mut program = QuantumProgram::from_instructions(total_num_qubits, total_num_cbits, instructions);
+        program.non_clifford = Some(apply_non_clifford::<Q>);
+        return Ok(program);
+    }
+
+    pub(crate) fn from_blocks_non_clifford(total_num_qubits: usize, total_num_cbits: usize, blocks: Vec<Block>) -> QuantumProgram<Q> {
+        let mut program = QuantumProgram::from_blocks(total_num_qubits, total_num_cbits, blocks);
+        program.non_clifford = Some(apply_non_clifford::<Q>);
+        return program;
+    }
 }
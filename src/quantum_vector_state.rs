@@ -1,61 +1,162 @@
 use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use bit_vec::BitVec;
+use num::Float;
 use num::complex::Complex;
+use num::traits::NumAssign;
 use rand_pcg::Lcg64Xsh32;
 use rand::{RngCore, SeedableRng};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::quantum_state::{Entropy, QuantumState, MzrForce, NonCliffordState};
+use crate::quantum_chp_state::PauliString;
+
+// Storage for the bits labelling a basis state, with qubit i stored in bit i
+pub trait BasisBits: Clone + Debug + Eq + Hash + Ord + Send + Sync + Serialize + DeserializeOwned {
+    // Largest number of qubits which can be stored, if there is one
+    fn max_qubits() -> Option<usize>;
+    fn zero(num_qubits: usize) -> Self;
+    fn get(&self, qubit: usize) -> bool;
+    fn flip(&mut self, qubit: usize);
+}
+
+macro_rules! impl_basis_bits {
+    ($t:ty) => {
+        impl BasisBits for $t {
+            fn max_qubits() -> Option<usize> {
+                return Some(<$t>::BITS as usize);
+            }
+
+            fn zero(_num_qubits: usize) -> $t {
+                return 0;
+            }
+
+            fn get(&self, qubit: usize) -> bool {
+                return (self >> qubit) & 1 == 1;
+            }
+
+            fn flip(&mut self, qubit: usize) {
+                *self ^= 1 << qubit;
+            }
+        }
+    };
+}
+
+impl_basis_bits!(u64);
+impl_basis_bits!(u128);
+
+// Arbitrary-length storage, for states with more than 128 qubits
+impl BasisBits for BitVec {
+    fn max_qubits() -> Option<usize> {
+        return None;
+    }
+
+    fn zero(num_qubits: usize) -> BitVec {
+        return BitVec::from_elem(num_qubits, false);
+    }
+
+    fn get(&self, qubit: usize) -> bool {
+        return self[qubit];
+    }
+
+    fn flip(&mut self, qubit: usize) {
+        let val: bool = self[qubit];
+        self.set(qubit, !val);
+    }
+}
+
+// Floating point type of the amplitudes
+pub trait Precision: Float + NumAssign + Debug + std::fmt::Display + Send + Sync + Serialize + DeserializeOwned {
+    // Amplitudes with a smaller norm are pruned from the state
+    fn eps() -> Self;
+}
 
-use crate::quantum_state::{Entropy, QuantumState, MzrForce};
+impl Precision for f32 {
+    fn eps() -> f32 {
+        return 1e-6;
+    }
+}
+
+impl Precision for f64 {
+    fn eps() -> f64 {
+        return 1e-12;
+    }
+}
+
+fn zero<F: Precision>() -> Complex<F> {
+    return Complex::new(F::zero(), F::zero());
+}
+
+fn one<F: Precision>() -> Complex<F> {
+    return Complex::new(F::one(), F::zero());
+}
 
-use std::f32::consts::SQRT_2;
-const ZERO : Complex<f32> = Complex::new(0., 0.);
-const ONE : Complex<f32> = Complex::new(1., 0.);
-const I : Complex<f32> = Complex::new(0., 1.);
-const _SQRT2 : Complex<f32> = Complex::new(1./SQRT_2, 0.);
-const N_SQRT2 : Complex<f32> = Complex::new(-1./SQRT_2, 0.);
-const EPS : f32 = 1e-6;
+fn imag<F: Precision>() -> Complex<F> {
+    return Complex::new(F::zero(), F::one());
+}
 
-const HGATE: [Complex<f32>; 4] = [_SQRT2, _SQRT2, _SQRT2, N_SQRT2]; 
+fn hgate<F: Precision>() -> [Complex<F>; 4] {
+    let r: Complex<F> = Complex::new(F::from(std::f64::consts::FRAC_1_SQRT_2).unwrap(), F::zero());
+    return [r, r, r, -r];
+}
 
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct BasisState {
-    bits : u64,
-    amp : Complex<f32>
+#[serde(bound = "")]
+pub struct BasisState<B: BasisBits, F: Precision> {
+    bits : B,
+    amp : Complex<F>
 }
 
-impl BasisState {
+impl<B: BasisBits, F: Precision> BasisState<B, F> {
     fn qubit_val(&self, qubit : usize) -> u64 {
-        return self.bits >> qubit & 1;
+        return self.bits.get(qubit) as u64;
     }
 }
 
-fn approx_equal(f1: Complex<f32>, f2: Complex<f32>) -> bool {
-    return ((f1.re - f2.re).abs()/f1.norm()) < EPS && ((f1.im - f2.im).abs()/f1.norm()) < EPS;
+fn approx_equal<F: Precision>(f1: Complex<F>, f2: Complex<F>) -> bool {
+    return ((f1.re - f2.re).abs()/f1.norm()) < F::eps() && ((f1.im - f2.im).abs()/f1.norm()) < F::eps();
 }
 
-impl std::cmp::PartialEq for BasisState {
-    fn eq(&self, other: &BasisState) -> bool {
+impl<B: BasisBits, F: Precision> std::cmp::PartialEq for BasisState<B, F> {
+    fn eq(&self, other: &BasisState<B, F>) -> bool {
         return self.bits == other.bits && approx_equal(self.amp, other.amp);
     }
 }
 
+// Sparse state vector, storing only the basis states with nonzero amplitude. B sets how many qubits
+// can be stored and F the precision of the amplitudes.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct QuantumVectorState {
+#[serde(bound = "")]
+pub struct GenericVectorState<B: BasisBits, F: Precision> {
     pub num_qubits : usize,
-    pub state : Vec<BasisState>,
+    pub state : Vec<BasisState<B, F>>,
+
+    // Total probability removed by pruning small amplitudes, which is restored by renormalizing
+    #[serde(default)]
+    discarded_weight : f64,
 
     rng : Lcg64Xsh32,
 }
 
-impl QuantumVectorState {
-    pub fn add_basis(&mut self, bits: u64, amp: Complex<f32>) {
+pub type QuantumVectorState = GenericVectorState<u64, f32>;
+
+impl<B: BasisBits, F: Precision> GenericVectorState<B, F> {
+    pub fn add_basis(&mut self, bits: B, amp: Complex<F>) {
         self.state.push(BasisState { bits: bits, amp: amp });
     }
 
+    // Total probability which has been discarded by pruning over the lifetime of the state
+    pub fn discarded_weight(&self) -> f64 {
+        return self.discarded_weight;
+    }
+
     fn normalize(&mut self) {
-        let mut norm: f32 = 0.;
+        let mut norm: F = F::zero();
         for b in &self.state {
-            norm += b.amp.norm().powi(2);
+            norm += b.amp.norm_sqr();
         }
         norm = norm.sqrt();
         for b in &mut self.state {
@@ -63,41 +164,50 @@ impl QuantumVectorState {
         }
     }
 
-    fn arbitrary_gate(&mut self, qubit: usize, gate: [Complex<f32>; 4]) {
-        let bitshift: u64 = 1 << qubit;
-        let mut new_state_map: HashMap<u64, Complex<f32>> = HashMap::new();
+    // Removes the basis states whose amplitude is below the precision, keeping track of the
+    // probability removed and renormalizing so that it is not lost over long simulations
+    fn prune(&mut self) {
+        let mut discarded: F = F::zero();
+        self.state.retain(|b| {
+            let keep: bool = b.amp.norm() > F::eps();
+            if !keep {
+                discarded += b.amp.norm_sqr();
+            }
+            keep
+        });
+
+        if discarded > F::zero() {
+            self.discarded_weight += discarded.to_f64().unwrap();
+            self.normalize();
+        }
+    }
+
+    fn random_float(&mut self) -> F {
+        return F::from((self.rng.next_u32() as f64) / (u32::MAX as f64)).unwrap();
+    }
+
+    fn arbitrary_gate(&mut self, qubit: usize, gate: [Complex<F>; 4]) {
+        let mut new_state_map: HashMap<B, Complex<F>> = HashMap::new();
         for b in &self.state {
             let qubit_val = b.qubit_val(qubit);
 
-            new_state_map.entry(b.bits).or_insert(ZERO);
-            new_state_map.insert(b.bits, new_state_map[&b.bits] + b.amp*(if qubit_val == 0 { gate[0] } else { gate[3] }));
-
-            new_state_map.entry(b.bits ^ bitshift).or_insert(ZERO);
-            new_state_map.insert(b.bits ^ bitshift, new_state_map[&(b.bits ^ bitshift)] + b.amp*(if qubit_val == 0 { gate[2] } else { gate[1] }));
-        }
+            *new_state_map.entry(b.bits.clone()).or_insert(zero()) += b.amp*(if qubit_val == 0 { gate[0] } else { gate[3] });
 
-        let mut new_state = Vec::new();
-        for (key, val) in &new_state_map {
-            if val.norm() > EPS {
-                new_state.push(BasisState { bits: *key, amp: *val} );
-            }
+            let mut flipped: B = b.bits.clone();
+            flipped.flip(qubit);
+            *new_state_map.entry(flipped).or_insert(zero()) += b.amp*(if qubit_val == 0 { gate[2] } else { gate[1] });
         }
 
-        self.state = new_state;
-
+        self.state = new_state_map.into_iter().map(|(bits, amp)| BasisState { bits: bits, amp: amp }).collect();
+        self.prune();
     }
 
     fn fix_phase(&mut self) {
-        let mut min_basis: u64 = u64::MAX;
-        let mut phase: Complex<f32> = ZERO;
-
         // We fix global phase with respect to smallest basis vector which appears
-        for b in self.state.iter() {
-            if b.bits < min_basis {
-                min_basis = b.bits;
-                phase = b.amp / b.amp.norm();
-            }
-        }
+        let phase: Complex<F> = match self.state.iter().min_by(|b1, b2| b1.bits.cmp(&b2.bits)) {
+            Some(b) => b.amp / b.amp.norm(),
+            None => return,
+        };
 
         for i in 0..self.state.len() {
             self.state[i].amp /= phase;
@@ -109,21 +219,18 @@ impl QuantumVectorState {
     }
 
     // Returns the partial density matrix for the specified qubits
-    pub fn reduced_state(&self, qubits: &Vec<usize>) -> Vec<Vec<Complex<f32>>> {
-        let mut rho: Vec<Vec<Complex<f32>>> = vec![vec![ZERO; 1 << qubits.len()]; 1 << qubits.len()];
-        let mut idx: usize; 
+    pub fn reduced_state(&self, qubits: &Vec<usize>) -> Vec<Vec<Complex<F>>> {
+        let mut rho: Vec<Vec<Complex<F>>> = vec![vec![zero(); 1 << qubits.len()]; 1 << qubits.len()];
+        let mut idx: usize;
         let mut jdx: usize;
 
-        let mut mask: u64 = !0;
-        for i in 0..qubits.len() {
-            mask ^= 1 << qubits[i];
-        }
+        let env: Vec<usize> = (0..self.num_qubits).filter(|q| !qubits.contains(q)).collect();
 
         for b1 in 0..self.state.len() {
             for b2 in 0..self.state.len() {
                 idx = 0;
                 jdx = 0;
-                if (self.state[b1].bits & mask) == (self.state[b2].bits & mask) {
+                if env.iter().all(|q| self.state[b1].bits.get(*q) == self.state[b2].bits.get(*q)) {
                     for j in 0..qubits.len() {
                         idx += (self.state[b1].qubit_val(qubits[j]) as usize) * (1 << j);
                         jdx += (self.state[b2].qubit_val(qubits[j]) as usize) * (1 << j);
@@ -137,10 +244,10 @@ impl QuantumVectorState {
     }
 }
 
-impl Entropy for QuantumVectorState {
+impl<B: BasisBits, F: Precision> Entropy for GenericVectorState<B, F> {
     fn renyi_entropy(&self, qubits: &Vec<usize>) -> f32 {
-        let rhoA: Vec<Vec<Complex<f32>>> = self.reduced_state(qubits);
-        let mut rhoA2: Vec<Vec<Complex<f32>>> = vec![vec![ZERO; rhoA.len()]; rhoA.len()];
+        let rhoA: Vec<Vec<Complex<F>>> = self.reduced_state(qubits);
+        let mut rhoA2: Vec<Vec<Complex<F>>> = vec![vec![zero(); rhoA.len()]; rhoA.len()];
         for i in 0..rhoA.len() {
             for j in 0..rhoA.len() {
                 for k in 0..rhoA.len() {
@@ -149,17 +256,17 @@ impl Entropy for QuantumVectorState {
             }
         }
 
-		let mut s: f32 = 0.;
+		let mut s: F = F::zero();
         for i in 0..rhoA.len() {
             s += rhoA2[i][i].re;
         }
 
-        return -s.log2();
+        return -s.log2().to_f32().unwrap();
     }
 }
 
-impl std::cmp::PartialEq for QuantumVectorState {
-    fn eq(&self, other: &QuantumVectorState) -> bool {
+impl<B: BasisBits, F: Precision> std::cmp::PartialEq for GenericVectorState<B, F> {
+    fn eq(&self, other: &GenericVectorState<B, F>) -> bool {
         if self.num_qubits != other.num_qubits {
             return false;
         }
@@ -168,15 +275,15 @@ impl std::cmp::PartialEq for QuantumVectorState {
             return false;
         }
 
-        let mut state1: QuantumVectorState = self.clone();
+        let mut state1: GenericVectorState<B, F> = self.clone();
         state1.finish_execution();
-        let mut state2: QuantumVectorState = other.clone();
+        let mut state2: GenericVectorState<B, F> = other.clone();
         state2.finish_execution();
 
         for i in 0..self.state.len() {
-            if state1.state[i] != state2.state[i] { 
+            if state1.state[i] != state2.state[i] {
                 println!("{:?} != {:?}", state1.state[i], state2.state[i]);
-                return false 
+                return false
             }
         }
 
@@ -185,19 +292,28 @@ impl std::cmp::PartialEq for QuantumVectorState {
 }
 
 
-impl QuantumState for QuantumVectorState {
-    fn new(num_qubits: usize) -> QuantumVectorState {
-        let mut s : Vec<BasisState> = Vec::new();
-        s.push(BasisState { bits: 0, amp: ONE });
+impl<B: BasisBits, F: Precision> QuantumState for GenericVectorState<B, F> {
+    fn new(num_qubits: usize) -> GenericVectorState<B, F> {
+        if let Some(max_qubits) = B::max_qubits() {
+            assert!(num_qubits <= max_qubits, "at most {max_qubits} qubits can be stored");
+        }
+        let mut s : Vec<BasisState<B, F>> = Vec::new();
+        s.push(BasisState { bits: B::zero(num_qubits), amp: one() });
         let rng = Lcg64Xsh32::from_entropy();
-        return QuantumVectorState { num_qubits: num_qubits, state: s, rng: rng };
+        return GenericVectorState { num_qubits: num_qubits, state: s, discarded_weight: 0., rng: rng };
+    }
+
+    fn new_with_seed(num_qubits: usize, seed: u64) -> GenericVectorState<B, F> {
+        let mut state = GenericVectorState::new(num_qubits);
+        state.rng = Lcg64Xsh32::seed_from_u64(seed);
+        return state;
     }
 
     fn print(&self) -> String {
         let mut s : String = String::from("\n");
 
         for b in self.state.iter() {
-            let bits: String = format!("{:0width$b}", b.bits as usize, width=self.num_qubits).chars().rev().collect();
+            let bits: String = (0..self.num_qubits).map(|q| if b.bits.get(q) { '1' } else { '0' }).collect();
             s += &format!("{}: {:.2}\n", bits, b.amp);
         }
         s = s[0..s.len()-1].to_string();
@@ -210,87 +326,77 @@ impl QuantumState for QuantumVectorState {
 
     fn x_gate(&mut self, qubit: usize) {
         assert!(qubit < self.num_qubits);
-        let bitshift : u64 = 1 << qubit;
         for b in &mut self.state {
-            b.bits ^= bitshift;
+            b.bits.flip(qubit);
         }
     }
 
     fn y_gate(&mut self, qubit: usize) {
         assert!(qubit < self.num_qubits);
-        let bitshift = 1 << qubit;
         for b in &mut self.state {
-            b.amp *= if b.qubit_val(qubit) == 0 { I } else { -I };
-            b.bits ^= bitshift;
-        } 
+            b.amp *= if b.qubit_val(qubit) == 0 { imag() } else { -imag::<F>() };
+            b.bits.flip(qubit);
+        }
     }
 
     fn z_gate(&mut self, qubit: usize) {
         assert!(qubit < self.num_qubits);
         for b in &mut self.state {
-            b.amp *= if b.qubit_val(qubit) == 1 { -ONE } else { ONE }; 
+            b.amp *= if b.qubit_val(qubit) == 1 { -one::<F>() } else { one() };
         }
     }
 
     fn h_gate(&mut self, qubit: usize) {
         assert!(qubit < self.num_qubits);
-        self.arbitrary_gate(qubit, HGATE);
+        self.arbitrary_gate(qubit, hgate());
     }
 
     fn s_gate(&mut self, qubit: usize) {
         assert!(qubit < self.num_qubits);
         for b in &mut self.state {
-            b.amp *= if b.qubit_val(qubit) == 0 { ONE } else { I }; 
+            b.amp *= if b.qubit_val(qubit) == 0 { one() } else { imag() };
         }
     }
-    
-    fn cx_gate(&mut self, qubit1: usize, qubit2: usize) { 
-        let bitshift: u64 = 1 << qubit2;
+
+    fn cx_gate(&mut self, qubit1: usize, qubit2: usize) {
         for b in &mut self.state {
             if b.qubit_val(qubit1) == 1 {
-                b.bits ^= bitshift;
+                b.bits.flip(qubit2);
             }
         }
     }
 
-    fn cy_gate(&mut self, qubit1: usize, qubit2: usize) { 
-        let bitshift: u64 = 1 << qubit2;
+    fn cy_gate(&mut self, qubit1: usize, qubit2: usize) {
         for b in &mut self.state {
             if b.qubit_val(qubit1) == 1 {
-                b.bits ^= bitshift;
-                b.amp *= if b.qubit_val(qubit2) == 0 { I } else { -I };
+                b.amp *= if b.qubit_val(qubit2) == 0 { imag() } else { -imag::<F>() };
+                b.bits.flip(qubit2);
             }
         }
     }
 
-    fn cz_gate(&mut self, qubit1: usize, qubit2: usize) { 
+    fn cz_gate(&mut self, qubit1: usize, qubit2: usize) {
         for b in &mut self.state {
             if b.qubit_val(qubit1) == 1 {
-                b.amp *= if b.qubit_val(qubit2) == 0 { ONE } else { -ONE };
+                b.amp *= if b.qubit_val(qubit2) == 0 { one() } else { -one::<F>() };
             }
-        } 
+        }
     }
 
     fn mzr_qubit(&mut self, qubit: usize) -> i32 {
-        let mut prob_zero: f32 = 0.;
-        let mut prob_one: f32 = 0.;
+        let mut prob_zero: F = F::zero();
         for b in &self.state {
-            match b.qubit_val(qubit) {
-                0 => prob_zero += b.amp.norm().powi(2),
-                1 => prob_one += b.amp.norm().powi(2),
-                _ => panic!()
-            };
+            if b.qubit_val(qubit) == 0 {
+                prob_zero += b.amp.norm_sqr();
+            }
         }
 
-        let p: f32 = ((self.rng.next_u32() as f64) / (u32::MAX as f64)) as f32;
+        let p: F = self.random_float();
         let measured: i32 = if p < prob_zero { 0 } else { 1 };
-        let norm = if p < prob_zero { prob_zero.sqrt() } else { prob_one.sqrt() };
-
-        for b in &mut self.state {
-            if b.qubit_val(qubit) == measured as u64 { b.amp /= norm; } else { b.amp = ZERO; }
-        }
 
-        self.state.retain(|b| b.amp.norm() > EPS);
+        self.state.retain(|b| b.qubit_val(qubit) == measured as u64);
+        self.normalize();
+        self.prune();
 
         return measured;
     }
@@ -302,14 +408,74 @@ impl QuantumState for QuantumVectorState {
         return bit;
     }
 
+    // Projects onto an eigenspace of pauli, i.e. |psi> -> (|psi> +- P|psi>)/2, after sampling the
+    // outcome from <psi|P|psi>
+    fn measure_pauli(&mut self, pauli: &PauliString) -> i32 {
+        let x_qubits: Vec<usize> = (0..pauli.num_qubits).filter(|&i| pauli.x(i)).collect();
+        let z_qubits: Vec<usize> = (0..pauli.num_qubits).filter(|&i| pauli.z(i)).collect();
+        let num_y: usize = (0..pauli.num_qubits).filter(|&i| pauli.x(i) && pauli.z(i)).count();
+
+        // P|b> = (-1)^r i^num_y (-1)^(b.z) |b ^ x>, using Y = iXZ
+        let mut phase: Complex<F> = [one(), imag(), -one::<F>(), -imag::<F>()][num_y % 4];
+        if pauli.r() {
+            phase = -phase;
+        }
+        let image: Vec<BasisState<B, F>> = self.state.iter().map(|b| {
+            let sign: Complex<F> = if z_qubits.iter().filter(|q| b.bits.get(**q)).count() % 2 == 0 { one() } else { -one::<F>() };
+            let mut bits: B = b.bits.clone();
+            for q in &x_qubits {
+                bits.flip(*q);
+            }
+            BasisState { bits: bits, amp: phase*sign*b.amp }
+        }).collect();
+
+        let amplitudes: HashMap<B, Complex<F>> = self.state.iter().map(|b| (b.bits.clone(), b.amp)).collect();
+        let expectation: F = image.iter().map(|b| match amplitudes.get(&b.bits) {
+            Some(amp) => (amp.conj()*b.amp).re,
+            None => F::zero(),
+        }).fold(F::zero(), |sum, x| sum + x);
+        let prob_zero: F = (F::one() + expectation)/F::from(2.).unwrap();
+
+        let p: F = self.random_float();
+        let measured: i32 = if p < prob_zero { 0 } else { 1 };
+        let eigenvalue: Complex<F> = if measured == 0 { one() } else { -one::<F>() };
+
+        let mut projected: HashMap<B, Complex<F>> = amplitudes;
+        for b in image {
+            *projected.entry(b.bits).or_insert(zero()) += eigenvalue*b.amp;
+        }
+
+        self.state = projected.into_iter().map(|(bits, amp)| BasisState { bits: bits, amp: amp }).collect();
+        self.normalize();
+        self.prune();
+
+        return measured;
+    }
+
     fn finish_execution(&mut self) {
         self.fix_phase();
         self.sort_basis();
     }
 }
 
+impl<B: BasisBits, F: Precision> NonCliffordState for GenericVectorState<B, F> {
+    fn unitary_gate(&mut self, qubit: usize, gate: [Complex<f64>; 4]) {
+        assert!(qubit < self.num_qubits);
+        self.arbitrary_gate(qubit, gate.map(|g| Complex::new(F::from(g.re).unwrap(), F::from(g.im).unwrap())));
+    }
 
-impl MzrForce for QuantumVectorState {
+    fn cphase_gate(&mut self, qubit1: usize, qubit2: usize, phi: f64) {
+        let phase: Complex<F> = Complex::from_polar(F::one(), F::from(phi).unwrap());
+        for b in &mut self.state {
+            if b.qubit_val(qubit1) == 1 && b.qubit_val(qubit2) == 1 {
+                b.amp *= phase;
+            }
+        }
+    }
+}
+
+
+impl<B: BasisBits, F: Precision> MzrForce for GenericVectorState<B, F> {
     fn mzr_qubit_forced(&mut self, qubit: usize, outcome: bool) -> bool {
         let mut valid: bool = false;
         for b in &self.state {
@@ -321,13 +487,9 @@ impl MzrForce for QuantumVectorState {
 
         if !valid { return false }
 
-        for b in &mut self.state {
-            if b.qubit_val(qubit) == outcome as u64 {  } else { b.amp = ZERO; }
-        }
-
+        self.state.retain(|b| b.qubit_val(qubit) == outcome as u64);
         self.normalize();
-
-        self.state.retain(|b| b.amp.norm() > EPS);
+        self.prune();
         return true
     }
-}
\ No newline at end of file
+}
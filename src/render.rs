@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use crate::quantum_state::{Condition, Gate, Instruction};
+
+// Circuit diagrams. Instructions are laid out into time-ordered columns, each placed in the first
+// column after every instruction which shares a wire with it, or which writes a classical register
+// it reads or writes, or reads a register it writes. Multi-qubit instructions occupy all
+// the wires between their outermost qubits, so that their connectors never cross another gate, and
+// print pragmas occupy every wire.
+
+// What an instruction draws on a single wire
+#[derive(Clone, Debug, PartialEq)]
+enum Cell {
+    Empty,
+    Gate(String),
+    Control,
+    // A vertical connector passing over a wire which the instruction does not act on
+    Cross,
+    Print,
+}
+
+struct Column {
+    // One cell per wire
+    cells: Vec<Cell>,
+    // Wires joined to the wire below them by a connector
+    connected: Vec<bool>,
+}
+
+impl Column {
+    fn width(&self) -> usize {
+        return self.cells.iter().map(|cell| match cell {
+            Cell::Gate(label) => label.chars().count(),
+            _ => 1,
+        }).max().unwrap_or(1);
+    }
+}
+
+fn condition_label(condition: &Condition) -> String {
+    return match condition {
+        Condition::Equals(cbits, val) => format!("{}=={val}", cbits.iter().map(|c| format!("r{c}")).collect::<Vec<String>>().join(",")),
+        Condition::Parity(cbits, val) => format!("{}=={val}", cbits.iter().map(|c| format!("r{c}")).collect::<Vec<String>>().join("^")),
+    };
+}
+
+// Cells drawn by inst on each of its qubits
+fn gate_cells(inst: &Instruction) -> Vec<Cell> {
+    let name: String = inst.id.name().to_uppercase();
+    let mut cells: Vec<Cell> = match inst.id {
+        Gate::CX => vec![Cell::Control, Cell::Gate(String::from("X"))],
+        Gate::CY => vec![Cell::Control, Cell::Gate(String::from("Y"))],
+        Gate::CZ => vec![Cell::Control, Cell::Control],
+        Gate::MXR => vec![Cell::Gate(format!("MX->r{}", inst.cbits[0]))],
+        Gate::MYR => vec![Cell::Gate(format!("MY->r{}", inst.cbits[0]))],
+        Gate::MZR => vec![Cell::Gate(format!("M->r{}", inst.cbits[0]))],
+        Gate::MR => vec![Cell::Gate(format!("MR->r{}", inst.cbits[0]))],
+        Gate::MPP => inst.paulis.iter().enumerate().map(|(i, p)| {
+            let label: String = format!("MPP:{}", p.name().to_uppercase());
+            Cell::Gate(if i == 0 { format!("{label}->r{}", inst.cbits[0]) } else { label })
+        }).collect(),
+        Gate::R => vec![Cell::Gate(String::from("R"))],
+        Gate::RX => vec![Cell::Gate(String::from("RX"))],
+        Gate::RY => vec![Cell::Gate(String::from("RY"))],
+        Gate::Noise(channel) => {
+            let probs: Vec<String> = channel.probs().iter().map(|p| p.to_string()).collect();
+            vec![Cell::Gate(format!("{name}({})", probs.join(","))); inst.qubits.len()]
+        },
+        Gate::CP(phi) => vec![Cell::Control, Cell::Gate(format!("P({phi})"))],
+        Gate::Rx(_) | Gate::Ry(_) | Gate::Rz(_) | Gate::U3(_, _, _) => {
+            let angles: Vec<String> = inst.id.params().iter().map(|a| a.to_string()).collect();
+            vec![Cell::Gate(format!("{name}({})", angles.join(",")))]
+        },
+        _ => vec![Cell::Gate(name)],
+    };
+
+    if let Some(condition) = &inst.condition {
+        let label: String = format!("[{}]", condition_label(condition));
+        cells = cells.into_iter().map(|cell| match cell {
+            Cell::Gate(name) => Cell::Gate(name + &label),
+            Cell::Control => Cell::Gate(String::from("@") + &label),
+            cell => cell,
+        }).collect();
+    }
+
+    return cells;
+}
+
+fn layout(instructions: &Vec<Instruction>, num_qubits: usize) -> Vec<Column> {
+    let mut columns: Vec<Column> = Vec::new();
+    if num_qubits == 0 {
+        return columns;
+    }
+
+    // Index of the first column in which each wire is free
+    let mut free: Vec<usize> = vec![0; num_qubits];
+    // Index of the first column in which each classical register may be read, and written
+    let mut read_free: HashMap<usize, usize> = HashMap::new();
+    let mut write_free: HashMap<usize, usize> = HashMap::new();
+
+    for inst in instructions {
+        let (low, high) = match inst.id {
+            Gate::PRINT => (0, num_qubits - 1),
+            _ => (*inst.qubits.iter().min().unwrap(), *inst.qubits.iter().max().unwrap()),
+        };
+        let reads: &[usize] = match &inst.condition {
+            Some(condition) => condition.cbits(),
+            None => &[],
+        };
+
+        let c: usize = free[low..=high].iter()
+            .chain(reads.iter().filter_map(|r| read_free.get(r)))
+            .chain(inst.cbits.iter().filter_map(|w| write_free.get(w)))
+            .cloned().max().unwrap();
+        if c == columns.len() {
+            columns.push(Column { cells: vec![Cell::Empty; num_qubits], connected: vec![false; num_qubits] });
+        }
+        let column: &mut Column = &mut columns[c];
+
+        if let Gate::PRINT = inst.id {
+            column.cells = vec![Cell::Print; num_qubits];
+        } else {
+            for q in low..=high {
+                column.cells[q] = Cell::Cross;
+            }
+            for (q, cell) in inst.qubits.iter().zip(gate_cells(inst)) {
+                column.cells[*q] = cell;
+            }
+        }
+        for q in low..high {
+            column.connected[q] = true;
+        }
+        for q in low..=high {
+            free[q] = c + 1;
+        }
+        for r in reads {
+            let w: &mut usize = write_free.entry(*r).or_insert(0);
+            *w = (*w).max(c + 1);
+        }
+        for w in &inst.cbits {
+            read_free.insert(*w, c + 1);
+            write_free.insert(*w, c + 1);
+        }
+    }
+
+    return columns;
+}
+
+// Draws the circuit with one line per qubit, separated by lines carrying the connectors of
+// multi-qubit gates
+pub(crate) fn render_ascii(instructions: &Vec<Instruction>, num_qubits: usize) -> String {
+    let columns: Vec<Column> = layout(instructions, num_qubits);
+    let prefix_width: usize = format!("q{}: ", num_qubits.max(1) - 1).len();
+
+    let mut lines: Vec<String> = Vec::new();
+    for q in 0..num_qubits {
+        let mut wire: String = format!("{:<prefix_width$}", format!("q{q}: "));
+        let mut spacer: String = " ".repeat(prefix_width);
+
+        for column in &columns {
+            let width: usize = column.width();
+            let center: usize = (width - 1)/2;
+            let label: String = match &column.cells[q] {
+                Cell::Empty => String::from("-"),
+                Cell::Gate(label) => label.clone(),
+                Cell::Control => String::from("@"),
+                Cell::Cross => String::from("|"),
+                Cell::Print => String::from("#"),
+            };
+
+            // Single characters sit on the connector; longer labels are centered on it
+            let left: usize = if label.chars().count() == 1 { center } else { (width - label.chars().count())/2 };
+            let right: usize = width - left - label.chars().count();
+            wire += &format!("-{}{}{}-", "-".repeat(left), label, "-".repeat(right));
+
+            let joined: bool = column.connected[q] || column.cells[q] == Cell::Print && q + 1 < num_qubits;
+            let link: char = if column.cells[q] == Cell::Print { '#' } else { '|' };
+            spacer += &format!(" {}{}{} ", " ".repeat(center), if joined { link } else { ' ' }, " ".repeat(width - center - 1));
+        }
+
+        lines.push(wire);
+        if q + 1 < num_qubits {
+            lines.push(String::from(spacer.trim_end()));
+        }
+    }
+
+    return lines.join("\n");
+}
+
+fn escape(label: &str) -> String {
+    return label.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+}
+
+const WIRE_SPACING: usize = 40;
+const CHAR_WIDTH: usize = 8;
+const MARGIN: usize = 40;
+
+// Draws the circuit as a standalone SVG document
+pub(crate) fn render_svg(instructions: &Vec<Instruction>, num_qubits: usize) -> String {
+    let columns: Vec<Column> = layout(instructions, num_qubits);
+    let widths: Vec<usize> = columns.iter().map(|column| column.width()*CHAR_WIDTH + 16).collect();
+    let total_width: usize = 2*MARGIN + widths.iter().map(|w| w + 10).sum::<usize>();
+    let total_height: usize = MARGIN + num_qubits*WIRE_SPACING;
+    let y = |q: usize| -> usize { MARGIN + q*WIRE_SPACING };
+
+    let mut svg: Vec<String> = Vec::new();
+    svg.push(format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{total_width}\" height=\"{total_height}\" font-family=\"monospace\" font-size=\"12\">"));
+    svg.push(format!("<rect width=\"{total_width}\" height=\"{total_height}\" fill=\"white\"/>"));
+    for q in 0..num_qubits {
+        svg.push(format!("<text x=\"4\" y=\"{}\" dominant-baseline=\"middle\">q{q}</text>", y(q)));
+        svg.push(format!("<line x1=\"{MARGIN}\" y1=\"{0}\" x2=\"{1}\" y2=\"{0}\" stroke=\"black\"/>", y(q), total_width - MARGIN/2));
+    }
+
+    let mut x0: usize = MARGIN + 5;
+    for (column, width) in columns.iter().zip(&widths) {
+        let x: usize = x0 + width/2;
+        for q in 0..num_qubits {
+            if column.connected[q] && column.cells[q] != Cell::Print {
+                svg.push(format!("<line x1=\"{x}\" y1=\"{}\" x2=\"{x}\" y2=\"{}\" stroke=\"black\"/>", y(q), y(q + 1)));
+            }
+        }
+
+        for (q, cell) in column.cells.iter().enumerate() {
+            match cell {
+                Cell::Gate(label) => {
+                    let box_width: usize = label.chars().count()*CHAR_WIDTH + 16;
+                    svg.push(format!("<rect x=\"{}\" y=\"{}\" width=\"{box_width}\" height=\"24\" fill=\"white\" stroke=\"black\"/>", x - box_width/2, y(q) - 12));
+                    svg.push(format!("<text x=\"{x}\" y=\"{}\" text-anchor=\"middle\" dominant-baseline=\"middle\">{}</text>", y(q), escape(label)));
+                },
+                Cell::Control => svg.push(format!("<circle cx=\"{x}\" cy=\"{}\" r=\"4\" fill=\"black\"/>", y(q))),
+                Cell::Print => {
+                    if q == 0 {
+                        svg.push(format!("<line x1=\"{x}\" y1=\"{}\" x2=\"{x}\" y2=\"{}\" stroke=\"gray\" stroke-dasharray=\"4\"/>", y(0) - 16, y(num_qubits - 1) + 16));
+                        svg.push(format!("<text x=\"{x}\" y=\"{}\" text-anchor=\"middle\">print</text>", y(0) - 20));
+                    }
+                },
+                Cell::Empty | Cell::Cross => (),
+            }
+        }
+
+        x0 += width + 10;
+    }
+
+    svg.push(String::from("</svg>"));
+    return svg.join("\n");
+}
@@ -0,0 +1,631 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::noise::NoiseChannel;
+use crate::quantum_state::{flatten_blocks, split_tokens, Block, Condition, Definition, ExportError, Gate, Instruction, QasmError};
+
+// Reader and writer for the Stim circuit language. Each measurement is written to the next classical
+// register, mirroring Stim's measurement record. REPEAT blocks are kept as repeat blocks, whose
+// measurements reuse the same registers on every iteration, unless that would lose a record entry
+// which is read later or the body holds annotations; such blocks are unrolled. Annotations which do
+// not affect the state are kept verbatim so that they can be written back out.
+
+const ANNOTATIONS: [&str; 5] = ["DETECTOR", "OBSERVABLE_INCLUDE", "QUBIT_COORDS", "SHIFT_COORDS", "TICK"];
+
+fn single_qubit_gate(name: &str) -> Option<Vec<Gate>> {
+    let gates = match name {
+        "I" => vec![Gate::I],
+        "X" => vec![Gate::X],
+        "Y" => vec![Gate::Y],
+        "Z" => vec![Gate::Z],
+        "H" | "H_XZ" => vec![Gate::H],
+        "S" | "SQRT_Z" => vec![Gate::S],
+        "S_DAG" | "SQRT_Z_DAG" => vec![Gate::Sd],
+        "SQRT_X" => vec![Gate::SQRTX],
+        "SQRT_X_DAG" => vec![Gate::SQRTXd],
+        "SQRT_Y" => vec![Gate::SQRTY],
+        "SQRT_Y_DAG" => vec![Gate::SQRTYd],
+        "R" | "RZ" => vec![Gate::R],
+        "RX" => vec![Gate::RX],
+        "RY" => vec![Gate::RY],
+        _ => return None,
+    };
+
+    return Some(gates);
+}
+
+fn measurement(name: &str) -> Option<Gate> {
+    match name {
+        "M" | "MZ" => Some(Gate::MZR),
+        "MX" => Some(Gate::MXR),
+        "MY" => Some(Gate::MYR),
+        "MR" | "MRZ" => Some(Gate::MR),
+        _ => None,
+    }
+}
+
+fn noise_channel(name: &str) -> Option<NoiseChannel> {
+    let name: &str = match name {
+        "PAULI_CHANNEL_1" => "PAULI_CHANNEL",
+        "DEPOLARIZE1" | "DEPOLARIZE2" | "X_ERROR" | "Z_ERROR" => name,
+        _ => return None,
+    };
+    return NoiseChannel::from_name(name).map(|(channel, _)| channel);
+}
+
+fn two_qubit_gate(name: &str) -> Option<Gate> {
+    match name {
+        "CX" | "CNOT" | "ZCX" => Some(Gate::CX),
+        "CY" | "ZCY" => Some(Gate::CY),
+        "CZ" | "ZCZ" => Some(Gate::CZ),
+        _ => None,
+    }
+}
+
+// A single line of a Stim circuit; the name, its parenthesized arguments if any, and its targets
+struct Operation {
+    line: usize,
+    col: usize,
+    name: String,
+    args: Option<String>,
+    targets: Vec<(usize, String)>,
+    text: String,
+}
+
+enum Statement {
+    Operation(Operation),
+    // Index of the block in order of appearance, the number of repetitions and the body
+    Repeat(usize, usize, Vec<Statement>),
+}
+
+fn parse_operation(line_num: usize, line: &str) -> Result<Operation, QasmError> {
+    let start: usize = line.len() - line.trim_start().len();
+    let mut end: usize = start;
+    for (i, c) in line[start..].char_indices() {
+        if c.is_whitespace() || c == '(' {
+            break;
+        }
+        end = start + i + c.len_utf8();
+    }
+    let name: &str = &line[start..end];
+
+    let mut args: Option<String> = None;
+    let mut rest: usize = end;
+    if line[end..].starts_with('(') {
+        match line[end..].find(')') {
+            Some(i) => {
+                args = Some(String::from(&line[(end + 1)..(end + i)]));
+                rest = end + i + 1;
+            },
+            None => return Err(QasmError::new(line_num, end + 1, &line[end..], "unterminated argument list")),
+        }
+    }
+
+    let targets: Vec<(usize, String)> = split_tokens(&line[rest..]).into_iter().map(|(col, t)| (col + rest, String::from(t))).collect();
+    return Ok(Operation { line: line_num, col: start + 1, name: name.to_uppercase(), args: args, targets: targets,
+                          text: String::from(line.trim()) });
+}
+
+// Parses lines into statements until the end of input or a closing brace. Returns the statements
+// and whether the block was closed by a brace.
+fn parse_block(lines: &mut std::iter::Peekable<std::iter::Enumerate<std::str::Lines>>, num_repeats: &mut usize) -> Result<(Vec<Statement>, bool), QasmError> {
+    let mut statements: Vec<Statement> = Vec::new();
+    while let Some((line_idx, raw_line)) = lines.next() {
+        let line_num: usize = line_idx + 1;
+        let line: &str = match raw_line.find('#') {
+            Some(i) => &raw_line[0..i],
+            None => raw_line,
+        };
+        let tokens: Vec<(usize, &str)> = split_tokens(line);
+        if tokens.len() == 0 {
+            continue;
+        }
+
+        if tokens[0].1 == "}" {
+            if tokens.len() > 1 {
+                return Err(QasmError::new(line_num, tokens[1].0, tokens[1].1, "unexpected token after '}'"));
+            }
+            return Ok((statements, true));
+        }
+
+        if tokens[0].1.to_uppercase() == "REPEAT" {
+            if tokens.len() != 3 || tokens[2].1 != "{" {
+                return Err(QasmError::new(line_num, tokens[0].0, tokens[0].1, "expected 'REPEAT <count> {'"));
+            }
+            let count: usize = match tokens[1].1.parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => return Err(QasmError::new(line_num, tokens[1].0, tokens[1].1, "expected a non-negative integer")),
+            };
+
+            let id: usize = *num_repeats;
+            *num_repeats += 1;
+            let (body, closed) = parse_block(lines, num_repeats)?;
+            if !closed {
+                return Err(QasmError::new(line_num, tokens[0].0, tokens[0].1, "REPEAT block is never closed"));
+            }
+            statements.push(Statement::Repeat(id, count, body));
+            continue;
+        }
+
+        statements.push(Statement::Operation(parse_operation(line_num, line)?));
+    }
+
+    return Ok((statements, false));
+}
+
+// Target of an operation; either a qubit, possibly with an inverted result, or an entry of the
+// measurement record
+enum Target {
+    Qubit(usize, bool),
+    Record(usize),
+}
+
+struct Lowering {
+    num_qubits: usize,
+    num_cbits: usize,
+    // Classical register holding each entry of the measurement record, or the index of the REPEAT
+    // block whose later iterations overwrite it
+    record: Vec<Result<usize, usize>>,
+    // Earliest entry of the measurement record read by an operation in the block being lowered
+    earliest_read: usize,
+    blocks: Vec<Block>,
+    // Number of instructions in the expanded circuit, which positions annotations
+    num_instructions: usize,
+    annotations: Vec<(usize, String)>,
+
+    // REPEAT blocks which have to be unrolled, and the block found to need unrolling when lowering fails
+    unrolled: HashSet<usize>,
+    unroll: Option<usize>,
+}
+
+impl Lowering {
+    fn new(unrolled: HashSet<usize>) -> Self {
+        return Lowering { num_qubits: 0, num_cbits: 0, record: Vec::new(), earliest_read: usize::MAX, blocks: Vec::new(),
+                          num_instructions: 0, annotations: Vec::new(), unrolled: unrolled, unroll: None };
+    }
+
+    // Position in the measurement record of a rec[-k] target
+    fn lookback(&self, op: &Operation, col: usize, token: &str) -> Option<Result<usize, QasmError>> {
+        let offset: &str = token.strip_prefix("rec[-").and_then(|t| t.strip_suffix(']'))?;
+        return Some(match offset.parse::<usize>() {
+            Ok(k) if k >= 1 && k <= self.record.len() => Ok(self.record.len() - k),
+            Ok(_) => Err(QasmError::new(op.line, col, token, "measurement record lookback out of range")),
+            Err(_) => Err(QasmError::new(op.line, col, token, "expected measurement record target rec[-k]")),
+        });
+    }
+
+    // Marks block to be unrolled, returning an error which stops lowering so that it can start over
+    fn unroll(&mut self, block: usize) -> QasmError {
+        self.unroll = Some(block);
+        return QasmError::new(0, 0, "REPEAT", "REPEAT block has to be unrolled");
+    }
+
+    fn target(&mut self, op: &Operation, col: usize, token: &str) -> Result<Target, QasmError> {
+        if let Some(position) = self.lookback(op, col, token) {
+            let position: usize = position?;
+            self.earliest_read = std::cmp::min(self.earliest_read, position);
+            return match self.record[position] {
+                Ok(cbit) => Ok(Target::Record(cbit)),
+                Err(block) => Err(self.unroll(block)),
+            };
+        }
+
+        let (inverted, index) = match token.strip_prefix('!') {
+            Some(index) => (true, index),
+            None => (false, token),
+        };
+        return match index.parse::<usize>() {
+            Ok(q) => {
+                self.num_qubits = std::cmp::max(self.num_qubits, q + 1);
+                Ok(Target::Qubit(q, inverted))
+            },
+            Err(_) => Err(QasmError::new(op.line, col, token, "unsupported target")),
+        };
+    }
+
+    fn qubit(&mut self, op: &Operation, col: usize, token: &str) -> Result<usize, QasmError> {
+        return match self.target(op, col, token)? {
+            Target::Qubit(q, false) => Ok(q),
+            _ => Err(QasmError::new(op.line, col, token, &format!("{} expects qubit targets", op.name))),
+        };
+    }
+
+    fn push(&mut self, id: Gate, qubits: Vec<usize>, cbits: Vec<usize>, condition: Option<Condition>) {
+        self.push_instruction(Instruction { id: id, qubits: qubits, cbits: cbits, condition: condition, paulis: Vec::new() });
+    }
+
+    fn push_instruction(&mut self, inst: Instruction) {
+        self.blocks.push(Block::Instruction(inst));
+        self.num_instructions += 1;
+    }
+
+    // Appends a measurement to the record, returning the classical register it is written to
+    fn record_measurement(&mut self) -> usize {
+        let cbit: usize = self.num_cbits;
+        self.num_cbits += 1;
+        self.record.push(Ok(cbit));
+        return cbit;
+    }
+
+    fn operation(&mut self, op: &Operation) -> Result<(), QasmError> {
+        if ANNOTATIONS.contains(&op.name.as_str()) {
+            // Evaluate record targets so that invalid lookbacks are still reported
+            for (col, token) in &op.targets {
+                if token.starts_with("rec[") {
+                    self.lookback(op, *col, token).unwrap_or_else(|| Err(QasmError::new(op.line, *col, token, "expected measurement record target rec[-k]")))?;
+                }
+            }
+            self.annotations.push((self.num_instructions, op.text.clone()));
+            return Ok(());
+        }
+
+        if let Some(channel) = noise_channel(&op.name) {
+            let args: &str = op.args.as_deref().unwrap_or("");
+            let probs: Vec<f32> = match args.split(',').map(|p| p.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>() {
+                Ok(probs) if probs.len() == channel.probs().len() => probs,
+                _ => return Err(QasmError::new(op.line, op.col, &op.name, &format!("expected {} probabilities", channel.probs().len()))),
+            };
+            let channel = channel.with_probs(&probs).map_err(|msg| QasmError::new(op.line, op.col, &op.name, &msg))?;
+
+            let num_qubits: usize = channel.num_qubits();
+            if op.targets.len() % num_qubits != 0 {
+                let (col, token) = &op.targets[op.targets.len() - 1];
+                return Err(QasmError::new(op.line, *col, token, &format!("{} expects an even number of targets", op.name)));
+            }
+            for group in op.targets.chunks(num_qubits) {
+                let mut qubits: Vec<usize> = Vec::new();
+                for (col, token) in group {
+                    qubits.push(self.qubit(op, *col, token)?);
+                }
+                self.push(Gate::Noise(channel), qubits, Vec::new(), None);
+            }
+            return Ok(());
+        }
+
+        if op.args.is_some() {
+            return Err(QasmError::new(op.line, op.col, &op.name, "parenthesized arguments are only supported on annotations and noise channels"));
+        }
+
+        if let Some(gates) = single_qubit_gate(&op.name) {
+            for (col, token) in &op.targets {
+                let q = self.qubit(op, *col, token)?;
+                for id in &gates {
+                    self.push(*id, vec![q], Vec::new(), None);
+                }
+            }
+        } else if let Some(id) = measurement(&op.name) {
+            for (col, token) in &op.targets {
+                let (q, inverted) = match self.target(op, *col, token)? {
+                    Target::Qubit(q, inverted) => (q, inverted),
+                    Target::Record(_) => return Err(QasmError::new(op.line, *col, token, "cannot measure a measurement record")),
+                };
+
+                // An inverted result is obtained by flipping the measured observable before and, unless
+                // the qubit is reset, after the measurement
+                let flip: Gate = if let Gate::MZR | Gate::MR = id { Gate::X } else { Gate::Z };
+                if inverted {
+                    self.push(flip, vec![q], Vec::new(), None);
+                }
+                let cbit: usize = self.record_measurement();
+                self.push(id, vec![q], vec![cbit], None);
+                if inverted && id != Gate::MR {
+                    self.push(flip, vec![q], Vec::new(), None);
+                }
+            }
+        } else if op.name == "MPP" {
+            // Each target is a product such as X0*Z3*Y7, measured into its own register
+            for (col, token) in &op.targets {
+                let mut qubits: Vec<usize> = Vec::new();
+                let mut paulis: Vec<Gate> = Vec::new();
+                for factor in token.split('*') {
+                    let pauli: Gate = match factor.chars().next().map(|c| c.to_ascii_uppercase()) {
+                        Some('X') => Gate::X,
+                        Some('Y') => Gate::Y,
+                        Some('Z') => Gate::Z,
+                        _ => return Err(QasmError::new(op.line, *col, token, "expected a Pauli product such as X0*Z1")),
+                    };
+                    let q = self.qubit(op, *col, &factor[1..])?;
+                    if qubits.contains(&q) {
+                        return Err(QasmError::new(op.line, *col, token, "repeated qubit argument"));
+                    }
+                    qubits.push(q);
+                    paulis.push(pauli);
+                }
+
+                let cbit: usize = self.record_measurement();
+                self.push_instruction(Instruction { id: Gate::MPP, qubits: qubits, cbits: vec![cbit], condition: None, paulis: paulis });
+            }
+        } else if let Some(id) = two_qubit_gate(&op.name) {
+            if op.targets.len() % 2 != 0 {
+                let (col, token) = &op.targets[op.targets.len() - 1];
+                return Err(QasmError::new(op.line, *col, token, &format!("{} expects an even number of targets", op.name)));
+            }
+
+            for pair in op.targets.chunks(2) {
+                let (col1, token1) = &pair[0];
+                let (col2, token2) = &pair[1];
+                let control = self.target(op, *col1, token1)?;
+                let target = self.target(op, *col2, token2)?;
+
+                // Classically controlled Paulis; CZ is symmetric, so either side may be a record
+                let pauli: Gate = match id {
+                    Gate::CX => Gate::X,
+                    Gate::CY => Gate::Y,
+                    _ => Gate::Z,
+                };
+                match (control, target) {
+                    (Target::Qubit(q1, false), Target::Qubit(q2, false)) => {
+                        if q1 == q2 {
+                            return Err(QasmError::new(op.line, *col2, token2, "repeated qubit argument"));
+                        }
+                        self.push(id, vec![q1, q2], Vec::new(), None);
+                    },
+                    (Target::Record(c), Target::Qubit(q, false)) => self.push(pauli, vec![q], Vec::new(), Some(Condition::Equals(vec![c], 1))),
+                    (Target::Qubit(q, false), Target::Record(c)) if id == Gate::CZ => self.push(pauli, vec![q], Vec::new(), Some(Condition::Equals(vec![c], 1))),
+                    _ => return Err(QasmError::new(op.line, *col1, token1, &format!("unsupported targets for {}", op.name))),
+                }
+            }
+        } else {
+            return Err(QasmError::new(op.line, op.col, &op.name, "unsupported instruction"));
+        }
+
+        return Ok(());
+    }
+
+    fn statements(&mut self, statements: &Vec<Statement>) -> Result<(), QasmError> {
+        for statement in statements {
+            match statement {
+                Statement::Operation(op) => self.operation(op)?,
+                Statement::Repeat(id, count, body) if *count == 0 || self.unrolled.contains(id) => {
+                    for _ in 0..*count {
+                        self.statements(body)?;
+                    }
+                },
+                Statement::Repeat(id, count, body) => self.repeat(*id, *count, body)?,
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Lowers the body of a REPEAT block once into a repeat block
+    fn repeat(&mut self, id: usize, count: usize, body: &Vec<Statement>) -> Result<(), QasmError> {
+        let outer: Vec<Block> = std::mem::take(&mut self.blocks);
+        let outer_earliest_read: usize = std::mem::replace(&mut self.earliest_read, usize::MAX);
+        let (start, num_annotations, num_instructions) = (self.record.len(), self.annotations.len(), self.num_instructions);
+        self.statements(body)?;
+        let body_blocks: Vec<Block> = std::mem::replace(&mut self.blocks, outer);
+
+        // Reading an entry made before the block would read a different entry on every iteration,
+        // unless the body makes no measurements
+        let num_measurements: usize = self.record.len() - start;
+        if self.annotations.len() > num_annotations || (num_measurements > 0 && self.earliest_read < start) {
+            return Err(self.unroll(id));
+        }
+        self.earliest_read = std::cmp::min(outer_earliest_read, self.earliest_read);
+
+        // Only the entries of the last iteration are left in the classical registers
+        self.record.splice(start..start, vec![Err(id); (count - 1)*num_measurements]);
+        self.num_instructions = num_instructions + count*(self.num_instructions - num_instructions);
+        self.blocks.push(Block::Repeat(count, body_blocks));
+        return Ok(());
+    }
+}
+
+// Parses a Stim circuit, returning the number of qubits, the number of classical registers, the
+// lowered blocks and the annotations, each paired with the index in the expanded circuit of the
+// instruction it precedes.
+pub(crate) fn parse(source: &str) -> Result<(usize, usize, Vec<Block>, Vec<(usize, String)>), QasmError> {
+    let mut lines = source.lines().enumerate().peekable();
+    let mut num_repeats: usize = 0;
+    let (statements, closed) = parse_block(&mut lines, &mut num_repeats)?;
+    if closed {
+        let line_num = source.lines().enumerate().filter(|(_, l)| l.trim() == "}").map(|(i, _)| i + 1).next().unwrap_or(1);
+        return Err(QasmError::new(line_num, 1, "}", "unmatched '}'"));
+    }
+
+    // Whether a REPEAT block can be kept may only become clear after it has been lowered, in which
+    // case lowering starts over with the block unrolled
+    let mut unrolled: HashSet<usize> = HashSet::new();
+    loop {
+        let mut lowering = Lowering::new(unrolled.clone());
+        match (lowering.statements(&statements), lowering.unroll) {
+            (Ok(()), _) => return Ok((lowering.num_qubits, lowering.num_cbits, lowering.blocks, lowering.annotations)),
+            (Err(_), Some(id)) => { unrolled.insert(id); },
+            (Err(err), None) => return Err(err),
+        }
+    }
+}
+
+fn gate_name(id: Gate) -> &'static str {
+    match id {
+        Gate::I => "I",
+        Gate::X => "X",
+        Gate::Y => "Y",
+        Gate::Z => "Z",
+        Gate::H => "H",
+        Gate::S | Gate::SQRTZ => "S",
+        Gate::Sd | Gate::SQRTZd => "S_DAG",
+        Gate::SQRTX => "SQRT_X",
+        Gate::SQRTXd => "SQRT_X_DAG",
+        Gate::SQRTY => "SQRT_Y",
+        Gate::SQRTYd => "SQRT_Y_DAG",
+        Gate::CX => "CX",
+        Gate::CY => "CY",
+        Gate::CZ => "CZ",
+        Gate::MXR => "MX",
+        Gate::MYR => "MY",
+        Gate::MZR => "M",
+        Gate::R => "R",
+        Gate::RX => "RX",
+        Gate::RY => "RY",
+        Gate::MR => "MR",
+        Gate::MPP => "MPP",
+        Gate::Noise(NoiseChannel::Depolarize1(_)) => "DEPOLARIZE1",
+        Gate::Noise(NoiseChannel::Depolarize2(_)) => "DEPOLARIZE2",
+        Gate::Noise(NoiseChannel::XError(_)) => "X_ERROR",
+        Gate::Noise(NoiseChannel::ZError(_)) => "Z_ERROR",
+        Gate::Noise(NoiseChannel::PauliChannel(_, _, _)) => "PAULI_CHANNEL_1",
+        Gate::PRINT => "# @pragma print",
+        // Rejected by Emitter::instruction
+        Gate::T | Gate::Td | Gate::Rx(_) | Gate::Ry(_) | Gate::Rz(_) | Gate::U3(_, _, _) | Gate::CP(_) => unreachable!(),
+    }
+}
+
+// Whether inst writes a measurement to the record
+fn is_measurement(inst: &Instruction) -> bool {
+    return matches!(inst.id, Gate::MXR | Gate::MYR | Gate::MZR | Gate::MR | Gate::MPP);
+}
+
+struct Emitter<'a> {
+    definitions: &'a Vec<Definition>,
+    annotations: &'a [(usize, String)],
+    lines: Vec<String>,
+
+    // Position in the measurement record of the latest measurement into each classical register
+    records: HashMap<usize, usize>,
+    num_measurements: usize,
+    // Number of instructions of the expanded circuit written so far, and annotations written so far
+    num_instructions: usize,
+    num_annotations: usize,
+}
+
+impl<'a> Emitter<'a> {
+    fn push(&mut self, line: String, indent: usize) {
+        self.lines.push(format!("{}{line}", "    ".repeat(indent)));
+    }
+
+    // Writes the annotations which precede the next instruction
+    fn annotations(&mut self, indent: usize) {
+        while self.num_annotations < self.annotations.len() && self.annotations[self.num_annotations].0 <= self.num_instructions {
+            self.push(self.annotations[self.num_annotations].1.clone(), indent);
+            self.num_annotations += 1;
+        }
+    }
+
+    fn instruction(&mut self, inst: &Instruction, indent: usize) -> Result<(), ExportError> {
+        self.annotations(indent);
+
+        let i: usize = self.num_instructions;
+        if inst.id.is_non_clifford() {
+            return Err(ExportError::new(i, &format!("non-Clifford gate {} cannot be expressed in Stim", inst.id.name())));
+        }
+        let targets: Vec<String> = inst.qubits.iter().map(|q| q.to_string()).collect();
+        let line: String = match &inst.condition {
+            None => {
+                if let Gate::PRINT = inst.id {
+                    String::from(gate_name(inst.id))
+                } else if let Gate::MPP = inst.id {
+                    let factors: Vec<String> = inst.paulis.iter().zip(&targets).map(|(p, q)| format!("{}{q}", gate_name(*p))).collect();
+                    format!("MPP {}", factors.join("*"))
+                } else if let Gate::Noise(channel) = inst.id {
+                    let probs: Vec<String> = channel.probs().iter().map(|p| p.to_string()).collect();
+                    format!("{}({}) {}", gate_name(inst.id), probs.join(", "), targets.join(" "))
+                } else {
+                    format!("{} {}", gate_name(inst.id), targets.join(" "))
+                }
+            },
+            Some(Condition::Equals(cbits, 1)) if cbits.len() == 1 && [Gate::X, Gate::Y, Gate::Z].contains(&inst.id) => {
+                let record = match self.records.get(&cbits[0]) {
+                    Some(r) => *r,
+                    None => return Err(ExportError::new(i, &format!("condition on register {} which is not yet measured cannot be expressed in Stim", cbits[0]))),
+                };
+                format!("C{} rec[-{}] {}", gate_name(inst.id), self.num_measurements - record, targets[0])
+            },
+            Some(condition) => return Err(ExportError::new(i, &format!("'{} {}' cannot be expressed in Stim", condition.to_qasm(), inst.id.name()))),
+        };
+
+        if is_measurement(inst) {
+            self.records.insert(inst.cbits[0], self.num_measurements);
+            self.num_measurements += 1;
+        }
+
+        self.push(line, indent);
+        self.num_instructions += 1;
+        return Ok(());
+    }
+
+    // Whether the expanded body of a repeat block can be written once inside REPEAT. Conditions then
+    // have to read the same record entry relative to the end of the record on every iteration, and
+    // annotations can only be written before or after the block.
+    fn repeatable(&self, body: &Vec<Instruction>, count: usize) -> bool {
+        let end: usize = self.num_instructions + count*body.len();
+        if self.annotations.iter().any(|(j, _)| self.num_instructions < *j && *j < end) {
+            return false;
+        }
+        if !body.iter().any(is_measurement) {
+            return true;
+        }
+
+        let mut measured: HashSet<usize> = HashSet::new();
+        for inst in body {
+            if let Some(condition) = &inst.condition {
+                if condition.cbits().iter().any(|c| !measured.contains(c)) {
+                    return false;
+                }
+            }
+            if is_measurement(inst) {
+                measured.insert(inst.cbits[0]);
+            }
+        }
+
+        return true;
+    }
+
+    fn blocks(&mut self, blocks: &Vec<Block>, qubit_map: Option<&Vec<usize>>, indent: usize) -> Result<(), ExportError> {
+        for block in blocks {
+            match block {
+                Block::Repeat(count, body) => {
+                    let mut expanded: Vec<Instruction> = Vec::new();
+                    flatten_blocks(body, self.definitions, qubit_map, &mut expanded);
+                    if *count == 0 || !self.repeatable(&expanded, *count) {
+                        for _ in 0..*count {
+                            self.blocks(body, qubit_map, indent)?;
+                        }
+                        continue;
+                    }
+
+                    self.annotations(indent);
+                    self.push(format!("REPEAT {count} {{"), indent);
+                    let start: usize = self.num_measurements;
+                    self.blocks(body, qubit_map, indent + 1)?;
+                    self.push(String::from("}"), indent);
+
+                    // Account for the iterations after the first; registers are left with the outcomes
+                    // of the last one
+                    let skipped: usize = (count - 1)*(self.num_measurements - start);
+                    for record in self.records.values_mut() {
+                        if *record >= start {
+                            *record += skipped;
+                        }
+                    }
+                    self.num_measurements += skipped;
+                    self.num_instructions += (count - 1)*expanded.len();
+                },
+                _ => {
+                    let mut expanded: Vec<Instruction> = Vec::new();
+                    flatten_blocks(&vec![block.clone()], self.definitions, qubit_map, &mut expanded);
+                    for inst in &expanded {
+                        self.instruction(inst, indent)?;
+                    }
+                },
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+// Formats blocks as a Stim circuit. Measurements are appended to the measurement record in order,
+// and conditions are written as record-controlled Paulis; fails on conditions which Stim cannot
+// express and on non-Clifford gates. Repeat blocks are written as REPEAT blocks where possible and unrolled otherwise, and
+// composite gates are inlined.
+pub(crate) fn emit(blocks: &Vec<Block>, definitions: &Vec<Definition>, annotations: &[(usize, String)]) -> Result<String, ExportError> {
+    let mut emitter = Emitter { definitions: definitions, annotations: annotations, lines: Vec::new(), records: HashMap::new(),
+                                num_measurements: 0, num_instructions: 0, num_annotations: 0 };
+    emitter.blocks(blocks, None, 0)?;
+    for (_, text) in &annotations[emitter.num_annotations..] {
+        emitter.lines.push(text.clone());
+    }
+
+    return Ok(emitter.lines.join("\n"));
+}
@@ -2,16 +2,25 @@
 
 #[cfg(test)]
 pub mod tests {
-	use rand::Rng;
+	use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+	use rand::{Rng, RngCore, SeedableRng};
+	use rand_pcg::Lcg64Xsh32;
 	use rand::rngs::ThreadRng;
 	use rayon::prelude::*;
 
 	use crate::util;
 
-	use crate::quantum_chp_state::QuantumCHPState;
+	use crate::quantum_chp_state::{PauliString, QuantumCHPState};
 	use crate::quantum_graph_state::QuantumGraphState;
-	use crate::quantum_vector_state::QuantumVectorState;
-	use crate::quantum_state::{QuantumProgram, QuantumState, Entropy, MzrForce};
+	use crate::quantum_vector_state::{GenericVectorState, QuantumVectorState};
+	use crate::quantum_dense_state::QuantumDenseState;
+	use crate::quantum_density_matrix_state::QuantumDensityMatrixState;
+	use crate::quantum_mps_state::QuantumMPSState;
+	use crate::quantum_stabilizer_rank_state::QuantumStabilizerRankState;
+	use crate::quantum_state::{Gate, QuantumProgram, QuantumState, Entropy, MzrForce, NonCliffordState};
+	use crate::circuit_builder::CircuitBuilder;
+	use crate::noise::NoiseChannel;
+	use crate::noise::NoiseModel;
 
 	const EPS: f32 = 0.0001;
 
@@ -26,7 +35,10 @@ pub mod tests {
 		H(usize),
 		MZR(usize, bool),
 		CZ(usize, usize),
-
+		T(usize),
+		CX(usize, usize),
+		CY(usize, usize),
+		CPHASE(usize, usize, f64),
 	}
 
 	impl Instruction {
@@ -47,17 +59,82 @@ pub mod tests {
 				_ => panic!()
 			}
 		}
+
+		// Also draws CX and CY, and T and CPHASE if non_clifford
+		pub fn random_extended<R: Rng>(rng: &mut R, num_qubits: usize, non_clifford: bool) -> Self {
+			let q1: usize = rng.gen_range(0..num_qubits);
+			let q2: usize = (q1 + rng.gen_range(1..num_qubits)) % num_qubits;
+			let num_cmds: usize = if non_clifford { 8 } else { 6 };
+			match rng.gen_range(0..num_cmds) {
+				0 => Instruction::S(q1),
+				1 => Instruction::H(q1),
+				2 => Instruction::MZR(q1, rng.gen()),
+				3 => Instruction::CZ(q1, q2),
+				4 => Instruction::CX(q1, q2),
+				5 => Instruction::CY(q1, q2),
+				6 => Instruction::T(q1),
+				_ => Instruction::CPHASE(q1, q2, rng.gen_range(0.0..std::f64::consts::TAU)),
+			}
+		}
+
+		// Applies a Clifford instruction to both states. Measurements are forced to the same outcome,
+		// which must be deterministic for both states or for neither.
+		fn apply_clifford<Q1: QuantumState + MzrForce, Q2: QuantumState + MzrForce>(&self, state1: &mut Q1, state2: &mut Q2) {
+			match *self {
+				Instruction::S(q) => { state1.s_gate(q); state2.s_gate(q); },
+				Instruction::H(q) => { state1.h_gate(q); state2.h_gate(q); },
+				Instruction::MZR(q, outcome) => {
+					if !state1.mzr_qubit_forced(q, outcome) {
+						state1.mzr_qubit_forced(q, !outcome);
+						assert!(!state2.mzr_qubit_forced(q, outcome));
+						state2.mzr_qubit_forced(q, !outcome);
+					} else {
+						assert!(state2.mzr_qubit_forced(q, outcome));
+					}
+				},
+				Instruction::CZ(q1, q2) => { state1.cz_gate(q1, q2); state2.cz_gate(q1, q2); },
+				Instruction::CX(q1, q2) => { state1.cx_gate(q1, q2); state2.cx_gate(q1, q2); },
+				Instruction::CY(q1, q2) => { state1.cy_gate(q1, q2); state2.cy_gate(q1, q2); },
+				_ => panic!("{self:?} is not a Clifford instruction"),
+			}
+		}
+
+		fn apply<Q1: NonCliffordState + MzrForce, Q2: NonCliffordState + MzrForce>(&self, state1: &mut Q1, state2: &mut Q2) {
+			match *self {
+				Instruction::T(q) => { state1.t_gate(q); state2.t_gate(q); },
+				Instruction::CPHASE(q1, q2, phi) => { state1.cphase_gate(q1, q2, phi); state2.cphase_gate(q1, q2, phi); },
+				_ => self.apply_clifford(state1, state2),
+			}
+		}
+	}
+
+	// Applies the same random circuit, with T and CPHASE gates if non_clifford, to a Q and to a dense
+	// state, and checks that they give the same measurement outcomes and entropies. Returns both states
+	// for further checks.
+	fn check_against_dense<Q: NonCliffordState + MzrForce + Entropy>(num_qubits: usize, depth: usize, non_clifford: bool, subsystems: &[Vec<usize>],
+																	  tolerance: f32, rng: &mut Lcg64Xsh32) -> (Q, QuantumDenseState) {
+		let mut state1 = Q::new(num_qubits);
+		let mut state2 = QuantumDenseState::new(num_qubits);
+		for _ in 0..depth {
+			Instruction::random_extended(rng, num_qubits, non_clifford).apply(&mut state1, &mut state2);
+		}
+
+		for qubits in subsystems {
+			assert!((state1.renyi_entropy(qubits) - state2.renyi_entropy(qubits)).abs() < tolerance, "{qubits:?}");
+		}
+		return (state1, state2);
 	}
 
 
 	#[test]
 	fn test_simulators() {
+		let mut rng = Lcg64Xsh32::seed_from_u64(1);
 		for i in 0..100 {
 			println!("{i}");
-			let circuit = util::generate_random_circuit(&util::GATES, 100, 10, 0);
-			let mut qc1 = QuantumProgram::<QuantumVectorState>::from_qasm(&circuit);
-			let mut qc2 = QuantumProgram::<QuantumGraphState>::from_qasm(&circuit);
-			let mut qc3 = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit);
+			let circuit = util::generate_random_circuit(&util::GATES, 100, 10, 0, &mut rng);
+			let mut qc1 = circuit.build::<QuantumVectorState>();
+			let mut qc2 = circuit.build::<QuantumGraphState>();
+			let mut qc3 = circuit.build::<QuantumCHPState>();
 			qc1.execute();
 			qc2.execute();
 			qc3.execute();
@@ -73,7 +150,7 @@ pub mod tests {
 		}
 	}
 
-	use crate::brickwall_run::timesteps_qa;
+	use crate::brickwall_run::{timesteps_qa, timesteps_qa_noisy};
 
 	#[test]
 	fn test_entropy() {
@@ -110,6 +187,98 @@ pub mod tests {
 	}
 
 
+	#[test]
+	fn test_chp_deterministic_measurement() {
+		// Deterministic outcomes are read off the stabilizers paired with the destabilizers which
+		// anticommute with Z, not the product of every stabilizer
+		let mut state = QuantumCHPState::new(3);
+		state.x_gate(0);
+		state.x_gate(1);
+		assert_eq!(state.mzr_qubit(1), 1);
+		assert_eq!(state.mzr_qubit(2), 0);
+
+		let mut ghz = QuantumCHPState::new(4);
+		ghz.h_gate(0);
+		for q in 1..4 {
+			ghz.cx_gate(q - 1, q);
+		}
+		let measured: i32 = ghz.mzr_qubit(0);
+		for q in 1..4 {
+			assert_eq!(ghz.mzr_qubit(q), measured);
+		}
+	}
+
+	fn check_myr<Q: QuantumState>() {
+		// |+i> = S|+> is the +1 eigenstate of Y, so it reads 0 and is left unchanged
+		let mut state = Q::new(1);
+		state.h_gate(0);
+		state.s_gate(0);
+		assert_eq!(state.myr_qubit(0), 0);
+		assert_eq!(state.myr_qubit(0), 0);
+		state.sd_gate(0);
+		assert_eq!(state.mxr_qubit(0), 0);
+
+		let mut state = Q::new(1);
+		state.h_gate(0);
+		state.sd_gate(0);
+		assert_eq!(state.myr_qubit(0), 1);
+	}
+
+	#[test]
+	fn test_myr() {
+		check_myr::<QuantumVectorState>();
+		check_myr::<QuantumGraphState>();
+		check_myr::<QuantumCHPState>();
+	}
+
+	#[test]
+	fn test_pauli_commutation() {
+		use crate::quantum_chp_state::PauliString;
+
+		// Two Pauli strings commute when they anticommute on an even number of sites. On an odd number
+		// of qubits this differs from the parity of the sites on which they commute.
+		let pauli = |ops: &str| -> PauliString {
+			let mut p = PauliString::new(ops.len());
+			for (i, op) in ops.chars().enumerate() {
+				p.set_x(i, op == 'X' || op == 'Y');
+				p.set_z(i, op == 'Z' || op == 'Y');
+			}
+			return p;
+		};
+
+		let cases: [(&str, &str, bool); 6] = [
+			("XXX", "XXX", true),
+			("XXX", "ZII", false),
+			("XXX", "ZZI", true),
+			("XYZ", "ZYX", true),
+			("XI", "ZI", false),
+			("XX", "ZZ", true),
+		];
+		for (a, b, commutes) in cases {
+			assert_eq!(pauli(a).commutes(&pauli(b)), commutes, "{a} and {b}");
+			assert_eq!(pauli(b).commutes(&pauli(a)), commutes, "{b} and {a}");
+		}
+	}
+
+	fn check_cy<Q: QuantumState>() {
+		// CY|+0> = (|00> + i|11>)/sqrt(2), which Sd on the control and CX map back to |+0>. With
+		// controlled -Y the control would end up in |->.
+		let mut state = Q::new(2);
+		state.h_gate(0);
+		state.cy_gate(0, 1);
+		state.sd_gate(0);
+		state.cx_gate(0, 1);
+		assert_eq!(state.mxr_qubit(0), 0);
+		assert_eq!(state.mzr_qubit(1), 0);
+	}
+
+	#[test]
+	fn test_cy() {
+		check_cy::<QuantumVectorState>();
+		check_cy::<QuantumGraphState>();
+		check_cy::<QuantumCHPState>();
+	}
+
 	#[test]
 	fn test_chp_vs_vector() {
 		let num_qubits: usize = 5;
@@ -151,7 +320,7 @@ pub mod tests {
 						state2.cz_gate(x, y);
 						state3.cz_gate(x, y);
 					}
-
+					_ => panic!(),
 				}
 			}
 			if state1.to_vector_state() != state2.to_vector_state() {
@@ -159,5 +328,1098 @@ pub mod tests {
 			}
 		}
 	}
-}
 
+	#[test]
+	fn test_openqasm() {
+		let native = String::from("@pragma total_num_qubits 4\n@pragma total_num_cbits 1\n\
+								   h q0\nh q2\ncx q0 q1\ncz q2 q3\nsd q1\nsqrtx q3\n\
+								   cx q0 q3\ncx q3 q0\ncx q0 q3\ny q1\ncy q1 q2");
+		let openqasm = String::from("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n\
+									 qreg a[2];\nqreg b[2]; // second register\ncreg c[1];\n\
+									 h a[0]; h b[0];\ncx a[0], a[1];\ncz b[0], b[1];\nsdg a[1];\nsx b[1];\n\
+									 barrier a, b;\nswap a[0], b[1];\ny a[1];\ncy a[1], b[0];");
+
+		let mut qc1 = QuantumProgram::<QuantumVectorState>::from_qasm(&native);
+		let mut qc2 = QuantumProgram::<QuantumVectorState>::from_qasm(&openqasm);
+		let mut qc3 = QuantumProgram::<QuantumGraphState>::from_qasm(&openqasm);
+		let mut qc4 = QuantumProgram::<QuantumCHPState>::from_qasm(&openqasm);
+		qc1.execute();
+		qc2.execute();
+		qc3.execute();
+		qc4.execute();
+
+		assert!(qc1.quantum_state == qc2.quantum_state);
+		assert!(qc2.quantum_state == qc3.quantum_state.to_vector_state());
+		assert!(qc2.quantum_state == qc4.quantum_state.to_vector_state());
+
+		// Measurements may be broadcast over whole registers
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[3];\ncreg c[3];\nx q[1];\nmeasure q -> c;");
+		let mut qc = QuantumProgram::<QuantumCHPState>::from_qasm(&openqasm);
+		qc.execute();
+		assert_eq!(qc.get_classical_data(), vec![0, 1, 0]);
+	}
+
+	#[test]
+	fn test_qasm_errors() {
+		let header = "@pragma total_num_qubits 4\n@pragma total_num_cbits 2\n";
+		let cases: [(&str, usize, usize, &str); 7] = [
+			("h q1\nfoo q2", 4, 1, "foo"),
+			("cx q1", 3, 1, "cx"),
+			("h q1 q2", 3, 6, "q2"),
+			("mzr q1 x0", 3, 8, "x0"),
+			("h q4", 3, 3, "q4"),
+			("mzr q0 r2", 3, 8, "r2"),
+			("cz q1 q1", 3, 7, "q1"),
+		];
+
+		for (body, line, column, token) in cases {
+			let circuit = String::from(header) + body;
+			let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&circuit).err().unwrap();
+			assert_eq!((err.line, err.column, err.token.as_str()), (line, column, token));
+		}
+
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&String::from("@pragma total_num_qubits four")).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (1, 26, "four"));
+
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[2];\ncreg c[2];\nh q[0];\ncx q[0], q[2];");
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&openqasm).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (5, 12, "2"));
+
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[2];\nt q[0];");
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&openqasm).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (3, 1, "t"));
+
+		// u0 takes a single parameter, which is parsed and then ignored
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[1];\ncreg c[1];\nu0(1) q[0];\nu0(2*(1+1)) q;\nmeasure q[0] -> c[0];");
+		assert_eq!(QuantumProgram::<QuantumCHPState>::from_qasm(&openqasm).to_qasm(),
+				   "@pragma total_num_qubits 1\n@pragma total_num_cbits 1\nid q0\nid q0\nmzr q0 r0");
+		let cases: [(&str, usize, usize, &str); 4] = [
+			("u0 q[0];", 3, 1, "u0"),
+			("u0(1, 2) q[0];", 3, 1, "u0"),
+			("u0() q[0];", 3, 4, ")"),
+			("h(1) q[0];", 3, 1, "h"),
+		];
+		for (body, line, column, token) in cases {
+			let openqasm = String::from("OPENQASM 2.0;\nqreg q[1];\n") + body;
+			let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&openqasm).err().unwrap();
+			assert_eq!((err.line, err.column, err.token.as_str()), (line, column, token));
+		}
+	}
+
+	fn teleportation_circuit(num_shots: usize) -> String {
+		// Teleports S.H|0> from q0 to q2, then rotates q2 back to |0> and measures it into r2
+		let mut circuit = String::from("@pragma total_num_qubits 3\n@pragma total_num_cbits 3\n");
+		for _ in 0..num_shots {
+			circuit += "h q0\ns q0\nh q1\ncx q1 q2\ncx q0 q1\nh q0\nmzr q0 r0\nmzr q1 r1\n\
+						if r1 == 1 x q2\nif r0 == 1 z q2\nsd q2\nh q2\nmzr q2 r2\n\
+						if r0 == 1 x q0\nif r1 == 1 x q1\nif r2 == 1 x q2\n";
+		}
+		return circuit;
+	}
+
+	fn check_teleportation<Q: QuantumState>() {
+		let mut qc = QuantumProgram::<Q>::from_qasm(&teleportation_circuit(20));
+		qc.execute();
+		assert_eq!(qc.get_classical_register(2), 0);
+	}
+
+	#[test]
+	fn test_conditionals() {
+		check_teleportation::<QuantumVectorState>();
+		check_teleportation::<QuantumGraphState>();
+		check_teleportation::<QuantumCHPState>();
+
+		// Parity conditions and conditions on registers which are yet to be measured
+		let circuit = String::from("@pragma total_num_qubits 4\n@pragma total_num_cbits 4\n\
+									if r3 == 0 x q0\nx q1\nmzr q0 r0\nmzr q1 r1\n\
+									if r0^r1 == 0 x q2\nif r0^r1^r3 == 1 x q3\nmzr q2 r2\nmzr q3 r3");
+		let mut qc = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit);
+		qc.execute();
+		assert_eq!(qc.get_classical_data(), vec![1, 1, 1, 0]);
+
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[3];\ncreg c[2];\ncreg d[1];\nx q[1];\nmeasure q[0] -> c[0];\n\
+									 measure q[1] -> c[1];\nif(c==2) x q[2];\nif(c==1) x q;\nmeasure q[2] -> d[0];");
+		let mut qc = QuantumProgram::<QuantumGraphState>::from_qasm(&openqasm);
+		qc.execute();
+		assert_eq!(qc.get_classical_register(2), 1);
+
+		// The condition is checked once for a whole statement, so a broadcast measurement may not write
+		// to the register it is conditioned on. A single measurement, or one into another register, may.
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[2];\ncreg c[2];\ncreg d[2];\nx q[0];\nx q[1];\n\
+									 if(c==0) measure q -> d;\nif(c==0) measure q[0] -> c[0];\nif(c==1) measure q[1] -> c[1];");
+		let mut qc = QuantumProgram::<QuantumCHPState>::from_qasm(&openqasm);
+		qc.execute();
+		assert_eq!(qc.get_classical_data(), vec![1, 1, 1, 1]);
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[2];\ncreg c[2];\nx q[0];\nx q[1];\nif(c==0) measure q -> c;");
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&openqasm).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (6, 10, "measure"));
+
+		let circuit = String::from("@pragma total_num_qubits 1\n@pragma total_num_cbits 1\nif r0 = 1 x q0");
+		assert!(QuantumProgram::<QuantumCHPState>::try_from_qasm(&circuit).is_err());
+		let circuit = String::from("@pragma total_num_qubits 1\n@pragma total_num_cbits 1\nif r0^r1 == 1 x q0");
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&circuit).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (3, 7, "r1"));
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let mut rng = Lcg64Xsh32::seed_from_u64(2);
+		let circuits: Vec<String> = vec![
+			util::generate_brick_wall_circuit(0.2, 8, 10, &mut rng).build::<QuantumCHPState>().to_qasm(),
+			util::generate_random_circuit(&util::GATES, 100, 10, 0, &mut rng).build::<QuantumCHPState>().to_qasm(),
+			teleportation_circuit(2),
+		];
+
+		for circuit in circuits {
+			let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit);
+			let native = qc.to_qasm();
+			assert_eq!(QuantumProgram::<QuantumCHPState>::from_qasm(&native).to_qasm(), native);
+
+			let openqasm = qc.to_openqasm().unwrap();
+			assert_eq!(QuantumProgram::<QuantumCHPState>::from_qasm(&openqasm).to_qasm(), native);
+		}
+
+		let circuit = String::from("@pragma total_num_qubits 2\n@pragma total_num_cbits 3\n\
+									sqrtz q0\nsqrtyd q1\nmxr q0 r2\n@pragma print\nif r0,r1 == 2 id q1\nif r0^r2 == 1 myr q1 r1");
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit);
+		assert_eq!(qc.to_qasm(), circuit);
+
+		// Condition values beyond i32, on up to usize::BITS registers
+		let registers: Vec<String> = (0..64).map(|c| format!("r{c}")).collect();
+		let circuit = format!("@pragma total_num_qubits 1\n@pragma total_num_cbits 65\nif {} == 2147483648 x q0", registers[0..32].join(","));
+		assert_eq!(QuantumProgram::<QuantumCHPState>::from_qasm(&circuit).to_qasm(), circuit);
+		let circuit = format!("@pragma total_num_qubits 1\n@pragma total_num_cbits 65\nif {} == {} x q0", registers.join(","), usize::MAX);
+		assert_eq!(QuantumProgram::<QuantumCHPState>::from_qasm(&circuit).to_qasm(), circuit);
+		let circuit = format!("@pragma total_num_qubits 1\n@pragma total_num_cbits 65\nif {},r64 == 0 x q0", registers.join(","));
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&circuit).err().unwrap();
+		assert_eq!((err.line, err.column), (3, 4));
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[1];\ncreg c[65];\nif(c==0) x q[0];");
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&openqasm).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (4, 4, "c"));
+
+		let openqasm = String::from("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\ncreg a[2];\ncreg b[1];\n\
+									 h q[0];\nmeasure q -> a;\nif(a==3) sx q[1];\nif(b==0) swap q[0], q[1];");
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&openqasm);
+		let qc2 = QuantumProgram::<QuantumCHPState>::from_qasm(&qc.to_openqasm().unwrap());
+		assert_eq!(qc.to_qasm(), qc2.to_qasm());
+		assert_eq!(qc.to_openqasm().unwrap(), qc2.to_openqasm().unwrap());
+
+		// Instructions which OpenQASM 2.0 or Stim cannot express are reported rather than mistranslated
+		let header = "@pragma total_num_qubits 2\n@pragma total_num_cbits 3\nmzr q0 r0\n";
+		let cases: [(&str, bool, bool); 6] = [
+			("if r0^r1 == 1 x q1", false, false),
+			("if r0,r2 == 1 x q1", false, false),
+			("x_error 0.1 q0", false, true),
+			("if r0 == 0 mxr q0 r0", false, false),
+			("if r0 == 0 mxr q0 r1", true, false),
+			("if r0 == 1 x q1", true, true),
+		];
+		for (body, openqasm, stim) in cases {
+			let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&(String::from(header) + body));
+			assert_eq!(qc.to_openqasm().is_ok(), openqasm, "{body}");
+			assert_eq!(qc.to_stim().is_ok(), stim, "{body}");
+		}
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&(String::from(header) + "h q1\nx_error 0.1 q1"));
+		assert_eq!(qc.to_openqasm().err().unwrap().instruction, 2);
+	}
+
+	#[test]
+	fn test_stim() {
+		// Repetition code on three data qubits with two ancillae; q0 is flipped and the syndrome
+		// is used to correct it through the measurement record
+		let circuit = String::from("QUBIT_COORDS(0, 0) 0\nX 0\nTICK\nREPEAT 2 {\n    CX 0 3 1 3 1 4 2 4\n    MR 3 4\n\
+									DETECTOR rec[-2]\n}\nCX rec[-2] 0\nM 0 1 !2\nOBSERVABLE_INCLUDE(0) rec[-1]");
+		let mut qc = QuantumProgram::<QuantumCHPState>::from_stim(&circuit);
+		qc.execute();
+		assert_eq!(qc.get_classical_data(), vec![1, 0, 1, 0, 0, 0, 1]);
+
+		let stim = qc.to_stim().unwrap();
+		assert!(stim.contains("DETECTOR rec[-2]") && stim.contains("OBSERVABLE_INCLUDE(0) rec[-1]"));
+		let qc2 = QuantumProgram::<QuantumCHPState>::from_stim(&stim);
+		assert_eq!(qc2.to_qasm(), qc.to_qasm());
+		assert_eq!(qc2.to_stim().unwrap(), stim);
+
+		let mut qc = QuantumProgram::<QuantumVectorState>::from_stim(&String::from("H 0\nS 0\nS 0\nH 0\nM 0\nMX 1\nMY !1"));
+		qc.execute();
+		assert_eq!(qc.get_classical_data()[0], 1);
+
+		// Registers are renumbered in measurement order, so only the Stim text is preserved
+		let stim = QuantumProgram::<QuantumCHPState>::from_qasm(&teleportation_circuit(2)).to_stim().unwrap();
+		assert_eq!(QuantumProgram::<QuantumCHPState>::from_stim(&stim).to_stim().unwrap(), stim);
+		let mut qc = QuantumProgram::<QuantumGraphState>::from_stim(&stim);
+		qc.execute();
+		assert_eq!(qc.get_classical_register(5), 0);
+
+		// REPEAT blocks stay compact, with measurements reusing registers on every iteration, unless a
+		// later read needs the outcome of an earlier iteration or a record entry from before the block
+		let stim = String::from("X 0\nREPEAT 3 {\n    H 0\n    CX 0 1\n    M 1\n    CX rec[-1] 1\n    CZ rec[-1] 0\n}\nM 0");
+		let qc = QuantumProgram::<QuantumCHPState>::from_stim(&stim);
+		assert_eq!((qc.instructions().len(), qc.get_classical_data().len()), (1 + 3*5 + 1, 2));
+		assert_eq!(qc.to_stim().unwrap(), stim);
+		let cases: [(&str, usize, &str); 3] = [
+			("REPEAT 2 {\n    M 0\n}\nCX rec[-2] 1", 2, "M 0\nM 0\nCX rec[-2] 1"),
+			("M 0\nREPEAT 2 {\n    CX rec[-1] 1\n    M 1\n}", 3, "M 0\nCX rec[-1] 1\nM 1\nCX rec[-1] 1\nM 1"),
+			("M 0\nREPEAT 2 {\n    CX rec[-1] 1\n    H 1\n}\nM 1", 2, "M 0\nREPEAT 2 {\n    CX rec[-1] 1\n    H 1\n}\nM 1"),
+		];
+		for (stim, num_cbits, expected) in cases {
+			let qc = QuantumProgram::<QuantumCHPState>::from_stim(&String::from(stim));
+			assert_eq!(qc.get_classical_data().len(), num_cbits);
+			assert_eq!(qc.to_stim().unwrap(), expected);
+		}
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 2\n@pragma total_num_cbits 2\n\
+																			  mzr q1 r1\n@repeat 2 {\n    h q0\n    mzr q0 r0\n}\nif r1 == 1 x q0"));
+		assert_eq!(qc.to_stim().unwrap(), "M 1\nREPEAT 2 {\n    H 0\n    M 0\n}\nCX rec[-3] 0");
+
+		let err = QuantumProgram::<QuantumCHPState>::try_from_stim(&String::from("H 0\nCX 0 rec[-1]")).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (2, 6, "rec[-1]"));
+
+		let err = QuantumProgram::<QuantumCHPState>::try_from_stim(&String::from("H(0.1) 0")).err().unwrap();
+		assert_eq!((err.line, err.column), (1, 1));
+
+		let err = QuantumProgram::<QuantumCHPState>::try_from_stim(&String::from("REPEAT 2 {\nH 0")).err().unwrap();
+		assert_eq!((err.line, err.token.as_str()), (1, "REPEAT"));
+	}
+
+	#[test]
+	fn test_blocks() {
+		let circuit = String::from("@pragma total_num_qubits 4\n@pragma total_num_cbits 4\n\
+									@def bell(a, b) {\n    h a\n    cx a b\n}\n\
+									@def swap(a, b) {\n    cx a b\n    cx b a\n    cx a b\n}\n\
+									bell q0 q1\n@repeat 3 {\n    swap q1 q2\n    @repeat 2 {\n        x q3\n    }\n}\n\
+									mzr q0 r0\nmzr q1 r1\nmzr q2 r2\nmzr q3 r3");
+		let mut qc = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit);
+		qc.execute();
+		let data = qc.get_classical_data();
+		assert_eq!((data[1], data[3]), (0, 0));
+		assert_eq!(data[0], data[2]);
+
+		// Blocks are written back unexpanded
+		let native = qc.to_qasm();
+		assert_eq!(native, circuit);
+		assert_eq!(qc.instructions().len(), 2 + 3*(3 + 2) + 4);
+
+		let flat = QuantumProgram::<QuantumCHPState>::from_qasm(&qc.to_openqasm().unwrap());
+		assert_eq!(flat.instructions(), qc.instructions());
+
+		let errors = vec![
+			("@pragma total_num_qubits 2\n@repeat 2 {\nh q0", (2, 1, "@repeat")),
+			("@pragma total_num_qubits 2\nh q0\n}", (3, 1, "}")),
+			("@pragma total_num_qubits 2\n@def f(a, b) {\nh q0\n}", (3, 3, "q0")),
+			("@pragma total_num_qubits 2\n@def f(a, a) {\n}", (2, 6, "a")),
+			("@pragma total_num_qubits 2\n@def cx(a, b) {\n}", (2, 6, "cx")),
+			("@pragma total_num_qubits 2\n@def f(a, b) {\n}\nf q0", (4, 1, "f")),
+			("@pragma total_num_qubits 2\n@repeat 2 {\n@def f(a) {\n}\n}", (3, 1, "@def")),
+		];
+		for (circuit, (line, column, token)) in errors {
+			let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&String::from(circuit)).err().unwrap();
+			assert_eq!((err.line, err.column, err.token.as_str()), (line, column, token));
+		}
+	}
+
+	fn check_sampling<Q: QuantumState>() {
+		// GHZ state on three qubits, with an unmeasured register
+		let circuit = String::from("@pragma total_num_qubits 3\n@pragma total_num_cbits 4\nh q0\ncx q0 q1\ncx q1 q2\n\
+									mzr q0 r0\nmzr q1 r1\nmzr q2 r2");
+		let qc = QuantumProgram::<Q>::from_qasm(&circuit);
+		let histogram = qc.sample(1000, 5);
+		assert_eq!(histogram.values().sum::<usize>(), 1000);
+		assert_eq!(histogram.len(), 2);
+		assert!(histogram["0000"] > 400 && histogram["1110"] > 400);
+
+		assert_eq!(qc.sample(1000, 5), histogram);
+		assert_eq!(qc.sample_parallel(1000, 5), histogram);
+		assert_ne!(qc.sample(1000, 6), histogram);
+	}
+
+	#[test]
+	fn test_sample() {
+		check_sampling::<QuantumVectorState>();
+		check_sampling::<QuantumGraphState>();
+		check_sampling::<QuantumCHPState>();
+
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&teleportation_circuit(5));
+		assert!(qc.sample_parallel(200, 0).keys().all(|outcome| outcome.ends_with('0')));
+	}
+
+	fn check_resets<Q: QuantumState>() {
+		// Each qubit is entangled, reset and then measured in the basis it was reset to. r4 and r6 hold
+		// random outcomes of mr, after which the qubit is measured again.
+		let circuit = String::from("@pragma total_num_qubits 5\n@pragma total_num_cbits 8\n\
+									h q0\ncx q0 q1\ncx q0 q2\ncx q0 q3\nh q4\ns q4\ncz q3 q4\n\
+									reset q0\nresetx q1\nresety q2\nmr q3 r4\nmzr q0 r0\nmxr q1 r1\nmyr q2 r2\nmzr q3 r3\n\
+									x q4\nresety q4\nmyr q4 r5\nmr q4 r6\nmzr q4 r7");
+		for (outcome, count) in QuantumProgram::<Q>::from_qasm(&circuit).sample(100, 1) {
+			assert_eq!(&outcome[0..4], "0000");
+			assert_eq!((&outcome[5..6], &outcome[7..]), ("0", "0"), "{count} shots with outcome {outcome}");
+		}
+	}
+
+	#[test]
+	fn test_resets() {
+		check_resets::<QuantumVectorState>();
+		check_resets::<QuantumGraphState>();
+		check_resets::<QuantumCHPState>();
+
+		// mr records the outcome of a random measurement
+		let circuit = String::from("@pragma total_num_qubits 1\n@pragma total_num_cbits 2\nh q0\nmr q0 r0\nmzr q0 r1");
+		let histogram = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit).sample(200, 0);
+		assert_eq!(histogram.len(), 2);
+		assert!(histogram.contains_key("10"));
+
+		let stim = String::from("H 0\nMR !0\nRX 1\nMX 1\nRY 1\nMY 1\nM 0");
+		let qc = QuantumProgram::<QuantumCHPState>::from_stim(&stim);
+		assert_eq!(qc.to_stim().unwrap(), "H 0\nX 0\nMR 0\nRX 1\nMX 1\nRY 1\nMY 1\nM 0");
+		assert!(qc.sample(100, 0).keys().all(|outcome| outcome.ends_with("000")));
+		let openqasm = QuantumProgram::<QuantumCHPState>::from_qasm(&qc.to_openqasm().unwrap());
+		assert_eq!(openqasm.sample(100, 0), qc.sample(100, 0));
+	}
+
+	fn check_pauli_measurements<Q: QuantumState>() {
+		// Stabilizers of a GHZ state have definite outcomes; the leading minus sign of YY on a Bell
+		// pair gives outcome 1
+		let circuit = String::from("@pragma total_num_qubits 3\n@pragma total_num_cbits 6\nh q0\ncx q0 q1\ncx q1 q2\n\
+									mpp XXX q0 q1 q2 r0\nmpp ZZ q0 q2 r1\nmpp XYY q0 q1 q2 r2\nmpp Z q1 r3\nmpp ZZ q1 q2 r4\nmpp Z q0 r5");
+		for (outcome, _) in QuantumProgram::<Q>::from_qasm(&circuit).sample(100, 3) {
+			assert_eq!(&outcome[0..3], "001");
+			assert_eq!(outcome[3..4], outcome[5..6]);
+			assert_eq!(&outcome[4..5], "0");
+		}
+
+		// Repeated measurements of commuting products agree, whatever the state
+		let mut circuit = util::generate_random_circuit(&util::GATES, 40, 5, 6, &mut Lcg64Xsh32::seed_from_u64(3));
+		for r in [0, 3] {
+			circuit.mpp(&[(Gate::X, 0), (Gate::Z, 2), (Gate::Y, 4)], r).mpp(&[(Gate::Y, 1), (Gate::Y, 3)], r + 1).mpp(&[(Gate::Z, 3), (Gate::Z, 1)], r + 2);
+		}
+		for (outcome, _) in circuit.build::<Q>().sample(20, 4) {
+			assert_eq!(outcome[0..3], outcome[3..6]);
+		}
+	}
+
+	#[test]
+	fn test_measure_pauli() {
+		check_pauli_measurements::<QuantumVectorState>();
+		check_pauli_measurements::<QuantumGraphState>();
+		check_pauli_measurements::<QuantumCHPState>();
+
+		// X on |0> is random, and collapses the state onto |+>
+		let circuit = String::from("@pragma total_num_qubits 2\n@pragma total_num_cbits 3\nmpp XZ q0 q1 r0\nmxr q0 r1\nmzr q1 r2");
+		for qc in [QuantumProgram::<QuantumCHPState>::from_qasm(&circuit), QuantumProgram::<QuantumCHPState>::from_stim(&String::from("MPP X0*Z1\nMX 0\nM 1"))] {
+			let histogram = qc.sample(200, 0);
+			assert_eq!(histogram.keys().cloned().collect::<HashSet<String>>(), HashSet::from([String::from("000"), String::from("110")]));
+
+			let openqasm = QuantumProgram::<QuantumCHPState>::from_qasm(&qc.to_openqasm().unwrap());
+			assert_eq!(openqasm.sample(200, 0).keys().collect::<HashSet<&String>>(), histogram.keys().collect::<HashSet<&String>>());
+		}
+
+		let qc = QuantumProgram::<QuantumVectorState>::from_stim(&String::from("H 0\nMPP Y0*X1 Z2"));
+		assert_eq!(qc.to_stim().unwrap(), "H 0\nMPP Y0*X1\nMPP Z2");
+		assert_eq!(QuantumProgram::<QuantumVectorState>::from_qasm(&qc.to_qasm()).to_qasm(), qc.to_qasm());
+
+		let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&String::from("@pragma total_num_qubits 2\nmpp XW q0 q1 r0")).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (2, 5, "XW"));
+	}
+
+	fn check_noise<Q: QuantumState>() {
+		let circuit = String::from("@pragma total_num_qubits 6\n@pragma total_num_cbits 6\n\
+									x_error 1 q0\nh q1\nz_error 1 q1\ndepolarize1 0.3 q2\ndepolarize2 0.6 q3 q4\npauli_channel 0.1 0.2 0.3 q5\n\
+									mzr q0 r0\nmxr q1 r1\nmzr q2 r2\nmzr q3 r3\nmzr q4 r4\nmzr q5 r5");
+		let num_shots: usize = 4000;
+		let histogram = QuantumProgram::<Q>::from_qasm(&circuit).sample_parallel(num_shots, 7);
+
+		// Fraction of shots in which each register is 1
+		let mut flipped: Vec<f32> = vec![0.; 6];
+		for (outcome, count) in histogram {
+			for (i, c) in outcome.chars().enumerate() {
+				if c == '1' {
+					flipped[i] += count as f32 / num_shots as f32;
+				}
+			}
+		}
+
+		let expected: Vec<f32> = vec![1., 1., 0.2, 0.32, 0.32, 0.3];
+		for i in 0..6 {
+			assert!((flipped[i] - expected[i]).abs() < 0.03, "register {i}: {} != {}", flipped[i], expected[i]);
+		}
+	}
+
+	#[test]
+	fn test_noise() {
+		check_noise::<QuantumVectorState>();
+		check_noise::<QuantumGraphState>();
+		check_noise::<QuantumCHPState>();
+
+		let circuit = String::from("@pragma total_num_qubits 2\n@pragma total_num_cbits 0\n\
+									depolarize1 0.01 q0\ndepolarize2 0.02 q1 q0\nx_error 0.5 q1\nz_error 0 q0\npauli_channel 0.1 0 0.25 q1");
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit);
+		assert_eq!(qc.to_qasm(), circuit);
+
+		let stim = qc.to_stim().unwrap();
+		assert_eq!(stim, "DEPOLARIZE1(0.01) 0\nDEPOLARIZE2(0.02) 1 0\nX_ERROR(0.5) 1\nZ_ERROR(0) 0\nPAULI_CHANNEL_1(0.1, 0, 0.25) 1");
+		assert_eq!(QuantumProgram::<QuantumCHPState>::from_stim(&stim).to_qasm(), circuit);
+
+		let errors = vec![
+			("@pragma total_num_qubits 1\nx_error 1.5 q0", (2, 9, "1.5")),
+			("@pragma total_num_qubits 1\npauli_channel 0.5 0.5 p q0", (2, 23, "p")),
+			("@pragma total_num_qubits 1\npauli_channel 0.5 0.5 0.5 q0", (2, 15, "0.5")),
+			("@pragma total_num_qubits 2\ndepolarize2 0.1 q0", (2, 1, "depolarize2")),
+		];
+		for (circuit, (line, column, token)) in errors {
+			let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&String::from(circuit)).err().unwrap();
+			assert_eq!((err.line, err.column, err.token.as_str()), (line, column, token));
+		}
+
+		let noise: NoiseModel = serde_json::from_str("{\"depolarize2\": 0.01, \"pauli_channel\": [0.1, 0.2, 0.3]}").unwrap();
+		assert_eq!(noise, NoiseModel { depolarize2: 0.01, pauli_channel: [0.1, 0.2, 0.3], ..NoiseModel::default() });
+		assert!(noise.validate().is_ok() && !noise.is_noiseless());
+		assert!(NoiseModel { pauli_channel: [0.5, 0.5, 0.5], ..NoiseModel::default() }.validate().is_err());
+
+		// Sampled Paulis are indexed as in the distribution of the channel
+		let mut rng = Lcg64Xsh32::seed_from_u64(5);
+		assert_eq!(NoiseChannel::XError(1.).sample(&mut rng), Some(1));
+		assert_eq!(NoiseChannel::ZError(0.).sample(&mut rng), None);
+		assert_eq!(NoiseChannel::PauliChannel(0., 1., 0.).sample(&mut rng), Some(2));
+		for _ in 0..100 {
+			let pauli: usize = NoiseChannel::Depolarize2(1.).sample(&mut rng).unwrap();
+			assert!((1..16).contains(&pauli));
+		}
+	}
+
+	#[test]
+	fn test_pauli_frames() {
+		// The frame histograms should agree with those of independent tableau simulations
+		let circuits = vec![
+			"@pragma total_num_qubits 3\n@pragma total_num_cbits 5\nh q0\ncx q0 q1\ncx q1 q2\nmzr q0 r0\nmzr q1 r1\nmxr q2 r2\nmzr q2 r3\nmzr q1 r4",
+			"@pragma total_num_qubits 3\n@pragma total_num_cbits 5\nh q0\ncy q0 q1\nsqrtx q2\ncz q1 q2\nmpp XY q0 q1 r0\nmpp ZZ q0 q2 r1\nmyr q1 r2\nmr q0 r3\nresety q2\nmyr q2 r4",
+			"@pragma total_num_qubits 2\n@pragma total_num_cbits 4\nresetx q0\ncx q0 q1\ndepolarize2 0.3 q0 q1\nmzr q0 r0\nmzr q1 r1\n@repeat 2 {\n    x_error 0.25 q0\n    mr q0 r2\n}\npauli_channel 0.1 0.2 0.3 q1\nmxr q1 r3",
+		];
+
+		let num_shots: usize = 4000;
+		for circuit in circuits {
+			let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from(circuit));
+			let reference = qc.sample_parallel(num_shots, 3);
+			let frames = qc.sample_frames(num_shots, 5);
+			assert_eq!(frames.values().sum::<usize>(), num_shots);
+
+			let outcomes: HashSet<&String> = reference.keys().chain(frames.keys()).collect();
+			for outcome in outcomes {
+				let p1 = *reference.get(outcome).unwrap_or(&0) as f32 / num_shots as f32;
+				let p2 = *frames.get(outcome).unwrap_or(&0) as f32 / num_shots as f32;
+				assert!((p1 - p2).abs() < 0.04, "{circuit}\n{outcome}: {p1} != {p2}");
+			}
+		}
+
+		// Deterministic outcomes are the same in every shot
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 2\n@pragma total_num_cbits 2\nx q0\nh q1\nmzr q0 r0\nmxr q1 r1"));
+		assert_eq!(qc.sample_frames(100, 0), HashMap::from([(String::from("10"), 100)]));
+
+		// Conditioned circuits are sampled shot by shot
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&teleportation_circuit(1));
+		let histogram = qc.sample_frames(200, 4);
+		assert_eq!(histogram, qc.sample(200, 4));
+		assert!(histogram.keys().all(|outcome| outcome.ends_with('0')));
+	}
+
+	#[test]
+	fn test_optimize() {
+		let mut qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 3\n@pragma total_num_cbits 1\n\
+			h q0\nh q0\ncz q0 q1\ns q0\ncz q1 q0\nsd q0\ncx q1 q2\ncx q1 q2\nh q2\ns q2\ns q2\nh q2\nmzr q2 r0\n@repeat 2 {\n    x q1\n    z q1\n    cy q0 q1\n}"));
+		assert_eq!(qc.optimize(), 12);
+		assert_eq!(qc.to_qasm(), "@pragma total_num_qubits 3\n@pragma total_num_cbits 1\nx q2\nmzr q2 r0\n@repeat 2 {\n    y q1\n    cy q0 q1\n}");
+
+		// Gates do not cancel across a print
+		let mut qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 2\n@pragma total_num_cbits 0\n\
+			h q0\ncz q0 q1\n@pragma print\ncz q0 q1"));
+		assert_eq!(qc.optimize(), 0);
+		assert_eq!(qc.to_qasm(), "@pragma total_num_qubits 2\n@pragma total_num_cbits 0\nh q0\ncz q0 q1\n@pragma print\ncz q0 q1");
+
+		// Optimized random circuits prepare the same state up to a global phase
+		let gates = ["h", "s", "sd", "x", "y", "z", "sqrtx", "sqrtxd", "sqrty", "sqrtyd", "sqrtz", "sqrtzd", "cz", "cx", "cy"];
+		let num_qubits: usize = 4;
+		let mut rng = rand::thread_rng();
+		for _ in 0..50 {
+			let mut circuit: String = format!("@pragma total_num_qubits {num_qubits}\n@pragma total_num_cbits 0");
+			for _ in 0..60 {
+				// Few distinct qubit pairs, so that two-qubit gates have a chance to cancel
+				let gate = gates[rng.gen_range(0..gates.len())];
+				let q1: usize = rng.gen_range(0..num_qubits);
+				circuit += &match gate {
+					"cz" | "cx" | "cy" => format!("\n{gate} q{} q{}", q1/2*2, q1/2*2 + 1),
+					_ => format!("\n{gate} q{q1}"),
+				};
+			}
+
+			let mut qc1 = QuantumProgram::<QuantumVectorState>::from_qasm(&circuit);
+			let mut qc2 = QuantumProgram::<QuantumVectorState>::from_qasm(&circuit);
+			let removed = qc2.optimize();
+			assert_eq!(qc2.to_qasm().lines().count() + removed, circuit.lines().count());
+
+			qc1.execute();
+			qc2.execute();
+			assert!(qc1.quantum_state == qc2.quantum_state, "{circuit}");
+		}
+	}
+
+	#[test]
+	fn test_analysis() {
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 5\n@pragma total_num_cbits 2\n\
+			h q0\nh q1\ncx q0 q1\ncz q3 q2\nx_error 0.1 q2\nmzr q1 r0\nif r0 == 1 x q4\n@repeat 2 {\n    cz q1 q0\n}\nmpp XZ q2 q3 r1\n@pragma print"));
+
+		let analysis = qc.analyze();
+		assert_eq!(analysis.depth, 5);
+		let counts: Vec<(&str, usize)> = analysis.gate_counts.iter().map(|(name, count)| (name.as_str(), *count)).collect();
+		assert_eq!(counts, vec![("cx", 1), ("cz", 3), ("h", 2), ("mpp", 1), ("mzr", 1), ("x", 1), ("x_error", 1)]);
+		assert_eq!(analysis.measurements, vec![0, 1, 1, 1, 0]);
+		assert_eq!(analysis.interactions, BTreeMap::from([((0, 1), 3), ((2, 3), 1)]));
+
+		// The condition on r0 brings the measurement of q1, and everything before it, into the cone of q4
+		let cone = qc.light_cone(4);
+		assert_eq!(cone.instructions, vec![0, 1, 2, 5, 6]);
+		assert_eq!(cone.qubits, BTreeSet::from([0, 1, 4]));
+		assert_eq!(qc.light_cone(3).qubits, BTreeSet::from([2, 3]));
+		assert_eq!(qc.light_cone(0).instructions, vec![0, 1, 2, 5, 7, 8]);
+
+		// A measurement into a register must wait for the conditions which read it before
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 2\n@pragma total_num_cbits 1\n\
+			if r0 == 1 x q1\nmzr q0 r0"));
+		assert_eq!(qc.analyze().depth, 2);
+	}
+
+	#[test]
+	fn test_render() {
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 3\n@pragma total_num_cbits 1\n\
+			h q0\ncx q0 q2\ns q1\nmzr q2 r0\n@pragma print\nif r0 == 1 z q1"));
+		let expected = [
+			"q0: -H--@---------#-----------",
+			"        |         #",
+			"q1: ----|----S----#--Z[r0==1]-",
+			"        |         #",
+			"q2: ----X--M->r0--#-----------",
+		];
+		assert_eq!(qc.to_ascii(), expected.join("\n"));
+
+		let svg = qc.to_svg();
+		assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\"") && svg.ends_with("</svg>"));
+		assert!(svg.contains(">M-&gt;r0</text>") && svg.contains(">print</text>") && svg.contains("<circle"));
+
+		// Conditions are drawn after the measurements they read, and measurements after the conditions
+		// which read their register
+		let qc = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 3\n@pragma total_num_cbits 1\n\
+			mzr q0 r0\nif r0 == 1 x q1\nmzr q2 r0"));
+		let expected = [
+			"q0: -M->r0------------------",
+			"",
+			"q1: --------X[r0==1]--------",
+			"",
+			"q2: ------------------M->r0-",
+		];
+		assert_eq!(qc.to_ascii(), expected.join("\n"));
+	}
+
+	#[test]
+	fn test_circuit_builder() {
+		let mut circuit = CircuitBuilder::new(3, 2);
+		circuit.h(0).cx(0, 1)
+			.repeat(2, |b| { b.cz(1, 2).sqrtx(2); })
+			.mpp(&[(Gate::X, 0), (Gate::Z, 2)], 0)
+			.noise(NoiseChannel::XError(0.5), &[1])
+			.print()
+			.mr(1, 1);
+
+		let expected = "@pragma total_num_qubits 3\n@pragma total_num_cbits 2\nh q0\ncx q0 q1\n@repeat 2 {\n    cz q1 q2\n    sqrtx q2\n}\n\
+						mpp XZ q0 q2 r0\nx_error 0.5 q1\n@pragma print\nmr q1 r1";
+		let qc = circuit.build::<QuantumCHPState>();
+		assert_eq!(qc.to_qasm(), expected);
+		assert_eq!(QuantumProgram::<QuantumCHPState>::from_qasm(&String::from(expected)).to_qasm(), expected);
+
+		// Generated circuits are equivalent to their parsed form
+		let circuit = util::generate_brick_wall_circuit(0.3, 6, 4, &mut Lcg64Xsh32::seed_from_u64(4));
+		let qc1 = circuit.build::<QuantumVectorState>();
+		let qc2 = QuantumProgram::<QuantumVectorState>::from_qasm(&qc1.to_qasm());
+		assert_eq!(qc1.to_qasm(), qc2.to_qasm());
+		assert_eq!(util::generate_fully_entangled_circuit(3).build::<QuantumGraphState>().to_qasm().lines().count(), 8);
+
+		let result = std::panic::catch_unwind(|| { CircuitBuilder::new(2, 0).cz(0, 2); });
+		assert!(result.is_err());
+		let result = std::panic::catch_unwind(|| { CircuitBuilder::new(2, 1).mzr(0, 1); });
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn test_seeding() {
+		// Any shot of sample can be replayed from its seed
+		let circuit = util::generate_brick_wall_circuit(0.3, 6, 6, &mut Lcg64Xsh32::seed_from_u64(5));
+		let mut qc = circuit.build::<QuantumCHPState>();
+		for i in 0..5 {
+			let mut histogram = qc.sample(i + 1, 11);
+			qc.set_seed(QuantumProgram::<QuantumCHPState>::shot_seed(11, i));
+			qc.execute();
+			let outcome: String = qc.get_classical_data().iter().map(|&c| if c == 1 { '1' } else { '0' }).collect();
+			assert!(histogram.remove(&outcome).is_some());
+		}
+
+		// Seeded brickwall trajectories are identical
+		let noise = NoiseModel { depolarize1: 0.05, ..NoiseModel::default() };
+		let run = |seed: u64| -> Vec<f32> {
+			let mut rng = Lcg64Xsh32::seed_from_u64(seed);
+			let mut state = QuantumGraphState::new_with_seed(16, rng.next_u64());
+			timesteps_qa_noisy(&mut state, 20, 0.2, &noise, &mut rng);
+			return (1..16).map(|i| state.renyi_entropy(&(0..i).collect())).collect();
+		};
+		assert_eq!(run(3), run(3));
+		assert_ne!(run(3), run(4));
+	}
+
+	#[test]
+	fn test_registers() {
+		let circuit = String::from("@qreg sys 3\n@qreg anc 2\n@creg syn 2\n@creg out 1\n\
+			x sys[1]\ncx sys[1] anc[0]\nmzr anc[0] syn[1]\nif syn[1] == 1 x anc[1]\nmzr anc[1] out[0]\nmzr sys[0] syn[0]");
+		let mut program = QuantumProgram::<QuantumCHPState>::from_qasm(&circuit);
+		assert_eq!(program.quantum_registers().iter().map(|r| (r.name.as_str(), r.offset, r.size)).collect::<Vec<_>>(),
+				   vec![("sys", 0, 3), ("anc", 3, 2)]);
+		assert_eq!(program.qubit_index("anc", 1), Some(4));
+		assert_eq!(program.qubit_index("anc", 2), None);
+		assert_eq!(program.cbit_index("out", 0), Some(2));
+		assert_eq!(program.cbit_index("foo", 0), None);
+
+		program.execute();
+		assert_eq!(program.get_named_classical_register("syn"), Some(vec![0, 1]));
+		assert_eq!(program.get_named_classical_register("out"), Some(vec![1]));
+		assert_eq!(program.get_classical_register(1), 1);
+		assert_eq!(program.get_named_classical_register("sys"), None);
+
+		let qasm = program.to_qasm();
+		assert!(qasm.contains("@qreg sys 3\n@qreg anc 2\n@creg syn 2\n@creg out 1"));
+		let round_trip = QuantumProgram::<QuantumCHPState>::from_qasm(&qasm);
+		assert_eq!(round_trip.to_qasm(), qasm);
+		assert_eq!(round_trip.quantum_registers(), program.quantum_registers());
+
+		// Registers and explicit totals can be mixed, with the larger total taking effect
+		let mixed = QuantumProgram::<QuantumCHPState>::from_qasm(&String::from("@pragma total_num_qubits 8\n@qreg sys 4\nh q7\nh sys[3]"));
+		assert_eq!(mixed.instructions().len(), 2);
+		assert_eq!(mixed.instructions()[1].qubits, vec![3]);
+
+		let header = "@qreg sys 2\n@creg syn 1\n";
+		let cases: [(&str, usize, usize, &str); 6] = [
+			("h foo[0]", 3, 3, "foo[0]"),
+			("h sys[2]", 3, 3, "sys[2]"),
+			("mzr sys[0] syn[x]", 3, 12, "syn[x]"),
+			("@qreg syn 2", 3, 7, "syn"),
+			("@creg 2", 3, 1, "@creg"),
+			("@pragma total_num_qubits 1", 3, 26, "1"),
+		];
+		for (body, line, column, token) in cases {
+			let circuit = String::from(header) + body;
+			let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&circuit).err().unwrap();
+			assert_eq!((err.line, err.column, err.token.as_str()), (line, column, token));
+		}
+	}
+
+	#[test]
+	fn test_dense_vs_vector() {
+		let num_qubits: usize = 6;
+		let mut rng = Lcg64Xsh32::seed_from_u64(18);
+		for _ in 0..20 {
+			// The single-precision vector state only matches the dense state exactly on Clifford circuits
+			let (state1, state2) = check_against_dense::<QuantumVectorState>(num_qubits, 100, false, &[vec![0], vec![0, 1, 2], vec![1, 3, 4, 5]], EPS, &mut rng);
+			assert!(state1 == state2.to_vector_state());
+		}
+
+		// Large enough for the kernels to run in parallel
+		let num_qubits: usize = 16;
+		let mut ghz = QuantumDenseState::new_with_seed(num_qubits, 3);
+		ghz.h_gate(0);
+		for q in 1..num_qubits {
+			ghz.cx_gate(q - 1, q);
+		}
+		assert!(isclose(ghz.renyi_entropy(&vec![0, 5, 9]), 1.));
+		let measured: i32 = ghz.mzr_qubit(7);
+		for q in 0..num_qubits {
+			assert_eq!(ghz.mzr_qubit(q), measured);
+		}
+		assert!(isclose(ghz.renyi_entropy(&(0..8).collect()), 0.));
+	}
+
+	fn check_non_clifford_identities<Q: NonCliffordState + PartialEq>() {
+		use std::f64::consts::PI;
+		let prepare = || -> Q {
+			let mut state = Q::new(2);
+			state.h_gate(0);
+			state.sqrty_gate(1);
+			state.cx_gate(0, 1);
+			state.t_gate(1);
+			state
+		};
+
+		let mut state1 = prepare();
+		let mut state2 = prepare();
+		state1.t_gate(0);
+		state1.t_gate(0);
+		state2.s_gate(0);
+		assert!(state1 == state2);
+
+		state1.td_gate(0);
+		state1.td_gate(0);
+		state2.sd_gate(0);
+		assert!(state1 == state2);
+
+		state1.rz_gate(1, PI/2.);
+		state2.s_gate(1);
+		assert!(state1 == state2);
+
+		state1.rx_gate(0, PI);
+		state2.x_gate(0);
+		assert!(state1 == state2);
+
+		state1.ry_gate(1, PI);
+		state2.y_gate(1);
+		assert!(state1 == state2);
+
+		state1.u3_gate(0, PI/2., 0., PI);
+		state2.h_gate(0);
+		assert!(state1 == state2);
+
+		state1.cphase_gate(0, 1, PI);
+		state2.cz_gate(0, 1);
+		assert!(state1 == state2);
+	}
+
+	#[test]
+	fn test_non_clifford() {
+		check_non_clifford_identities::<QuantumVectorState>();
+		check_non_clifford_identities::<QuantumDenseState>();
+
+		let num_qubits: usize = 5;
+		let mut rng = Lcg64Xsh32::seed_from_u64(19);
+		for _ in 0..10 {
+			let mut state1 = QuantumVectorState::new(num_qubits);
+			let mut state2 = QuantumDenseState::new(num_qubits);
+			for _ in 0..50 {
+				let q1: usize = rng.gen_range(0..num_qubits);
+				let q2: usize = (q1 + rng.gen_range(1..num_qubits)) % num_qubits;
+				let theta: f64 = rng.gen_range(0.0..std::f64::consts::TAU);
+				match rng.gen_range(0..6) {
+					0 => { state1.h_gate(q1); state2.h_gate(q1); },
+					1 => { state1.t_gate(q1); state2.t_gate(q1); },
+					2 => { state1.rx_gate(q1, theta); state2.rx_gate(q1, theta); },
+					3 => { state1.u3_gate(q1, theta, 2.*theta, 0.5); state2.u3_gate(q1, theta, 2.*theta, 0.5); },
+					4 => { state1.cphase_gate(q1, q2, theta); state2.cphase_gate(q1, q2, theta); },
+					_ => { state1.cx_gate(q1, q2); state2.cx_gate(q1, q2); },
+				}
+			}
+
+			for qubits in [vec![0], vec![1, 2], vec![0, 3, 4]] {
+				assert!((state1.renyi_entropy(&qubits) - state2.renyi_entropy(&qubits)).abs() < 1e-3);
+			}
+		}
+
+		// Programs built for a NonCliffordState accept non-Clifford gates in the native format and the builder
+		let circuit = "@pragma total_num_qubits 2\n@pragma total_num_cbits 1\n\
+					   h q0\nt q0\ntd q1\nrx 0.5 q1\nry -1.25 q0\nrz 3 q1\nu3 0.1 0.2 0.3 q0\ncp 1.5 q0 q1";
+		let mut qc1 = QuantumProgram::<QuantumVectorState>::from_qasm_non_clifford(&String::from(circuit));
+		assert_eq!(qc1.to_qasm(), circuit);
+		let mut qc2 = CircuitBuilder::new(2, 1).h(0).t(0).td(1).rx(1, 0.5).ry(0, -1.25).rz(1, 3.).u3(0, 0.1, 0.2, 0.3).cp(0, 1, 1.5)
+			.build_non_clifford::<QuantumVectorState>();
+		assert_eq!(qc2.to_qasm(), circuit);
+
+		let mut state = QuantumVectorState::new(2);
+		state.h_gate(0);
+		state.t_gate(0);
+		state.td_gate(1);
+		state.rx_gate(1, 0.5);
+		state.ry_gate(0, -1.25);
+		state.rz_gate(1, 3.);
+		state.u3_gate(0, 0.1, 0.2, 0.3);
+		state.cphase_gate(0, 1, 1.5);
+		qc1.execute();
+		qc2.execute();
+		assert!(qc1.quantum_state == state && qc2.quantum_state == state);
+
+		assert!(qc1.to_openqasm().unwrap().contains("\nt q[0];\ntdg q[1];\nrx(0.5) q[1];") && qc1.to_openqasm().unwrap().ends_with("cu1(1.5) q[0], q[1];"));
+		assert!(qc1.to_stim().is_err());
+
+		// Exported OpenQASM parses back to the same program, and angles may be given as expressions
+		let openqasm = qc1.to_openqasm().unwrap();
+		assert_eq!(QuantumProgram::<QuantumVectorState>::from_openqasm_non_clifford(&openqasm).to_qasm(), circuit);
+		assert_eq!(QuantumProgram::<QuantumVectorState>::from_qasm_non_clifford(&openqasm).to_qasm(), circuit);
+		let err = QuantumProgram::<QuantumVectorState>::try_from_openqasm(&openqasm).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (6, 1, "t"));
+		let openqasm = String::from("OPENQASM 2.0;\nqreg q[1];\nrx(-pi/2) q[0];\nu1(2*(1+0.5)^2) q[0];\nry(cos(0)) q[0];");
+		assert_eq!(QuantumProgram::<QuantumVectorState>::from_openqasm_non_clifford(&openqasm).instructions().iter().map(|inst| inst.id).collect::<Vec<Gate>>(),
+				   vec![Gate::Rx(-std::f64::consts::PI/2.), Gate::Rz(4.5), Gate::Ry(1.)]);
+		let err = QuantumProgram::<QuantumVectorState>::try_from_openqasm_non_clifford(&String::from("OPENQASM 2.0;\nqreg q[1];\nrz(theta) q[0];")).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (3, 4, "theta"));
+
+		let histogram = CircuitBuilder::new(1, 1).rx(0, std::f64::consts::PI).mzr(0, 0).build_non_clifford::<QuantumVectorState>().sample(20, 3);
+		assert_eq!(histogram, HashMap::from([(String::from("1"), 20)]));
+
+		// Other programs reject them
+		let errors = vec![
+			("@pragma total_num_qubits 1\nh q0\nt q0", (3, 1, "t")),
+			("@pragma total_num_qubits 1\n@pragma total_num_cbits 1\nif r0 == 1 rx 0.5 q0", (3, 12, "rx")),
+		];
+		for (circuit, (line, column, token)) in errors {
+			let err = QuantumProgram::<QuantumCHPState>::try_from_qasm(&String::from(circuit)).err().unwrap();
+			assert_eq!((err.line, err.column, err.token.as_str()), (line, column, token));
+		}
+		let err = QuantumProgram::<QuantumVectorState>::try_from_qasm_non_clifford(&String::from("@pragma total_num_qubits 1\nrx q0")).err().unwrap();
+		assert_eq!((err.line, err.column, err.token.as_str()), (2, 4, "q0"));
+		let result = std::panic::catch_unwind(|| { CircuitBuilder::new(1, 0).t(0).build::<QuantumVectorState>(); });
+		assert!(result.is_err());
+	}
+
+	fn check_wide_vector_state<Q: QuantumState + Entropy + MzrForce>(num_qubits: usize) {
+		let mut state = Q::new_with_seed(num_qubits, 20);
+		state.h_gate(0);
+		for q in [1, 70, num_qubits - 1] {
+			state.cx_gate(0, q);
+		}
+		assert!(isclose(state.renyi_entropy(&vec![num_qubits - 1]), 1.));
+		assert!(isclose(state.renyi_entropy(&vec![0, 1, 70]), 1.));
+		assert!(isclose(state.renyi_entropy(&vec![2, 3]), 0.));
+
+		let measured: i32 = state.mzr_qubit(70);
+		assert_eq!(state.mzr_qubit(num_qubits - 1), measured);
+		assert_eq!(state.mzr_qubit(0), measured);
+		assert!(!state.mzr_qubit_forced(1, measured == 0));
+	}
+
+	#[test]
+	fn test_vector_precision() {
+		check_wide_vector_state::<GenericVectorState<u128, f32>>(128);
+		check_wide_vector_state::<GenericVectorState<bit_vec::BitVec, f64>>(200);
+
+		// Tiny rotations leave amplitudes below the precision, which are pruned and renormalized
+		let mut state = QuantumVectorState::new(2);
+		state.h_gate(0);
+		for _ in 0..1000 {
+			state.rx_gate(1, 1e-7);
+		}
+		assert!(state.discarded_weight() > 0.);
+		assert!(isclose(state.renyi_entropy(&vec![]), 0.));
+
+		let mut state = GenericVectorState::<u64, f64>::new(2);
+		state.h_gate(0);
+		for _ in 0..1000 {
+			state.rx_gate(1, 1e-7);
+		}
+		assert_eq!(state.discarded_weight(), 0.);
+		assert!(isclose(state.renyi_entropy(&vec![1]), 0.));
+	}
+
+	#[test]
+	fn test_density_matrix() {
+		// Pure states evolve as the dense backend does
+		let num_qubits: usize = 4;
+		let mut rng = Lcg64Xsh32::seed_from_u64(21);
+		for _ in 0..10 {
+			let subsystems: [Vec<usize>; 3] = [vec![0], vec![1, 2], vec![0, 2, 3]];
+			let (state1, state2) = check_against_dense::<QuantumDensityMatrixState>(num_qubits, 40, true, &subsystems, EPS, &mut rng);
+
+			let rho1 = state1.density_matrix();
+			let rho2 = state2.to_vector_state().reduced_state(&(0..num_qubits).collect());
+			for i in 0..rho1.len() {
+				for j in 0..rho1.len() {
+					assert!((rho1[i][j].re - rho2[i][j].re as f64).abs() < 1e-4 && (rho1[i][j].im - rho2[i][j].im as f64).abs() < 1e-4);
+				}
+			}
+			for qubits in &subsystems {
+				assert!(isclose(state1.renyi_entropy_n(qubits, 2.), state1.renyi_entropy(qubits)));
+			}
+		}
+
+		// Entropies of a Bell pair and of mixed states
+		let mut bell = QuantumDensityMatrixState::new_with_seed(3, 21);
+		bell.h_gate(0);
+		bell.cx_gate(0, 1);
+		for n in [1., 2., 3.] {
+			assert!(isclose(bell.renyi_entropy_n(&vec![0], n), 1.));
+			assert!(isclose(bell.renyi_entropy_n(&vec![0, 1], n), 0.));
+		}
+		let reduced = bell.partial_trace(&[1, 2]);
+		assert!(reduced == QuantumDensityMatrixState::maximally_mixed(1));
+		assert!(isclose(QuantumDensityMatrixState::maximally_mixed(3).von_neumann_entropy(&vec![0, 1, 2]), 3.));
+
+		// Noise channels are applied exactly
+		let mut state = QuantumDensityMatrixState::new(2);
+		state.apply_noise(&NoiseChannel::Depolarize1(0.75), &[0], &mut rng);
+		assert!(state.partial_trace(&[1]) == QuantumDensityMatrixState::maximally_mixed(1));
+		state.apply_noise(&NoiseChannel::Depolarize2(15./16.), &[0, 1], &mut rng);
+		assert!(state == QuantumDensityMatrixState::maximally_mixed(2));
+
+		// Amplitude damping with gamma = 1 takes |1> to |0>
+		let (zero, one) = (num::complex::Complex::new(0., 0.), num::complex::Complex::new(1., 0.));
+		let mut state = QuantumDensityMatrixState::new(1);
+		state.x_gate(0);
+		state.apply_kraus(&[vec![one, zero, zero, zero], vec![zero, one, zero, zero]], &[0]);
+		assert!(state == QuantumDensityMatrixState::new(1));
+		assert!(isclose(state.trace() as f32, 1.));
+	}
+
+	#[test]
+	fn test_mps() {
+		let num_qubits: usize = 6;
+		let mut rng = Lcg64Xsh32::seed_from_u64(22);
+		for _ in 0..10 {
+			// Without truncation the state is exact, and entropies of cuts and of bulk intervals agree
+			// with the dense state
+			let subsystems: Vec<Vec<usize>> = [(0, 1), (0, 3), (2, 6), (1, 4), (2, 3)].iter().map(|&(start, end)| (start..end).collect()).collect();
+			let (state1, state2) = check_against_dense::<QuantumMPSState>(num_qubits, 100, true, &subsystems, EPS, &mut rng);
+			assert!(state1.to_dense_state() == state2);
+			assert_eq!(state1.discarded_weight(), 0.);
+			let schmidt: Vec<f64> = state1.schmidt_values(2);
+			assert!((schmidt.iter().map(|s| s*s).sum::<f64>() - 1.).abs() < 1e-8);
+			assert!(isclose(state1.bond_entropy(2, 2.), state2.renyi_entropy(&vec![0, 1, 2])));
+		}
+
+		// A volume-law state truncated to bond dimension 2 loses weight, which is reported
+		let num_qubits: usize = 10;
+		let mut state = QuantumMPSState::new_with_seed(num_qubits, 5);
+		state.set_truncation(2, 0.);
+		for _ in 0..10 {
+			for i in 0..num_qubits {
+				state.h_gate(i);
+				state.t_gate(i);
+			}
+			for i in 0..num_qubits - 1 {
+				state.cz_gate(i, i + 1);
+			}
+		}
+		assert!(state.max_bond_dimension() <= 2);
+		assert!(state.discarded_weight() > 0.);
+		assert!((state.to_dense_state().amplitudes.iter().map(|a| a.norm_sqr()).sum::<f64>() - 1.).abs() < 1e-8);
+	}
+
+	#[test]
+	fn test_stabilizer_rank() {
+		let num_qubits: usize = 5;
+		let mut rng = Lcg64Xsh32::seed_from_u64(23);
+		for _ in 0..20 {
+			let subsystems: [Vec<usize>; 4] = [vec![0], vec![1, 3], vec![0, 2, 4], vec![4, 1, 0, 3]];
+			let (mut state1, mut state2) = check_against_dense::<QuantumStabilizerRankState>(num_qubits, 60, true, &subsystems, 1e-3, &mut rng);
+			assert!(state1.to_dense_state() == state2);
+			assert_eq!(state1.discarded_weight(), 0.);
+
+			// Gates with X and Y components expand into more branches
+			state1.rx_gate(0, 0.3);
+			state2.rx_gate(0, 0.3);
+			state1.ry_gate(2, 1.1);
+			state2.ry_gate(2, 1.1);
+			assert!(state1.to_dense_state() == state2);
+		}
+
+		// Random Cliffords act on the tableau as they do for the CHP state with the same seed, also
+		// alongside non-stabilizer branches on other qubits
+		let num_qubits: usize = 6;
+		let mut state1 = QuantumStabilizerRankState::new_with_seed(num_qubits, 3);
+		let mut state2 = QuantumCHPState::new_with_seed(num_qubits, 3);
+		state1.h_gate(0);
+		state1.t_gate(0);
+		state2.h_gate(0);
+		state1.random_clifford(vec![1, 2, 3, 4, 5]);
+		state2.random_clifford(vec![1, 2, 3, 4, 5]);
+		state1.random_clifford(vec![4, 2]);
+		state2.random_clifford(vec![4, 2]);
+		assert_eq!(state1.num_branches(), 2);
+		for qubits in [vec![0], vec![1], vec![2, 3], vec![1, 4, 5], vec![0, 2, 5]] {
+			assert!(isclose(state1.renyi_entropy(&qubits), state2.renyi_entropy(&qubits)));
+		}
+
+		// Each T gate on |+> splits the branches in two, and measuring collapses them
+		let num_qubits: usize = 40;
+		let mut state = QuantumStabilizerRankState::new_with_seed(num_qubits, 7);
+		for i in 0..num_qubits {
+			state.h_gate(i);
+		}
+		for i in 0..6 {
+			state.t_gate(i);
+		}
+		assert_eq!(state.num_branches(), 64);
+		for i in 0..num_qubits - 1 {
+			state.cx_gate(i, i + 1);
+		}
+		assert_eq!(state.num_branches(), 64);
+		for i in 0..6 {
+			state.mzr_qubit(i);
+		}
+		assert_eq!(state.num_branches(), 1);
+
+		// Capping the branches discards weight
+		let mut state = QuantumStabilizerRankState::new_with_seed(num_qubits, 7);
+		state.set_max_branches(8);
+		for i in 0..num_qubits {
+			state.h_gate(i);
+			state.t_gate(i);
+		}
+		assert_eq!(state.num_branches(), 8);
+		assert!(state.discarded_weight() > 0.);
+	}
+
+	#[test]
+	fn test_tableau_serialization() {
+		let mut state = QuantumCHPState::new_with_seed(3, 5);
+		state.h_gate(0);
+		state.cx_gate(0, 1);
+		state.s_gate(2);
+		state.h_gate(2);
+		state.mzr_qubit(2);
+		state.cz_gate(1, 2);
+
+		// The same state as written by the row-based tableau, including its scratch row
+		let legacy: &str = r#"{"num_qubits":3,"tableau":{"rows":[{"num_qubits":3,"bit_string":{"storage":[8],"nbits":6},"phase":false},{"num_qubits":3,"bit_string":{"storage":[34],"nbits":6},"phase":false},{"num_qubits":3,"bit_string":{"storage":[20],"nbits":6},"phase":false},{"num_qubits":3,"bit_string":{"storage":[35],"nbits":6},"phase":false},{"num_qubits":3,"bit_string":{"storage":[24],"nbits":6},"phase":false},{"num_qubits":3,"bit_string":{"storage":[32],"nbits":6},"phase":true},{"num_qubits":3,"bit_string":{"storage":[0],"nbits":6},"phase":false}],"track_destabilizers":true,"print_ops":true},"rng":{"state":17338913627034512915,"increment":18127503437953003681}}"#;
+		let mut legacy_state: QuantumCHPState = serde_json::from_str(legacy).unwrap();
+		assert_eq!(legacy_state.print(), state.print());
+
+		let serialized: String = serde_json::to_string(&state).unwrap();
+		assert!(serialized.contains("\"version\":2"));
+		let mut round_trip: QuantumCHPState = serde_json::from_str(&serialized).unwrap();
+		assert_eq!(round_trip.print(), state.print());
+		for i in 0..3 {
+			let outcome: i32 = state.mzr_qubit(i);
+			assert_eq!(legacy_state.mzr_qubit(i), outcome);
+			assert_eq!(round_trip.mzr_qubit(i), outcome);
+		}
+
+		let unknown: String = serialized.replace("\"version\":2", "\"version\":7");
+		assert!(serde_json::from_str::<QuantumCHPState>(&unknown).is_err());
+		// Columns which fit the row count are still rejected if the row count does not fit the qubits
+		assert!(serialized.contains("\"num_rows\":6"));
+		let wrong_rows: String = serialized.replace("\"num_rows\":6", "\"num_rows\":3");
+		assert!(serde_json::from_str::<QuantumCHPState>(&wrong_rows).is_err());
+		let wrong_rows: String = serialized.replace("\"track_destabilizers\":true", "\"track_destabilizers\":false");
+		assert!(serde_json::from_str::<QuantumCHPState>(&wrong_rows).is_err());
+	}
+
+	#[test]
+	fn test_stabilizer_only() {
+		let mut rng = Lcg64Xsh32::seed_from_u64(25);
+		// Sizes above 64 qubits span several words of the packed tableau
+		for (num_qubits, num_circuits) in [(12, 20), (70, 3)] {
+			for _ in 0..num_circuits {
+				let seed: u64 = rng.gen();
+				let mut state1 = QuantumCHPState::new_with_seed(num_qubits, seed);
+				let mut state2 = QuantumCHPState::new_with_seed(num_qubits, seed);
+				state2.drop_destabilizers();
+				// Deterministic outcomes must agree, and random ones are forced to agree
+				for _ in 0..200 {
+					Instruction::random_extended(&mut rng, num_qubits, false).apply_clifford(&mut state1, &mut state2);
+				}
+
+				for i in 0..num_qubits {
+					let qubits: Vec<usize> = (0..i).collect();
+					assert_eq!(state1.renyi_entropy(&qubits), state2.renyi_entropy(&qubits));
+				}
+
+				let mut pauli = PauliString::rand(num_qubits, &mut rng);
+				for _ in 0..10 {
+					let mut copy1: QuantumCHPState = state1.clone();
+					let mut copy2: QuantumCHPState = state2.clone();
+					for i in 0..num_qubits {
+						assert_eq!(copy1.measure_pauli(&pauli), copy2.measure_pauli(&pauli));
+						assert_eq!(copy1.mzr_qubit(i), copy2.mzr_qubit(i));
+					}
+					pauli = PauliString::rand(num_qubits, &mut rng);
+				}
+			}
+		}
+	}
+}